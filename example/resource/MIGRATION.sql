@@ -1,9 +1,70 @@
+-- The schema as of the latest migration, see `Adapter::migrate` to let the adapter manage it instead
 CREATE TABLE IF NOT EXISTS events (
     id UUID PRIMARY KEY,
     entity_id TEXT NOT NULL,
     seq_nr BIGINT NOT NULL,
     timestamp TIMESTAMPTZ NOT NULL,
-    payload JSONB NOT NULL
+    -- JSONB by default, JSON or BYTEA with PostgresAdapterBuilder::with_payload_type
+    payload JSONB NOT NULL,
+    position BIGSERIAL NOT NULL,
+    category TEXT GENERATED ALWAYS AS (split_part(entity_id, ':', 1)) STORED
 );
 
 CREATE UNIQUE INDEX IF NOT EXISTS events_entity_id_seq_nr_idx ON events (entity_id, seq_nr);
+
+CREATE UNIQUE INDEX IF NOT EXISTS events_position_idx ON events (position);
+
+CREATE INDEX IF NOT EXISTS events_category_position_idx ON events (category, position);
+
+-- Only needed to query payloads, see PostgresAdapterBuilder::with_payload_index
+-- CREATE INDEX IF NOT EXISTS events_payload_idx ON events USING GIN (payload);
+
+CREATE TABLE IF NOT EXISTS outbox (
+    id BIGSERIAL PRIMARY KEY,
+    entity_id TEXT NOT NULL,
+    timestamp TIMESTAMPTZ NOT NULL,
+    payload BYTEA NOT NULL,
+    sent BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE INDEX IF NOT EXISTS outbox_unsent_idx ON outbox (id) WHERE sent = FALSE;
+
+CREATE TABLE IF NOT EXISTS snapshots (
+    entity_id TEXT NOT NULL,
+    seq_nr BIGINT NOT NULL,
+    timestamp TIMESTAMPTZ NOT NULL,
+    state_version BIGINT NOT NULL DEFAULT 0,
+    payload JSONB NOT NULL,
+    PRIMARY KEY (entity_id, seq_nr)
+);
+
+ALTER TABLE snapshots ADD COLUMN IF NOT EXISTS state_version BIGINT NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS checkpoints (
+    projection TEXT PRIMARY KEY,
+    position BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+    entity_id TEXT NOT NULL,
+    key TEXT NOT NULL,
+    first_seq_nr BIGINT NOT NULL,
+    last_seq_nr BIGINT NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (entity_id, key)
+);
+
+ALTER TABLE events ADD COLUMN IF NOT EXISTS correlation_id UUID;
+ALTER TABLE events ADD COLUMN IF NOT EXISTS source TEXT;
+ALTER TABLE events ADD COLUMN IF NOT EXISTS hash TEXT;
+
+CREATE TABLE IF NOT EXISTS effects (
+    id BIGSERIAL PRIMARY KEY,
+    entity_id TEXT NOT NULL,
+    seq_nr BIGINT NOT NULL,
+    name TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    done BOOLEAN NOT NULL DEFAULT FALSE
+);
+CREATE INDEX IF NOT EXISTS effects_pending_idx ON effects (id) WHERE done = FALSE;
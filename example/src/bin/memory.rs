@@ -7,7 +7,7 @@ use mnemosyne::{
     Unit,
 };
 use serde::{Deserialize, Serialize};
-use std::{fmt::Debug, time::Duration};
+use std::fmt::Debug;
 
 #[derive(Default, Debug, Clone, Deserialize)]
 pub struct State {
@@ -15,7 +15,7 @@ pub struct State {
 }
 
 #[derive(Debug, Clone, Serialize, MCommand, Deserialize)]
-#[command(state = "State", directive = "UserEvent")]
+#[command(state = "State", directive = "UserEvent", name_from_variant)]
 #[serde(tag = "type")]
 pub enum UserCommand {
     Increment(Increment),
@@ -67,15 +67,13 @@ impl Event<State> for Incremented {
 }
 
 #[actix::main]
-async fn main() {
+async fn main() -> Result<Unit, Error> {
     let mut configuration = ClientConfig::new();
     let configuration = configuration.set("bootstrap.servers", "localhost:9092");
     println!("Configuration created");
 
     let engine: Engine<State, MemoryAdapter, UserCommand, Incremented> =
-        Engine::start(configuration.to_owned(), MemoryAdapter::default())
-            .await
-            .expect("Could not create engine");
+        Engine::start(configuration.to_owned(), MemoryAdapter::default()).await?;
 
     println!("Engine created");
 
@@ -83,18 +81,15 @@ async fn main() {
         let command = UserCommand::Increment(Increment);
         println!("Command: {:?}", command);
 
-        engine
-            .enqueue(command.clone())
-            .await
-            .expect("Could not enqueue command");
+        let outcome = engine.enqueue(command.clone()).await?.await?;
 
-        tokio::time::sleep(Duration::from_secs(1)).await;
+        println!("Events: {:?}", outcome.events());
     }
 
-    tokio::time::sleep(Duration::from_secs(3)).await;
-
-    let state = engine.state(ENTITY_ID).await.expect("Could not get state");
+    let state = engine.state(ENTITY_ID).await?;
 
     assert_eq!(state.count, 10);
     println!("State: {:?}", state); // State { count: 10 }
+
+    Ok(())
 }
@@ -1,17 +1,17 @@
 use mnemosyne::{
-    algebra::{Command, Engine, Event},
-    domain::{Error, NonEmptyVec},
+    algebra::{Command, Engine, Event, StateFactory},
+    domain::{Consistency, Error, NonEmptyVec},
     prelude::{event_vec, Command as MCommand, Event as MEvent},
     rdkafka::ClientConfig,
     storage::{PostgresAdapter, PostgresAdapterBuilder, SslMode},
     Unit,
 };
 use serde::{Deserialize, Serialize};
-use std::{fmt::Debug, time::Duration};
+use std::fmt::Debug;
 
 pub const ENTITY_ID: &str = "tictactoe::player::1";
 
-#[derive(Default, Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 /// Tic Tac Toe
 pub struct State {
     pub board: Board,
@@ -37,16 +37,27 @@ impl Default for Board {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum Player {
-    #[default]
     X,
     O,
 }
 
+impl StateFactory for State {
+    /// Every game starts on an empty board with X to move.
+    fn initial(_entity_id: &str) -> Self {
+        Self {
+            board: Board::default(),
+            current: Player::X,
+            winner: None,
+            draw: false,
+        }
+    }
+}
+
 impl Event<State> for Player {
     fn apply(&self, _state: &State) -> Option<State> {
-        Some(State::default())
+        Some(State::initial(ENTITY_ID))
     }
 }
 
@@ -71,7 +82,7 @@ impl Command<State> for Move {
 
     fn validate(&self, state: &State) -> Result<Unit, Error> {
         if self.x > 2 || self.y > 2 {
-            return Err(Error::new("Move is out of bounds"));
+            return Err(Self::reject("out_of_bounds", "Move is out of bounds"));
         }
 
         if state
@@ -83,11 +94,11 @@ impl Command<State> for Move {
             .flatten()
             .is_some()
         {
-            return Err(Error::new("Cell is already occupied"));
+            return Err(Self::reject("cell_occupied", "Cell is already occupied"));
         }
 
         if self.player != state.current {
-            return Err(Error::new("It is not your turn"));
+            return Err(Self::reject("not_your_turn", "It is not your turn"));
         }
 
         Ok(())
@@ -218,12 +229,12 @@ pub struct GameDraw;
 
 impl Event<State> for GameDraw {
     fn apply(&self, _state: &State) -> Option<State> {
-        Some(State::default())
+        Some(State::initial(ENTITY_ID))
     }
 }
 
 #[actix::main]
-async fn main() {
+async fn main() -> Result<Unit, Error> {
     let mut configuration = ClientConfig::new();
     let configuration = configuration.set("bootstrap.servers", "localhost:9092");
     let storage = PostgresAdapter::connect(PostgresAdapterBuilder::new(
@@ -235,12 +246,10 @@ async fn main() {
         10,
         SslMode::new(false),
     ))
-    .await;
+    .await?;
 
     let engine: Engine<State, PostgresAdapter, PlayerCommand, PlayerEvent> =
-        Engine::start(configuration.to_owned(), storage)
-            .await
-            .expect("Could not create engine");
+        Engine::start(configuration.to_owned(), storage).await?;
 
     let move_1 = Move {
         player: Player::X,
@@ -279,11 +288,14 @@ async fn main() {
     };
 
     for m in [move_1, move_2, move_3, move_4, move_5, move_6] {
-        engine
-            .enqueue(PlayerCommand::MakeMove(m))
-            .await
-            .expect("Could not enqueue command");
+        engine.enqueue(PlayerCommand::MakeMove(m)).await?;
     }
 
-    tokio::time::sleep(Duration::from_secs(10)).await;
+    // Wait for the moves to be processed rather than for an arbitrary amount of time
+    let state = engine
+        .state_with_consistency(ENTITY_ID, Consistency::Strong)
+        .await?;
+    println!("State: {:?}", state);
+
+    Ok(())
 }
@@ -0,0 +1,45 @@
+use proc_macro2::TokenStream;
+use quote::{quote, ToTokens};
+use std::collections::HashMap;
+use syn::{DataEnum, Fields, Ident};
+
+/// `From` conversions into the enum from the type wrapped by each of its variants, e.g.
+/// `From<Increment> for UserCommand`.
+///
+/// Only variants wrapping a single unnamed field get one, and only if no other variant wraps
+/// the same type, so the conversion is unambiguous.
+pub fn from_impls(enum_ident: &Ident, data: &DataEnum) -> TokenStream {
+    let wrapped = data
+        .variants
+        .iter()
+        .filter_map(|variant| match &variant.fields {
+            Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
+                Some((&variant.ident, &fields.unnamed[0].ty))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    // Types are compared as written, so the same type written two ways gets two conflicting
+    // implementations, which the compiler reports rather than one being picked silently
+    let mut wrapping = HashMap::new();
+    for (_, ty) in &wrapped {
+        *wrapping
+            .entry(ty.to_token_stream().to_string())
+            .or_insert(0) += 1;
+    }
+
+    wrapped
+        .into_iter()
+        .filter(|(_, ty)| wrapping[&ty.to_token_stream().to_string()] == 1)
+        .map(|(variant_ident, ty)| {
+            quote! {
+                impl From<#ty> for #enum_ident {
+                    fn from(value: #ty) -> Self {
+                        #enum_ident::#variant_ident(value)
+                    }
+                }
+            }
+        })
+        .collect()
+}
@@ -1,4 +1,4 @@
-use super::{AttributeArgs, DIRECTIVE, STATE};
+use super::{AttributeArgs, DIRECTIVE, NAME, NAME_FROM_VARIANT, SCHEMA, STATE};
 use syn::{meta::ParseNestedMeta, Attribute, Lit};
 
 pub fn get_str_lit(meta: &ParseNestedMeta) -> Result<String, syn::Error> {
@@ -21,10 +21,13 @@ pub fn get_str_lit(meta: &ParseNestedMeta) -> Result<String, syn::Error> {
 pub fn get_inner_attribute(attrs: &Vec<Attribute>, att: &str) -> Result<AttributeArgs, syn::Error> {
     let mut state = None;
     let mut directive = None;
+    let mut name = None;
+    let mut name_from_variant = false;
+    let mut schema = false;
 
     for attr in attrs {
         if !attr.path().is_ident(att) {
-            break;
+            continue;
         }
 
         if let Err(err) = attr.parse_nested_meta(|meta| {
@@ -36,10 +39,20 @@ pub fn get_inner_attribute(attrs: &Vec<Attribute>, att: &str) -> Result<Attribut
                 let res = get_str_lit(&meta)?;
                 directive = Some(res);
                 Ok(())
+            } else if meta.path == NAME {
+                let res = get_str_lit(&meta)?;
+                name = Some(res);
+                Ok(())
+            } else if meta.path == NAME_FROM_VARIANT {
+                name_from_variant = true;
+                Ok(())
+            } else if meta.path == SCHEMA {
+                schema = true;
+                Ok(())
             } else {
                 Err(syn::Error::new_spanned(
                     meta.path,
-                    "Only `state` and `directive` attributes are supported",
+                    "Only `state`, `directive`, `name`, `name_from_variant` and `schema` attributes are supported",
                 ))
             }
         }) {
@@ -47,5 +60,11 @@ pub fn get_inner_attribute(attrs: &Vec<Attribute>, att: &str) -> Result<Attribut
         }
     }
 
-    Ok(AttributeArgs { directive, state })
+    Ok(AttributeArgs {
+        directive,
+        state,
+        name,
+        name_from_variant,
+        schema,
+    })
 }
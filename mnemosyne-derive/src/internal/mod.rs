@@ -1,11 +1,15 @@
 use self::symbol::Symbol;
 
+pub mod conversion;
 pub mod getter;
 pub mod symbol;
 
 pub struct AttributeArgs {
     pub directive: Option<String>,
     pub state: Option<String>,
+    pub name: Option<String>,
+    pub name_from_variant: bool,
+    pub schema: bool,
 }
 
 // Attributes
@@ -14,4 +18,7 @@ pub const EVENT_ATTRIBUTE: &str = "event";
 
 // Symbols
 pub const DIRECTIVE: Symbol = Symbol("directive");
+pub const NAME: Symbol = Symbol("name");
+pub const NAME_FROM_VARIANT: Symbol = Symbol("name_from_variant");
+pub const SCHEMA: Symbol = Symbol("schema");
 pub const STATE: Symbol = Symbol("state");
@@ -2,7 +2,10 @@ extern crate proc_macro;
 extern crate proc_macro2;
 
 mod internal;
-use internal::{getter::get_inner_attribute, AttributeArgs, COMMAND_ATTRIBUTE, EVENT_ATTRIBUTE};
+use internal::{
+    conversion::from_impls, getter::get_inner_attribute, AttributeArgs, COMMAND_ATTRIBUTE,
+    EVENT_ATTRIBUTE,
+};
 use quote::quote;
 use syn::{parse_macro_input, DeriveInput};
 
@@ -42,6 +45,34 @@ use syn::{parse_macro_input, DeriveInput};
 /// #[derive(Debug, Clone, Serialize, Deserialize)]
 /// pub struct Reset;
 /// ```
+///
+/// By default `Command::name` is the path of the enum, e.g. `my_crate::commands::UserCommand`, which changes
+/// whenever the enum is moved. The name is stored with every command, so it is best kept stable:
+///
+/// - `#[command(name = "...")]` names every command of the enum with the given name.
+/// - `#[command(name_from_variant)]` names every command after its variant, e.g. `Increment`.
+///
+/// With either option, `Display` is implemented as well and renders the name.
+///
+/// ```rust,ignore
+/// #[derive(Debug, Clone, Serialize, Command, Deserialize)]
+/// #[command(state = "UserState", directive = "UserEvent", name_from_variant)]
+/// #[serde(tag = "type")]
+/// pub enum UserCommand {
+///    Increment(Increment),
+///    Decrement(Decrement),
+///    Reset(Reset),
+/// }
+///
+/// assert_eq!(UserCommand::Increment(Increment).to_string(), "Increment");
+/// ```
+///
+/// The enum implements `From` the command of every variant, so `engine.enqueue(Increment.into())`
+/// works, unless another variant wraps the same type.
+///
+/// With the `schema` feature, `#[command(schema)]` gives the enum a `schema()` method returning
+/// the JSON Schema of its records on the command topic, see `Engine::command_schema`. The enum
+/// has to derive `schemars::JsonSchema`.
 #[proc_macro_derive(Command, attributes(command))] // TODO: Improve to accept SOLO enums and deeply nested enums
 pub fn derive_command(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     // Parse the input tokens as a DeriveInput
@@ -50,16 +81,30 @@ pub fn derive_command(input: proc_macro::TokenStream) -> proc_macro::TokenStream
     // Extract the enum identifier and its variants
     let enum_ident = input.ident.clone();
     let mut match_arms_validate = quote! {};
+    let mut match_arms_validate_with = quote! {};
     let mut match_arms_directive = quote! {};
     let mut match_arms_entity_id = quote! {};
     let mut match_arms_effects = quote! {};
+    let mut match_arms_durable_effects = quote! {};
+    let mut match_arms_is_creation = quote! {};
+    let mut match_arms_resurrects = quote! {};
+    let mut match_arms_name = quote! {};
+    let mut conversions = quote! {};
 
     if let syn::Data::Enum(data) = input.clone().data {
+        conversions = from_impls(&enum_ident, &data);
         for variant in data.variants {
             let variant_ident = variant.ident;
+            let variant_name = variant_ident.to_string();
+            match_arms_name.extend(quote! {
+                #enum_ident::#variant_ident(_) => #variant_name.to_string(),
+            });
             match_arms_validate.extend(quote! {
                 #enum_ident::#variant_ident(command) => command.validate(state),
             });
+            match_arms_validate_with.extend(quote! {
+                #enum_ident::#variant_ident(command) => command.validate_with(state, ctx).await,
+            });
             match_arms_entity_id.extend(quote! {
                 #enum_ident::#variant_ident(command) => command.entity_id(),
             });
@@ -67,7 +112,16 @@ pub fn derive_command(input: proc_macro::TokenStream) -> proc_macro::TokenStream
                 #enum_ident::#variant_ident(command) => command.directive(state),
             });
             match_arms_effects.extend(quote! {
-                #enum_ident::#variant_ident(command) => command.effects(before, after),
+                #enum_ident::#variant_ident(command) => command.effects(before, after).await,
+            });
+            match_arms_durable_effects.extend(quote! {
+                #enum_ident::#variant_ident(command) => command.durable_effects(before, after),
+            });
+            match_arms_is_creation.extend(quote! {
+                #enum_ident::#variant_ident(command) => command.is_creation(),
+            });
+            match_arms_resurrects.extend(quote! {
+                #enum_ident::#variant_ident(command) => command.resurrects(),
             });
         }
     } else {
@@ -75,11 +129,29 @@ pub fn derive_command(input: proc_macro::TokenStream) -> proc_macro::TokenStream
             .to_compile_error()
             .into();
     }
-    let (state, directive) = match get_inner_attribute(&input.attrs, COMMAND_ATTRIBUTE) {
+    let (state, directive, name, name_from_variant, schema) = match get_inner_attribute(
+        &input.attrs,
+        COMMAND_ATTRIBUTE,
+    ) {
+        Ok(AttributeArgs {
+            name: Some(_),
+            name_from_variant: true,
+            ..
+        }) => {
+            return syn::Error::new_spanned(
+                input,
+                "Command derive macro accepts either a `name` or a `name_from_variant` attribute, not both",
+            )
+            .to_compile_error()
+            .into()
+        }
         Ok(AttributeArgs {
             state: Some(state),
             directive: Some(directive),
-        }) => (state, directive),
+            name,
+            name_from_variant,
+            schema,
+        }) => (state, directive, name, name_from_variant, schema),
         Ok(_) => {
             return syn::Error::new_spanned(
                 input,
@@ -94,6 +166,38 @@ pub fn derive_command(input: proc_macro::TokenStream) -> proc_macro::TokenStream
     let state_ident = syn::Ident::new(&state, proc_macro2::Span::call_site());
     let directive_ident = syn::Ident::new(&directive, proc_macro2::Span::call_site());
 
+    // Without a name, the default of `Command::name` applies and `Display` is left to the user
+    let (name_fn, display_impl) = match (name, name_from_variant) {
+        (None, false) => (quote! {}, quote! {}),
+        (name, _) => {
+            let name_body = match name {
+                Some(name) => quote! { #name.to_string() },
+                None => quote! {
+                    match self {
+                        #match_arms_name
+                    }
+                },
+            };
+
+            (
+                quote! {
+                    fn name(&self) -> String {
+                        #name_body
+                    }
+                },
+                quote! {
+                    impl std::fmt::Display for #enum_ident {
+                        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                            f.write_str(&mnemosyne::prelude::Command::<#state_ident>::name(self))
+                        }
+                    }
+                },
+            )
+        }
+    };
+
+    let schema_fn = schema_fn(&enum_ident, schema);
+
     let gen = quote! {
 
     impl mnemosyne::prelude::Command<#state_ident> for #enum_ident {
@@ -105,15 +209,34 @@ pub fn derive_command(input: proc_macro::TokenStream) -> proc_macro::TokenStream
                     }
                 }
 
+                // The variants validate with futures of different types, so they are awaited
+                // in a future of the enum's own
+                fn validate_with(&self, state: &#state_ident, ctx: &mnemosyne::domain::ValidationContext<'_>) -> impl mnemosyne::futures::Future<Output = Result<mnemosyne::Unit, mnemosyne::domain::Error>> {
+                    async move {
+                        match self {
+                            #match_arms_validate_with
+                        }
+                    }
+                }
+
                 fn directive(&self, state: &#state_ident) -> Result<mnemosyne::prelude::NonEmptyVec<Box<#directive_ident>>, mnemosyne::domain::Error> {
                     match self {
                         #match_arms_directive
                     }
                 }
 
+                // Awaited in a future of the enum's own, as for `validate_with`
                 fn effects(&self, before: &#state_ident, after: &#state_ident) -> impl mnemosyne::futures::Future<Output = Result<mnemosyne::Unit, mnemosyne::domain::Error>> {
+                    async move {
+                        match self {
+                            #match_arms_effects
+                        }
+                    }
+                }
+
+                fn durable_effects(&self, before: &#state_ident, after: &#state_ident) -> Result<Vec<mnemosyne::storage::Effect>, mnemosyne::domain::Error> {
                     match self {
-                        #match_arms_effects
+                        #match_arms_durable_effects
                     }
                 }
 
@@ -122,7 +245,27 @@ pub fn derive_command(input: proc_macro::TokenStream) -> proc_macro::TokenStream
                         #match_arms_entity_id
                     }
                 }
+
+                fn is_creation(&self) -> bool {
+                    match self {
+                        #match_arms_is_creation
+                    }
+                }
+
+                fn resurrects(&self) -> bool {
+                    match self {
+                        #match_arms_resurrects
+                    }
+                }
+
+                #name_fn
             }
+
+            #display_impl
+
+            #conversions
+
+            #schema_fn
         };
 
     gen.into()
@@ -155,6 +298,16 @@ pub fn derive_command(input: proc_macro::TokenStream) -> proc_macro::TokenStream
 /// #[derive(Debug, Clone, Serialize, Deserialize)]
 /// struct Reset;
 /// ```
+///
+/// `Event::name` is forwarded to the event of the variant, unless the enum has a
+/// `#[event(name_from_variant)]` attribute, which names every event after its variant.
+///
+/// The enum implements `From` the event of every variant, e.g. `Incremented.into()`, unless
+/// another variant wraps the same type.
+///
+/// With the `schema` feature, `#[event(schema)]` gives the enum a `schema()` method returning
+/// the JSON Schema of its records on the event topic, see `Engine::event_schema`. The enum has
+/// to derive `schemars::JsonSchema`.
 #[proc_macro_derive(Event, attributes(event))]
 pub fn derive_event(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     // Parse the input tokens as a DeriveInput
@@ -163,17 +316,29 @@ pub fn derive_event(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     // Extract the enum identifier and its variants
     let enum_ident = input.ident.clone();
     let mut match_arms_apply = quote! {};
-    let mut match_arms_effects = quote! {};
+    let mut match_arms_apply_with_meta = quote! {};
+    let mut match_arms_deletes = quote! {};
+    let mut match_arms_restores = quote! {};
+    let mut variant_idents = Vec::new();
+    let mut conversions = quote! {};
 
     if let syn::Data::Enum(ref data) = input.data {
+        conversions = from_impls(&enum_ident, data);
         for variant in data.variants.iter() {
             let variant_ident = &variant.ident;
             match_arms_apply.extend(quote! {
                 #enum_ident::#variant_ident(event) => event.apply(state),
             });
-            match_arms_effects.extend(quote! {
-                #enum_ident::#variant_ident(event) => event.effects(before, after),
+            match_arms_apply_with_meta.extend(quote! {
+                #enum_ident::#variant_ident(event) => event.apply_with_meta(state, meta),
+            });
+            match_arms_deletes.extend(quote! {
+                #enum_ident::#variant_ident(event) => event.deletes(),
+            });
+            match_arms_restores.extend(quote! {
+                #enum_ident::#variant_ident(event) => event.restores(),
             });
+            variant_idents.push(variant_ident.clone());
         }
     } else {
         return syn::Error::new_spanned(input, "Event derive macro only works on enums")
@@ -181,22 +346,46 @@ pub fn derive_event(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
             .into();
     }
 
-    let state = match get_inner_attribute(&input.attrs, EVENT_ATTRIBUTE) {
-        Ok(AttributeArgs {
-            state: Some(state), ..
-        }) => state,
-        Ok(_) => {
-            return syn::Error::new_spanned(
+    let (state, name_from_variant, schema) =
+        match get_inner_attribute(&input.attrs, EVENT_ATTRIBUTE) {
+            Ok(AttributeArgs { name: Some(_), .. }) => return syn::Error::new_spanned(
                 input,
-                "Event derive macro requires a `state` attribute",
+                "Event derive macro does not support a `name` attribute, use `name_from_variant`",
             )
             .to_compile_error()
-            .into()
-        }
-        Err(e) => return e.to_compile_error().into(),
-    };
+            .into(),
+            Ok(AttributeArgs {
+                state: Some(state),
+                name_from_variant,
+                schema,
+                ..
+            }) => (state, name_from_variant, schema),
+            Ok(_) => {
+                return syn::Error::new_spanned(
+                    input,
+                    "Event derive macro requires a `state` attribute",
+                )
+                .to_compile_error()
+                .into()
+            }
+            Err(e) => return e.to_compile_error().into(),
+        };
 
     let state_ident = syn::Ident::new(&state, proc_macro2::Span::call_site());
+    let mut match_arms_name = quote! {};
+    for variant_ident in variant_idents {
+        let variant_name = variant_ident.to_string();
+        // Qualified, as the event of the variant may be a command as well
+        match_arms_name.extend(if name_from_variant {
+            quote! { #enum_ident::#variant_ident(_) => #variant_name.to_string(), }
+        } else {
+            quote! {
+                #enum_ident::#variant_ident(event) => mnemosyne::prelude::Event::<#state_ident>::name(event),
+            }
+        });
+    }
+
+    let schema_fn = schema_fn(&enum_ident, schema);
 
     // Generate the trait implementation code
     let gen = quote! {
@@ -206,19 +395,65 @@ pub fn derive_event(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
                     #match_arms_apply
                 }
             }
+
+            fn apply_with_meta(&self, state: &#state_ident, meta: &mnemosyne::prelude::EventMeta) -> Option<#state_ident> {
+                match self {
+                    #match_arms_apply_with_meta
+                }
+            }
+
+            fn deletes(&self) -> bool {
+                match self {
+                    #match_arms_deletes
+                }
+            }
+
+            fn restores(&self) -> bool {
+                match self {
+                    #match_arms_restores
+                }
+            }
+
+            fn name(&self) -> String {
+                match self {
+                    #match_arms_name
+                }
+            }
         }
+
+        #conversions
+
+        #schema_fn
     };
 
     gen.into()
 }
 
+/// The `schema()` method of a command or event enum, returning the JSON Schema of its records,
+/// with the `schema` feature and for enums that opt in only, as the enum has to derive
+/// `schemars::JsonSchema`.
+fn schema_fn(enum_ident: &syn::Ident, schema: bool) -> proc_macro2::TokenStream {
+    if cfg!(feature = "schema") && schema {
+        quote! {
+            impl #enum_ident {
+                /// The JSON Schema of the records of this type, i.e. the envelope and the payload.
+                pub fn schema() -> mnemosyne::schemars::schema::RootSchema {
+                    mnemosyne::schemars::schema_for!(mnemosyne::prelude::Record<#enum_ident>)
+                }
+            }
+        }
+    } else {
+        quote! {}
+    }
+}
+
 /// Procedural macro to create events in the following format:
-/// mnemosyne::domain::NonEmptyVec::new(vec![Box::new(Event)]);
+/// mnemosyne::domain::NonEmptyVec::new(vec![Box::new(Event)]).map_err(mnemosyne::domain::Error::from);
 #[proc_macro]
 pub fn event_vec(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let input = parse_macro_input!(input as syn::Expr);
     let gen = quote! {
-        mnemosyne::domain::NonEmptyVec::new(vec![Box::new(#input)])
+        mnemosyne::domain::NonEmptyVec::new(vec![Box::new(#input)]).map_err(mnemosyne::domain::Error::from)
     };
     gen.into()
 }
@@ -0,0 +1,240 @@
+//! Write and replay throughput of the storage adapters at various batch sizes, and the cost
+//! of folding a replayed history into a state event by event or a chunk at a time.
+//!
+//! The memory adapter is also benchmarked replaying one entity and reading its highest
+//! sequence number in a store crowded with other entities, which should not depend on how
+//! many entities the store holds.
+//!
+//! The memory adapter is always benchmarked. The Postgres adapter is benchmarked with the
+//! `postgres` feature when `BENCH_POSTGRES_HOST` is set, against a database laid out as in
+//! `example/resource/MIGRATION.sql`, e.g.
+//!
+//! ```sh
+//! BENCH_POSTGRES_HOST=localhost cargo bench -p mnemosyne --features postgres
+//! ```
+
+use chrono::Utc;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::StreamExt;
+use mnemosyne::{
+    algebra::{Event, EventMeta, Record},
+    domain::REPLAY_CHUNK_SIZE,
+    storage::{Adapter, MemoryAdapter},
+};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::runtime::Runtime;
+
+const BATCH_SIZES: [usize; 4] = [1, 10, 100, 1000];
+const REPLAY_SIZE: u64 = 1000;
+const FOLD_SIZE: u64 = 10_000;
+const CROWD_SIZES: [u64; 3] = [100, 1000, 10_000];
+const CROWD_EVENTS: u64 = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Incremented {
+    by: u64,
+}
+
+impl Event<u64> for Incremented {
+    fn apply(&self, state: &u64) -> Option<u64> {
+        Some(state + self.by)
+    }
+}
+
+// Every iteration writes to a fresh entity, so sequence numbers never collide
+static ENTITY: AtomicU64 = AtomicU64::new(0);
+
+fn next_entity_id() -> String {
+    format!(
+        "bench::{}::{}",
+        std::process::id(),
+        ENTITY.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+async fn write_batch<Store: Adapter>(store: &Store, events: &[Incremented]) {
+    let entity_id = next_entity_id();
+    let batch = events
+        .iter()
+        .enumerate()
+        .map(|(index, event)| Record::event(entity_id.clone(), index as i64 + 1, event, Utc::now()))
+        .collect::<Vec<_>>();
+
+    store.write(batch).await.unwrap();
+}
+
+async fn replay_all<Store: Adapter>(store: &Store, entity_id: &str) -> usize {
+    store
+        .replay::<Incremented>(entity_id, 0, REPLAY_SIZE, REPLAY_SIZE)
+        .await
+        .unwrap()
+        .count()
+        .await
+}
+
+// Fold with an await per event
+async fn fold_each<Store: Adapter>(store: &Store, entity_id: &str) -> u64 {
+    store
+        .replay::<Incremented>(entity_id, 0, FOLD_SIZE, FOLD_SIZE)
+        .await
+        .unwrap()
+        .fold(0, |state, record| {
+            let meta = EventMeta::from(&record);
+            let state = record.message().apply_with_meta(&state, &meta).unwrap();
+            async move { state }
+        })
+        .await
+}
+
+// Fold a chunk of ready events at a time, as the engine does
+async fn fold_chunked<Store: Adapter>(store: &Store, entity_id: &str) -> u64 {
+    let mut chunks = store
+        .replay::<Incremented>(entity_id, 0, FOLD_SIZE, FOLD_SIZE)
+        .await
+        .unwrap()
+        .ready_chunks(REPLAY_CHUNK_SIZE);
+    let mut state = 0;
+
+    while let Some(chunk) = chunks.next().await {
+        for record in chunk {
+            let meta = EventMeta::from(&record);
+            state = record.message().apply_with_meta(&state, &meta).unwrap();
+        }
+    }
+
+    state
+}
+
+fn bench_adapter<Store: Adapter>(c: &mut Criterion, runtime: &Runtime, name: &str, store: Store) {
+    let mut group = c.benchmark_group(format!("{}/write", name));
+    for size in BATCH_SIZES {
+        let events = (0..size as u64)
+            .map(|by| Incremented { by })
+            .collect::<Vec<_>>();
+
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &events, |b, events| {
+            b.to_async(runtime).iter(|| write_batch(&store, events))
+        });
+    }
+    group.finish();
+
+    let entity_id = next_entity_id();
+    let events = (0..REPLAY_SIZE)
+        .map(|by| Incremented { by })
+        .collect::<Vec<_>>();
+    runtime.block_on(async {
+        let batch = events
+            .iter()
+            .enumerate()
+            .map(|(index, event)| {
+                Record::event(entity_id.clone(), index as i64 + 1, event, Utc::now())
+            })
+            .collect::<Vec<_>>();
+        store.write(batch).await.unwrap();
+    });
+
+    let mut group = c.benchmark_group(format!("{}/replay", name));
+    group.throughput(Throughput::Elements(REPLAY_SIZE));
+    group.bench_function(BenchmarkId::from_parameter(REPLAY_SIZE), |b| {
+        b.to_async(runtime).iter(|| replay_all(&store, &entity_id))
+    });
+    group.finish();
+
+    let entity_id = next_entity_id();
+    runtime.block_on(async {
+        let events = (0..FOLD_SIZE)
+            .map(|by| Incremented { by })
+            .collect::<Vec<_>>();
+        let batch = events
+            .iter()
+            .enumerate()
+            .map(|(index, event)| {
+                Record::event(entity_id.clone(), index as i64 + 1, event, Utc::now())
+            })
+            .collect::<Vec<_>>();
+        store.write(batch).await.unwrap();
+    });
+
+    let mut group = c.benchmark_group(format!("{}/fold", name));
+    group.throughput(Throughput::Elements(FOLD_SIZE));
+    group.bench_function("each", |b| {
+        b.to_async(runtime).iter(|| fold_each(&store, &entity_id))
+    });
+    group.bench_function("chunked", |b| {
+        b.to_async(runtime)
+            .iter(|| fold_chunked(&store, &entity_id))
+    });
+    group.finish();
+}
+
+// Replay and read the highest sequence number of one entity among `entities` entities
+fn bench_crowded(c: &mut Criterion, runtime: &Runtime, name: &str) {
+    let mut group = c.benchmark_group(format!("{}/crowded", name));
+    for entities in CROWD_SIZES {
+        let store = MemoryAdapter::new();
+        let events = (0..CROWD_EVENTS)
+            .map(|by| Incremented { by })
+            .collect::<Vec<_>>();
+        let entity_ids = (0..entities).map(|_| next_entity_id()).collect::<Vec<_>>();
+        runtime.block_on(async {
+            for entity_id in &entity_ids {
+                let batch = events
+                    .iter()
+                    .enumerate()
+                    .map(|(index, event)| {
+                        Record::event(entity_id.clone(), index as i64 + 1, event, Utc::now())
+                    })
+                    .collect::<Vec<_>>();
+                store.write(batch).await.unwrap();
+            }
+        });
+        let entity_id = &entity_ids[entity_ids.len() / 2];
+
+        group.bench_with_input(BenchmarkId::new("replay", entities), entity_id, |b, id| {
+            b.to_async(runtime).iter(|| replay_all(&store, id))
+        });
+        group.bench_with_input(BenchmarkId::new("highest", entities), entity_id, |b, id| {
+            b.to_async(runtime)
+                .iter(|| store.read_highest_sequence_number(id))
+        });
+    }
+    group.finish();
+}
+
+fn memory(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    bench_adapter(c, &runtime, "memory", MemoryAdapter::new());
+    bench_crowded(c, &runtime, "memory");
+}
+
+#[cfg(feature = "postgres")]
+fn postgres(c: &mut Criterion) {
+    use mnemosyne::storage::{PostgresAdapter, PostgresAdapterBuilder, SslMode};
+
+    let Ok(host) = std::env::var("BENCH_POSTGRES_HOST") else {
+        return;
+    };
+
+    let runtime = Runtime::new().unwrap();
+    let store = runtime
+        .block_on(PostgresAdapter::connect(PostgresAdapterBuilder::new(
+            &host,
+            "postgres",
+            5432,
+            "postgres",
+            "mnemosyne",
+            10,
+            SslMode::new(false),
+        )))
+        .unwrap();
+
+    bench_adapter(c, &runtime, "postgres", store);
+}
+
+#[cfg(not(feature = "postgres"))]
+fn postgres(_c: &mut Criterion) {}
+
+criterion_group!(benches, memory, postgres);
+criterion_main!(benches);
@@ -0,0 +1,155 @@
+//! Command throughput of the engine, from enqueueing a batch of commands to the outcome of
+//! every one of them, with the batch spread over one or many entities. The entities of a
+//! consumed chunk are processed concurrently, so the more entities a batch targets the
+//! higher the throughput should be.
+//!
+//! Batches targeting entities never seen before measure looking up and starting the actors
+//! of the entities, with the entities kept in memory capped by `EngineConfig::with_max_entities`
+//! so the least recently used ones are evicted as the benchmark goes.
+//!
+//! The engine is benchmarked against the memory adapter and needs a Kafka broker, so it is
+//! only benchmarked when `BENCH_KAFKA_BROKERS` is set, e.g.
+//!
+//! ```sh
+//! BENCH_KAFKA_BROKERS=localhost:9092 cargo bench -p mnemosyne --bench engine
+//! ```
+
+use actix::{System, SystemRunner};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use futures::future::join_all;
+use mnemosyne::{
+    algebra::{Command, Engine, Event},
+    domain::{EngineConfig, Error, NonEmptyVec},
+    rdkafka::ClientConfig,
+    storage::MemoryAdapter,
+    Unit,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const BATCH_SIZE: usize = 100;
+const ENTITY_COUNTS: [usize; 3] = [1, 10, 100];
+const MAX_ENTITIES: usize = 1_000;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Counter(u64);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Incremented;
+
+impl Event<Counter> for Incremented {
+    fn apply(&self, state: &Counter) -> Option<Counter> {
+        Some(Counter(state.0 + 1))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Increment(String);
+
+impl Command<Counter> for Increment {
+    type T = Incremented;
+
+    fn validate(&self, _: &Counter) -> Result<Unit, Error> {
+        Ok(())
+    }
+
+    fn directive(&self, _: &Counter) -> Result<NonEmptyVec<Box<Incremented>>, Error> {
+        Ok(NonEmptyVec::new(vec![Box::new(Incremented)])?)
+    }
+
+    fn entity_id(&self) -> String {
+        self.0.clone()
+    }
+}
+
+type Counters = Engine<Counter, MemoryAdapter, Increment, Incremented>;
+
+fn start(system: &SystemRunner, brokers: &str, config: EngineConfig) -> Counters {
+    let mut configuration = ClientConfig::new();
+    configuration.set("bootstrap.servers", brokers);
+
+    system
+        .block_on(Engine::start_with_config(
+            configuration,
+            MemoryAdapter::new(),
+            config,
+        ))
+        .expect("an engine")
+}
+
+// Enqueue the whole batch before awaiting any outcome, so the commands are consumed in as
+// few chunks as possible
+async fn run_batch(engine: &Counters, entity_ids: &[String]) {
+    let mut handles = Vec::with_capacity(BATCH_SIZE);
+    for index in 0..BATCH_SIZE {
+        let entity_id = entity_ids[index % entity_ids.len()].clone();
+        handles.push(engine.enqueue(Increment(entity_id)).await.unwrap());
+    }
+
+    for outcome in join_all(handles).await {
+        outcome.unwrap();
+    }
+}
+
+fn entity_ids(prefix: &str, count: usize) -> Vec<String> {
+    (0..count)
+        .map(|index| format!("bench::{}::{}::{}", std::process::id(), prefix, index))
+        .collect()
+}
+
+// Every batch of distinct entities targets entities never seen before
+static ENTITY: AtomicU64 = AtomicU64::new(0);
+
+fn next_entity_ids(count: usize) -> Vec<String> {
+    let first = ENTITY.fetch_add(count as u64, Ordering::Relaxed);
+    (first..first + count as u64)
+        .map(|index| format!("bench::{}::distinct::{}", std::process::id(), index))
+        .collect()
+}
+
+// Every engine consumes commands as the same group, so a single engine runs the benchmarks,
+// as another one would be assigned commands whose outcome the first is awaiting
+fn engine(c: &mut Criterion) {
+    let Ok(brokers) = std::env::var("BENCH_KAFKA_BROKERS") else {
+        return;
+    };
+    let system = System::new();
+    let engine = start(
+        &system,
+        &brokers,
+        EngineConfig::default().with_max_entities(MAX_ENTITIES),
+    );
+
+    throughput(c, &system, &engine);
+    distinct_entities(c, &system, &engine);
+}
+
+fn throughput(c: &mut Criterion, system: &SystemRunner, engine: &Counters) {
+    let mut group = c.benchmark_group("engine/throughput");
+    group.throughput(Throughput::Elements(BATCH_SIZE as u64));
+    for count in ENTITY_COUNTS {
+        let entity_ids = entity_ids("throughput", count);
+        group.bench_with_input(
+            BenchmarkId::new("entities", count),
+            &entity_ids,
+            |b, ids| b.iter(|| system.block_on(run_batch(engine, ids))),
+        );
+    }
+    group.finish();
+}
+
+fn distinct_entities(c: &mut Criterion, system: &SystemRunner, engine: &Counters) {
+    let mut group = c.benchmark_group("engine/distinct_entities");
+    group.throughput(Throughput::Elements(BATCH_SIZE as u64));
+    group.bench_function(BenchmarkId::new("max_entities", MAX_ENTITIES), |b| {
+        b.iter_batched(
+            || next_entity_ids(BATCH_SIZE),
+            |ids| system.block_on(run_batch(engine, &ids)),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, engine);
+criterion_main!(benches);
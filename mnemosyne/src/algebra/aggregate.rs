@@ -1,69 +1,258 @@
-use super::{Command, Event, Inner, Record};
+use super::{
+    load, Command, CommandOutcome, EnqueueHandle, Event, Inner, Pending, Record, StateFactory,
+};
 use crate::domain::{
-    Dequeue, Error, Process, CHUNK_BACKPRESSURE, CHUNK_SIZE, COMMAND_TOPIC, GROUP_ID,
+    ActorFailure, ActorKind, Dequeue, Dispatch, EngineConfig, Error, FailureAction, Live, Process,
+    Reload, UnknownCommandHandler, UnknownCommandPolicy, Watch, CHUNK_BACKPRESSURE, CHUNK_SIZE,
+    GROUP_ID, MAX_POLL_INTERVAL, PAUSE_BACKOFF, SEEK_TIMEOUT, WATCH_CAPACITY,
 };
 use crate::storage::Adapter;
 use crate::Unit;
 use actix::prelude::*;
 use futures::lock::Mutex;
-use futures::StreamExt;
+use futures::{Future, FutureExt, StreamExt};
 use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
 use rdkafka::error::KafkaError;
-use rdkafka::message::BorrowedMessage;
-use rdkafka::{ClientConfig, Message};
+use rdkafka::message::{BorrowedMessage, Header, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::util::Timeout;
+use rdkafka::{ClientConfig, Message, Offset, TopicPartitionList};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use std::collections::HashMap;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+type InnerAddr<State, Store, Evt> = Addr<Inner<State, Store, Evt>>;
+// A partition of a topic, and a command by its partition and offset
+pub(crate) type Partition = (String, i32);
+pub(crate) type Position = (Partition, i64);
+// The actor of an entity, when it was last used and how many are using it, see `Actors`
+type Entry<State, Store, Evt> = (InnerAddr<State, Store, Evt>, u64, usize);
+
+/// The actors of the entities, each with when it was last used, so the least recently used
+/// actors can be evicted, and the number of commands and watches using it at the moment, so
+/// an actor in use is never evicted. The watchers of an entity outlive its actor, so watching
+/// an evicted entity goes on once it is started again.
+struct Actors<State, Store, Evt>
+where
+    State: Debug + Send + Sync + Unpin + Clone + 'static,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Evt: Event<State> + DeserializeOwned + Serialize + Unpin + Debug + 'static,
+{
+    entries: HashMap<String, Entry<State, Store, Evt>>,
+    watchers: HashMap<String, broadcast::Sender<State>>,
+    clock: u64,
+}
+
+impl<State, Store, Evt> Default for Actors<State, Store, Evt>
+where
+    State: Debug + Send + Sync + Unpin + Clone + 'static,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Evt: Event<State> + DeserializeOwned + Serialize + Unpin + Debug + 'static,
+{
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            watchers: HashMap::new(),
+            clock: 0,
+        }
+    }
+}
+
+impl<State, Store, Evt> Actors<State, Store, Evt>
+where
+    State: Debug + Send + Sync + Unpin + Clone + 'static,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Evt: Event<State> + DeserializeOwned + Serialize + Unpin + Debug + 'static,
+{
+    fn get(&self, entity_id: &str) -> Option<InnerAddr<State, Store, Evt>> {
+        self.entries.get(entity_id).map(|(addr, _, _)| addr.clone())
+    }
+
+    /// The actor of an entity, started with the watchers of the entity if the entity has none,
+    /// and kept from eviction until it is given back with `release`.
+    ///
+    /// Looking up and starting the actor happen on the same entry, under the lock of the
+    /// actors, so an entity never has two actors, however many of its commands are processed
+    /// at once.
+    fn get_or_start(
+        &mut self,
+        entity_id: &str,
+        start: impl FnOnce(broadcast::Sender<State>) -> InnerAddr<State, Store, Evt>,
+    ) -> InnerAddr<State, Store, Evt> {
+        self.clock += 1;
+        let clock = self.clock;
+        let watchers = &mut self.watchers;
+        let (addr, used, leases) = self
+            .entries
+            .entry(entity_id.to_string())
+            .or_insert_with(|| {
+                let watchers = watchers
+                    .entry(entity_id.to_string())
+                    .or_insert_with(|| broadcast::channel(WATCH_CAPACITY).0);
+                (start(watchers.clone()), clock, 0)
+            });
+        *used = clock;
+        *leases += 1;
+        addr.clone()
+    }
+
+    /// Give back an actor taken with `get_or_start`, then evict the least recently used
+    /// actors beyond `max` that are not in use. An evicted actor stops once its last address
+    /// is dropped, and the entity is loaded from storage again by the actor started for its
+    /// next command.
+    fn release(&mut self, entity_id: &str, max: Option<usize>) {
+        if let Some((_, _, leases)) = self.entries.get_mut(entity_id) {
+            *leases = leases.saturating_sub(1);
+        }
+
+        let Some(max) = max.filter(|max| self.entries.len() > *max) else {
+            return;
+        };
+        let mut idle = self
+            .entries
+            .iter()
+            .filter(|(_, (_, _, leases))| *leases == 0)
+            .map(|(entity_id, (_, used, _))| (*used, entity_id.clone()))
+            .collect::<Vec<_>>();
+        idle.sort_unstable();
 
-type AddrMap<State, Store, Evt> = HashMap<String, Addr<Inner<State, Store, Evt>>>;
+        let excess = self.entries.len() - max;
+        for (_, entity_id) in idle.into_iter().take(excess) {
+            self.entries.remove(&entity_id);
+        }
+        self.watchers.retain(|entity_id, watchers| {
+            self.entries.contains_key(entity_id) || watchers.receiver_count() > 0
+        });
+    }
+}
 
 #[derive(Clone)]
 pub struct Aggregate<State, Store, Cmd, Evt>
 where
     State: Debug + Send + Sync + Unpin + Clone + 'static,
     Store: Adapter + Clone + Send + Sync + 'static + Unpin,
-    Cmd: Send + Sync + Unpin + 'static,
+    Cmd: Command<State> + Send + Sync + Unpin + 'static,
     Evt: Event<State> + DeserializeOwned + Serialize + Unpin + Debug + 'static,
 {
-    addr: Arc<Mutex<AddrMap<State, Store, Evt>>>,
+    addr: Arc<Mutex<Actors<State, Store, Evt>>>,
+    // The commands dealt with beyond the offset their partition was rewound to, see
+    // `process_chunk`
+    dealt: Arc<Mutex<HashSet<Position>>>,
+    // The last command per entity enqueued by this instance, shared with `Init`, which waits
+    // for them to be processed on `Consistency::Strong` reads, see `forget_processed`
+    enqueued: Arc<Mutex<HashMap<String, Position>>>,
     store: Store,
     consumer: Arc<StreamConsumer>,
+    // How long a chunk is processed before consumption is paused, see `keep_alive`
+    keep_alive: Duration,
+    producer: Arc<FutureProducer>,
+    pending: Pending<State, Cmd::T>,
+    config: EngineConfig,
+    paused: Arc<AtomicBool>,
+    // Whether the partitions of the consumer are paused because consuming is, see `hold`
+    held: Arc<AtomicBool>,
     _marker: std::marker::PhantomData<Cmd>,
 }
 
 impl<State, Store, Cmd, Evt> Aggregate<State, Store, Cmd, Evt>
 where
-    State: Debug + Send + Sync + Unpin + Clone + Default + 'static,
+    State: Debug + Send + Sync + Unpin + Clone + StateFactory + 'static,
     Store: Adapter + Clone + Send + Sync + 'static + Unpin,
-    Cmd: Send + Sync + Unpin + 'static,
+    Cmd: Command<State> + Send + Sync + Unpin + 'static,
     Evt: Event<State> + DeserializeOwned + Serialize + Unpin + Debug + 'static,
 {
-    pub fn new(configuration: ClientConfig, store: Store) -> Result<Self, Error> {
-        Ok(Self {
+    pub fn new(
+        configuration: ClientConfig,
+        store: Store,
+        producer: Arc<FutureProducer>,
+        pending: Pending<State, Cmd::T>,
+        config: EngineConfig,
+        paused: Arc<AtomicBool>,
+    ) -> Result<Self, Error> {
+        let mut configuration = configuration;
+        if let Some(max_poll_interval) = config.max_poll_interval() {
+            configuration.set(
+                "max.poll.interval.ms",
+                max_poll_interval.as_millis().to_string(),
+            );
+        }
+        if let Some(session_timeout) = config.session_timeout() {
+            configuration.set(
+                "session.timeout.ms",
+                session_timeout.as_millis().to_string(),
+            );
+        }
+        let max_poll_interval = configuration
+            .get("max.poll.interval.ms")
+            .and_then(|millis| millis.parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or(Duration::from_secs(MAX_POLL_INTERVAL));
+
+        let consumer = configuration
+            .set("group.id", GROUP_ID)
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", "earliest")
+            .create::<StreamConsumer>()
+            .map_err(Error::Kafka)?;
+
+        Ok(Self::with_consumer(
+            Arc::new(consumer),
+            max_poll_interval,
+            store,
+            producer,
+            pending,
+            config,
+            paused,
+        ))
+    }
+
+    pub(crate) fn consumer(&self) -> Arc<StreamConsumer> {
+        self.consumer.clone()
+    }
+
+    pub(crate) fn enqueued(&self) -> Arc<Mutex<HashMap<String, Position>>> {
+        self.enqueued.clone()
+    }
+
+    /// Create an aggregate consuming commands with a consumer built by the caller, whose
+    /// `max.poll.interval.ms` is `max_poll_interval`.
+    pub(crate) fn with_consumer(
+        consumer: Arc<StreamConsumer>,
+        max_poll_interval: Duration,
+        store: Store,
+        producer: Arc<FutureProducer>,
+        pending: Pending<State, Cmd::T>,
+        config: EngineConfig,
+        paused: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
             addr: Default::default(),
+            dealt: Default::default(),
+            enqueued: Default::default(),
             store,
+            consumer,
+            keep_alive: max_poll_interval / 3,
+            producer,
+            pending,
+            config,
+            paused,
+            held: Default::default(),
             _marker: std::marker::PhantomData,
-            consumer: {
-                let mut configuration = configuration;
-
-                Arc::new(
-                    configuration
-                        .set("group.id", GROUP_ID)
-                        .set("enable.auto.commit", "false")
-                        .set("auto.offset.reset", "earliest")
-                        .create::<StreamConsumer>()
-                        .map_err(Error::Kafka)?,
-                )
-            },
-        })
+        }
     }
 }
 
 impl<State, Store, Cmd, Evt> Actor for Aggregate<State, Store, Cmd, Evt>
 where
-    State: Debug + Clone + Send + Sync + Unpin + 'static + Default + DeserializeOwned,
+    State: Debug + Clone + Send + Sync + Unpin + 'static + StateFactory + DeserializeOwned,
     Store: Adapter + Clone + Send + Sync + 'static + Unpin,
     Cmd: Send + Sync + Unpin + 'static + Debug + DeserializeOwned + Command<State> + Serialize,
     Evt: Event<State> + 'static + DeserializeOwned + Serialize + Unpin + Debug,
@@ -71,109 +260,272 @@ where
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        let topics = self
+            .config
+            .command_topics()
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+
+        // Subscribe once, the subscription outlives every dequeue. Without it there is
+        // nothing to consume, so the actor stops and its supervisor restarts it.
+        if let Err(e) = self.consumer.subscribe(&topics) {
+            self.config
+                .report(ActorFailure::new(ActorKind::Aggregate, None, e));
+            ctx.stop();
+            return;
+        }
+        tracing::info!("Subscribed to command topics {:?}", topics);
+
         ctx.notify(Dequeue);
     }
 }
 
 impl<State, Store, Cmd, Evt> Supervised for Aggregate<State, Store, Cmd, Evt>
 where
-    State: Debug + Clone + Send + Sync + Unpin + 'static + Default + DeserializeOwned,
+    State: Debug + Clone + Send + Sync + Unpin + 'static + StateFactory + DeserializeOwned,
     Store: Adapter + Clone + Send + Sync + 'static + Unpin,
     Cmd: Send + Sync + Unpin + 'static + Debug + DeserializeOwned + Command<State> + Serialize,
     Evt: Event<State> + 'static + DeserializeOwned + Serialize + Unpin + Debug,
 {
-    // TODO: Add state recovery
-    fn restarting(&mut self, _ctx: &mut Self::Context) {}
+    fn restarting(&mut self, _ctx: &mut Self::Context) {
+        self.config.restarted(ActorKind::Aggregate, None);
+    }
+}
+
+impl<State, Store, Cmd, Evt> Handler<Reload<State>> for Aggregate<State, Store, Cmd, Evt>
+where
+    State: Debug + Clone + Send + Sync + Unpin + 'static + StateFactory + DeserializeOwned,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Cmd: Send + Sync + Unpin + 'static + Debug + DeserializeOwned + Command<State> + Serialize,
+    Evt: Event<State> + 'static + DeserializeOwned + Serialize + Unpin + Debug + Send + Sync,
+{
+    type Result = ResponseFuture<Result<(u64, State), Error>>;
+
+    fn handle(&mut self, msg: Reload<State>, _ctx: &mut Self::Context) -> Self::Result {
+        let actors = self.addr.clone();
+        let store = self.store.clone();
+        let config = self.config.clone();
+        Box::pin(async move {
+            let addr = actors.lock().await.get(msg.entity_id());
+            match addr {
+                Some(addr) => addr.send(msg).await.map_err(Error::Actix)?,
+                // No actor holds the entity in memory, there is nothing to discard
+                None => load::<State, Store, Evt>(&store, msg.entity_id(), &config)
+                    .await
+                    .map(|(seq_nr, state, _)| (seq_nr, state)),
+            }
+        })
+    }
+}
+
+impl<State, Store, Cmd, Evt> Handler<Live<State>> for Aggregate<State, Store, Cmd, Evt>
+where
+    State: Debug + Clone + Send + Sync + Unpin + 'static + StateFactory + DeserializeOwned,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Cmd: Send + Sync + Unpin + 'static + Debug + DeserializeOwned + Command<State> + Serialize,
+    Evt: Event<State> + 'static + DeserializeOwned + Serialize + Unpin + Debug + Send + Sync,
+{
+    type Result = ResponseFuture<Result<Option<(i64, State)>, Error>>;
+
+    fn handle(&mut self, msg: Live<State>, _ctx: &mut Self::Context) -> Self::Result {
+        let actors = self.addr.clone();
+        Box::pin(async move {
+            let addr = actors.lock().await.get(msg.entity_id());
+            match addr {
+                Some(addr) => addr.send(msg).await.map_err(Error::Actix)?,
+                // Unlike a watch, reading the state does not start an actor
+                None => Ok(None),
+            }
+        })
+    }
+}
+
+impl<State, Store, Cmd, Evt> Handler<Dispatch<State, Cmd, Cmd::T>>
+    for Aggregate<State, Store, Cmd, Evt>
+where
+    State: Debug + Clone + Send + Sync + Unpin + 'static + StateFactory + DeserializeOwned,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Cmd: Send + Sync + Unpin + 'static + Debug + DeserializeOwned + Command<State> + Serialize,
+    Evt: Event<State> + 'static + DeserializeOwned + Serialize + Unpin + Debug + Send + Sync,
+{
+    type Result = ResponseFuture<Result<CommandOutcome<State, Cmd::T>, Error>>;
+
+    // A dispatched command goes to the actor of its entity, like a consumed one
+    fn handle(
+        &mut self,
+        msg: Dispatch<State, Cmd, Cmd::T>,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let actors = self.addr.clone();
+        let store = self.store.clone();
+        let producer = self.producer.clone();
+        let config = self.config.clone();
+        Box::pin(async move {
+            let command = msg.into_command();
+            let key = command.entity_id();
+            config.check_aggregate_type(&key)?;
+            let name = command.name();
+            let mut record = Record::command(&key, command, chrono::Utc::now(), name, 0);
+            if let Some(source) = config.service_name() {
+                record = record.with_source(source);
+            }
+
+            let addr = actors.lock().await.get_or_start(&key, |watchers| {
+                let inner =
+                    Inner::<State, Store, Evt>::new(&key, store, producer, watchers, &config);
+                config.start_local(inner)
+            });
+            let outcome = addr
+                .send(Process::<State, Cmd, Cmd::T>::new(record))
+                .await
+                .map_err(Error::Actix);
+            actors.lock().await.release(&key, config.max_entities());
+            outcome?
+        })
+    }
+}
+
+impl<State, Store, Cmd, Evt> Handler<Watch<State>> for Aggregate<State, Store, Cmd, Evt>
+where
+    State: Debug + Clone + Send + Sync + Unpin + 'static + StateFactory + DeserializeOwned,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Cmd: Send + Sync + Unpin + 'static + Debug + DeserializeOwned + Command<State> + Serialize,
+    Evt: Event<State> + 'static + DeserializeOwned + Serialize + Unpin + Debug + Send + Sync,
+{
+    type Result = ResponseFuture<Result<(State, broadcast::Receiver<State>), Error>>;
+
+    fn handle(&mut self, msg: Watch<State>, _ctx: &mut Self::Context) -> Self::Result {
+        let actors = self.addr.clone();
+        let store = self.store.clone();
+        let producer = self.producer.clone();
+        let config = self.config.clone();
+        Box::pin(async move {
+            // The entity is held in memory to be watched, like one receiving a command
+            let entity_id = msg.entity_id().to_string();
+            let addr = actors.lock().await.get_or_start(&entity_id, |watchers| {
+                let inner =
+                    Inner::<State, Store, Evt>::new(&entity_id, store, producer, watchers, &config);
+                config.start_local(inner)
+            });
+            let watched = addr.send(msg).await.map_err(Error::Actix);
+            actors
+                .lock()
+                .await
+                .release(&entity_id, config.max_entities());
+            watched?
+        })
+    }
 }
 
-// TODO: Add logging
 impl<State, Store, Cmd, Evt> Handler<Dequeue> for Aggregate<State, Store, Cmd, Evt>
 where
-    State: Debug + Clone + Send + Sync + Unpin + 'static + Default + DeserializeOwned,
+    State: Debug + Clone + Send + Sync + Unpin + 'static + StateFactory + DeserializeOwned,
     Store: Adapter + Clone + Send + Sync + 'static + Unpin,
     Cmd: Send + Sync + Unpin + 'static + Debug + DeserializeOwned + Command<State> + Serialize,
     Evt: Event<State> + 'static + DeserializeOwned + Serialize + Unpin + Debug,
 {
     type Result = ResponseActFuture<Self, Result<Unit, Error>>;
 
-    // TODO: Add logging
     fn handle(&mut self, _: Dequeue, _: &mut Self::Context) -> Self::Result {
         let store = self.store.clone();
         let consumer = self.consumer.clone();
         let actors = self.addr.clone();
+        let dealt = self.dealt.clone();
+        let enqueued = self.enqueued.clone();
+        let producer = self.producer.clone();
+        let pending = self.pending.clone();
+        let config = self.config.clone();
+        let paused = self.paused.clone();
+        let held = self.held.clone();
+        let keep_alive_interval = self.keep_alive;
 
         Box::pin(
             async move {
-                consumer.subscribe(&[COMMAND_TOPIC]).map_err(Error::Kafka)?;
+                // While paused nothing new is consumed, the chunk being processed when
+                // pausing has already been drained by the time this is checked again.
+                if paused.load(Ordering::SeqCst) {
+                    hold(&consumer, Duration::from_secs(PAUSE_BACKOFF)).await;
+                    held.store(true, Ordering::SeqCst);
+                    return Ok(());
+                }
+                if held.swap(false, Ordering::SeqCst) {
+                    match consumer
+                        .assignment()
+                        .and_then(|partitions| consumer.resume(&partitions))
+                    {
+                        Ok(()) => tracing::info!("Resumed consuming commands"),
+                        Err(e) => tracing::error!("Could not resume consuming commands: {}", e),
+                    }
+                }
 
                 let mut chunks = consumer.stream().ready_chunks(CHUNK_SIZE as usize);
 
-                if let Some(messages) = chunks.next().await {
-                    if messages.is_empty() {
-                        return Ok(());
-                    }
+                let polled = tokio::time::timeout(config.poll_timeout(), chunks.next())
+                    .await
+                    .ok()
+                    .flatten()
+                    .filter(|messages| !messages.is_empty());
+
+                // Nothing to process, back off instead of polling again right away.
+                if polled.is_none() {
+                    tokio::time::sleep(config.idle_backoff()).await;
+                }
 
+                if let Some(messages) = polled {
                     if messages.len() <= 2 {
                         // sleep for a bit to allow for more messages to come in
                         tokio::time::sleep(tokio::time::Duration::from_secs(CHUNK_BACKPRESSURE))
                             .await;
                     }
 
-                    let mut result = Vec::with_capacity(messages.len());
-                    for msg in messages.iter() {
-                        let actors = actors.clone();
-                        let store = store.clone();
-                        let msg = msg.as_ref().map_err(|e| Error::Kafka(e.to_owned()))?;
-
-                        let key = msg.key().ok_or(Error::InvalidKey(format!(
-                            "Could not find key in message {:?}",
-                            msg
-                        )))?;
-
-                        let key = String::from_utf8(key.to_vec()).map_err(|e| {
-                            Error::InvalidKey(format!("Could not decode key: {}", e))
-                        })?;
-
-                        if !actors.lock().await.contains_key(&key) {
-                            let inner = Inner::<State, Store, Evt>::new(&key, store.clone());
-                            let supervised = Supervisor::start(|_| inner);
-                            actors.lock().await.insert(key.clone(), supervised.clone());
-                            result.push(process::<State, Store, Cmd, Evt>(msg, supervised).await)
-                        } else {
-                            result.push(
-                                process::<State, Store, Cmd, Evt>(
-                                    msg,
-                                    actors.lock().await[&key].clone(),
-                                )
-                                .await,
-                            )
-                        }
-                    }
+                    let processing = process_chunk::<State, Store, Cmd, Evt, _>(
+                        messages.iter().map(Result::as_ref),
+                        &actors,
+                        &dealt,
+                        &store,
+                        &producer,
+                        &pending,
+                        &config,
+                    );
+                    let (_, resume) = keep_alive(&consumer, keep_alive_interval, processing).await;
+                    forget_processed(&enqueued, &resume).await;
+
+                    let mut offsets = TopicPartitionList::new();
+                    for ((topic, partition), (offset, retry)) in resume {
+                        offsets
+                            .add_partition_offset(&topic, partition, Offset::Offset(offset))
+                            .map_err(Error::Kafka)?;
 
-                    let is_allowed = result.iter().filter(|r| r.is_err()).all(|r| match r {
-                        Err(error) => !matches!(
-                            error,
-                            Error::StorageError(_)
-                                | Error::ConnectionError(_)
-                                | Error::ConnectionRetrievalError(_)
-                        ),
-                        _ => true,
-                    });
-
-                    if is_allowed {
-                        if let Some(Ok(msg)) = messages.last() {
+                        // Rewind the partition so the failed command is consumed again on
+                        // the next dequeue.
+                        if retry {
                             consumer
-                                .commit_message(msg, CommitMode::Async)
+                                .seek(
+                                    &topic,
+                                    partition,
+                                    Offset::Offset(offset),
+                                    std::time::Duration::from_secs(SEEK_TIMEOUT),
+                                )
                                 .map_err(Error::Kafka)?;
                         }
                     }
+
+                    if offsets.count() > 0 {
+                        consumer
+                            .commit(&offsets, CommitMode::Async)
+                            .map_err(Error::Kafka)?;
+                    }
                 }
 
                 Ok(())
             }
             .into_actor(self)
-            .map(|_: Result<Unit, KafkaError>, _, ctx| {
-                // TODO: Figure out what to do with errors
+            .map(|result: Result<Unit, Error>, act, ctx| {
+                if let Err(e) = result {
+                    act.config
+                        .report(ActorFailure::new(ActorKind::Aggregate, None, e));
+                }
                 ctx.notify(Dequeue);
                 Ok(())
             }),
@@ -181,25 +533,1401 @@ where
     }
 }
 
-async fn process<'a, State, Store, Cmd, Evt>(
-    msg: &'a BorrowedMessage<'a>,
-    addr: Addr<Inner<State, Store, Evt>>,
-) -> Result<Unit, Error>
+/// Forget the commands enqueued by this instance that are processed, i.e. those before the
+/// offset their partition resumes from, so `Consistency::Strong` reads do not wait for them and
+/// the entities they were enqueued for are not remembered forever. Commands this instance does
+/// not consume are forgotten by the first strong read of their entity instead.
+async fn forget_processed(
+    enqueued: &Mutex<HashMap<String, Position>>,
+    resume: &HashMap<Partition, (i64, bool)>,
+) {
+    enqueued.lock().await.retain(|_, (partition, offset)| {
+        resume
+            .get(partition)
+            .is_none_or(|(resume, _)| *offset >= *resume)
+    });
+}
+
+/// Process a chunk of commands, returning what became of every command, by its position, and
+/// for every partition of the chunk the offset to resume from and whether the partition is to
+/// be rewound to it, see `resume_offsets`.
+///
+/// Once a command of an entity has to be consumed again, the following commands of the entity
+/// are held back, so they are consumed again after it rather than processed before it. The
+/// commands of other entities dealt with beyond the offset a partition is rewound to are
+/// remembered in `dealt`, so they are not processed a second time once consumed again.
+async fn process_chunk<'a, State, Store, Cmd, Evt, M>(
+    messages: impl IntoIterator<Item = Result<&'a M, &'a KafkaError>>,
+    actors: &Mutex<Actors<State, Store, Evt>>,
+    dealt: &Mutex<HashSet<Position>>,
+    store: &Store,
+    producer: &Arc<FutureProducer>,
+    pending: &Pending<State, Cmd::T>,
+    config: &EngineConfig,
+) -> (
+    Vec<(Position, Disposition)>,
+    HashMap<Partition, (i64, bool)>,
+)
+where
+    State: Debug + Clone + Send + Sync + Unpin + 'static + StateFactory + DeserializeOwned,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Cmd: Send + Sync + Unpin + 'static + Debug + DeserializeOwned + Command<State> + Serialize,
+    Evt: Event<State> + 'static + DeserializeOwned + Serialize + Unpin + Debug,
+    M: Message + Debug + 'a,
+{
+    let groups = group_by_entity(messages, |msg| entity_id::<State, Cmd, _>(*msg, config));
+
+    // The lock is taken once as the chunk starts and once as it ends, the actors of the chunk
+    // are kept from eviction until all of it is processed
+    let mut entities = Vec::with_capacity(groups.len());
+    let mut leased = Vec::with_capacity(groups.len());
+    {
+        let mut actors = actors.lock().await;
+        for (key, msgs) in groups {
+            if let Ok(key) = &key {
+                leased.push(key.clone());
+            }
+            let addr = key.map(|key| {
+                actors.get_or_start(&key, |watchers| {
+                    let inner = Inner::<State, Store, Evt>::new(
+                        &key,
+                        store.clone(),
+                        producer.clone(),
+                        watchers,
+                        config,
+                    );
+                    config.start_local(inner)
+                })
+            });
+            entities.push((addr, msgs));
+        }
+    }
+
+    // Chunks are processed one at a time, so nothing else waits for the lock meanwhile
+    let mut dealt = dealt.lock().await;
+    let results = {
+        let dealt = &*dealt;
+        per_entity(
+            entities,
+            move |addr, msg| {
+                let addr = match addr {
+                    Ok(addr) => Ok(addr.clone()),
+                    Err(e) => Err(e.replicate()),
+                };
+                async move {
+                    if dealt.contains(&position(msg)) {
+                        return Disposition::Redelivered;
+                    }
+                    process::<State, Store, Cmd, Evt, M>(msg, addr, pending, config, producer).await
+                }
+            },
+            Disposition::is_retried,
+        )
+        .await
+    };
+    {
+        let mut actors = actors.lock().await;
+        for key in &leased {
+            actors.release(key, config.max_entities());
+        }
+    }
+
+    let mut outcomes = Vec::with_capacity(results.len());
+    let mut skipped = 0;
+    let mut dead_lettered = 0;
+    let mut requeued = 0;
+    for (msg, disposition) in results {
+        let disposition = disposition.unwrap_or(Disposition::HeldBack);
+        match &disposition {
+            Disposition::Processed => {}
+            Disposition::Redelivered => tracing::debug!(
+                topic = msg.topic(),
+                partition = msg.partition(),
+                offset = msg.offset(),
+                "Command was dealt with before its partition was rewound and is not processed again"
+            ),
+            Disposition::Skipped(error) => {
+                skipped += 1;
+                tracing::error!(
+                    topic = msg.topic(),
+                    partition = msg.partition(),
+                    offset = msg.offset(),
+                    "Command could not be processed and is skipped: {}",
+                    error
+                );
+            }
+            Disposition::DeadLettered(error) => {
+                dead_lettered += 1;
+                tracing::error!(
+                    topic = msg.topic(),
+                    partition = msg.partition(),
+                    offset = msg.offset(),
+                    "Command could not be processed and is dead-lettered: {}",
+                    error
+                );
+            }
+            Disposition::Requeued(error) => {
+                requeued += 1;
+                tracing::warn!(
+                    topic = msg.topic(),
+                    partition = msg.partition(),
+                    offset = msg.offset(),
+                    "Command could not be processed and is requeued: {}",
+                    error
+                );
+            }
+            Disposition::Unhandled(error) => tracing::warn!(
+                topic = msg.topic(),
+                partition = msg.partition(),
+                offset = msg.offset(),
+                "Command will be consumed again: {}",
+                error
+            ),
+            Disposition::HeldBack => tracing::warn!(
+                topic = msg.topic(),
+                partition = msg.partition(),
+                offset = msg.offset(),
+                "Command will be consumed again after an earlier command of its entity"
+            ),
+        }
+        outcomes.push((position(msg), disposition));
+    }
+
+    if skipped > 0 || dead_lettered > 0 || requeued > 0 {
+        tracing::warn!(
+            "{} out of {} commands of the chunk were skipped, {} were dead-lettered, {} were requeued",
+            skipped,
+            outcomes.len(),
+            dead_lettered,
+            requeued
+        );
+    }
+
+    let resume = resume_offsets(outcomes.iter().map(|((partition, offset), disposition)| {
+        (partition.clone(), *offset, disposition.is_retried())
+    }));
+    remember_dealt(
+        &mut dealt,
+        outcomes
+            .iter()
+            .map(|(position, disposition)| (position, disposition.is_retried())),
+        &resume,
+    );
+    (outcomes, resume)
+}
+
+/// The position of a command, by its partition and offset.
+fn position(msg: &impl Message) -> Position {
+    ((msg.topic().to_string(), msg.partition()), msg.offset())
+}
+
+/// Remember the commands dealt with beyond the offset their partition is rewound to, so they
+/// are not processed again once consumed again, and forget the ones their partition resumes
+/// past, which are not consumed again.
+fn remember_dealt<'a>(
+    dealt: &mut HashSet<Position>,
+    outcomes: impl IntoIterator<Item = (&'a Position, bool)>,
+    resume: &HashMap<Partition, (i64, bool)>,
+) {
+    dealt.retain(|(partition, offset)| {
+        resume
+            .get(partition)
+            .is_none_or(|(resumed, _)| offset >= resumed)
+    });
+    for (position @ (partition, offset), retry) in outcomes {
+        if let Some((resumed, true)) = resume.get(partition) {
+            if !retry && offset > resumed {
+                dealt.insert(position.clone());
+            }
+        }
+    }
+}
+
+/// For every partition of a chunk, given the offset of every command and whether it is to be
+/// consumed again, the offset to resume from and whether the partition is to be rewound to it.
+///
+/// That is either the first command that could neither be processed nor be dealt with by the
+/// failure policy, which is consumed again, or the one following the last command of the
+/// chunk. Partitions are committed independently, so a failing command only holds back the
+/// commands following it in its own partition. Results come grouped by entity rather than by
+/// offset, so the lowest failed offset of a partition has to be kept.
+fn resume_offsets<P>(positions: impl IntoIterator<Item = (P, i64, bool)>) -> HashMap<P, (i64, bool)>
+where
+    P: Eq + Hash,
+{
+    let mut resume: HashMap<P, (i64, bool)> = HashMap::new();
+    for (partition, offset, retry) in positions {
+        match resume.get_mut(&partition) {
+            Some((resumed, true)) if retry => *resumed = (*resumed).min(offset),
+            Some((_, true)) => {}
+            Some(entry) if retry => *entry = (offset, true),
+            Some(entry) => entry.0 = entry.0.max(offset + 1),
+            None => {
+                resume.insert(partition, (if retry { offset } else { offset + 1 }, retry));
+            }
+        }
+    }
+    resume
+}
+
+/// Group a chunk by entity id, keeping the order of the commands of each entity and the order
+/// in which entities first appear.
+///
+/// Errors polling the chunk are logged and dropped, as there is no message to deal with. A
+/// message whose entity id cannot be found is a group of its own, failing with the error, so
+/// it goes through the failure policy without holding back the rest of the chunk.
+fn group_by_entity<'a, M>(
+    messages: impl IntoIterator<Item = Result<M, &'a KafkaError>>,
+    entity_id: impl Fn(&M) -> Result<String, Error>,
+) -> Vec<(Result<String, Error>, Vec<M>)> {
+    let mut groups: Vec<(Result<String, Error>, Vec<M>)> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for msg in messages {
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(e) => {
+                tracing::error!("Could not consume command: {}", e);
+                continue;
+            }
+        };
+
+        match entity_id(&msg) {
+            Ok(key) => match index.get(&key) {
+                Some(position) => groups[*position].1.push(msg),
+                None => {
+                    index.insert(key.clone(), groups.len());
+                    groups.push((Ok(key), vec![msg]));
+                }
+            },
+            Err(e) => groups.push((Err(e), vec![msg])),
+        }
+    }
+    groups
+}
+
+/// Process the commands of several entities, the entities concurrently and the commands of
+/// an entity one after the other, in order. Returns every command with its result, grouped
+/// by entity. Once `halts` holds for the result of a command, the following commands of its
+/// entity are not processed, and come without a result.
+async fn per_entity<A, T, F, Fut>(
+    entities: Vec<(A, Vec<T>)>,
+    process: F,
+    halts: impl Fn(&Fut::Output) -> bool,
+) -> Vec<(T, Option<Fut::Output>)>
+where
+    T: Copy,
+    F: Fn(&A, T) -> Fut,
+    Fut: Future,
+{
+    let (process, halts) = (&process, &halts);
+    futures::future::join_all(entities.into_iter().map(|(addr, commands)| async move {
+        let mut results = Vec::with_capacity(commands.len());
+        let mut halted = false;
+        for command in commands {
+            if halted {
+                results.push((command, None));
+                continue;
+            }
+            let result = process(&addr, command).await;
+            halted = halts(&result);
+            results.push((command, Some(result)));
+        }
+        results
+    }))
+    .await
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// What became of a consumed command.
+enum Disposition {
+    Processed,
+    /// The command failed and the failure policy skipped it.
+    Skipped(Error),
+    /// The command failed and was produced to the dead-letter topic.
+    DeadLettered(Error),
+    /// The command is of an unknown type and was produced to its topic again.
+    Requeued(Error),
+    /// The command failed and could not be dealt with, it has to be consumed again.
+    Unhandled(Error),
+    /// An earlier command of the entity has to be consumed again, and so does the command.
+    HeldBack,
+    /// The command was dealt with before its partition was rewound, and is not processed again.
+    Redelivered,
+}
+
+impl Disposition {
+    /// Whether the command has to be consumed again.
+    fn is_retried(&self) -> bool {
+        matches!(self, Disposition::Unhandled(_) | Disposition::HeldBack)
+    }
+}
+
+/// Process a command, applying the failure policy of the engine if it fails, and report
+/// the final outcome to the handle awaiting it, if the command was enqueued by this process.
+async fn process<State, Store, Cmd, Evt, M>(
+    msg: &M,
+    addr: Result<Addr<Inner<State, Store, Evt>>, Error>,
+    pending: &Pending<State, Cmd::T>,
+    config: &EngineConfig,
+    producer: &FutureProducer,
+) -> Disposition
+where
+    State: Clone + Send + Sync + Unpin + 'static + StateFactory + Debug + DeserializeOwned,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Cmd: Send + Sync + Unpin + 'static + Debug + DeserializeOwned + Command<State> + Serialize,
+    Evt: Event<State> + DeserializeOwned + Serialize + Unpin + Debug + 'static,
+    M: Message,
+{
+    let policy = config.failure_policy();
+    let mut attempts = 0;
+
+    loop {
+        let (id, outcome) = process_once::<State, Store, Cmd, Evt, M>(msg, &addr, config).await;
+
+        let error = match outcome {
+            Ok(outcome) => {
+                if let Some(id) = id {
+                    EnqueueHandle::resolve(pending, &id, Ok(outcome)).await;
+                }
+                return Disposition::Processed;
+            }
+            Err(error) => error,
+        };
+
+        if let (Error::UnknownCommand(_), Some(handler)) =
+            (&error, config.unknown_command_handler())
+        {
+            return unknown(msg, handler.as_ref(), pending, config, producer, error).await;
+        }
+
+        let action = policy.action(&error);
+        if let FailureAction::Retry { max, backoff } = action {
+            if attempts < max {
+                attempts += 1;
+                tracing::warn!(
+                    topic = msg.topic(),
+                    partition = msg.partition(),
+                    offset = msg.offset(),
+                    "Retrying command, attempt {} of {}: {}",
+                    attempts,
+                    max,
+                    error
+                );
+                tokio::time::sleep(backoff).await;
+                continue;
+            }
+        }
+
+        let disposition = match action {
+            FailureAction::Skip => Disposition::Skipped(error.replicate()),
+            // Retries are exhausted
+            FailureAction::Retry { .. } | FailureAction::DeadLetter => {
+                match dead_letter(msg, producer, config, &error).await {
+                    Ok(()) => Disposition::DeadLettered(error.replicate()),
+                    // Leave the handle pending, the command is processed again
+                    Err(e) => {
+                        return Disposition::Unhandled(Error::Error(format!(
+                            "Could not dead-letter command that failed with {}: {}",
+                            error, e
+                        )))
+                    }
+                }
+            }
+        };
+
+        if let Some(id) = id {
+            EnqueueHandle::resolve(pending, &id, Err(error)).await;
+        }
+        return disposition;
+    }
+}
+
+/// Process a command once, returning the id of its record, if it could be decoded, together
+/// with the outcome. Fails right away if no entity could be found for the command.
+async fn process_once<State, Store, Cmd, Evt, M>(
+    msg: &M,
+    addr: &Result<Addr<Inner<State, Store, Evt>>, Error>,
+    config: &EngineConfig,
+) -> (Option<Uuid>, Result<CommandOutcome<State, Cmd::T>, Error>)
 where
-    State: Clone + Send + Sync + Unpin + 'static + Default + Debug + DeserializeOwned,
+    State: Clone + Send + Sync + Unpin + 'static + StateFactory + Debug + DeserializeOwned,
     Store: Adapter + Clone + Send + Sync + 'static + Unpin,
     Cmd: Send + Sync + Unpin + 'static + Debug + DeserializeOwned + Command<State> + Serialize,
     Evt: Event<State> + DeserializeOwned + Serialize + Unpin + Debug + 'static,
+    M: Message,
+{
+    let addr = match addr {
+        Ok(addr) => addr,
+        Err(e) => return (None, Err(e.replicate())),
+    };
+
+    let payload = match msg.payload() {
+        Some(payload) => payload,
+        None => {
+            return (
+                None,
+                Err(Error::InvalidCommand(
+                    "Could not find payload in message".to_string(),
+                )),
+            )
+        }
+    };
+
+    // Reject oversized payloads before deserializing them, so a single message
+    // cannot force an arbitrarily large allocation.
+    if let Some(max) = config.max_payload_size() {
+        if payload.len() > max {
+            return (
+                None,
+                Err(Error::InvalidCommand(format!(
+                    "Payload of {} bytes exceeds the maximum of {} bytes",
+                    payload.len(),
+                    max
+                ))),
+            );
+        }
+    }
+
+    let record = match Record::<Cmd>::decode(payload, config.command_format(), Cmd::entity_id) {
+        Ok(record) => record,
+        // A record that can be read, but whose message is not a `Cmd`, holds an unknown command
+        Err(e) => {
+            let error = match decode_unknown(payload, config) {
+                Some(record) => Error::UnknownCommand(format!(
+                    "Could not decode command {}: {}",
+                    record.r#type().unwrap_or("without type"),
+                    e
+                )),
+                None => Error::InvalidCommand(format!("Could not decode command: {}", e)),
+            };
+            return (None, Err(error));
+        }
+    };
+    let id = record.id();
+    let entity_id = record.entity_id().to_string();
+
+    let outcome = match addr.send(Process::<State, Cmd, Cmd::T>::new(record)).await {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            config.report(ActorFailure::new(ActorKind::Inner, Some(&entity_id), e));
+            Err(Error::InvalidCommand(format!(
+                "Could not send command: {}",
+                e
+            )))
+        }
+    };
+
+    (id, outcome)
+}
+
+/// The entity id of a command, read from its payload, as the key of the message only
+/// partitions commands, see `CommandKey`. Commands whose payload carries no entity id, or
+/// cannot be decoded, fall back to the key, so they are still dealt with by `process`.
+fn entity_id<State, Cmd, M>(msg: &M, config: &EngineConfig) -> Result<String, Error>
+where
+    M: Message + Debug,
+    State: Debug + Clone + Send + Sync + 'static,
+    Cmd: Command<State> + DeserializeOwned,
+{
+    let payload = msg.payload().filter(|payload| {
+        config
+            .max_payload_size()
+            .is_none_or(|max| payload.len() <= max)
+    });
+    if let Some(payload) = payload {
+        let entity_id = Record::<Cmd>::decode_entity_id(payload).or_else(|| {
+            Record::<Cmd>::decode(payload, config.command_format(), Cmd::entity_id)
+                .ok()
+                .map(|record| record.entity_id().to_string())
+        });
+        if let Some(entity_id) = entity_id {
+            return Ok(entity_id);
+        }
+    }
+
+    let key = msg.key().ok_or(Error::InvalidKey(format!(
+        "Could not find key in message {:?}",
+        msg
+    )))?;
+
+    // Keys need not be UTF-8, e.g. `HashedKey`, the command fails to decode in `process`
+    Ok(String::from_utf8_lossy(key).into_owned())
+}
+
+/// Decode a record whose message is not a `Cmd`, keeping the message as JSON.
+fn decode_unknown(payload: &[u8], config: &EngineConfig) -> Option<Record<Value>> {
+    Record::<Value>::decode(payload, config.command_format(), |_| String::new()).ok()
+}
+
+/// Apply the unknown command handler of the engine to a command of an unknown type, and
+/// report the outcome to the handle awaiting it, if the command was enqueued by this process
+/// and is not requeued.
+async fn unknown<State, Evt>(
+    msg: &impl Message,
+    handler: &dyn UnknownCommandHandler,
+    pending: &Pending<State, Evt>,
+    config: &EngineConfig,
+    producer: &FutureProducer,
+    error: Error,
+) -> Disposition {
+    let Some(record) = msg
+        .payload()
+        .and_then(|payload| decode_unknown(payload, config))
+    else {
+        return Disposition::Skipped(error);
+    };
+
+    let disposition = match handler.handle(&record) {
+        UnknownCommandPolicy::Skip => Disposition::Skipped(error.replicate()),
+        UnknownCommandPolicy::DeadLetter => {
+            match dead_letter(msg, producer, config, &error).await {
+                Ok(()) => Disposition::DeadLettered(error.replicate()),
+                Err(e) => {
+                    return Disposition::Unhandled(Error::Error(format!(
+                        "Could not dead-letter command that failed with {}: {}",
+                        error, e
+                    )))
+                }
+            }
+        }
+        // Leave the handle pending, the command is processed again
+        UnknownCommandPolicy::Requeue => {
+            return match requeue(msg, producer).await {
+                Ok(()) => Disposition::Requeued(error),
+                Err(e) => Disposition::Unhandled(Error::Error(format!(
+                    "Could not requeue command that failed with {}: {}",
+                    error, e
+                ))),
+            }
+        }
+    };
+
+    if let Some(id) = record.id() {
+        EnqueueHandle::resolve(pending, &id, Err(error)).await;
+    }
+    disposition
+}
+
+/// Produce a command to the end of the topic it was consumed from, as is.
+async fn requeue(msg: &impl Message, producer: &FutureProducer) -> Result<Unit, Error> {
+    let mut record = FutureRecord::<[u8], [u8]>::to(msg.topic());
+    if let Some(key) = msg.key() {
+        record = record.key(key);
+    }
+    if let Some(payload) = msg.payload() {
+        record = record.payload(payload);
+    }
+
+    producer
+        .send(record, Timeout::Never)
+        .await
+        .map(|_| ())
+        .map_err(|(e, _)| Error::Kafka(e))
+}
+
+/// Produce a command that failed to the dead-letter topic, as is, with the error and its
+/// original position in the headers.
+async fn dead_letter(
+    msg: &impl Message,
+    producer: &FutureProducer,
+    config: &EngineConfig,
+    error: &Error,
+) -> Result<Unit, Error> {
+    let headers = OwnedHeaders::new()
+        .insert(Header {
+            key: "error",
+            value: Some(error.to_string().as_str()),
+        })
+        .insert(Header {
+            key: "topic",
+            value: Some(msg.topic()),
+        })
+        .insert(Header {
+            key: "partition",
+            value: Some(msg.partition().to_string().as_str()),
+        })
+        .insert(Header {
+            key: "offset",
+            value: Some(msg.offset().to_string().as_str()),
+        });
+
+    let mut record = FutureRecord::<[u8], [u8]>::to(config.dead_letter_topic()).headers(headers);
+    if let Some(key) = msg.key() {
+        record = record.key(key);
+    }
+    if let Some(payload) = msg.payload() {
+        record = record.payload(payload);
+    }
+
+    producer
+        .send(record, Timeout::Never)
+        .await
+        .map(|_| ())
+        .map_err(|(e, _)| Error::Kafka(e))
+}
+
+/// Await the processing of a chunk, keeping the consumer in its group when it takes long.
+///
+/// Once processing takes longer than `interval`, the partitions of the consumer are paused
+/// and it polls every `interval`, which keeps `max.poll.interval.ms` from expiring without
+/// consuming anything. A message polled regardless, e.g. of a partition assigned meanwhile,
+/// is rewound so it is consumed again. The partitions are resumed once the chunk is
+/// processed.
+async fn keep_alive<F>(consumer: &StreamConsumer, interval: Duration, processing: F) -> F::Output
+where
+    F: Future,
 {
-    match msg.payload() {
-        Some(payload) => {
-            let payload = serde_json::from_slice::<Record<Cmd>>(payload)
-                .map_err(|e| Error::InvalidCommand(format!("Could not decode command: {}", e)))?;
-            Ok(addr
-                .send(Process::<Cmd>::new(payload))
+    let mut processing = std::pin::pin!(processing);
+    let mut paused: Option<TopicPartitionList> = None;
+
+    loop {
+        if let Ok(output) = tokio::time::timeout(interval, &mut processing).await {
+            if let Some(partitions) = paused {
+                if let Err(e) = consumer.resume(&partitions) {
+                    tracing::error!("Could not resume consuming commands: {}", e);
+                }
+            }
+            return output;
+        }
+
+        if paused.is_none() {
+            match consumer
+                .assignment()
+                .and_then(|partitions| consumer.pause(&partitions).map(|_| partitions))
+            {
+                Ok(partitions) => {
+                    tracing::warn!(
+                        "Processing commands takes longer than {:?}, consuming is paused until done",
+                        interval
+                    );
+                    paused = Some(partitions);
+                }
+                Err(e) => tracing::error!("Could not pause consuming commands: {}", e),
+            }
+        }
+
+        if let Some(Ok(msg)) = consumer.recv().now_or_never() {
+            rewind(consumer, &msg);
+        }
+    }
+}
+
+/// Keep the consumer in its group while consuming is paused, see `Engine::pause`.
+///
+/// Every partition assigned to the consumer is paused, including the ones assigned since the
+/// last call, and the consumer polls for up to `interval`, which keeps `max.poll.interval.ms`
+/// from expiring. A message polled regardless is rewound so it is consumed once resumed.
+async fn hold(consumer: &StreamConsumer, interval: Duration) {
+    if let Err(e) = consumer
+        .assignment()
+        .and_then(|partitions| consumer.pause(&partitions))
+    {
+        tracing::error!("Could not pause consuming commands: {}", e);
+    }
+
+    if let Ok(Ok(msg)) = tokio::time::timeout(interval, consumer.recv()).await {
+        rewind(consumer, &msg);
+    }
+}
+
+/// Seek back to a command that was polled without being processed, so it is consumed again.
+fn rewind(consumer: &StreamConsumer, msg: &BorrowedMessage<'_>) {
+    if let Err(e) = consumer.seek(
+        msg.topic(),
+        msg.partition(),
+        Offset::Offset(msg.offset()),
+        Duration::from_secs(SEEK_TIMEOUT),
+    ) {
+        tracing::error!(
+            topic = msg.topic(),
+            partition = msg.partition(),
+            offset = msg.offset(),
+            "Could not rewind to a command polled without processing it: {}",
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::{FailurePolicy, NonEmptyVec, PublishMode},
+        storage::MemoryAdapter,
+    };
+    use rdkafka::message::OwnedMessage;
+    use rdkafka::Timestamp;
+    use serde::Deserialize;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+    use tokio::sync::Barrier;
+
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    struct Ticket {
+        open: bool,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Opened;
+
+    impl Event<Ticket> for Opened {
+        fn apply(&self, _: &Ticket) -> Option<Ticket> {
+            Some(Ticket { open: true })
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Open(String);
+
+    impl Command<Ticket> for Open {
+        type T = Opened;
+
+        fn validate(&self, _: &Ticket) -> Result<Unit, Error> {
+            Ok(())
+        }
+
+        fn directive(&self, _: &Ticket) -> Result<NonEmptyVec<Box<Opened>>, Error> {
+            Ok(NonEmptyVec::new(vec![Box::new(Opened)])?)
+        }
+
+        fn entity_id(&self) -> String {
+            self.0.clone()
+        }
+    }
+
+    #[actix::test]
+    async fn subscribes_once_across_dequeues() {
+        // Without a broker every poll returns the error of the unreachable broker, so the
+        // actor dequeues every few seconds, as it waits for more messages to come in
+        let mut configuration = ClientConfig::new();
+        configuration
+            .set("bootstrap.servers", "localhost:9")
+            .set("log_level", "0");
+        let producer = configuration.create().expect("a producer");
+        let config = EngineConfig::default()
+            .with_poll_timeout(Duration::from_millis(10))
+            .with_idle_backoff(Duration::from_millis(10));
+        let aggregate = Aggregate::<Ticket, MemoryAdapter, Open, Opened>::new(
+            configuration,
+            MemoryAdapter::new(),
+            Arc::new(producer),
+            Default::default(),
+            config,
+            Default::default(),
+        )
+        .expect("an aggregate");
+        let consumer = aggregate.consumer();
+        aggregate.start();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // A dequeue subscribing again would replace this subscription, and rebalance the group
+        // as the assignment is revoked
+        consumer.subscribe(&["commands", "probe"]).unwrap();
+        let mut samples = Vec::new();
+        for _ in 0..50 {
+            let subscription = consumer.subscription().unwrap();
+            let topics = subscription
+                .elements()
+                .iter()
+                .map(|element| element.topic().to_string())
+                .collect::<Vec<_>>();
+            samples.push((topics, consumer.assignment().unwrap().count()));
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        assert!(samples
+            .iter()
+            .all(|sample| *sample == (vec!["commands".to_string(), "probe".to_string()], 0)));
+    }
+
+    #[actix::test]
+    async fn two_commands_of_a_new_entity_start_a_single_actor() {
+        let store = MemoryAdapter::new();
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", "localhost:9")
+            .set("log_level", "0")
+            .create()
+            .expect("a producer");
+        let producer = Arc::new(producer);
+        // Events go through the outbox, so the producer is never used
+        let config = EngineConfig::default().with_publish_mode(PublishMode::Outbox);
+        let started = AtomicUsize::new(0);
+        let mut actors = Actors::<Ticket, MemoryAdapter, Opened>::default();
+
+        let mut addrs = Vec::new();
+        for _ in 0..2 {
+            addrs.push(actors.get_or_start("ticket:1", |watchers| {
+                started.fetch_add(1, Ordering::SeqCst);
+                config.start_local(Inner::new(
+                    "ticket:1",
+                    store.clone(),
+                    producer.clone(),
+                    watchers,
+                    &config,
+                ))
+            }));
+        }
+        for addr in &addrs {
+            let record = Record::command(
+                "ticket:1",
+                Open("ticket:1".into()),
+                chrono::Utc::now(),
+                "Open".into(),
+                0,
+            );
+            addr.send(Process::<Ticket, Open, Opened>::new(record))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+
+        assert_eq!(started.load(Ordering::SeqCst), 1);
+        assert_eq!(addrs[0], addrs[1]);
+        assert_eq!(
+            store
+                .read_highest_sequence_number("ticket:1")
+                .await
+                .unwrap(),
+            Some(2)
+        );
+    }
+
+    #[actix::test]
+    async fn actors_in_use_are_not_evicted_by_dispatched_commands() {
+        let store = MemoryAdapter::new();
+        let mut configuration = ClientConfig::new();
+        configuration
+            .set("bootstrap.servers", "localhost:9")
+            .set("log_level", "0");
+        let producer: FutureProducer = configuration.create().expect("a producer");
+        let producer = Arc::new(producer);
+        let config = EngineConfig::default()
+            .with_publish_mode(PublishMode::Outbox)
+            .with_max_entities(1);
+        let aggregate = Aggregate::<Ticket, MemoryAdapter, Open, Opened>::new(
+            configuration,
+            store.clone(),
+            producer.clone(),
+            Default::default(),
+            config.clone(),
+            Default::default(),
+        )
+        .expect("an aggregate");
+        let actors = aggregate.addr.clone();
+        let started = AtomicUsize::new(0);
+        let start = |watchers| {
+            started.fetch_add(1, Ordering::SeqCst);
+            config.start_local(Inner::new(
+                "ticket:1",
+                store.clone(),
+                producer.clone(),
+                watchers,
+                &config,
+            ))
+        };
+
+        // The actor of a chunk being dequeued, while commands of other entities are dispatched
+        let dequeued = actors.lock().await.get_or_start("ticket:1", start);
+        let aggregate = aggregate.start();
+        for entity_id in ["ticket:2", "ticket:3"] {
+            aggregate
+                .send(Dispatch::new(Open(entity_id.into())))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        let again = actors.lock().await.get_or_start("ticket:1", start);
+
+        assert_eq!(started.load(Ordering::SeqCst), 1);
+        assert_eq!(dequeued, again);
+        // The entities beyond the cap that are not in use are the ones evicted
+        assert!(actors.lock().await.get("ticket:2").is_none());
+        assert!(actors.lock().await.get("ticket:3").is_none());
+    }
+
+    #[tokio::test]
+    async fn entities_are_processed_concurrently_and_their_commands_in_order() {
+        // The first command of either entity only completes once both started, which never
+        // happens if the entities are processed one after the other
+        let barrier = Barrier::new(2);
+        let processed = Mutex::new(Vec::new());
+        let entities = vec![("order:1", vec![1, 2, 3]), ("order:2", vec![4, 5])];
+
+        let results = tokio::time::timeout(
+            Duration::from_secs(5),
+            per_entity(
+                entities,
+                |entity, command| {
+                    let (barrier, processed) = (&barrier, &processed);
+                    let entity = *entity;
+                    async move {
+                        if command == 1 || command == 4 {
+                            barrier.wait().await;
+                        }
+                        tokio::task::yield_now().await;
+                        processed.lock().unwrap().push((entity, command));
+                        command * 10
+                    }
+                },
+                |_| false,
+            ),
+        )
+        .await
+        .expect("the entities to be processed concurrently");
+
+        let processed = processed.into_inner().unwrap();
+        let of = |entity| {
+            processed
+                .iter()
+                .filter(|(of, _)| *of == entity)
+                .map(|(_, command)| *command)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(of("order:1"), vec![1, 2, 3]);
+        assert_eq!(of("order:2"), vec![4, 5]);
+        assert_eq!(
+            results,
+            vec![
+                (1, Some(10)),
+                (2, Some(20)),
+                (3, Some(30)),
+                (4, Some(40)),
+                (5, Some(50))
+            ]
+        );
+    }
+
+    // Punching a ticket fails with the given error, if any
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Punch {
+        ticket: String,
+        fails: Option<Failure>,
+    }
+
+    #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+    enum Failure {
+        // Retryable
+        Storage,
+        Torn,
+        Forged,
+    }
+
+    impl Command<Ticket> for Punch {
+        type T = Opened;
+
+        fn validate(&self, _: &Ticket) -> Result<Unit, Error> {
+            Ok(())
+        }
+
+        fn directive(&self, _: &Ticket) -> Result<NonEmptyVec<Box<Opened>>, Error> {
+            match self.fails {
+                Some(Failure::Storage) => Err(Error::StorageError("the store is down".into())),
+                Some(Failure::Torn) => Err(Error::InvalidState("the ticket is torn".into())),
+                Some(Failure::Forged) => Err(Error::Validation("the ticket is forged".into())),
+                None => Ok(NonEmptyVec::new(vec![Box::new(Opened)])?),
+            }
+        }
+
+        fn entity_id(&self) -> String {
+            self.ticket.clone()
+        }
+    }
+
+    fn punch(partition: i32, offset: i64, ticket: &str, fails: Option<Failure>) -> OwnedMessage {
+        let command = Punch {
+            ticket: ticket.into(),
+            fails,
+        };
+        let record = Record::command(ticket, command, chrono::Utc::now(), "Punch".into(), 0);
+        message(partition, offset, ticket, &record)
+    }
+
+    fn message<T: Serialize>(
+        partition: i32,
+        offset: i64,
+        key: &str,
+        record: &Record<T>,
+    ) -> OwnedMessage {
+        let payload = record
+            .encode(EngineConfig::default().command_format())
+            .unwrap();
+        OwnedMessage::new(
+            Some(payload),
+            Some(key.as_bytes().to_vec()),
+            "commands".into(),
+            Timestamp::NotAvailable,
+            partition,
+            offset,
+            None,
+        )
+    }
+
+    // Every failure is dealt with by the same action
+    #[derive(Debug)]
+    struct Always(FailureAction);
+
+    impl FailurePolicy for Always {
+        fn action(&self, _: &Error) -> FailureAction {
+            self.0
+        }
+    }
+
+    /// Processes chunks of punches as a dequeue does, without a broker, so commands cannot be
+    /// dead-lettered or requeued.
+    struct Punches {
+        actors: futures::lock::Mutex<Actors<Ticket, MemoryAdapter, Opened>>,
+        dealt: futures::lock::Mutex<HashSet<Position>>,
+        store: MemoryAdapter,
+        producer: Arc<FutureProducer>,
+        config: EngineConfig,
+    }
+
+    impl Punches {
+        fn new(config: EngineConfig) -> Self {
+            let producer: FutureProducer = ClientConfig::new()
+                .set("bootstrap.servers", "localhost:9")
+                .set("message.timeout.ms", "100")
+                .set("log_level", "0")
+                .create()
+                .expect("a producer");
+
+            Self {
+                actors: Default::default(),
+                dealt: Default::default(),
+                store: MemoryAdapter::new(),
+                producer: Arc::new(producer),
+                config: config.with_publish_mode(PublishMode::Outbox),
+            }
+        }
+
+        async fn process(
+            &self,
+            messages: &[OwnedMessage],
+        ) -> (
+            Vec<(i32, i64, &'static str)>,
+            HashMap<Partition, (i64, bool)>,
+        ) {
+            let (outcomes, resume) = process_chunk::<Ticket, MemoryAdapter, Punch, Opened, _>(
+                messages.iter().map(Ok),
+                &self.actors,
+                &self.dealt,
+                &self.store,
+                &self.producer,
+                &Default::default(),
+                &self.config,
+            )
+            .await;
+
+            let mut dispositions = outcomes
+                .iter()
+                .map(|(((_, partition), offset), disposition)| {
+                    let disposition = match disposition {
+                        Disposition::Processed => "processed",
+                        Disposition::Skipped(_) => "skipped",
+                        Disposition::DeadLettered(_) => "dead-lettered",
+                        Disposition::Requeued(_) => "requeued",
+                        Disposition::Unhandled(_) => "unhandled",
+                        Disposition::HeldBack => "held back",
+                        Disposition::Redelivered => "redelivered",
+                    };
+                    (*partition, *offset, disposition)
+                })
+                .collect::<Vec<_>>();
+            dispositions.sort();
+            (dispositions, resume)
+        }
+
+        async fn punched(&self, ticket: &str) -> Option<u64> {
+            self.store
+                .read_highest_sequence_number(ticket)
                 .await
-                .map_err(|e| Error::InvalidCommand(format!("Could not send command: {}", e)))??)
+                .unwrap()
+        }
+    }
+
+    #[actix::test]
+    async fn commands_following_a_failed_one_of_their_entity_are_held_back() {
+        let punches = Punches::new(EngineConfig::default().with_failure_policy(Always(
+            FailureAction::Retry {
+                max: 1,
+                backoff: Duration::ZERO,
+            },
+        )));
+        let messages = vec![
+            punch(0, 0, "ticket:1", Some(Failure::Storage)),
+            punch(0, 1, "ticket:1", None),
+            punch(0, 2, "ticket:2", None),
+        ];
+
+        let (dispositions, resume) = punches.process(&messages).await;
+
+        // The first punch is retried in place, and cannot be dead-lettered once retries are
+        // exhausted, so it is consumed again and the punch following it waits for it
+        assert_eq!(
+            dispositions,
+            vec![
+                (0, 0, "unhandled"),
+                (0, 1, "held back"),
+                (0, 2, "processed")
+            ]
+        );
+        assert_eq!(resume, HashMap::from([(("commands".into(), 0), (0, true))]));
+        assert_eq!(punches.punched("ticket:1").await, None);
+        assert_eq!(punches.punched("ticket:2").await, Some(1));
+    }
+
+    #[actix::test]
+    async fn commands_dealt_with_before_a_rewind_are_not_processed_again() {
+        let punches = Punches::new(
+            EngineConfig::default().with_failure_policy(Always(FailureAction::DeadLetter)),
+        );
+        let failing = punch(0, 0, "ticket:1", Some(Failure::Storage));
+        let chunk = vec![
+            failing.clone(),
+            punch(0, 1, "ticket:2", None),
+            punch(0, 2, "ticket:1", None),
+            punch(1, 0, "ticket:3", None),
+        ];
+        // Partition 0 is consumed again from the failed punch, partition 1 goes on
+        let redelivered = vec![
+            failing,
+            punch(0, 1, "ticket:2", None),
+            punch(0, 2, "ticket:1", None),
+            punch(1, 1, "ticket:3", None),
+        ];
+
+        let (first, resumed) = punches.process(&chunk).await;
+        let (second, resumed_again) = punches.process(&redelivered).await;
+
+        assert_eq!(
+            first,
+            vec![
+                (0, 0, "unhandled"),
+                (0, 1, "processed"),
+                (0, 2, "held back"),
+                (1, 0, "processed")
+            ]
+        );
+        assert_eq!(
+            second,
+            vec![
+                (0, 0, "unhandled"),
+                (0, 1, "redelivered"),
+                (0, 2, "held back"),
+                (1, 1, "processed")
+            ]
+        );
+        let partitions = HashMap::from([
+            (("commands".into(), 0), (0, true)),
+            (("commands".into(), 1), (1, false)),
+        ]);
+        assert_eq!(resumed, partitions);
+        assert_eq!(resumed_again[&("commands".into(), 0)], (0, true));
+        assert_eq!(resumed_again[&("commands".into(), 1)], (2, false));
+        // Every punch dealt with is applied once
+        assert_eq!(punches.punched("ticket:1").await, None);
+        assert_eq!(punches.punched("ticket:2").await, Some(1));
+        assert_eq!(punches.punched("ticket:3").await, Some(2));
+
+        // Punches are forgotten once their partition resumes past them
+        let mut dealt = punches.dealt.lock().await.clone();
+        assert_eq!(dealt, HashSet::from([(("commands".into(), 0), 1)]));
+        remember_dealt(
+            &mut dealt,
+            [],
+            &HashMap::from([(("commands".into(), 0), (3, false))]),
+        );
+        assert!(dealt.is_empty());
+    }
+
+    // Retries storage errors once, skips torn tickets and dead-letters forged ones
+    #[derive(Debug, Default)]
+    struct ByClass {
+        retried: Arc<AtomicUsize>,
+    }
+
+    impl FailurePolicy for ByClass {
+        fn action(&self, error: &Error) -> FailureAction {
+            match error {
+                error if error.is_retryable() => {
+                    self.retried.fetch_add(1, Ordering::SeqCst);
+                    FailureAction::Retry {
+                        max: 1,
+                        backoff: Duration::ZERO,
+                    }
+                }
+                Error::InvalidState(_) => FailureAction::Skip,
+                _ => FailureAction::DeadLetter,
+            }
+        }
+    }
+
+    #[actix::test]
+    async fn every_class_of_error_goes_through_its_failure_action() {
+        let policy = ByClass::default();
+        let retried = policy.retried.clone();
+        let punches = Punches::new(EngineConfig::default().with_failure_policy(policy));
+        let messages = vec![
+            punch(0, 0, "ticket:1", Some(Failure::Storage)),
+            punch(1, 0, "ticket:2", Some(Failure::Torn)),
+            punch(2, 0, "ticket:3", Some(Failure::Forged)),
+            punch(3, 0, "ticket:4", None),
+        ];
+
+        let (dispositions, resume) = punches.process(&messages).await;
+
+        // Without a broker nothing can be dead-lettered, so the retried and the forged punch
+        // are consumed again rather than committed
+        assert_eq!(
+            dispositions,
+            vec![
+                (0, 0, "unhandled"),
+                (1, 0, "skipped"),
+                (2, 0, "unhandled"),
+                (3, 0, "processed")
+            ]
+        );
+        assert_eq!(retried.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            resume,
+            HashMap::from([
+                (("commands".into(), 0), (0, true)),
+                (("commands".into(), 1), (1, false)),
+                (("commands".into(), 2), (0, true)),
+                (("commands".into(), 3), (1, false)),
+            ])
+        );
+    }
+
+    #[actix::test]
+    async fn unknown_commands_go_through_the_unknown_command_handler() {
+        let shred = Record::command(
+            "ticket:1",
+            serde_json::json!({ "shredded": "ticket:1" }),
+            chrono::Utc::now(),
+            "Shred".into(),
+            0,
+        );
+        let messages = vec![message(0, 0, "ticket:1", &shred)];
+
+        for (policy, disposition, resumed) in [
+            (UnknownCommandPolicy::Skip, "skipped", (1, false)),
+            // Without a broker unknown commands can neither be dead-lettered nor requeued
+            (UnknownCommandPolicy::DeadLetter, "unhandled", (0, true)),
+            (UnknownCommandPolicy::Requeue, "unhandled", (0, true)),
+        ] {
+            let punches =
+                Punches::new(EngineConfig::default().with_unknown_command_handler(policy));
+
+            let (dispositions, resume) = punches.process(&messages).await;
+
+            assert_eq!(dispositions, vec![(0, 0, disposition)], "{:?}", policy);
+            assert_eq!(
+                resume,
+                HashMap::from([(("commands".into(), 0), resumed)]),
+                "{:?}",
+                policy
+            );
         }
-        None => Ok(()),
+    }
+
+    #[actix::test]
+    async fn enqueued_commands_are_forgotten_once_processed() {
+        let punches = Punches::new(EngineConfig::default().with_failure_policy(Always(
+            FailureAction::Retry {
+                max: 1,
+                backoff: Duration::ZERO,
+            },
+        )));
+        let position = |partition, offset| (("commands".to_string(), partition), offset);
+        let enqueued = futures::lock::Mutex::new(HashMap::from([
+            ("ticket:1".to_string(), position(0, 1)),
+            ("ticket:2".to_string(), position(1, 0)),
+            // Enqueued after the chunk was consumed
+            ("ticket:3".to_string(), position(0, 2)),
+            // Consumed by another instance
+            ("ticket:4".to_string(), position(2, 0)),
+        ]));
+        let messages = vec![
+            punch(0, 0, "ticket:1", None),
+            punch(0, 1, "ticket:1", None),
+            punch(1, 0, "ticket:2", Some(Failure::Storage)),
+        ];
+
+        let (_, resume) = punches.process(&messages).await;
+        forget_processed(&enqueued, &resume).await;
+
+        let mut remembered = enqueued.into_inner().into_keys().collect::<Vec<_>>();
+        remembered.sort();
+        assert_eq!(remembered, vec!["ticket:2", "ticket:3", "ticket:4"]);
+    }
+
+    #[test]
+    fn partitions_resume_after_their_own_failures() {
+        // Commands of a partition come grouped by entity, so out of offset order
+        let positions = vec![
+            (("commands", 0), 10, false),
+            (("commands", 1), 20, false),
+            (("commands", 0), 12, false),
+            (("commands", 0), 11, true),
+            (("commands", 1), 21, false),
+            (("commands", 2), 35, true),
+            (("commands", 2), 33, true),
+            (("commands", 2), 34, false),
+            (("other", 0), 5, false),
+        ];
+
+        let resume = resume_offsets(positions);
+
+        assert_eq!(
+            resume,
+            HashMap::from([
+                (("commands", 0), (11, true)),
+                (("commands", 1), (22, false)),
+                (("commands", 2), (33, true)),
+                (("other", 0), (6, false)),
+            ])
+        );
+    }
+
+    #[test]
+    fn bad_messages_do_not_abort_the_chunk() {
+        // Commands are (entity id, offset), an empty entity id cannot be found
+        let error = KafkaError::PartitionEOF(0);
+        let messages = vec![
+            Ok(("order:1", 0)),
+            Err(&error),
+            Ok(("", 2)),
+            Ok(("order:2", 3)),
+            Ok(("order:1", 4)),
+            Ok(("", 5)),
+        ];
+
+        let groups = group_by_entity(messages, |(entity_id, _)| match *entity_id {
+            "" => Err(Error::InvalidKey("no entity id".to_string())),
+            entity_id => Ok(entity_id.to_string()),
+        });
+
+        let groups = groups
+            .into_iter()
+            .map(|(key, msgs)| {
+                let offsets = msgs
+                    .into_iter()
+                    .map(|(_, offset)| offset)
+                    .collect::<Vec<_>>();
+                (key.ok(), offsets)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            groups,
+            vec![
+                (Some("order:1".to_string()), vec![0, 4]),
+                (None, vec![2]),
+                (Some("order:2".to_string()), vec![3]),
+                (None, vec![5]),
+            ]
+        );
     }
 }
@@ -0,0 +1,61 @@
+use super::Record;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+/// An event of an entity as it appears in its audit trail, see `Engine::audit_trail`.
+///
+/// The source and the correlation id are only known for events written with them, i.e.
+/// events of an engine with a service name, see `EngineConfig::with_service_name`, and
+/// events of enqueued commands respectively, and only if the adapter stores them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditEntry {
+    entity_id: String,
+    seq_nr: u64,
+    timestamp: DateTime<Utc>,
+    name: String,
+    source: Option<String>,
+    correlation_id: Option<Uuid>,
+}
+
+impl AuditEntry {
+    pub(crate) fn new<T>(record: &Record<T>, name: String) -> Self {
+        Self {
+            entity_id: record.entity_id().to_string(),
+            seq_nr: record.seq_nr() as u64,
+            timestamp: record.timestamp(),
+            name,
+            source: record.source().map(str::to_owned),
+            correlation_id: record.id(),
+        }
+    }
+
+    pub fn entity_id(&self) -> &str {
+        &self.entity_id
+    }
+
+    pub fn seq_nr(&self) -> u64 {
+        self.seq_nr
+    }
+
+    /// When the event was written.
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    /// The name of the event, see `Event::name`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The name of the service that wrote the event.
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    /// The correlation id of the command that produced the event, or else the id of the
+    /// command, shared by every event it produced.
+    pub fn correlation_id(&self) -> Option<Uuid> {
+        self.correlation_id
+    }
+}
@@ -1,6 +1,8 @@
 use super::event::Event;
 use crate::{
+    domain::ValidationContext,
     prelude::{Error, NonEmptyVec},
+    storage::Effect,
     Unit,
 };
 use futures::Future;
@@ -17,8 +19,28 @@ where
     /// function. If the command is invalid, an error should be returned.
     fn validate(&self, state: &State) -> Result<Unit, Error>;
 
+    /// Validate the command against its state and what else the engine shares with it, e.g.
+    /// a read model spanning every entity, see `ValidationContext`. This is what the engine
+    /// calls, by default it only calls `validate`.
+    ///
+    /// The read model may lag behind the commands being processed, see `ValidationContext`.
+    #[allow(unused_variables)]
+    fn validate_with(
+        &self,
+        state: &State,
+        ctx: &ValidationContext<'_>,
+    ) -> impl Future<Output = Result<Unit, Error>> {
+        async move { self.validate(state) }
+    }
+
     /// Yield a directive. Essentially, it should return an event or a list of events.
-    /// Event order is ensured and enforced by the engine.
+    ///
+    /// The events are applied and persisted in exactly the order they are yielded in: each
+    /// event is applied to the state the previous one produced, and gets a higher sequence
+    /// number than it, so replaying the entity from storage applies them in that same order.
+    /// With `EngineConfig::with_replay_check`, the engine reads the events back once they are
+    /// written and checks they are the ones it applied, in the same order, and that they
+    /// apply again on replay.
     fn directive(&self, state: &State) -> Result<NonEmptyVec<Box<Self::T>>, Error>;
 
     /// Return the entity id of the entity.
@@ -33,8 +55,28 @@ where
     ///
     /// - The entity id must be unique.
     /// - The entity id must be a string.
+    ///
+    /// Entities keyed by several fields can build their id with `CompositeKey`.
     fn entity_id(&self) -> String;
 
+    /// Whether the command creates its entity, i.e. whether it is only valid for an entity
+    /// that has no events yet.
+    ///
+    /// Only enforced with `EngineConfig::with_creation_checks`: creation commands then fail
+    /// with `Error::AlreadyExists` if the entity already has events, and every other
+    /// command fails with `Error::EntityNotFound` if it has none, before `validate` is called.
+    fn is_creation(&self) -> bool {
+        false
+    }
+
+    /// Whether the command is accepted by an entity deleted by a tombstone event, see
+    /// `Event::deletes`. Every other command sent to a deleted entity fails with
+    /// `Error::EntityDeleted` before `validate` is called. The events of a resurrecting
+    /// command are expected to restore the entity, see `Event::restores`.
+    fn resurrects(&self) -> bool {
+        false
+    }
+
     /// Performs side effects based on the application of the event.
     ///
     /// This method is not pure and may trigger side effects. It does not modify the state.
@@ -43,6 +85,49 @@ where
         async move { Ok(()) }
     }
 
+    /// Side effects of the command that must not be lost, e.g. sending an email. Unlike
+    /// `effects`, which run once the events are written and are lost if the engine stops
+    /// before they complete, durable effects are written together with the events, in the
+    /// same transaction, and run until they succeed by the `EffectHandler` registered with
+    /// `EngineConfig::with_effect_handler`.
+    ///
+    /// Only adapters supporting durable effects can write them, the command fails otherwise.
+    ///
+    /// # Examples
+    /// ```rust,ignore
+    /// fn durable_effects(&self, before: &User, after: &User) -> Result<Vec<Effect>, Error> {
+    ///     Ok(vec![Effect::new("send_welcome_email", &WelcomeEmail::to(&after.email))?])
+    /// }
+    /// ```
+    #[allow(unused_variables)]
+    fn durable_effects(&self, before: &State, after: &State) -> Result<Vec<Effect>, Error> {
+        Ok(Vec::new())
+    }
+
+    /// Build a rejection to return from `validate`. Unlike a free-text error, the code
+    /// of a rejection is preserved through to the caller, so it can be localized or
+    /// branched on.
+    ///
+    /// # Examples
+    /// ```rust,ignore
+    /// fn validate(&self, state: &State) -> Result<Unit, Error> {
+    ///     if state.is_occupied(self.x, self.y) {
+    ///         return Err(Self::reject("cell_occupied", "Cell is already occupied"));
+    ///     }
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    fn reject(code: impl Into<String>, message: impl Into<String>) -> Error
+    where
+        Self: Sized,
+    {
+        Error::Rejected {
+            code: code.into(),
+            message: message.into(),
+        }
+    }
+
     /// Return the name of the command.
     fn name(&self) -> String {
         std::any::type_name::<Self>().to_string()
@@ -0,0 +1,97 @@
+use crate::{
+    domain::{run_effect, ActorFailure, ActorKind, EffectHandler, EngineConfig},
+    storage::Adapter,
+};
+use actix::prelude::*;
+use std::sync::Arc;
+
+/// Runs the pending durable effects of commands, see `EngineConfig::with_effect_handler`.
+pub(crate) struct EffectRunner<Store>
+where
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+{
+    store: Store,
+    handler: Arc<dyn EffectHandler>,
+    config: EngineConfig,
+}
+
+impl<Store> EffectRunner<Store>
+where
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+{
+    pub fn new(store: Store, handler: Arc<dyn EffectHandler>, config: EngineConfig) -> Self {
+        Self {
+            store,
+            handler,
+            config,
+        }
+    }
+}
+
+impl<Store> Actor for EffectRunner<Store>
+where
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+{
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.config.relay_interval(), |act, ctx| {
+            let store = act.store.clone();
+            let handler = act.handler.clone();
+            let recorder = act.config.effect_recorder();
+            let batch_size = act.config.relay_batch_size();
+            let config = act.config.clone();
+
+            let future = async move {
+                let ran = store
+                    .run_effects(batch_size, |effects| async move {
+                        let mut done = Vec::with_capacity(effects.len());
+
+                        for effect in effects {
+                            let run = handler.run(&effect);
+                            match run_effect(
+                                recorder.as_ref(),
+                                effect.name(),
+                                effect.entity_id(),
+                                true,
+                                run,
+                            )
+                            .await
+                            {
+                                Ok(()) => done.push(effect.id()),
+                                Err(e) => tracing::error!(
+                                    "Could not run effect {} ({}) of entity {}, attempt {}: {}",
+                                    effect.id(),
+                                    effect.name(),
+                                    effect.entity_id(),
+                                    effect.attempts() + 1,
+                                    e
+                                ),
+                            }
+                        }
+
+                        done
+                    })
+                    .await;
+
+                if let Err(e) = ran {
+                    tracing::error!("Could not run the pending effects: {}", e);
+                    config.report(ActorFailure::new(ActorKind::EffectRunner, None, e));
+                }
+            };
+
+            // Wait for the effects to run before handling the next tick, so effects are not
+            // picked up twice by overlapping runs.
+            ctx.wait(future.into_actor(act));
+        });
+    }
+}
+
+impl<Store> Supervised for EffectRunner<Store>
+where
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+{
+    fn restarting(&mut self, _: &mut Self::Context) {
+        self.config.restarted(ActorKind::EffectRunner, None);
+    }
+}
@@ -1,55 +1,705 @@
-use super::{Event, Init};
+use super::{
+    is_deleted, AuditEntry, CommandEnvelope, EnqueueHandle, Event, EventMeta, Init, Record,
+    StateFactory,
+};
 use crate::{
     algebra::Command,
-    domain::{Enqueue, Error, GetState},
-    storage::Adapter,
+    domain::{
+        ActorKind, Consistency, Dispatch, EngineConfig, Enqueue, Error, GetState, GetStates,
+        Health, IsPaused, Mailbox, NonEmptyVec, Pause, RebuildSnapshot, Reload, Resume,
+        SequenceGenerator, Watch, FOR_EACH_CONCURRENCY, GROUP_ID,
+    },
+    storage::{check_all_gaps, check_gaps, verify_integrity, Adapter, IntegrityReport},
     Unit,
 };
-use actix::{Addr, Supervisor};
-use rdkafka::ClientConfig;
+use futures::{future::BoxFuture, Stream, StreamExt, TryStreamExt};
+use rdkafka::{consumer::StreamConsumer, producer::FutureProducer, ClientConfig};
 use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
 
 pub struct Engine<State, Store, Cmd, Evt>
 where
-    State: Debug + Send + Sync + Unpin + Clone + 'static + DeserializeOwned + Default,
+    State: Debug + Send + Sync + Unpin + Clone + 'static + DeserializeOwned + StateFactory,
     Store: Adapter + Clone + Send + Sync + 'static + Unpin,
     Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
     Cmd: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Command<State> + Serialize,
 {
-    addr: Addr<Init<State, Store, Cmd, Evt>>,
+    addr: Mailbox<Init<State, Store, Cmd, Evt>>,
+    store: Store,
+    sequence_generator: Arc<dyn SequenceGenerator>,
 }
 
 impl<State, Store, Cmd, Evt> Engine<State, Store, Cmd, Evt>
 where
-    State: Debug + Send + Sync + Unpin + Clone + 'static + DeserializeOwned + Default,
+    State: Debug + Send + Sync + Unpin + Clone + 'static + DeserializeOwned + StateFactory,
     Store: Adapter + Clone + Send + Sync + 'static + Unpin,
     Cmd: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Command<State> + Serialize,
     Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
 {
-    pub async fn enqueue(&self, command: Cmd) -> Result<Unit, Error> {
+    /// Enqueue a command. Returns once the command is accepted, i.e. the broker acknowledged
+    /// it was written to the command topic, with the acks the producer is configured with.
+    /// The returned handle can be awaited to get the outcome of applying it.
+    ///
+    /// With the `otel` feature, the trace context of the current span is propagated with
+    /// the command, see `Record::traceparent`.
+    pub async fn enqueue(&self, command: Cmd) -> Result<EnqueueHandle<State, Cmd::T>, Error> {
+        self.send_enqueue(Enqueue::from_command(command)).await
+    }
+
+    /// Enqueue a command carrying an idempotency key, e.g. the `Idempotency-Key` header of
+    /// the HTTP request it comes from. A command enqueued again with the same key for the
+    /// same entity within `EngineConfig::with_idempotency_window` is not processed again,
+    /// its handle resolves to the events of the first command instead.
+    ///
+    /// Keys are written together with the events of the command, through
+    /// `Adapter::write_with_idempotency_key`. The adapter must support them, the command
+    /// fails otherwise.
+    pub async fn enqueue_with_idempotency_key(
+        &self,
+        command: Cmd,
+        idempotency_key: impl Into<String>,
+    ) -> Result<EnqueueHandle<State, Cmd::T>, Error> {
+        self.send_enqueue(Enqueue::from_command(command).with_idempotency_key(idempotency_key))
+            .await
+    }
+
+    /// Start building a command carrying metadata, see `CommandEnvelope`.
+    ///
+    /// # Examples
+    /// ```rust,ignore
+    /// let handle = engine
+    ///     .command(command)
+    ///     .correlation_id(request_id)
+    ///     .idempotency_key(key)
+    ///     .expires_in(Duration::from_secs(30))
+    ///     .enqueue()
+    ///     .await?;
+    /// ```
+    pub fn command(&self, command: Cmd) -> CommandEnvelope<'_, State, Store, Cmd, Evt> {
+        CommandEnvelope::new(self, Enqueue::from_command(command))
+    }
+
+    pub(crate) async fn send_enqueue(
+        &self,
+        enqueue: Enqueue<Cmd, Evt, State>,
+    ) -> Result<EnqueueHandle<State, Cmd::T>, Error> {
+        #[cfg(feature = "otel")]
+        let enqueue = enqueue.with_traceparent(crate::domain::current_traceparent());
+
+        self.addr.send(enqueue).await.map_err(Error::Actix)?
+    }
+
+    /// Process a command right away, without going through the command topic, and return the
+    /// resulting state of its entity together with the events it produced.
+    ///
+    /// The command is processed by the actor of its entity, so it is applied in turn with the
+    /// commands consumed for the entity. As it is never written to the command topic, a
+    /// command that fails is not retried and the failure policy does not apply to it: the
+    /// error is returned instead.
+    ///
+    /// # Examples
+    /// ```rust,ignore
+    /// let (state, events) = engine.dispatch(command).await?;
+    /// ```
+    pub async fn dispatch(&self, command: Cmd) -> Result<(State, NonEmptyVec<Box<Cmd::T>>), Error> {
+        let outcome = self
+            .addr
+            .send(Dispatch::new(command))
+            .await
+            .map_err(Error::Actix)??;
+        let state = outcome.state().clone();
+
+        Ok((state, outcome.into_events()))
+    }
+
+    /// Return the current state of an entity, as of the last event written to storage.
+    ///
+    /// The state held in memory by the actor of the entity is returned if it is as recent as
+    /// storage, the state is folded from the latest snapshot and the events of the entity
+    /// otherwise, e.g. when this instance holds no actor for it. No actor is started to
+    /// answer. An entity without events is in its initial state, see `StateFactory`.
+    ///
+    /// Commands still on the command topic are not reflected, see `state_with_consistency`.
+    pub async fn state(&self, entity_id: &str) -> Result<State, Error> {
+        self.state_with_consistency(entity_id, Consistency::Eventual)
+            .await
+    }
+
+    /// Return the current state of an entity, with the given consistency. With
+    /// `Consistency::Strong`, the state reflects every command enqueued by this instance
+    /// before the call, without having to await their handles.
+    ///
+    /// # Examples
+    /// ```rust,ignore
+    /// engine.enqueue(command).await?;
+    /// let state = engine
+    ///     .state_with_consistency(&entity_id, Consistency::Strong)
+    ///     .await?;
+    /// ```
+    pub async fn state_with_consistency(
+        &self,
+        entity_id: &str,
+        consistency: Consistency,
+    ) -> Result<State, Error> {
         self.addr
-            .send(Enqueue::from_command(command))
+            .send(GetState::new(entity_id).with_consistency(consistency))
             .await
             .map_err(Error::Actix)?
     }
 
-    /// Return the current state of the domain. This state is always guaranteed to be the latest
-    /// state of the domain. Even if the actor has just been created, or restarted.
-    pub async fn state(&self, entity_id: &str) -> Result<State, Error> {
+    /// Watch the state of an entity, e.g. to drive a live view of it. The stream yields the
+    /// current state of the entity, then the state it moves to after every command applied
+    /// by this instance.
+    ///
+    /// A watcher falling more than `WATCH_CAPACITY` states behind skips to the most recent
+    /// ones. The stream goes on when the entity is evicted from memory, see
+    /// `EngineConfig::with_max_entities`.
+    ///
+    /// # Examples
+    /// ```rust,ignore
+    /// let mut states = engine.watch("game:42").await?;
+    /// while let Some(board) = states.next().await {
+    ///     render(&board);
+    /// }
+    /// ```
+    pub async fn watch(&self, entity_id: &str) -> Result<impl Stream<Item = State>, Error> {
+        let (state, receiver) = self
+            .addr
+            .send(Watch::new(entity_id))
+            .await
+            .map_err(Error::Actix)??;
+
+        let changes = futures::stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(state) => return Some((state, receiver)),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+
+        Ok(futures::stream::once(async { state }).chain(changes))
+    }
+
+    /// Return the current state of several entities at once. The events of all the entities
+    /// are read in a single replay, see `Adapter::replay_many`, instead of one per entity.
+    /// Entities without events are absent from the map rather than failing the call.
+    pub async fn states(&self, entity_ids: &[&str]) -> Result<HashMap<String, State>, Error> {
         self.addr
-            .send(GetState::new(entity_id))
+            .send(GetStates::new(entity_ids))
             .await
             .map_err(Error::Actix)?
     }
 
+    /// Replay the events of an entity into an arbitrary handler, e.g. for one-off data fixes
+    /// or analytics that do not warrant a projection.
+    ///
+    /// Events are streamed from storage and up to `FOR_EACH_CONCURRENCY` handlers run at
+    /// once, so the handler must not rely on the order of the events. Streaming stops on the
+    /// first error, of a handler or an event that cannot be read, which is returned. An
+    /// entity without events is not an error.
+    ///
+    /// # Examples
+    /// ```rust,ignore
+    /// engine
+    ///     .for_each_event("user-1", |record| {
+    ///         Box::pin(async move { audit(record.message()).await })
+    ///     })
+    ///     .await?;
+    /// ```
+    pub async fn for_each_event<F>(&self, entity_id: &str, f: F) -> Result<Unit, Error>
+    where
+        F: FnMut(Record<Evt>) -> BoxFuture<'static, Result<Unit, Error>>,
+    {
+        let highest_seq_nr = match self.store.read_highest_sequence_number(entity_id).await? {
+            Some(highest_seq_nr) => highest_seq_nr,
+            None => return Ok(()),
+        };
+
+        self.store
+            .try_replay::<Evt>(entity_id, 0, highest_seq_nr, highest_seq_nr + 1)
+            .await?
+            .try_for_each_concurrent(FOR_EACH_CONCURRENCY, f)
+            .await
+    }
+
+    /// Return the audit trail of an entity, i.e. every event of the entity in order, with
+    /// when it was written, by which service and for which command, see `AuditEntry`. An
+    /// entity without events has an empty trail.
+    ///
+    /// # Examples
+    /// ```rust,ignore
+    /// for entry in engine.audit_trail("account:42").await? {
+    ///     println!("{} {} by {:?}", entry.timestamp(), entry.name(), entry.source());
+    /// }
+    /// ```
+    pub async fn audit_trail(&self, entity_id: &str) -> Result<Vec<AuditEntry>, Error> {
+        let highest_seq_nr = match self.store.read_highest_sequence_number(entity_id).await? {
+            Some(highest_seq_nr) => highest_seq_nr,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut trail = self
+            .store
+            .try_replay::<Evt>(entity_id, 0, highest_seq_nr, highest_seq_nr + 1)
+            .await?
+            .map_ok(|record| AuditEntry::new(&record, record.message().name()))
+            .try_collect::<Vec<_>>()
+            .await?;
+        trail.sort_by_key(AuditEntry::seq_nr);
+
+        Ok(trail)
+    }
+
+    /// Return the state of an entity as of a sequence number, i.e. folded from its events up
+    /// to and including `seq_nr`, e.g. to compare two versions of the entity. Version 0 is the
+    /// initial state. Fails with `Error::VersionNotFound` if the entity has not reached it.
+    ///
+    /// # Examples
+    /// ```rust,ignore
+    /// let before = engine.state_at_version("account:42", 7).await?;
+    /// let after = engine.state_at_version("account:42", 8).await?;
+    /// ```
+    pub async fn state_at_version(&self, entity_id: &str, seq_nr: u64) -> Result<State, Error> {
+        let highest = self
+            .store
+            .read_highest_sequence_number(entity_id)
+            .await?
+            .unwrap_or_default();
+        if seq_nr > highest {
+            return Err(Error::VersionNotFound {
+                entity_id: entity_id.to_string(),
+                seq_nr,
+                highest,
+            });
+        }
+
+        self.store
+            .try_replay::<Evt>(entity_id, 0, seq_nr, seq_nr + 1)
+            .await?
+            .try_fold(State::initial(entity_id), |state, record| async move {
+                let meta = EventMeta::from(&record);
+                record
+                    .message()
+                    .apply_with_meta(&state, &meta)
+                    .ok_or_else(|| {
+                        Error::InvalidState(format!(
+                            "Event {:?} could not be applied to state {:?} of entity {}",
+                            record.message(),
+                            state,
+                            record.entity_id()
+                        ))
+                    })
+            })
+            .await
+    }
+
+    /// Walk the hash chain of an entity and report the first event that was altered,
+    /// removed or reordered in storage since it was written, see `verify_integrity`.
+    ///
+    /// # Examples
+    /// ```rust,ignore
+    /// let report = engine.verify_integrity("account:42").await?;
+    /// if let Some(broken) = report.broken() {
+    ///     tracing::error!("History of account:42 was tampered with: {:?}", broken);
+    /// }
+    /// ```
+    pub async fn verify_integrity(&self, entity_id: &str) -> Result<IntegrityReport, Error> {
+        verify_integrity::<Evt, Store>(&self.store, entity_id).await
+    }
+
+    /// Return the sequence numbers missing from the events of an entity, i.e. events that
+    /// were lost, see `check_gaps`. Replays can be checked as they happen with
+    /// `EngineConfig::with_gap_check`.
+    ///
+    /// # Examples
+    /// ```rust,ignore
+    /// let missing = engine.check_gaps("account:42").await?;
+    /// if !missing.is_empty() {
+    ///     tracing::error!("Events {:?} of account:42 were lost", missing);
+    /// }
+    /// ```
+    pub async fn check_gaps(&self, entity_id: &str) -> Result<Vec<u64>, Error> {
+        check_gaps::<Evt, Store>(&self.store, entity_id, self.sequence_generator.as_ref()).await
+    }
+
+    /// Return the sequence numbers missing from the events of every entity with gaps in the
+    /// store, see `check_all_gaps`. This reads every event of the store.
+    pub async fn check_all_gaps(&self) -> Result<BTreeMap<String, Vec<u64>>, Error> {
+        check_all_gaps::<Evt, Store>(&self.store, self.sequence_generator.as_ref()).await
+    }
+
+    /// Rebuild the snapshot of an entity from scratch, ignoring any existing snapshot. The
+    /// full event history is replayed and a fresh snapshot is written at the highest
+    /// sequence number, which is returned together with the state.
+    ///
+    /// This is safe to run while commands are being processed, as snapshots are only a
+    /// cache of the state folded from the events.
+    pub async fn rebuild_snapshot(&self, entity_id: &str) -> Result<(u64, State), Error>
+    where
+        State: Serialize,
+    {
+        self.addr
+            .send(RebuildSnapshot::new(entity_id))
+            .await
+            .map_err(Error::Actix)?
+    }
+
+    /// Write a snapshot of an entity at its highest sequence number, e.g. ahead of
+    /// `EngineConfig::with_snapshot_every` for an entity that is read often. Unlike
+    /// `rebuild_snapshot`, only the events following the latest snapshot are replayed.
+    /// Returns the sequence number the snapshot was written at and the state.
+    pub async fn snapshot(&self, entity_id: &str) -> Result<(u64, State), Error>
+    where
+        State: Serialize,
+    {
+        self.addr
+            .send(RebuildSnapshot::from_latest(entity_id))
+            .await
+            .map_err(Error::Actix)?
+    }
+
+    /// Discard the state the engine holds in memory for an entity and rehydrate it from its
+    /// latest snapshot and event history, e.g. when the cached state is suspected to have drifted from
+    /// storage. Returns the highest sequence number of the entity and the reloaded state.
+    ///
+    /// Commands of the entity wait for the reload to complete.
+    pub async fn reload_entity(&self, entity_id: &str) -> Result<(u64, State), Error>
+    where
+        State: Serialize,
+    {
+        self.addr
+            .send(Reload::new(entity_id))
+            .await
+            .map_err(Error::Actix)?
+    }
+
+    /// Bring the schema of the storage up to date, see `Adapter::migrate`. To migrate before
+    /// the first command is processed, use `EngineConfig::with_ensure_schema` instead.
+    pub async fn migrate(&self) -> Result<Unit, Error> {
+        self.store.migrate().await
+    }
+
+    /// Whether an entity is deleted by a tombstone event, see `Event::deletes`. The answer is
+    /// folded from the event history of the entity in storage.
+    pub async fn is_deleted(&self, entity_id: &str) -> Result<bool, Error> {
+        is_deleted::<State, Store, Evt>(&self.store, entity_id).await
+    }
+
+    /// Stop consuming commands without stopping the engine. Commands that were already
+    /// consumed are still processed, commands enqueued while paused stay on the command
+    /// topic until `resume` is called.
+    ///
+    /// The partitions of the Kafka consumer are paused while it keeps polling, so it stays in
+    /// its consumer group however long consuming is paused.
+    pub async fn pause(&self) -> Result<Unit, Error> {
+        self.addr.send(Pause).await.map_err(Error::Actix)
+    }
+
+    /// Resume consuming commands after `pause`.
+    pub async fn resume(&self) -> Result<Unit, Error> {
+        self.addr.send(Resume).await.map_err(Error::Actix)
+    }
+
+    /// Whether consuming commands is paused.
+    pub async fn is_paused(&self) -> Result<bool, Error> {
+        self.addr.send(IsPaused).await.map_err(Error::Actix)
+    }
+
+    /// Check the health of the storage backing the engine, see `Adapter::health`.
+    pub async fn health(&self) -> Result<Unit, Error> {
+        self.addr.send(Health).await.map_err(Error::Actix)?
+    }
+
+    /// The JSON Schema of the records on the command topic, in the `CommandFormat::Enveloped`
+    /// format. The command type has to derive `schemars::JsonSchema`.
+    #[cfg(feature = "schema")]
+    pub fn command_schema() -> schemars::schema::RootSchema
+    where
+        Cmd: schemars::JsonSchema,
+    {
+        schemars::schema_for!(Record<Cmd>)
+    }
+
+    /// The JSON Schema of the records on the event topic. The event type has to derive
+    /// `schemars::JsonSchema`.
+    #[cfg(feature = "schema")]
+    pub fn event_schema() -> schemars::schema::RootSchema
+    where
+        Cmd::T: schemars::JsonSchema,
+    {
+        schemars::schema_for!(Record<Cmd::T>)
+    }
+
     pub async fn start(
         configuration: ClientConfig,
         store: Store,
     ) -> Result<Engine<State, Store, Cmd, Evt>, Error> {
-        let addr = Init::empty(configuration, store).await?;
-        let supervisor = Supervisor::start(|_| addr);
+        Self::start_with_config(configuration, store, EngineConfig::default()).await
+    }
+
+    /// Start the engine with an explicit `EngineConfig`, see `EngineConfig` for the
+    /// available options.
+    ///
+    /// The configuration is validated up front: the Kafka client must have
+    /// `bootstrap.servers` set, the storage must be healthy, see `Adapter::health`, and the
+    /// `EngineConfig` must be sane. Fails with an `Error::InvalidConfiguration` listing every
+    /// problem found.
+    ///
+    /// Must be called from within a running actix system, unless the actors are started on
+    /// an arbiter of their own, see `EngineConfig::with_arbiter`.
+    pub async fn start_with_config(
+        configuration: ClientConfig,
+        store: Store,
+        config: EngineConfig,
+    ) -> Result<Engine<State, Store, Cmd, Evt>, Error> {
+        // Report misconfigurations and an unavailable storage up front rather than on the
+        // first command
+        let mut problems = Vec::new();
+
+        if configuration
+            .get("bootstrap.servers")
+            .is_none_or(|servers| servers.trim().is_empty())
+        {
+            problems.push("`bootstrap.servers` is not set".to_string());
+        }
+        if let Some(group_id) = configuration.get("group.id") {
+            if group_id != GROUP_ID {
+                problems.push(format!(
+                    "`group.id` is set to {}, but commands are always consumed as group {}",
+                    group_id, GROUP_ID
+                ));
+            }
+        }
+        prepare::<State, Store>(&store, &config, problems).await?;
+
+        let addr = Init::empty(configuration, store.clone(), config.clone()).await?;
+        let supervisor = config.start(addr);
+
+        Ok(Self {
+            addr: Mailbox::new(supervisor, ActorKind::Init, &config),
+            store,
+            sequence_generator: config.sequence_generator(),
+        })
+    }
+
+    /// Start the engine with Kafka clients built by the caller, e.g. to install a custom
+    /// `ConsumerContext`, partitioner or statistics callback, see
+    /// `start_with_clients_and_config`.
+    pub async fn start_with_clients(
+        consumer: StreamConsumer,
+        producer: FutureProducer,
+        store: Store,
+    ) -> Result<Engine<State, Store, Cmd, Evt>, Error> {
+        Self::start_with_clients_and_config(consumer, producer, store, EngineConfig::default())
+            .await
+    }
+
+    /// Start the engine with Kafka clients built by the caller and an explicit
+    /// `EngineConfig`.
+    ///
+    /// The engine uses the clients as they are, so the settings it otherwise applies are up
+    /// to the caller: the consumer must be in group `GROUP_ID`, with `enable.auto.commit` set
+    /// to false and, to consume the topics from the start, `auto.offset.reset` set to
+    /// `earliest`. The consumer is subscribed by the engine. `EngineConfig::with_max_poll_interval`
+    /// and `EngineConfig::with_session_timeout` are not applied to the consumer, set
+    /// `max.poll.interval.ms` on it and the same interval on the config, as the engine keeps
+    /// the consumer in its group based on it. The storage and the `EngineConfig` are
+    /// validated as in `start_with_config`.
+    pub async fn start_with_clients_and_config(
+        consumer: StreamConsumer,
+        producer: FutureProducer,
+        store: Store,
+        config: EngineConfig,
+    ) -> Result<Engine<State, Store, Cmd, Evt>, Error> {
+        prepare::<State, Store>(&store, &config, Vec::new()).await?;
+
+        let addr = Init::with_clients(consumer, producer, store.clone(), config.clone());
+        let supervisor = config.start(addr);
+
+        Ok(Self {
+            addr: Mailbox::new(supervisor, ActorKind::Init, &config),
+            store,
+            sequence_generator: config.sequence_generator(),
+        })
+    }
+}
+
+/// Validate the `EngineConfig` against the state of the engine and the health of the storage,
+/// failing with every problem found on top of the given ones, and migrate the storage if the
+/// config asks for it.
+async fn prepare<State, Store>(
+    store: &Store,
+    config: &EngineConfig,
+    mut problems: Vec<String>,
+) -> Result<Unit, Error>
+where
+    State: 'static,
+    Store: Adapter,
+{
+    problems.extend(config.problems());
+    problems.extend(config.state_problems::<State>());
+    if let Err(e) = store.health().await {
+        problems.push(format!("the storage is not healthy: {}", e));
+    }
+
+    if !problems.is_empty() {
+        return Err(Error::InvalidConfiguration(problems.join("; ")));
+    }
+
+    if config.ensure_schema() {
+        store.migrate().await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        algebra::Record,
+        domain::{NonEmptyVec, PublishMode},
+        storage::MemoryAdapter,
+        Unit,
+    };
+    use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+    use serde::Deserialize;
+
+    const ACCOUNT: &str = "account:1";
+
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    struct Account {
+        balance: u64,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Deposited(u64);
+
+    impl Event<Account> for Deposited {
+        fn apply(&self, state: &Account) -> Option<Account> {
+            Some(Account {
+                balance: state.balance + self.0,
+            })
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Deposit(u64);
+
+    impl Command<Account> for Deposit {
+        type T = Deposited;
+
+        fn validate(&self, _: &Account) -> Result<Unit, Error> {
+            Ok(())
+        }
+
+        fn directive(&self, _: &Account) -> Result<NonEmptyVec<Box<Deposited>>, Error> {
+            Ok(NonEmptyVec::new(vec![Box::new(Deposited(self.0))])?)
+        }
+
+        fn entity_id(&self) -> String {
+            ACCOUNT.to_string()
+        }
+    }
+
+    type Accounts = Engine<Account, MemoryAdapter, Deposit, Deposited>;
+
+    // Nothing is enqueued, so the engine never reaches a broker
+    async fn start(store: &MemoryAdapter, config: EngineConfig) -> Accounts {
+        let mut configuration = ClientConfig::new();
+        configuration
+            .set("bootstrap.servers", "localhost:9")
+            .set("log_level", "0");
+
+        Engine::start_with_config(configuration, store.clone(), config)
+            .await
+            .expect("an engine")
+    }
+
+    #[actix::test]
+    async fn undelivered_commands_fail_their_enqueue() {
+        // Nothing listens on the broker, so the command times out before it is delivered
+        let mut configuration = ClientConfig::new();
+        configuration
+            .set("bootstrap.servers", "localhost:9")
+            .set("log_level", "0")
+            .set("message.timeout.ms", "100");
+        let engine: Accounts =
+            Engine::start_with_config(configuration, MemoryAdapter::new(), EngineConfig::default())
+                .await
+                .expect("an engine");
+
+        let enqueued = engine.enqueue(Deposit(10)).await;
+
+        assert!(matches!(
+            enqueued,
+            Err(Error::Kafka(KafkaError::MessageProduction(
+                RDKafkaErrorCode::MessageTimedOut
+            )))
+        ));
+    }
+
+    #[actix::test]
+    async fn rebuild_snapshot_replaces_a_corrupted_snapshot() {
+        let store = MemoryAdapter::new();
+        let deposits = [Deposited(10), Deposited(20), Deposited(30)];
+        let records = deposits
+            .iter()
+            .zip(1..)
+            .map(|(event, seq_nr)| {
+                Record::event(ACCOUNT.to_string(), seq_nr, event, chrono::Utc::now())
+            })
+            .collect();
+        store.write(records).await.unwrap();
+        store
+            .write_snapshot(ACCOUNT, 3, 0, &Account { balance: 1_000 }, false)
+            .await
+            .unwrap();
+        let engine = start(&store, EngineConfig::default()).await;
+
+        let rebuilt = engine.rebuild_snapshot(ACCOUNT).await.unwrap();
+
+        assert_eq!(rebuilt, (3, Account { balance: 60 }));
+        assert_eq!(
+            store
+                .read_latest_snapshot(ACCOUNT)
+                .await
+                .unwrap()
+                .map(|snapshot| (snapshot.seq_nr(), snapshot.state().unwrap())),
+            Some((3, Account { balance: 60 }))
+        );
+    }
+
+    #[actix::test]
+    async fn dispatched_commands_return_the_state_and_their_events() {
+        let store = MemoryAdapter::new();
+        // Nothing relays the outbox without a broker, so nothing is published
+        let engine = start(
+            &store,
+            EngineConfig::default().with_publish_mode(PublishMode::Outbox),
+        )
+        .await;
+
+        engine.dispatch(Deposit(10)).await.unwrap();
+        let (state, events) = engine.dispatch(Deposit(20)).await.unwrap();
 
-        Ok(Self { addr: supervisor })
+        assert_eq!(state, Account { balance: 30 });
+        assert_eq!(
+            events.iter().map(|event| event.0).collect::<Vec<_>>(),
+            vec![20]
+        );
+        assert_eq!(
+            store.read_highest_sequence_number(ACCOUNT).await.unwrap(),
+            Some(2)
+        );
+        assert_eq!(
+            engine.state(ACCOUNT).await.unwrap(),
+            Account { balance: 30 }
+        );
     }
 }
@@ -0,0 +1,71 @@
+use super::{Command, Engine, EnqueueHandle, Event, StateFactory};
+use crate::{
+    domain::{Enqueue, Error},
+    storage::Adapter,
+};
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{fmt::Debug, time::Duration};
+use uuid::Uuid;
+
+/// A command together with the metadata it is enqueued with, see `Engine::command`. Every
+/// piece of metadata is optional, `Engine::enqueue` enqueues a command without any.
+pub struct CommandEnvelope<'a, State, Store, Cmd, Evt>
+where
+    State: Debug + Send + Sync + Unpin + Clone + 'static + DeserializeOwned + StateFactory,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Cmd: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Command<State> + Serialize,
+    Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
+{
+    engine: &'a Engine<State, Store, Cmd, Evt>,
+    enqueue: Enqueue<Cmd, Evt, State>,
+}
+
+impl<'a, State, Store, Cmd, Evt> CommandEnvelope<'a, State, Store, Cmd, Evt>
+where
+    State: Debug + Send + Sync + Unpin + Clone + 'static + DeserializeOwned + StateFactory,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Cmd: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Command<State> + Serialize,
+    Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
+{
+    pub(crate) fn new(
+        engine: &'a Engine<State, Store, Cmd, Evt>,
+        enqueue: Enqueue<Cmd, Evt, State>,
+    ) -> Self {
+        Self { engine, enqueue }
+    }
+
+    /// Correlate the command with the request it comes from. The events it produces carry
+    /// the correlation id instead of the id of the command, see `AuditEntry::correlation_id`.
+    pub fn correlation_id(mut self, correlation_id: Uuid) -> Self {
+        self.enqueue = self.enqueue.with_correlation_id(correlation_id);
+        self
+    }
+
+    /// Deduplicate retries of the command, see `Engine::enqueue_with_idempotency_key`.
+    pub fn idempotency_key(mut self, idempotency_key: impl Into<String>) -> Self {
+        self.enqueue = self.enqueue.with_idempotency_key(idempotency_key);
+        self
+    }
+
+    /// Reject the command with `Error::Expired` if it is not processed within `ttl` of now,
+    /// e.g. when the request it comes from has timed out by then.
+    pub fn expires_in(self, ttl: Duration) -> Self {
+        let expires_at = chrono::Duration::from_std(ttl)
+            .ok()
+            .and_then(|ttl| Utc::now().checked_add_signed(ttl))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+        self.expires_at(expires_at)
+    }
+
+    /// Reject the command with `Error::Expired` if it is not processed by `expires_at`.
+    pub fn expires_at(mut self, expires_at: DateTime<Utc>) -> Self {
+        self.enqueue = self.enqueue.with_expires_at(expires_at);
+        self
+    }
+
+    /// Enqueue the command, see `Engine::enqueue`.
+    pub async fn enqueue(self) -> Result<EnqueueHandle<State, Cmd::T>, Error> {
+        self.engine.send_enqueue(self.enqueue).await
+    }
+}
@@ -1,5 +1,42 @@
+use chrono::{DateTime, Utc};
 use std::fmt::Debug;
 
+/// Metadata of an event being applied, taken from its `Record`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventMeta {
+    entity_id: String,
+    seq_nr: i64,
+    timestamp: DateTime<Utc>,
+}
+
+impl EventMeta {
+    pub fn new(entity_id: &str, seq_nr: i64, timestamp: DateTime<Utc>) -> Self {
+        Self {
+            entity_id: entity_id.to_string(),
+            seq_nr,
+            timestamp,
+        }
+    }
+
+    pub fn entity_id(&self) -> &str {
+        &self.entity_id
+    }
+
+    pub fn seq_nr(&self) -> i64 {
+        self.seq_nr
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+}
+
+impl<T> From<&super::Record<T>> for EventMeta {
+    fn from(record: &super::Record<T>) -> Self {
+        Self::new(record.entity_id(), record.seq_nr(), record.timestamp())
+    }
+}
+
 pub trait Event<State>: Sync + Send
 where
     State: Debug + Clone + Send + Sync + 'static,
@@ -8,4 +45,47 @@ where
     ///
     /// This method should be a pure function, ensuring determinism and idempotence.
     fn apply(&self, state: &State) -> Option<State>;
+
+    /// Applies the event to the state, with access to the metadata of the event, e.g. to
+    /// keep a version counter or the time of the last update in the state.
+    ///
+    /// The engine always applies events through this method, which defaults to `apply`.
+    /// Events that need their metadata override it.
+    fn apply_with_meta(&self, state: &State, meta: &EventMeta) -> Option<State> {
+        let _ = meta;
+        self.apply(state)
+    }
+
+    /// Whether the event is a tombstone, i.e. whether it soft-deletes its entity. The
+    /// history of a deleted entity is kept, but every command sent to it fails with
+    /// `Error::EntityDeleted`, unless it resurrects the entity, see `Command::resurrects`.
+    fn deletes(&self) -> bool {
+        false
+    }
+
+    /// Whether the event undoes the deletion of its entity, see `deletes`.
+    fn restores(&self) -> bool {
+        false
+    }
+
+    /// Return the name of the event, e.g. for the audit trail of its entity, see
+    /// `Engine::audit_trail`.
+    fn name(&self) -> String {
+        std::any::type_name::<Self>().to_string()
+    }
+}
+
+/// Whether an entity is deleted after the event, given whether it was deleted before.
+pub(crate) fn tombstoned<State, Evt>(deleted: bool, event: &Evt) -> bool
+where
+    State: Debug + Clone + Send + Sync + 'static,
+    Evt: Event<State> + ?Sized,
+{
+    if event.deletes() {
+        true
+    } else if event.restores() {
+        false
+    } else {
+        deleted
+    }
 }
@@ -0,0 +1,81 @@
+use super::CommandOutcome;
+use crate::domain::Error;
+use futures::{channel::oneshot, lock::Mutex, Future};
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use uuid::Uuid;
+
+type Outcome<State, Evt> = Result<CommandOutcome<State, Evt>, Error>;
+
+/// Commands enqueued by this process that are still waiting to be processed, keyed by
+/// the id of the command record.
+pub(crate) type Pending<State, Evt> =
+    Arc<Mutex<HashMap<Uuid, oneshot::Sender<Outcome<State, Evt>>>>>;
+
+/// Handle returned by `Engine::enqueue`.
+///
+/// Awaiting the handle resolves once the command has been consumed from Kafka and
+/// processed by the entity it targets, yielding its `CommandOutcome` or the error that
+/// rejected it. Dropping the handle does not cancel the command, it just means
+/// nobody is interested in its outcome.
+///
+/// Only the first processing attempt is reported. If a retryable error causes the
+/// command to be consumed again, the retry is not observed through the handle.
+pub struct EnqueueHandle<State, Evt> {
+    id: Uuid,
+    receiver: oneshot::Receiver<Outcome<State, Evt>>,
+}
+
+impl<State, Evt> EnqueueHandle<State, Evt> {
+    /// Register a new pending command and return the handle awaiting its outcome.
+    pub(crate) async fn register(pending: &Pending<State, Evt>) -> Self {
+        let id = Uuid::new_v4();
+        let (sender, receiver) = oneshot::channel();
+
+        let mut pending = pending.lock().await;
+        // Handles that have been dropped will never be awaited, so there is no point
+        // in keeping their senders around.
+        pending.retain(|_, sender| !sender.is_canceled());
+        pending.insert(id, sender);
+
+        Self { id, receiver }
+    }
+
+    /// Resolve the pending command with the given id, if it was enqueued by this process.
+    pub(crate) async fn resolve(
+        pending: &Pending<State, Evt>,
+        id: &Uuid,
+        outcome: Outcome<State, Evt>,
+    ) {
+        if let Some(sender) = pending.lock().await.remove(id) {
+            // The receiving end may have been dropped, which is fine.
+            let _ = sender.send(outcome);
+        }
+    }
+
+    /// The id of the command record this handle is waiting on.
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+}
+
+impl<State, Evt> Future for EnqueueHandle<State, Evt> {
+    type Output = Outcome<State, Evt>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let id = self.id;
+
+        Pin::new(&mut self.receiver).poll(cx).map(|outcome| {
+            outcome.unwrap_or_else(|_| {
+                Err(Error::Error(format!(
+                    "Command {} was dropped before it was processed",
+                    id
+                )))
+            })
+        })
+    }
+}
@@ -1,20 +1,37 @@
-use super::{Aggregate, Event};
+use super::{
+    tombstoned, Aggregate, CommandOutcome, EffectRunner, EnqueueHandle, Event, EventMeta, Pending,
+    Position, Relay, StateFactory,
+};
 use crate::{
     algebra::{Command, Record},
-    domain::{Enqueue, Error, GetState, BATCH_BACKPRESSURE, COMMAND_TOPIC},
-    storage::Adapter,
+    domain::{
+        ActorKind, Consistency, Dispatch, EngineConfig, Enqueue, Error, GetState, GetStates,
+        Health, IsPaused, Live, Pacer, Pause, PublishMode, RebuildSnapshot, Reload, ReplayThrottle,
+        Resume, Watch, COMMAND_TOPIC, CONSISTENCY_BACKOFF, MAX_POLL_INTERVAL, POLL_TIMEOUT,
+        REPLAY_CHUNK_SIZE,
+    },
+    storage::{Adapter, Gaps},
     Unit,
 };
-use actix::{
-    Actor, AsyncContext, Context, Handler, ResponseFuture, Supervised, Supervisor, WrapFuture,
-};
-use futures::{lock::Mutex, StreamExt};
+use actix::{Actor, Context, Handler, Recipient, ResponseFuture, Supervised};
+use futures::{lock::Mutex, StreamExt, TryStreamExt};
 use rdkafka::{
-    producer::{DeliveryFuture, FutureProducer, FutureRecord},
-    ClientConfig,
+    consumer::{Consumer, StreamConsumer},
+    error::KafkaError,
+    producer::{FutureProducer, FutureRecord},
+    ClientConfig, Offset, TopicPartitionList,
 };
 use serde::{de::DeserializeOwned, Serialize};
-use std::{fmt::Debug, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::{broadcast, Semaphore};
 
 pub struct Init<State, Store, Cmd, Evt>
 where
@@ -25,14 +42,28 @@ where
 {
     store: Store,
     producer: Arc<FutureProducer>,
-    batch: Arc<Mutex<Vec<DeliveryFuture>>>,
     seq_nr: Arc<Mutex<i64>>,
+    pending: Pending<State, Cmd::T>,
+    config: EngineConfig,
+    paused: Arc<AtomicBool>,
+    // The consumer of the aggregate, to read the offsets its group committed
+    consumer: Arc<StreamConsumer>,
+    // The topic, partition and offset of the last command this instance enqueued per entity,
+    // until it is processed, shared with the aggregate
+    enqueued: Arc<Mutex<HashMap<String, Position>>>,
+    // Dispatched commands and reloads are handled by the aggregate, which knows the actors of
+    // the entities
+    dispatch: Recipient<Dispatch<State, Cmd, Cmd::T>>,
+    reload: Recipient<Reload<State>>,
+    watch: Recipient<Watch<State>>,
+    live: Recipient<Live<State>>,
+    rebuilds: Option<Arc<Semaphore>>,
     _marker: std::marker::PhantomData<(State, Cmd, Evt)>,
 }
 
 impl<State, Store, Cmd, Evt> Init<State, Store, Cmd, Evt>
 where
-    State: Debug + Send + Sync + Unpin + Clone + Default + 'static + DeserializeOwned,
+    State: Debug + Send + Sync + Unpin + Clone + StateFactory + 'static + DeserializeOwned,
     Store: Adapter + Clone + Send + Sync + 'static + Unpin,
     Cmd: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Command<State> + Serialize,
     Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
@@ -40,138 +71,299 @@ where
     pub(crate) async fn empty(
         configuration: ClientConfig,
         store: Store,
+        config: EngineConfig,
     ) -> Result<Init<State, Store, Cmd, Evt>, Error> {
-        let producer: FutureProducer = configuration.create().map_err(Error::Kafka)?;
+        let producer: Arc<FutureProducer> = Arc::new(configuration.create().map_err(Error::Kafka)?);
+        let pending: Pending<State, Cmd::T> = Default::default();
+        let paused = Arc::new(AtomicBool::new(false));
+
+        let aggregate = Aggregate::<State, Store, Cmd, Evt>::new(
+            configuration.clone(),
+            store.clone(),
+            producer.clone(),
+            pending.clone(),
+            config.clone(),
+            paused.clone(),
+        )?;
+
+        Ok(Self::assemble(
+            aggregate, producer, pending, paused, store, config,
+        ))
+    }
+
+    /// Create the actor around clients built by the caller, see `Engine::start_with_clients`.
+    pub(crate) fn with_clients(
+        consumer: StreamConsumer,
+        producer: FutureProducer,
+        store: Store,
+        config: EngineConfig,
+    ) -> Init<State, Store, Cmd, Evt> {
+        let producer = Arc::new(producer);
+        let pending: Pending<State, Cmd::T> = Default::default();
+        let paused = Arc::new(AtomicBool::new(false));
 
-        let aggregate =
-            Aggregate::<State, Store, Cmd, Evt>::new(configuration.clone(), store.clone())?;
-        Supervisor::start(|_| aggregate);
+        let aggregate = Aggregate::<State, Store, Cmd, Evt>::with_consumer(
+            Arc::new(consumer),
+            config
+                .max_poll_interval()
+                .unwrap_or(Duration::from_secs(MAX_POLL_INTERVAL)),
+            store.clone(),
+            producer.clone(),
+            pending.clone(),
+            config.clone(),
+            paused.clone(),
+        );
 
-        Ok(Self {
+        Self::assemble(aggregate, producer, pending, paused, store, config)
+    }
+
+    // Start the aggregate, the outbox relay if events are published through the outbox, and
+    // the effect runner if effects are handled
+    fn assemble(
+        aggregate: Aggregate<State, Store, Cmd, Evt>,
+        producer: Arc<FutureProducer>,
+        pending: Pending<State, Cmd::T>,
+        paused: Arc<AtomicBool>,
+        store: Store,
+        config: EngineConfig,
+    ) -> Init<State, Store, Cmd, Evt> {
+        let consumer = aggregate.consumer();
+        let enqueued = aggregate.enqueued();
+        let aggregate = config.start(aggregate);
+        let dispatch = aggregate.clone().recipient();
+        let reload = aggregate.clone().recipient();
+        let watch = aggregate.clone().recipient();
+        let live = aggregate.recipient();
+
+        if config.publish_mode() == PublishMode::Outbox {
+            let relay = Relay::new(store.clone(), producer.clone(), config.clone());
+            config.start(relay);
+        }
+
+        if let Some(handler) = config.effect_handler() {
+            let runner = EffectRunner::new(store.clone(), handler, config.clone());
+            config.start(runner);
+        }
+
+        Self {
             store: store.clone(),
-            producer: Arc::new(producer),
-            batch: Arc::new(Mutex::new(Vec::new())),
+            producer,
             seq_nr: Arc::new(Mutex::new(0)),
+            pending,
+            paused,
+            consumer,
+            enqueued,
+            dispatch,
+            reload,
+            watch,
+            live,
+            rebuilds: config
+                .replay_throttle()
+                .max_concurrent_entities()
+                .map(|max| Arc::new(Semaphore::new(max))),
+            config,
             _marker: std::marker::PhantomData,
-        })
+        }
     }
 }
 
-// Ensure that Kafka is running, that the topic exists and that we can produce to it.
-// async fn ensure(producer: &FutureProducer) -> Result<Unit, Error> {
-//     let record = FutureRecord::to(COMMAND_TOPIC)
-//         .payload("droppable")
-//         .key(&[0]);
-//
-//     let res = producer
-//         .send(record, Duration::from_secs(1))
-//         .await
-//         .map_err(|(e, _)| Error::Kafka(e));
-//
-//     Ok(())
-// }
-
 impl<State, Store, Cmd, Evt> Actor for Init<State, Store, Cmd, Evt>
 where
-    State: Debug + Send + Sync + Unpin + Clone + Default + 'static + DeserializeOwned,
+    State: Debug + Send + Sync + Unpin + Clone + StateFactory + 'static + DeserializeOwned,
     Store: Adapter + Clone + Send + Sync + 'static + Unpin,
     Cmd: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Command<State> + Serialize,
     Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
 {
     type Context = Context<Self>;
-
-    fn started(&mut self, ctx: &mut Self::Context) {
-        ctx.run_interval(Duration::from_secs(BATCH_BACKPRESSURE), |act, ctx| {
-            let batch = act.batch.clone();
-            let future = async move {
-                for record in batch.lock().await.drain(..) {
-                    match record.await {
-                        Ok(result) => match result {
-                            Ok((_partition, _offset)) => {}
-                            Err((_e, _)) => {}
-                        },
-                        Err(_e) => {}
-                    }
-                }
-
-                batch.lock().await.clear();
-            };
-
-            ctx.spawn(future.into_actor(act));
-        });
-    }
 }
 
 impl<State, Store, Cmd, Evt> Supervised for Init<State, Store, Cmd, Evt>
 where
-    State: Debug + Send + Sync + Unpin + Clone + Default + 'static + DeserializeOwned,
+    State: Debug + Send + Sync + Unpin + Clone + StateFactory + 'static + DeserializeOwned,
     Store: Adapter + Clone + Send + Sync + 'static + Unpin,
     Cmd: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Command<State> + Serialize,
     Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
 {
     fn restarting(&mut self, _: &mut Self::Context) {
-        // TODO: fetch state from somewhere and restore it
+        self.config.restarted(ActorKind::Init, None);
     }
 }
 
 impl<State, Store, Evt, Cmd> Handler<Enqueue<Cmd, Evt, State>> for Init<State, Store, Cmd, Evt>
 where
-    State: Debug + Send + Sync + Unpin + Clone + Default + 'static + DeserializeOwned,
+    State: Debug + Send + Sync + Unpin + Clone + StateFactory + 'static + DeserializeOwned,
     Store: Adapter + Clone + Send + Sync + 'static + Unpin,
     Cmd: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Command<State> + Serialize,
     Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
 {
-    type Result = ResponseFuture<Result<Unit, Error>>;
+    type Result = ResponseFuture<Result<EnqueueHandle<State, Cmd::T>, Error>>;
 
-    // TODO: Add logging  + Save seq_nr to store
     fn handle(&mut self, msg: Enqueue<Cmd, Evt, State>, _ctx: &mut Self::Context) -> Self::Result {
         let producer = self.producer.clone();
-        let batch = self.batch.clone();
         let seq_nr = self.seq_nr.clone();
+        let pending = self.pending.clone();
+        let command_format = self.config.command_format();
+        let sequence_generator = self.config.sequence_generator();
+        let command_key = self.config.command_key();
+        let source = self.config.service_name().map(str::to_owned);
+        let config = self.config.clone();
+        let enqueued = self.enqueued.clone();
         Box::pin(async move {
             let command = msg.command().ok_or_else(|| {
                 Error::InvalidCommand("Could not extract command from enqueue message".to_string())
             })?;
             let key = command.entity_id();
+            config.check_aggregate_type(&key)?;
             let timestamp = chrono::Utc::now();
             let name = command.name();
             let mut seq_nr = seq_nr.lock().await;
-            let record = serde_json::to_vec(&Record::command(
-                &key,
-                msg.command(),
-                timestamp,
-                name,
-                *seq_nr,
-            ))
-            .map_err(|e| Error::InvalidCommand(format!("Could not serialize command: {}", e)))?;
+            let handle = EnqueueHandle::register(&pending).await;
+            let mut record =
+                Record::command(&key, command, timestamp, name, *seq_nr).with_id(handle.id());
+            if let Some(traceparent) = msg.traceparent() {
+                record = record.with_traceparent(traceparent);
+            }
+            if let Some(source) = &source {
+                record = record.with_source(source);
+            }
+            if let Some(idempotency_key) = msg.idempotency_key() {
+                record = record.with_idempotency_key(idempotency_key);
+            }
+            if let Some(correlation_id) = msg.correlation_id() {
+                record = record.with_correlation_id(correlation_id);
+            }
+            if let Some(expires_at) = msg.expires_at() {
+                record = record.with_expires_at(expires_at);
+            }
+            let record = record.encode(command_format).map_err(|e| {
+                Error::InvalidCommand(format!("Could not serialize command: {}", e))
+            })?;
 
-            let record = FutureRecord::to(COMMAND_TOPIC)
+            let partition_key = command_key.key(&key);
+            let topic = COMMAND_TOPIC;
+            let record = FutureRecord::to(topic)
                 .payload(&record)
-                .key(&key)
+                .key(&partition_key)
                 .timestamp(timestamp.timestamp_millis());
 
-            let record = producer
-                .send_result(record)
-                .map_err(|(e, _)| Error::Kafka(e));
-
-            *seq_nr += 1;
+            // Commands are handed to the producer in the order of their sequence numbers, the
+            // lock is released before waiting for them to be delivered
+            let delivery = producer.send_result(record).map_err(|(e, _)| {
+                tracing::error!("Could not produce the command of entity {}: {}", key, e);
+                Error::Kafka(e)
+            })?;
+            *seq_nr = sequence_generator.next(&key, *seq_nr);
+            drop(seq_nr);
 
-            match record {
-                Ok(record) => {
-                    batch.lock().await.push(record);
+            // The command is accepted once the broker acknowledged it, a failed delivery is
+            // returned to the caller, who decides whether to enqueue the command again
+            let delivered = match delivery.await {
+                Ok(Ok((partition, offset))) => {
+                    let produced = ((topic.to_string(), partition), offset);
+                    let mut enqueued = enqueued.lock().await;
+                    let last = enqueued.entry(key.clone()).or_insert(produced.clone());
+                    // Deliveries of the same entity may be acknowledged out of order
+                    if last.0 != produced.0 || last.1 < offset {
+                        *last = produced;
+                    }
                     Ok(())
                 }
-                Err(e) => Err(e),
+                Ok(Err((e, _))) => Err(Error::Kafka(e)),
+                Err(_) => Err(Error::Kafka(KafkaError::Canceled)),
+            };
+            if let Err(e) = &delivered {
+                tracing::error!("The command of entity {} was not delivered: {}", key, e);
             }
+            delivered?;
+
+            Ok(handle)
         })
     }
 }
 
+impl<State, Store, Cmd, Evt> Handler<Dispatch<State, Cmd, Cmd::T>> for Init<State, Store, Cmd, Evt>
+where
+    State: Debug + Send + Sync + Unpin + Clone + StateFactory + 'static + DeserializeOwned,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Cmd: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Command<State> + Serialize,
+    Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
+{
+    type Result = ResponseFuture<Result<CommandOutcome<State, Cmd::T>, Error>>;
+
+    fn handle(
+        &mut self,
+        msg: Dispatch<State, Cmd, Cmd::T>,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let dispatch = self.dispatch.clone();
+        Box::pin(async move { dispatch.send(msg).await.map_err(Error::Actix)? })
+    }
+}
+
+impl<State, Store, Cmd, Evt> Handler<Pause> for Init<State, Store, Cmd, Evt>
+where
+    State: Debug + Send + Sync + Unpin + Clone + StateFactory + 'static + DeserializeOwned,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Cmd: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Command<State> + Serialize,
+    Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
+{
+    type Result = ();
+
+    fn handle(&mut self, _: Pause, _ctx: &mut Self::Context) -> Self::Result {
+        tracing::info!("Pausing the command consumer");
+        self.paused.store(true, Ordering::SeqCst);
+    }
+}
+
+impl<State, Store, Cmd, Evt> Handler<Resume> for Init<State, Store, Cmd, Evt>
+where
+    State: Debug + Send + Sync + Unpin + Clone + StateFactory + 'static + DeserializeOwned,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Cmd: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Command<State> + Serialize,
+    Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
+{
+    type Result = ();
+
+    fn handle(&mut self, _: Resume, _ctx: &mut Self::Context) -> Self::Result {
+        tracing::info!("Resuming the command consumer");
+        self.paused.store(false, Ordering::SeqCst);
+    }
+}
+
+impl<State, Store, Cmd, Evt> Handler<IsPaused> for Init<State, Store, Cmd, Evt>
+where
+    State: Debug + Send + Sync + Unpin + Clone + StateFactory + 'static + DeserializeOwned,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Cmd: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Command<State> + Serialize,
+    Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
+{
+    type Result = bool;
+
+    fn handle(&mut self, _: IsPaused, _ctx: &mut Self::Context) -> Self::Result {
+        self.paused.load(Ordering::SeqCst)
+    }
+}
+
+impl<State, Store, Cmd, Evt> Handler<Health> for Init<State, Store, Cmd, Evt>
+where
+    State: Debug + Send + Sync + Unpin + Clone + StateFactory + 'static + DeserializeOwned,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Cmd: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Command<State> + Serialize,
+    Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
+{
+    type Result = ResponseFuture<Result<Unit, Error>>;
+
+    fn handle(&mut self, _: Health, _ctx: &mut Self::Context) -> Self::Result {
+        let store = self.store.clone();
+        Box::pin(async move { store.health().await })
+    }
+}
+
 const BUFFER_SIZE: u64 = 100;
 
 impl<State, Store, Cmd, Evt> Handler<GetState<State>> for Init<State, Store, Cmd, Evt>
 where
-    State: Debug + Send + Sync + Unpin + Clone + Default + 'static + DeserializeOwned,
+    State: Debug + Send + Sync + Unpin + Clone + StateFactory + 'static + DeserializeOwned,
     Store: Adapter + Clone + Send + Sync + 'static + Unpin,
     Cmd: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Command<State> + Serialize,
     Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
@@ -181,29 +373,590 @@ where
     fn handle(&mut self, msg: GetState<State>, _ctx: &mut Self::Context) -> Self::Result {
         let store = self.store.clone();
         let entity_id = msg.entity_id().to_owned();
+        let consistency = msg.consistency();
+        let consumer = self.consumer.clone();
+        let enqueued = self.enqueued.clone();
+        let config = self.config.clone();
+        let live = self.live.clone();
         Box::pin(async move {
-            let highest_seq_nr = store.read_highest_sequence_number(&entity_id).await?;
+            if consistency == Consistency::Strong {
+                read_your_writes(
+                    consumer,
+                    &enqueued,
+                    &entity_id,
+                    config.consistency_timeout(),
+                )
+                .await?;
+            }
 
-            match highest_seq_nr {
-                Some(highest_seq_nr) => {
-                    let state = store
-                        .replay::<Evt>(&entity_id, 0, highest_seq_nr, highest_seq_nr + BUFFER_SIZE)
-                        .await?
-                        .fold(State::default(), |mut state, record| {
-                            let event = record.into_message();
-                            let new_state = event.apply(&state).unwrap();
-                            state = new_state;
-                            async move { state }
-                        })
-                        .await;
+            // The actor of the entity, if any, holds the state it last wrote. Another instance
+            // may have written since, e.g. after the partition of the entity was reassigned,
+            // so it is only used if storage holds nothing newer. An aggregate that cannot be
+            // reached, e.g. while it restarts, leaves storage to answer.
+            let live = match live.send(Live::new(&entity_id)).await {
+                Ok(live) => live?,
+                Err(_) => None,
+            };
+            let highest_seq_nr = store.read_highest_sequence_number(&entity_id).await?;
 
+            match (live, highest_seq_nr) {
+                (Some((seq_nr, state)), highest_seq_nr)
+                    if seq_nr as u64 >= highest_seq_nr.unwrap_or_default() =>
+                {
                     Ok(state)
                 }
-                None => Err(Error::InvalidCommand(format!(
-                    "Could not find entity with id {}",
-                    entity_id
-                ))),
+                (_, None) => Ok(State::initial(&entity_id)),
+                (_, Some(_)) => {
+                    let snapshot =
+                        latest_snapshot::<State, Store>(&store, &entity_id, &config).await;
+                    fold_history::<State, Store, Evt>(&store, &entity_id, &config, None, snapshot)
+                        .await
+                        .map(|(_, state, _)| state)
+                }
             }
         })
     }
 }
+
+impl<State, Store, Cmd, Evt> Handler<GetStates<State>> for Init<State, Store, Cmd, Evt>
+where
+    State: Debug + Send + Sync + Unpin + Clone + StateFactory + 'static + DeserializeOwned,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Cmd: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Command<State> + Serialize,
+    Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
+{
+    type Result = ResponseFuture<Result<HashMap<String, State>, Error>>;
+
+    fn handle(&mut self, msg: GetStates<State>, _ctx: &mut Self::Context) -> Self::Result {
+        let store = self.store.clone();
+        let entity_ids = msg.entity_ids().to_vec();
+        Box::pin(async move {
+            let mut records = store.replay_many::<Evt>(&entity_ids).await?;
+
+            let mut states: HashMap<String, State> = HashMap::with_capacity(entity_ids.len());
+            while let Some(record) = records.next().await {
+                let entity_id = record.entity_id().to_owned();
+                let meta = EventMeta::from(&record);
+                let event = record.into_message();
+                let state = match states.remove(&entity_id) {
+                    Some(state) => state,
+                    None => State::initial(&entity_id),
+                };
+                let state = event.apply_with_meta(&state, &meta).ok_or_else(|| {
+                    Error::InvalidState(format!(
+                        "Event {:?} could not be applied to state {:?} of entity {}",
+                        event, state, entity_id
+                    ))
+                })?;
+                states.insert(entity_id, state);
+            }
+
+            Ok(states)
+        })
+    }
+}
+
+impl<State, Store, Cmd, Evt> Handler<RebuildSnapshot<State>> for Init<State, Store, Cmd, Evt>
+where
+    State:
+        Debug + Send + Sync + Unpin + Clone + StateFactory + 'static + DeserializeOwned + Serialize,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Cmd: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Command<State> + Serialize,
+    Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
+{
+    type Result = ResponseFuture<Result<(u64, State), Error>>;
+
+    fn handle(&mut self, msg: RebuildSnapshot<State>, _ctx: &mut Self::Context) -> Self::Result {
+        let store = self.store.clone();
+        let entity_id = msg.entity_id().to_owned();
+        let throttle = self.config.replay_throttle();
+        let rebuilds = self.rebuilds.clone();
+        let state_version = self.config.snapshot_upcaster::<State>().version();
+        let from_latest = msg.is_from_latest();
+        let config = self.config.clone();
+        Box::pin(async move {
+            // Hold a permit for the whole rebuild, if the number of concurrent rebuilds is capped
+            let _permit = match rebuilds {
+                Some(rebuilds) => Some(
+                    rebuilds
+                        .acquire_owned()
+                        .await
+                        .map_err(|e| Error::Error(e.to_string()))?,
+                ),
+                None => None,
+            };
+
+            // Unless asked to, any existing snapshot is ignored, the state is folded from the
+            // first event. Events written while rebuilding are not part of the snapshot, which
+            // is fine as the snapshot is only a cache that is caught up by replaying the tail.
+            let snapshot = match from_latest {
+                true => latest_snapshot::<State, Store>(&store, &entity_id, &config).await,
+                false => None,
+            };
+            let (highest_seq_nr, state, deleted) = fold_history::<State, Store, Evt>(
+                &store,
+                &entity_id,
+                &config,
+                Some(throttle),
+                snapshot,
+            )
+            .await?;
+
+            store
+                .write_snapshot(
+                    &entity_id,
+                    highest_seq_nr as i64,
+                    state_version,
+                    &state,
+                    deleted,
+                )
+                .await?;
+
+            Ok((highest_seq_nr, state))
+        })
+    }
+}
+
+impl<State, Store, Cmd, Evt> Handler<Reload<State>> for Init<State, Store, Cmd, Evt>
+where
+    State:
+        Debug + Send + Sync + Unpin + Clone + StateFactory + 'static + DeserializeOwned + Serialize,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Cmd: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Command<State> + Serialize,
+    Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
+{
+    type Result = ResponseFuture<Result<(u64, State), Error>>;
+
+    fn handle(&mut self, msg: Reload<State>, _ctx: &mut Self::Context) -> Self::Result {
+        let reload = self.reload.clone();
+        Box::pin(async move { reload.send(msg).await.map_err(Error::Actix)? })
+    }
+}
+
+impl<State, Store, Cmd, Evt> Handler<Watch<State>> for Init<State, Store, Cmd, Evt>
+where
+    State: Debug + Send + Sync + Unpin + Clone + StateFactory + 'static + DeserializeOwned,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Cmd: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Command<State> + Serialize,
+    Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
+{
+    type Result = ResponseFuture<Result<(State, broadcast::Receiver<State>), Error>>;
+
+    fn handle(&mut self, msg: Watch<State>, _ctx: &mut Self::Context) -> Self::Result {
+        let watch = self.watch.clone();
+        Box::pin(async move { watch.send(msg).await.map_err(Error::Actix)? })
+    }
+}
+
+/// Wait until the last command enqueued for an entity, if any, is processed, failing with
+/// `Error::ConsistencyTimeout` once the wait exceeds `timeout`, see `Consistency::Strong`.
+async fn read_your_writes(
+    consumer: Arc<StreamConsumer>,
+    enqueued: &Mutex<HashMap<String, Position>>,
+    entity_id: &str,
+    timeout: Duration,
+) -> Result<Unit, Error> {
+    let Some(((topic, partition), offset)) = enqueued.lock().await.get(entity_id).cloned() else {
+        return Ok(());
+    };
+
+    tokio::time::timeout(timeout, caught_up(consumer, &topic, partition, offset))
+        .await
+        .map_err(|_| {
+            Error::ConsistencyTimeout(format!(
+                "The command of entity {} at offset {} of partition {} of topic {} was not processed within {:?}",
+                entity_id, offset, partition, topic, timeout
+            ))
+        })??;
+
+    // Nothing is left to wait for, unless the entity was enqueued to meanwhile
+    let mut enqueued = enqueued.lock().await;
+    if enqueued.get(entity_id) == Some(&((topic, partition), offset)) {
+        enqueued.remove(entity_id);
+    }
+    Ok(())
+}
+
+/// Wait until the consumer group committed past the given offset of a partition of the topic
+/// a command was produced to, i.e. until the command at that offset is processed, see
+/// `Consistency::Strong`.
+async fn caught_up(
+    consumer: Arc<StreamConsumer>,
+    topic: &str,
+    partition: i32,
+    offset: i64,
+) -> Result<Unit, Error> {
+    let mut partitions = TopicPartitionList::new();
+    partitions.add_partition(topic, partition);
+
+    loop {
+        let committed = {
+            let consumer = consumer.clone();
+            let partitions = partitions.clone();
+            tokio::task::spawn_blocking(move || {
+                consumer.committed_offsets(partitions, Duration::from_secs(POLL_TIMEOUT))
+            })
+            .await
+            .map_err(|e| Error::Error(format!("Could not read the committed offsets: {}", e)))?
+            .map_err(Error::Kafka)?
+        };
+
+        let processed = committed.elements().iter().any(
+            |element| matches!(element.offset(), Offset::Offset(committed) if committed > offset),
+        );
+        if processed {
+            return Ok(());
+        }
+
+        tokio::time::sleep(Duration::from_millis(CONSISTENCY_BACKOFF)).await;
+    }
+}
+
+/// Load an entity from its full event history, returning the highest sequence number
+/// together with the resulting state and whether the entity is deleted, see `Event::deletes`.
+/// An entity without events is at its initial state.
+pub(crate) async fn load<State, Store, Evt>(
+    store: &Store,
+    entity_id: &str,
+    config: &EngineConfig,
+) -> Result<(u64, State, bool), Error>
+where
+    State: Debug + Send + Sync + Unpin + Clone + StateFactory + 'static + DeserializeOwned,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
+{
+    match store.read_highest_sequence_number(entity_id).await? {
+        Some(_) => {
+            let snapshot = latest_snapshot::<State, Store>(store, entity_id, config).await;
+            fold_history::<State, Store, Evt>(store, entity_id, config, None, snapshot).await
+        }
+        None => Ok((0, State::initial(entity_id), false)),
+    }
+}
+
+/// The latest usable snapshot of an entity, i.e. its sequence number, state and deletion, if
+/// snapshots are enabled, see `EngineConfig::with_snapshot_every`.
+///
+/// This is `Adapter::read_latest_state` for states that are only serializable once snapshots
+/// are enabled. A snapshot that cannot be read is logged and ignored, as snapshots are only
+/// a cache of the events.
+pub(crate) async fn latest_snapshot<State, Store>(
+    store: &Store,
+    entity_id: &str,
+    config: &EngineConfig,
+) -> Option<(u64, State, bool)>
+where
+    State: Debug + Send + Sync + Clone + 'static + DeserializeOwned,
+    Store: Adapter,
+{
+    let encode = config.snapshot_encoder::<State>()?;
+    let upcaster = config.snapshot_upcaster::<State>();
+    let snapshot = match store.read_latest_snapshot(entity_id).await {
+        Ok(snapshot) => snapshot?,
+        Err(e) => {
+            tracing::warn!("Could not read the snapshot of entity {}: {}", entity_id, e);
+            return None;
+        }
+    };
+
+    if snapshot.state_version() == upcaster.version() {
+        return match snapshot.state::<State>() {
+            Ok(state) => Some((snapshot.seq_nr() as u64, state, snapshot.is_deleted())),
+            Err(e) => {
+                tracing::warn!("Ignoring snapshot of entity {}: {}", entity_id, e);
+                None
+            }
+        };
+    }
+
+    let Some(state) = upcaster.upcast(snapshot.state_version(), snapshot.payload().clone()) else {
+        tracing::info!(
+            "Discarding snapshot of entity {} at version {}, the current version is {}",
+            entity_id,
+            snapshot.state_version(),
+            upcaster.version()
+        );
+        return None;
+    };
+
+    // The migrated snapshot replaces the outdated one
+    let written = match encode(&state) {
+        Ok(payload) => {
+            store
+                .write_snapshot(
+                    entity_id,
+                    snapshot.seq_nr(),
+                    upcaster.version(),
+                    &payload,
+                    snapshot.is_deleted(),
+                )
+                .await
+        }
+        Err(e) => Err(Error::StorageError(format!("Failed to serialize: {}", e))),
+    };
+    if let Err(e) = written {
+        tracing::warn!(
+            "Could not write the migrated snapshot of entity {}: {}",
+            entity_id,
+            e
+        );
+    }
+
+    Some((snapshot.seq_nr() as u64, state, snapshot.is_deleted()))
+}
+
+/// Whether an entity is deleted, i.e. whether the last tombstone in its event history was
+/// not followed by a restoring event, see `Event::deletes`.
+pub(crate) async fn is_deleted<State, Store, Evt>(
+    store: &Store,
+    entity_id: &str,
+) -> Result<bool, Error>
+where
+    State: Debug + Send + Sync + Clone + 'static,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
+{
+    store
+        .try_replay::<Evt>(entity_id, 0, u64::MAX, u64::MAX)
+        .await?
+        .try_fold(false, |deleted, record| async move {
+            Ok(tombstoned::<State, Evt>(deleted, record.message()))
+        })
+        .await
+}
+
+/// Fold the event history of an entity, returning the highest sequence number together with
+/// the resulting state and whether the entity is deleted. Events are replayed at the pace of `throttle`, if any. Given a
+/// snapshot, only the events following it are folded onto its state, and checked for gaps
+/// if the config asks for it, see `EngineConfig::with_gap_check`.
+async fn fold_history<State, Store, Evt>(
+    store: &Store,
+    entity_id: &str,
+    config: &EngineConfig,
+    throttle: Option<ReplayThrottle>,
+    snapshot: Option<(u64, State, bool)>,
+) -> Result<(u64, State, bool), Error>
+where
+    State: Debug + Send + Sync + Unpin + Clone + StateFactory + 'static + DeserializeOwned,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
+{
+    let highest_seq_nr = store.read_highest_sequence_number(entity_id).await?;
+    let mut pacer = throttle.as_ref().map(Pacer::new);
+
+    match highest_seq_nr {
+        Some(highest_seq_nr) => {
+            // Events are applied a chunk at a time, applying is synchronous so there is no
+            // need to await every single event unless the replay is throttled
+            let (from_seq_nr, mut state, mut deleted) = match snapshot {
+                Some((seq_nr, state, deleted)) => (seq_nr + 1, state, deleted),
+                None => (0, State::initial(entity_id), false),
+            };
+            let generator = config.sequence_generator();
+            let mut gaps = config.gap_check().then(|| {
+                Gaps::new(
+                    entity_id,
+                    from_seq_nr.saturating_sub(1) as i64,
+                    generator.as_ref(),
+                )
+            });
+            let mut chunks = store
+                .try_replay::<Evt>(
+                    entity_id,
+                    from_seq_nr,
+                    highest_seq_nr,
+                    highest_seq_nr.saturating_sub(from_seq_nr) + BUFFER_SIZE,
+                )
+                .await?
+                .ready_chunks(REPLAY_CHUNK_SIZE);
+
+            while let Some(chunk) = chunks.next().await {
+                for record in chunk {
+                    // An event that cannot be read would leave the state silently wrong
+                    let record = record?;
+                    let delay = pacer.as_mut().map(Pacer::next_delay).unwrap_or_default();
+                    if !delay.is_zero() {
+                        tokio::time::sleep(delay).await;
+                    }
+
+                    if let Some(gaps) = gaps.as_mut() {
+                        gaps.observe(record.seq_nr());
+                    }
+
+                    let meta = EventMeta::from(&record);
+                    state = record
+                        .message()
+                        .apply_with_meta(&state, &meta)
+                        .ok_or_else(|| {
+                            Error::InvalidState(format!(
+                                "Event {:?} could not be applied to state {:?} of entity {}",
+                                record.message(),
+                                state,
+                                entity_id
+                            ))
+                        })?;
+                    deleted = tombstoned::<State, Evt>(deleted, record.message());
+                }
+            }
+
+            if let Some(missing) = gaps.map(Gaps::into_missing) {
+                if !missing.is_empty() {
+                    return Err(Error::SequenceGap {
+                        entity_id: entity_id.to_string(),
+                        missing,
+                    });
+                }
+            }
+
+            Ok((highest_seq_nr, state, deleted))
+        }
+        None => Err(Error::InvalidCommand(format!(
+            "Could not find entity with id {}",
+            entity_id
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryAdapter, SnapshotUpcaster};
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+
+    const PROFILE: &str = "profile:1";
+
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    struct Profile {
+        name: String,
+        locale: String,
+    }
+
+    // Version 1 of the profile had no locale
+    #[derive(Debug)]
+    struct ProfileUpcaster;
+
+    impl SnapshotUpcaster<Profile> for ProfileUpcaster {
+        fn version(&self) -> u32 {
+            2
+        }
+
+        fn upcast(&self, state_version: u32, mut payload: Value) -> Option<Profile> {
+            match state_version {
+                1 => {
+                    payload["locale"] = Value::from("en");
+                    serde_json::from_value(payload).ok()
+                }
+                _ => None,
+            }
+        }
+    }
+
+    fn config() -> EngineConfig {
+        EngineConfig::default()
+            .with_snapshot_every::<Profile>(10)
+            .with_snapshot_upcaster(ProfileUpcaster)
+    }
+
+    #[tokio::test]
+    async fn outdated_snapshots_are_upcast_and_written_back() {
+        let store = MemoryAdapter::new();
+        store
+            .write_snapshot(PROFILE, 7, 1, &json!({ "name": "Ada" }), false)
+            .await
+            .unwrap();
+
+        let snapshot = latest_snapshot::<Profile, _>(&store, PROFILE, &config()).await;
+
+        let upcast = Profile {
+            name: "Ada".to_string(),
+            locale: "en".to_string(),
+        };
+        assert_eq!(snapshot, Some((7, upcast.clone(), false)));
+        let written = store.read_latest_snapshot(PROFILE).await.unwrap().unwrap();
+        assert_eq!(written.state_version(), 2);
+        assert_eq!(written.state::<Profile>().unwrap(), upcast);
+    }
+
+    #[tokio::test]
+    async fn snapshots_the_upcaster_cannot_migrate_are_discarded() {
+        let store = MemoryAdapter::new();
+        store
+            .write_snapshot(PROFILE, 7, 0, &json!({ "full_name": "Ada" }), false)
+            .await
+            .unwrap();
+
+        let snapshot = latest_snapshot::<Profile, _>(&store, PROFILE, &config()).await;
+
+        assert_eq!(snapshot, None);
+    }
+
+    // Renaming only applies to the name it renames from
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Renamed {
+        from: String,
+        to: String,
+    }
+
+    impl Event<Profile> for Renamed {
+        fn apply(&self, state: &Profile) -> Option<Profile> {
+            (state.name == self.from).then(|| Profile {
+                name: self.to.clone(),
+                ..state.clone()
+            })
+        }
+    }
+
+    fn renamed(from: &str, to: &str) -> Renamed {
+        Renamed {
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn strong_reads_only_wait_for_the_commands_of_their_entity() {
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", "localhost:9")
+            .set("group.id", "consistency")
+            .set("log_level", "0")
+            .create()
+            .expect("a consumer");
+        let consumer = Arc::new(consumer);
+        let enqueued = Mutex::new(HashMap::from([(
+            "profile:2".to_string(),
+            ((COMMAND_TOPIC.to_string(), 0), 41),
+        )]));
+        let timeout = Duration::from_millis(100);
+
+        // No broker ever commits past the command of the other entity
+        let unrelated = read_your_writes(consumer.clone(), &enqueued, PROFILE, timeout).await;
+        let pending = read_your_writes(consumer, &enqueued, "profile:2", timeout).await;
+
+        assert!(unrelated.is_ok());
+        assert!(matches!(pending, Err(Error::ConsistencyTimeout(_))));
+        assert!(enqueued.lock().await.contains_key("profile:2"));
+    }
+
+    #[tokio::test]
+    async fn histories_that_do_not_apply_are_invalid_states() {
+        let store = MemoryAdapter::new();
+        let history = [
+            renamed("", "Ada"),
+            renamed("Ada", "Grace"),
+            renamed("Ada", "Joan"),
+        ];
+        let records = history
+            .iter()
+            .zip(1..)
+            .map(|(event, seq_nr)| {
+                Record::event(PROFILE.to_string(), seq_nr, event, chrono::Utc::now())
+            })
+            .collect();
+        store.write(records).await.unwrap();
+
+        let loaded = load::<Profile, _, Renamed>(&store, PROFILE, &EngineConfig::default()).await;
+
+        assert!(matches!(loaded, Err(Error::InvalidState(_))));
+    }
+}
@@ -1,19 +1,39 @@
-use super::{Event, Record};
+use super::{
+    load, send_event, send_state, tombstoned, CommandOutcome, Event, EventMeta, Record,
+    StateFactory,
+};
 use crate::{
     algebra::Command,
-    domain::{Error, GetState, Process},
-    storage::Adapter,
+    domain::{
+        run_effect, ActorKind, ApplyFailurePolicy, EngineConfig, Error, EventCodec, Live,
+        Middleware, Next, NonEmptyVec, Process, ProcessContext, PublishMode, Reload,
+        SequenceGenerator, StuckEntityPolicy, TokenBucket, ValidationContext, Watch,
+    },
+    storage::{chain_hash, hash_at, Adapter, IdempotencyKey},
     Unit,
 };
 use actix::prelude::*;
-use futures::lock::Mutex;
+use futures::{lock::Mutex, Future, TryStreamExt};
+use rdkafka::producer::FutureProducer;
 use serde::{de::DeserializeOwned, Serialize};
-use std::{fmt::Debug, sync::Arc};
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::{broadcast, watch};
+use tracing::Instrument;
+
+// A sequence number and the hash of the event at it, if any
+type HashedSeqNr = (i64, Option<String>);
 
 // The actor is essentially single threaded. So we can use a simple struct
 // without any mutexes or other synchronization primitives but we use them
 // simply because they make my life easier.
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub(crate) struct Inner<State, Store, Evt>
 where
     State: Debug + Send + Sync + 'static + Clone,
@@ -22,23 +42,54 @@ where
 {
     pub(crate) state: Arc<Mutex<State>>,
     pub(crate) seq_nr: Arc<Mutex<i64>>,
+    // Whether the entity is deleted, only changed while the state is locked
+    pub(crate) deleted: Arc<AtomicBool>,
+    // Whether the entity was loaded from storage, which happens on its first command
+    pub(crate) loaded: Arc<AtomicBool>,
+    // The sequence number and hash of the last event written, the next event is chained to
+    // it, see `Record::hash`. Only changed while the state is locked
+    pub(crate) last_hash: Arc<Mutex<Option<HashedSeqNr>>>,
+    // The states the entity moves to are sent to its watchers, see `Engine::watch`
+    pub(crate) watchers: broadcast::Sender<State>,
     pub(crate) entity_id: String,
     pub(crate) store: Store,
+    pub(crate) producer: Arc<FutureProducer>,
+    pub(crate) rate_limiter: Option<TokenBucket>,
+    pub(crate) sequence_generator: Arc<dyn SequenceGenerator>,
+    pub(crate) publish_mode: PublishMode,
+    pub(crate) middlewares: Vec<Arc<dyn Middleware>>,
+    pub(crate) config: EngineConfig,
     _marker: std::marker::PhantomData<Evt>,
 }
 
 impl<State, Store, Evt> Inner<State, Store, Evt>
 where
-    State: Debug + Send + Sync + Unpin + Clone + Default + 'static,
+    State: Debug + Send + Sync + Unpin + Clone + StateFactory + 'static,
     Store: Adapter + Clone + Send + Sync + 'static + Unpin,
     Evt: Debug + DeserializeOwned + Event<State> + Unpin + Serialize,
 {
-    pub fn new(entity_id: &str, store: Store) -> Self {
+    pub fn new(
+        entity_id: &str,
+        store: Store,
+        producer: Arc<FutureProducer>,
+        watchers: broadcast::Sender<State>,
+        config: &EngineConfig,
+    ) -> Self {
         Self {
-            state: Default::default(),
+            state: Arc::new(Mutex::new(State::initial(entity_id))),
             seq_nr: Default::default(),
+            deleted: Default::default(),
+            loaded: Default::default(),
+            last_hash: Default::default(),
+            watchers,
             entity_id: entity_id.to_string(),
             store,
+            producer,
+            rate_limiter: config.rate_limit().map(TokenBucket::new),
+            sequence_generator: config.sequence_generator(),
+            publish_mode: config.publish_mode(),
+            middlewares: config.middlewares().to_vec(),
+            config: config.clone(),
             _marker: std::marker::PhantomData,
         }
     }
@@ -52,7 +103,6 @@ where
 {
     type Context = Context<Self>;
 
-    // TODO: Add logging
     fn started(&mut self, _ctx: &mut Self::Context) {}
 }
 
@@ -62,93 +112,1305 @@ where
     Store: Adapter + Clone + Send + Sync + 'static + Unpin,
     Evt: Debug + DeserializeOwned + Event<State> + Unpin + Serialize + 'static,
 {
+    fn restarting(&mut self, _: &mut Self::Context) {
+        self.loaded.store(false, Ordering::SeqCst);
+        self.config
+            .restarted(ActorKind::Inner, Some(&self.entity_id));
+    }
 }
 
-impl<State, Store, Cmd, Evt> Handler<Process<Cmd>> for Inner<State, Store, Evt>
+impl<State, Store, Cmd, Evt> Handler<Process<State, Cmd, Cmd::T>> for Inner<State, Store, Evt>
 where
-    State: Debug + Clone + Send + Sync + Unpin + 'static + DeserializeOwned + Default,
+    State: Debug + Clone + Send + Sync + Unpin + 'static + DeserializeOwned + StateFactory,
     Store: Adapter + Clone + Send + Sync + 'static + Unpin,
     Cmd: Debug + DeserializeOwned + Command<State> + Unpin + Serialize,
     Evt: Debug + DeserializeOwned + Event<State> + Unpin + Serialize + 'static,
 {
-    type Result = ResponseFuture<Result<Unit, Error>>;
+    type Result = ResponseFuture<Result<CommandOutcome<State, Cmd::T>, Error>>;
+
+    fn handle(
+        &mut self,
+        msg: Process<State, Cmd, Cmd::T>,
+        ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        if let Some(rate_limiter) = self.rate_limiter.as_mut() {
+            if !rate_limiter.try_acquire() {
+                let entity_id = self.entity_id.clone();
+                return Box::pin(async move {
+                    Err(Error::RateLimited(format!(
+                        "Entity {} exceeded its command rate limit",
+                        entity_id
+                    )))
+                });
+            }
+        }
 
-    fn handle(&mut self, msg: Process<Cmd>, _: &mut Context<Self>) -> Self::Result {
         let state = self.state.clone();
         let seq_nr = self.seq_nr.clone();
+        let deleted = self.deleted.clone();
+        let loaded = self.loaded.clone();
+        let last_hash = self.last_hash.clone();
+        let watchers = self.watchers.clone();
         let id = self.entity_id.clone();
         let store = self.store.clone();
+        let producer = self.producer.clone();
+        let sequence_generator = self.sequence_generator.clone();
+        let publish_mode = self.publish_mode;
+        let middlewares = self.middlewares.clone();
+        let apply_failure_policy = self.config.apply_failure_policy();
+        let creation_checks = self.config.creation_checks();
+        let replay_check = self.config.replay_check();
+        let event_codec = self.config.event_codec();
+        let source = self.config.service_name().map(str::to_owned);
+        let correlation_id = msg.correlation_id();
+        let latency_recorder = self.config.latency_recorder();
+        let effect_recorder = self.config.effect_recorder();
+        let read_model = self.config.read_model();
+        let aggregate_type = self.config.check_aggregate_type(&self.entity_id);
+        let idempotency_window = self.config.idempotency_window();
+        let stuck_entity = self.config.stuck_entity();
+        let max_event_size = self.config.max_event_size();
+        let config = self.config.clone();
+        let snapshot_every = self.config.snapshot_every();
+        let snapshot_encoder = self.config.snapshot_encoder::<State>();
+        let state_version = self.config.snapshot_upcaster::<State>().version();
+        let state_encoder = self.config.state_encoder::<State>();
+        let restart = ctx.address().recipient();
+        let stuck_id = self.entity_id.clone();
+        let stuck_loaded = self.loaded.clone();
+        let (writing, stuck_writing) = watch::channel(false);
+
+        let span = tracing::info_span!(
+            "process",
+            aggregate_type = self.config.aggregate_type(),
+            entity_id = %self.entity_id,
+            command = %msg.command().name()
+        );
+        #[cfg(feature = "otel")]
+        if let Some(traceparent) = msg.traceparent() {
+            crate::domain::link_traceparent(&span, traceparent);
+        }
+
+        let processing = async move {
+            // Commands produced without going through `enqueue` are checked here
+            aggregate_type?;
 
-        Box::pin(async move {
             let cmd = msg.command();
             let mut state = state.lock().await;
             let mut seq_nr = seq_nr.lock().await;
+            let mut last_hash = last_hash.lock().await;
+            let mut processed = None;
 
-            // 1. Validate command
-            cmd.validate(&state).map_err(|e| {
-                Error::Validation(format!(
-                    "Command {:?} is not valid for state {:?}: {}",
-                    cmd, state, e
-                ))
-            })?;
+            if let Some(expires_at) = msg.expires_at() {
+                if chrono::Utc::now() > expires_at {
+                    return Err(Error::Expired(format!(
+                        "Command {:?} expired at {}",
+                        cmd, expires_at
+                    )));
+                }
+            }
 
-            // 2. If valid, yield events
-            let events = cmd.directive(&state)?;
+            // Pick up where the entity left off, e.g. before a restart or an eviction
+            ensure_loaded::<State, Store, Evt>(
+                &store,
+                &id,
+                &config,
+                &loaded,
+                &deleted,
+                &mut state,
+                &mut seq_nr,
+            )
+            .await?;
+            let previous_seq_nr = *seq_nr;
 
-            let records = events
-                .iter()
-                .map(|event| {
-                    *seq_nr += 1;
-                    Record::event(id.clone(), *seq_nr, event, chrono::Utc::now())
-                })
-                .collect::<Vec<_>>();
+            // A retried command resolves to the events of its first attempt
+            if let Some(key) = msg.idempotency_key() {
+                if let Some((events, version)) =
+                    idempotent_outcome::<State, Store, Cmd::T>(&store, &id, key).await?
+                {
+                    tracing::info!(
+                        "Command {:?} with idempotency key {} was already processed",
+                        cmd,
+                        key
+                    );
+                    return Ok(CommandOutcome::new(events, version, (*state).clone()));
+                }
+            }
 
-            // 3. Save events to storage, if this fails it is non-recoverable for now
-            store.write(records).await?;
+            let idempotency_key = msg.idempotency_key().map(|key| {
+                let expires_at = chrono::Duration::from_std(idempotency_window)
+                    .ok()
+                    .and_then(|window| chrono::Utc::now().checked_add_signed(window))
+                    .unwrap_or(chrono::DateTime::<chrono::Utc>::MAX_UTC);
+                IdempotencyKey::new(key, expires_at)
+            });
 
-            let initial_state = state.clone();
+            let ctx = ProcessContext::new(&id, cmd.name(), cmd, msg.source());
+            let next = Next::new(
+                &middlewares,
+                Box::new(|| {
+                    Box::pin(async {
+                        // 1. Validate command
+                        if deleted.load(Ordering::SeqCst) && !cmd.resurrects() {
+                            return Err(Error::EntityDeleted(id.clone()));
+                        }
+                        if creation_checks {
+                            check_creation(&id, *seq_nr, cmd.is_creation())?;
+                        }
+                        // Rejections are passed through as is, so their code reaches the caller
+                        let validation = ValidationContext::new(&id, read_model.as_ref());
+                        cmd.validate_with(&state, &validation)
+                            .await
+                            .map_err(|e| match e {
+                                Error::Rejected { .. } => e,
+                                e => Error::Validation(format!(
+                                    "Command {:?} is not valid for state {:?}: {}",
+                                    cmd, state, e
+                                )),
+                            })?;
 
-            // 4. Apply events to state and yield effects
-            let result = events
-                .iter()
-                .try_fold(initial_state, |current_state, event| {
-                    event.apply(&current_state).ok_or_else(|| {
-                        tracing::warn!(
-                            "Event {:?} could not be applied to state {:?}",
-                            event,
-                            current_state
+                        // 2. If valid, yield events
+                        let events = cmd.directive(&state)?;
+
+                        // 3. Apply events to state, before anything is written
+                        let (new_state, events, metas) = apply_events(
+                            &id,
+                            &*state,
+                            *seq_nr,
+                            events,
+                            sequence_generator.as_ref(),
+                            apply_failure_policy,
+                        )?;
+
+                        // The hash of the last event is read from storage after a (re)load
+                        let mut previous_hash = match &*last_hash {
+                            Some((hashed_seq_nr, hash)) if *hashed_seq_nr == *seq_nr => {
+                                hash.clone()
+                            }
+                            _ => hash_at::<Cmd::T, Store>(&store, &id, *seq_nr).await?,
+                        };
+                        let records = events
+                            .iter()
+                            .zip(metas.iter())
+                            .map(|(event, meta)| {
+                                let mut record = Record::event(
+                                    id.clone(),
+                                    meta.seq_nr(),
+                                    event,
+                                    meta.timestamp(),
+                                );
+                                if let Some(source) = &source {
+                                    record = record.with_source(source);
+                                }
+                                // Events are correlated with the command producing them
+                                if let Some(correlation_id) = correlation_id {
+                                    record = record.with_id(correlation_id);
+                                }
+                                let hash = chain_hash(previous_hash.as_deref(), &record)?;
+                                previous_hash = Some(hash.clone());
+                                Ok(record.with_hash(hash))
+                            })
+                            .collect::<Result<Vec<_>, Error>>()?;
+                        if let Some(max) = max_event_size {
+                            check_event_sizes(&id, &records, max)?;
+                        }
+
+                        // 4. Save events to storage, together with the durable effects and the
+                        // idempotency key of the command, if this fails it is non-recoverable
+                        // for now
+                        let durable = cmd.durable_effects(&state, &new_state)?;
+                        if publish_mode == PublishMode::BeforeStorage {
+                            publish(&producer, event_codec.as_ref(), &id, &records).await;
+                        }
+                        let with_outbox = publish_mode == PublishMode::Outbox;
+                        // A stuck entity is not restarted while its write is in flight
+                        writing.send_replace(true);
+                        let written = match idempotency_key {
+                            Some(key) => {
+                                store
+                                    .write_with_idempotency_key(
+                                        records.clone(),
+                                        key,
+                                        durable,
+                                        with_outbox,
+                                    )
+                                    .await
+                            }
+                            None if !durable.is_empty() => {
+                                store
+                                    .write_with_effects(records.clone(), durable, with_outbox)
+                                    .await
+                            }
+                            // The relay publishes the events once the outbox entries are committed
+                            None if with_outbox => store.write_with_outbox(records.clone()).await,
+                            None => store.write(records.clone()).await,
+                        };
+                        writing.send_replace(false);
+                        written?;
+
+                        // The events are written already, so a mismatch is logged rather
+                        // than failing the command
+                        if replay_check {
+                            if let Err(e) = check_replays::<State, Store, Cmd::T>(
+                                &store, &id, &state, &events, &metas,
+                            )
+                            .await
+                            {
+                                tracing::error!("{}", e);
+                            }
+                        }
+
+                        // The events are written, so the entity moves past them even if one
+                        // of the effects fails, and the next command gets the next seq_nr
+                        let before = std::mem::replace(&mut *state, new_state);
+                        if let Some(meta) = metas.last() {
+                            *seq_nr = meta.seq_nr();
+                            *last_hash = Some((meta.seq_nr(), previous_hash));
+                        }
+                        deleted.store(
+                            events
+                                .iter()
+                                .fold(deleted.load(Ordering::SeqCst), |deleted, event| {
+                                    tombstoned::<State, Cmd::T>(deleted, event.as_ref())
+                                }),
+                            Ordering::SeqCst,
                         );
+                        // Nobody may be watching, in which case the state is not cloned
+                        if watchers.receiver_count() > 0 {
+                            let _ = watchers.send((*state).clone());
+                        }
+
+                        // 5. Yield effects
+                        run_effect(
+                            effect_recorder.as_ref(),
+                            &cmd.name(),
+                            &id,
+                            false,
+                            cmd.effects(&before, &state),
+                        )
+                        .await?;
+
+                        // 6. Publish events to Kafka. Storage is the source of truth, so a failed
+                        // publish is logged rather than failing an already persisted command.
+                        if publish_mode == PublishMode::AfterStorage {
+                            publish(&producer, event_codec.as_ref(), &id, &records).await;
+                        }
+                        drop(records);
+
+                        processed = Some(events);
+                        Ok(())
                     })
-                });
+                }),
+            );
+
+            next.run(&ctx).await?;
+
+            let processed = processed.ok_or_else(|| {
+                Error::Error(format!(
+                    "Command {:?} was not processed, a middleware did not call next",
+                    cmd
+                ))
+            })?;
 
-            match result {
-                Ok(new_state) => {
-                    cmd.effects(&state, &new_state).await?;
-                    *state = new_state;
-                    Ok(())
+            // A snapshot is written whenever the events cross a multiple of `snapshot_every`.
+            // The events are written already, so a failed snapshot is logged rather than
+            // failing the command, loading falls back to the previous snapshot.
+            if let Some(every) = snapshot_every {
+                let crossed = previous_seq_nr as u64 / every != *seq_nr as u64 / every;
+                if let Some(encode) = snapshot_encoder.filter(|_| crossed) {
+                    let written = match encode(&state) {
+                        Ok(payload) => {
+                            store
+                                .write_snapshot(
+                                    &id,
+                                    *seq_nr,
+                                    state_version,
+                                    &payload,
+                                    deleted.load(Ordering::SeqCst),
+                                )
+                                .await
+                        }
+                        Err(e) => Err(Error::StorageError(format!("Failed to serialize: {}", e))),
+                    };
+                    if let Err(e) = written {
+                        tracing::warn!(
+                            "Failed to snapshot entity {} at sequence number {}: {}",
+                            id,
+                            *seq_nr,
+                            e
+                        );
+                    }
+                }
+            }
+
+            // Like events, a state that fails to be published is logged rather than failing
+            // the command, the next command of the entity publishes its state again
+            if let Some(encode) = state_encoder {
+                let payload = match deleted.load(Ordering::SeqCst) {
+                    true => Ok(None),
+                    false => encode(&state).and_then(|state| serde_json::to_vec(&state).map(Some)),
+                };
+                let published = match payload {
+                    Ok(payload) => send_state(&producer, &id, payload.as_deref()).await,
+                    Err(e) => Err(Error::Error(format!("Failed to serialize: {}", e))),
+                };
+                if let Err(e) = published {
+                    tracing::error!(
+                        "Could not publish the state of entity {} at sequence number {}: {}",
+                        id,
+                        *seq_nr,
+                        e
+                    );
                 }
-                Err(_) => Err(Error::Error(format!(
-                    "Could not apply events {:?} for command {:?}",
-                    events, cmd
-                ))),
             }
 
-            // 5. Publish events to Kafka (this should be done in a separate actor)
+            // A command enqueued on an instance whose clock is ahead has no latency
+            if let Some(latency_recorder) = &latency_recorder {
+                let latency = (chrono::Utc::now() - msg.enqueued_at())
+                    .to_std()
+                    .unwrap_or_default();
+                latency_recorder.record(&id, latency);
+            }
+
+            Ok(CommandOutcome::new(
+                processed,
+                *seq_nr as u64,
+                (*state).clone(),
+            ))
+        }
+        .instrument(span);
+
+        Box::pin(watch_over(
+            processing,
+            stuck_id,
+            stuck_entity,
+            stuck_loaded,
+            stuck_writing,
+            restart,
+        ))
+    }
+}
+
+/// Restart the actor of an entity stuck on a command, see `StuckEntityPolicy::Restart`.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Restart;
+
+impl<State, Store, Evt> Handler<Restart> for Inner<State, Store, Evt>
+where
+    State: Debug + Clone + Send + Sync + Unpin + 'static,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Evt: Debug + DeserializeOwned + Event<State> + Unpin + Serialize + 'static,
+{
+    type Result = ();
+
+    fn handle(&mut self, _: Restart, ctx: &mut Context<Self>) -> Self::Result {
+        // The supervisor starts the actor again, see `restarting`
+        ctx.stop();
+    }
+}
+
+impl<State, Store, Evt> Handler<Reload<State>> for Inner<State, Store, Evt>
+where
+    State: Debug + Clone + Send + Sync + Unpin + 'static + StateFactory + DeserializeOwned,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Evt: Debug + DeserializeOwned + Event<State> + Unpin + Serialize + 'static + Send + Sync,
+{
+    type Result = ResponseFuture<Result<(u64, State), Error>>;
+
+    fn handle(&mut self, _: Reload<State>, _: &mut Context<Self>) -> Self::Result {
+        let state = self.state.clone();
+        let seq_nr = self.seq_nr.clone();
+        let deleted = self.deleted.clone();
+        let loaded = self.loaded.clone();
+        let id = self.entity_id.clone();
+        let store = self.store.clone();
+        let config = self.config.clone();
+
+        Box::pin(async move {
+            // Hold both locks while replaying, so no command is processed against an entity
+            // that is being reloaded
+            let mut state = state.lock().await;
+            let mut seq_nr = seq_nr.lock().await;
+
+            let (highest_seq_nr, reloaded, is_deleted) =
+                load::<State, Store, Evt>(&store, &id, &config).await?;
+            tracing::info!(
+                "Reloaded entity {} from storage at sequence number {}",
+                id,
+                highest_seq_nr
+            );
+
+            *state = reloaded.clone();
+            *seq_nr = highest_seq_nr as i64;
+            deleted.store(is_deleted, Ordering::SeqCst);
+            loaded.store(true, Ordering::SeqCst);
+            Ok((highest_seq_nr, reloaded))
+        })
+    }
+}
+
+impl<State, Store, Evt> Handler<Watch<State>> for Inner<State, Store, Evt>
+where
+    State: Debug + Clone + Send + Sync + Unpin + 'static + StateFactory + DeserializeOwned,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Evt: Debug + DeserializeOwned + Event<State> + Unpin + Serialize + 'static + Send + Sync,
+{
+    type Result = ResponseFuture<Result<(State, broadcast::Receiver<State>), Error>>;
+
+    fn handle(&mut self, _: Watch<State>, _: &mut Context<Self>) -> Self::Result {
+        let state = self.state.clone();
+        let seq_nr = self.seq_nr.clone();
+        let deleted = self.deleted.clone();
+        let loaded = self.loaded.clone();
+        let watchers = self.watchers.clone();
+        let id = self.entity_id.clone();
+        let store = self.store.clone();
+        let config = self.config.clone();
+
+        Box::pin(async move {
+            let mut state = state.lock().await;
+            let mut seq_nr = seq_nr.lock().await;
+            ensure_loaded::<State, Store, Evt>(
+                &store,
+                &id,
+                &config,
+                &loaded,
+                &deleted,
+                &mut state,
+                &mut seq_nr,
+            )
+            .await?;
+
+            // Subscribing while the state is locked, no state is missed nor sent twice
+            Ok(((*state).clone(), watchers.subscribe()))
         })
     }
 }
 
-impl<State, Store, Evt> Handler<GetState<State>> for Inner<State, Store, Evt>
+impl<State, Store, Evt> Handler<Live<State>> for Inner<State, Store, Evt>
 where
     State: Debug + Clone + Send + Sync + Unpin + 'static,
     Store: Adapter + Clone + Send + Sync + 'static + Unpin,
     Evt: Debug + DeserializeOwned + Event<State> + Unpin + Serialize + 'static,
 {
-    type Result = ResponseFuture<Result<State, Error>>;
+    type Result = ResponseFuture<Result<Option<(i64, State)>, Error>>;
 
-    fn handle(&mut self, _: GetState<State>, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, _: Live<State>, _: &mut Context<Self>) -> Self::Result {
         let state = self.state.clone();
+        let seq_nr = self.seq_nr.clone();
+        let loaded = self.loaded.clone();
+
+        Box::pin(async move {
+            // Locked like a command, so the state is not read halfway through one
+            let state = state.lock().await;
+            let seq_nr = seq_nr.lock().await;
+
+            Ok(loaded
+                .load(Ordering::SeqCst)
+                .then(|| (*seq_nr, (*state).clone())))
+        })
+    }
+}
+
+/// Reject events whose record serializes to more than `max` bytes of JSON, before they are
+/// written or published, see `EngineConfig::with_max_event_size`.
+fn check_event_sizes<Evt>(
+    entity_id: &str,
+    records: &[Record<&Evt>],
+    max: usize,
+) -> Result<Unit, Error>
+where
+    Evt: Serialize,
+{
+    for record in records {
+        let size = serde_json::to_vec(record)
+            .map_err(|e| Error::InvalidEvent(e.to_string()))?
+            .len();
+        tracing::trace!(
+            "Event {} of entity {} is {} bytes",
+            record.seq_nr(),
+            entity_id,
+            size
+        );
+
+        if size > max {
+            tracing::warn!(
+                "Event {} of entity {} is {} bytes, above the maximum of {} bytes",
+                record.seq_nr(),
+                entity_id,
+                size,
+                max
+            );
+            return Err(Error::PayloadTooLarge {
+                entity_id: entity_id.to_string(),
+                size,
+                max,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Publish events to the event topic keyed by entity id, so all events of an entity
+/// land on the same partition and keyed compaction can be applied downstream.
+async fn publish<Evt>(
+    producer: &FutureProducer,
+    codec: &dyn EventCodec,
+    entity_id: &str,
+    records: &[Record<&Evt>],
+) where
+    Evt: Serialize,
+{
+    for record in records {
+        let payload = match serde_json::to_vec(record) {
+            Ok(payload) => payload,
+            Err(e) => {
+                tracing::error!(
+                    "Could not serialize event {} of entity {}: {}",
+                    record.seq_nr(),
+                    entity_id,
+                    e
+                );
+                continue;
+            }
+        };
+
+        if let Err(e) = send_event(producer, codec, entity_id, &payload, record.timestamp()).await {
+            tracing::error!(
+                "Could not publish event {} of entity {}: {}",
+                record.seq_nr(),
+                entity_id,
+                e
+            );
+        }
+    }
+}
+
+/// Check that a creation command targets an entity without events and that any other
+/// command targets an entity with events, given the highest sequence number of the entity.
+pub(crate) fn check_creation(
+    entity_id: &str,
+    seq_nr: i64,
+    is_creation: bool,
+) -> Result<Unit, Error> {
+    match (seq_nr > 0, is_creation) {
+        (true, true) => Err(Error::AlreadyExists(entity_id.to_string())),
+        (false, false) => Err(Error::EntityNotFound(entity_id.to_string())),
+        _ => Ok(()),
+    }
+}
+
+// The new state, the events that were applied and their metadata
+type Applied<State, Evt> = (State, NonEmptyVec<Box<Evt>>, Vec<EventMeta>);
+
+/// Apply events to a state according to `policy`, returning the new state together with
+/// the events that were applied and their metadata. Sequence numbers are assigned from
+/// `seq_nr` to applied events only.
+pub(crate) fn apply_events<State, Evt>(
+    entity_id: &str,
+    state: &State,
+    seq_nr: i64,
+    events: NonEmptyVec<Box<Evt>>,
+    sequence_generator: &dyn SequenceGenerator,
+    policy: ApplyFailurePolicy,
+) -> Result<Applied<State, Evt>, Error>
+where
+    State: Debug + Clone + Send + Sync + 'static,
+    Evt: Debug + Event<State>,
+{
+    let mut new_state = state.clone();
+    let mut seq_nr = seq_nr;
+    let mut applied = Vec::new();
+    let mut metas = Vec::new();
+    let mut failed = false;
+
+    for event in events {
+        let meta = EventMeta::new(
+            entity_id,
+            sequence_generator.next(entity_id, seq_nr),
+            chrono::Utc::now(),
+        );
+
+        match event.apply_with_meta(&new_state, &meta) {
+            Some(next_state) => {
+                new_state = next_state;
+                seq_nr = meta.seq_nr();
+                applied.push(event);
+                metas.push(meta);
+            }
+            None if policy == ApplyFailurePolicy::Strict => {
+                tracing::warn!(
+                    "Event {:?} of entity {} could not be applied to state {:?}",
+                    event,
+                    entity_id,
+                    new_state
+                );
+                failed = true;
+                break;
+            }
+            None => tracing::warn!(
+                "Skipping event {:?} of entity {}, it could not be applied to state {:?}",
+                event,
+                entity_id,
+                new_state
+            ),
+        }
+    }
+
+    if failed {
+        return Err(Error::Error(format!(
+            "Could not apply the events to state {:?} of entity {}",
+            state, entity_id
+        )));
+    }
+
+    let applied = NonEmptyVec::new(applied).map_err(|_| {
+        Error::Error(format!(
+            "None of the events could be applied to state {:?} of entity {}",
+            state, entity_id
+        ))
+    })?;
+
+    Ok((new_state, applied, metas))
+}
+
+/// Load the state, sequence number and deletion of an entity from storage, unless it was
+/// loaded already. Both locks of the entity must be held.
+async fn ensure_loaded<State, Store, Evt>(
+    store: &Store,
+    entity_id: &str,
+    config: &EngineConfig,
+    loaded: &AtomicBool,
+    deleted: &AtomicBool,
+    state: &mut State,
+    seq_nr: &mut i64,
+) -> Result<Unit, Error>
+where
+    State: Debug + Send + Sync + Unpin + Clone + StateFactory + 'static + DeserializeOwned,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
+{
+    if loaded.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    let (highest_seq_nr, loaded_state, is_deleted) =
+        load::<State, Store, Evt>(store, entity_id, config).await?;
+    *state = loaded_state;
+    *seq_nr = highest_seq_nr as i64;
+    deleted.store(is_deleted, Ordering::SeqCst);
+    loaded.store(true, Ordering::SeqCst);
+
+    Ok(())
+}
+
+/// The events produced by the command that first carried an idempotency key, together with
+/// the sequence number of the last one, or None if the key is unknown or expired.
+async fn idempotent_outcome<State, Store, Evt>(
+    store: &Store,
+    entity_id: &str,
+    key: &str,
+) -> Result<Option<(NonEmptyVec<Box<Evt>>, u64)>, Error>
+where
+    State: Debug + Clone + Send + Sync + 'static,
+    Store: Adapter,
+    Evt: Debug + DeserializeOwned + Event<State> + Serialize + 'static,
+{
+    let Some((first, last)) = store.read_idempotency_key(entity_id, key).await? else {
+        return Ok(None);
+    };
+
+    let events = store
+        .try_replay::<Evt>(
+            entity_id,
+            first as u64,
+            last as u64,
+            (last - first + 1) as u64,
+        )
+        .await?
+        .map_ok(|record| Box::new(record.into_message()))
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    Ok(Some((NonEmptyVec::new(events)?, last as u64)))
+}
+
+/// Check that the events just written read back from storage as the events that were
+/// applied, in the same order, and that they apply again onto the state they were applied to,
+/// as they would when the entity is replayed, see `EngineConfig::with_replay_check`. Events
+/// are compared as JSON, so maps compare regardless of their iteration order. The check is
+/// skipped if the events cannot be read.
+async fn check_replays<State, Store, Evt>(
+    store: &Store,
+    entity_id: &str,
+    state: &State,
+    events: &NonEmptyVec<Box<Evt>>,
+    metas: &[EventMeta],
+) -> Result<Unit, Error>
+where
+    State: Debug + Clone + Send + Sync + 'static,
+    Store: Adapter,
+    Evt: Debug + DeserializeOwned + Event<State> + Serialize + 'static,
+{
+    let (Some(first), Some(last)) = (metas.first(), metas.last()) else {
+        return Ok(());
+    };
+
+    let records = match store
+        .try_replay::<Evt>(
+            entity_id,
+            first.seq_nr() as u64,
+            last.seq_nr() as u64,
+            metas.len() as u64,
+        )
+        .await
+    {
+        Ok(records) => records.try_collect::<Vec<_>>().await,
+        Err(e) => Err(e),
+    };
+    let records = match records {
+        Ok(records) => records,
+        Err(e) => {
+            tracing::debug!(
+                "Could not read back the events of entity {} to check them: {}",
+                entity_id,
+                e
+            );
+            return Ok(());
+        }
+    };
+
+    let written = metas
+        .iter()
+        .zip(events.iter())
+        .map(|(meta, event)| (meta.seq_nr(), serde_json::to_value(event).ok()))
+        .collect::<Vec<_>>();
+    let read = records
+        .iter()
+        .map(|record| (record.seq_nr(), serde_json::to_value(record.message()).ok()))
+        .collect::<Vec<_>>();
+    if written != read {
+        return Err(Error::InvalidState(format!(
+            "The events of entity {} read back from storage {:?} differ from the events applied {:?}",
+            entity_id, read, written
+        )));
+    }
+
+    records
+        .iter()
+        .try_fold(state.clone(), |state, record| {
+            record
+                .message()
+                .apply_with_meta(&state, &EventMeta::from(record))
+                .ok_or_else(|| {
+                    Error::InvalidState(format!(
+                        "Event {:?} of entity {} does not apply to state {:?} on replay",
+                        record.message(),
+                        entity_id,
+                        state
+                    ))
+                })
+        })
+        .map(|_| ())
+}
+
+/// Await the processing of a command, applying the stuck entity policy once it holds the
+/// entity for longer than the timeout, see `EngineConfig::with_stuck_entity_timeout`.
+///
+/// `writing` tells whether the events of the command are being written. A write in flight
+/// may still be committed, so the command is only abandoned once its write is done.
+async fn watch_over<T>(
+    processing: impl Future<Output = Result<T, Error>>,
+    entity_id: String,
+    stuck_entity: Option<(Duration, StuckEntityPolicy)>,
+    loaded: Arc<AtomicBool>,
+    mut writing: watch::Receiver<bool>,
+    restart: Recipient<Restart>,
+) -> Result<T, Error> {
+    let Some((timeout, policy)) = stuck_entity else {
+        return processing.await;
+    };
+
+    let mut processing = Box::pin(processing);
+    if let Ok(result) = tokio::time::timeout(timeout, &mut processing).await {
+        return result;
+    }
+
+    match policy {
+        StuckEntityPolicy::Warn => {
+            tracing::warn!(
+                "Entity {} is stuck on a command for more than {:?}",
+                entity_id,
+                timeout
+            );
+            processing.await
+        }
+        StuckEntityPolicy::Restart => {
+            tracing::warn!(
+                "Entity {} is stuck on a command for more than {:?}, restarting it",
+                entity_id,
+                timeout
+            );
+            // The command may finish while its write is awaited, in which case it is not
+            // stuck anymore
+            tokio::select! {
+                result = &mut processing => return result,
+                _ = writing.wait_for(|writing| !writing) => {}
+            }
+
+            // Whatever the abandoned command wrote, the next command loads the entity again,
+            // from the highest sequence number in storage. Dropping the command releases the
+            // locks of the entity.
+            loaded.store(false, Ordering::SeqCst);
+            drop(processing);
+            restart.do_send(Restart);
+            Err(Error::EntityStuck(entity_id))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{algebra::TestEngine, domain::RateLimit, storage::MemoryAdapter, Unit};
+    use futures::StreamExt;
+    use rdkafka::ClientConfig;
+    use serde::Deserialize;
+
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    struct Counter {
+        count: u64,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Incremented;
+
+    impl Event<Counter> for Incremented {
+        fn apply(&self, state: &Counter) -> Option<Counter> {
+            Some(Counter {
+                count: state.count + 1,
+            })
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Increment(String);
+
+    impl Command<Counter> for Increment {
+        type T = Incremented;
+
+        fn validate(&self, _: &Counter) -> Result<Unit, Error> {
+            Ok(())
+        }
+
+        fn directive(&self, _: &Counter) -> Result<NonEmptyVec<Box<Incremented>>, Error> {
+            Ok(NonEmptyVec::new(vec![Box::new(Incremented)])?)
+        }
+
+        fn entity_id(&self) -> String {
+            self.0.clone()
+        }
+    }
+
+    // Events go through the outbox, which nothing relays in these tests, so the producer is
+    // never used and needs no broker
+    fn start(
+        entity_id: &str,
+        store: &MemoryAdapter,
+        config: &EngineConfig,
+    ) -> Addr<Inner<Counter, MemoryAdapter, Incremented>> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", "localhost:9")
+            .set("log_level", "0")
+            .create()
+            .expect("a producer");
+        let config = config.clone().with_publish_mode(PublishMode::Outbox);
+        let (watchers, _) = broadcast::channel(1);
+
+        config.start_local(Inner::new(
+            entity_id,
+            store.clone(),
+            Arc::new(producer),
+            watchers,
+            &config,
+        ))
+    }
+
+    async fn increment(
+        inner: &Addr<Inner<Counter, MemoryAdapter, Incremented>>,
+        entity_id: &str,
+    ) -> Result<CommandOutcome<Counter, Incremented>, Error> {
+        let command = Increment(entity_id.to_string());
+        let record = Record::command(
+            entity_id,
+            command,
+            chrono::Utc::now(),
+            "Increment".into(),
+            0,
+        );
+
+        inner
+            .send(Process::<Counter, Increment, Incremented>::new(record))
+            .await
+            .map_err(Error::Actix)?
+    }
+
+    #[actix::test]
+    async fn rate_limits_every_entity_on_its_own() {
+        let store = MemoryAdapter::new();
+        let config = EngineConfig::default().with_rate_limit(RateLimit::new(2, 0.001));
+        let busy = start("counter:busy", &store, &config);
+        let quiet = start("counter:quiet", &store, &config);
+
+        for _ in 0..2 {
+            increment(&busy, "counter:busy").await.unwrap();
+        }
+        let limited = increment(&busy, "counter:busy").await;
+        let outcome = increment(&quiet, "counter:quiet").await.unwrap();
+
+        assert!(matches!(limited, Err(Error::RateLimited(_))));
+        assert_eq!(outcome.state(), &Counter { count: 1 });
+        assert_eq!(
+            store
+                .read_highest_sequence_number("counter:busy")
+                .await
+                .unwrap(),
+            Some(2)
+        );
+    }
+
+    #[actix::test]
+    async fn retried_commands_resolve_to_the_events_of_their_first_attempt() {
+        let store = MemoryAdapter::new();
+        let counter = start("counter:1", &store, &EngineConfig::default());
+        let retry = || {
+            let record = Record::command(
+                "counter:1",
+                Increment("counter:1".into()),
+                chrono::Utc::now(),
+                "Increment".into(),
+                0,
+            )
+            .with_idempotency_key("request:1");
+            counter.send(Process::<Counter, Increment, Incremented>::new(record))
+        };
+
+        let first = retry().await.unwrap().unwrap();
+        let retried = retry().await.unwrap().unwrap();
+
+        // The key is written with the events, covering their sequence numbers
+        assert_eq!(first.version(), 1);
+        assert_eq!(retried.version(), 1);
+        assert_eq!(seq_nrs(&store, "counter:1").await, vec![1]);
+        assert_eq!(
+            store
+                .read_idempotency_key("counter:1", "request:1")
+                .await
+                .unwrap(),
+            Some((1, 1))
+        );
+    }
+
+    #[actix::test]
+    async fn stuck_entities_are_restarted_once_their_write_is_done() {
+        let store = MemoryAdapter::new();
+        let counter = start("counter:1", &store, &EngineConfig::default());
+        let loaded = Arc::new(AtomicBool::new(true));
+        let (writing, watched) = watch::channel(false);
+        let started = tokio::time::Instant::now();
+
+        // The write outlasts the timeout, what follows it never finishes
+        let processing = async move {
+            writing.send_replace(true);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            writing.send_replace(false);
+            futures::future::pending::<Result<Unit, Error>>().await
+        };
+        let result = watch_over(
+            processing,
+            "counter:1".to_string(),
+            Some((Duration::from_millis(10), StuckEntityPolicy::Restart)),
+            loaded.clone(),
+            watched,
+            counter.recipient(),
+        )
+        .await;
+
+        assert!(matches!(&result, Err(e @ Error::EntityStuck(_)) if !e.is_retryable()));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(!loaded.load(Ordering::SeqCst));
+    }
+
+    async fn seq_nrs(store: &MemoryAdapter, entity_id: &str) -> Vec<i64> {
+        store
+            .replay::<Incremented>(entity_id, 0, u64::MAX, u64::MAX)
+            .await
+            .unwrap()
+            .map(|record| record.seq_nr())
+            .collect()
+            .await
+    }
+
+    #[actix::test]
+    async fn snapshots_are_written_at_their_cadence_and_loaded_with_the_tail() {
+        let store = MemoryAdapter::new();
+        let config = EngineConfig::default().with_snapshot_every::<Counter>(2);
+        let counter = start("counter:1", &store, &config);
+        let mut snapshots = Vec::new();
+        for _ in 0..5 {
+            increment(&counter, "counter:1").await.unwrap();
+            let snapshot = store.read_latest_snapshot("counter:1").await.unwrap();
+            snapshots.push(snapshot.map(|snapshot| snapshot.seq_nr()));
+        }
+
+        // A snapshot that differs from the events tells whether it is used, no event of the
+        // counter deletes it so the deletion can only come from the snapshot
+        store
+            .write_snapshot("counter:1", 4, 0, &Counter { count: 100 }, true)
+            .await
+            .unwrap();
+        let loaded = load::<Counter, MemoryAdapter, Incremented>(&store, "counter:1", &config)
+            .await
+            .unwrap();
+
+        assert_eq!(snapshots, vec![None, Some(2), Some(2), Some(4), Some(4)]);
+        assert_eq!(loaded, (5, Counter { count: 101 }, true));
+    }
+
+    #[actix::test]
+    async fn aggregate_types_sharing_a_store_replay_on_their_own() {
+        let store = MemoryAdapter::new();
+        let mut orders: TestEngine<Counter, Increment> =
+            TestEngine::with_store(store.clone()).with_aggregate_type("order");
+        let mut payments: TestEngine<Counter, Increment> =
+            TestEngine::with_store(store.clone()).with_aggregate_type("payment");
+
+        for _ in 0..2 {
+            orders.enqueue(Increment("order:42".into())).await.unwrap();
+        }
+        payments
+            .enqueue(Increment("payment:42".into()))
+            .await
+            .unwrap();
+        let misplaced = orders.enqueue(Increment("payment:42".into())).await;
+
+        assert!(matches!(misplaced, Err(Error::InvalidEntityId(_))));
+        assert_eq!(seq_nrs(&store, "order:42").await, vec![1, 2]);
+        assert_eq!(seq_nrs(&store, "payment:42").await, vec![1]);
+        assert_eq!(payments.state("payment:42"), Counter { count: 1 });
+    }
+
+    const SHELF: &str = "shelf:1";
+
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    struct Shelf {
+        items: u64,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    enum Moved {
+        Stocked(u64),
+        Taken(u64),
+    }
+
+    impl Event<Shelf> for Moved {
+        fn apply(&self, state: &Shelf) -> Option<Shelf> {
+            let items = match self {
+                Moved::Stocked(items) => state.items + items,
+                // Taking more than is on the shelf cannot be applied
+                Moved::Taken(items) => state.items.checked_sub(*items)?,
+            };
+            Some(Shelf { items })
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Move(Vec<Moved>);
+
+    impl Command<Shelf> for Move {
+        type T = Moved;
+
+        fn validate(&self, _: &Shelf) -> Result<Unit, Error> {
+            Ok(())
+        }
+
+        fn directive(&self, _: &Shelf) -> Result<NonEmptyVec<Box<Moved>>, Error> {
+            Ok(NonEmptyVec::new(
+                self.0.iter().cloned().map(Box::new).collect(),
+            )?)
+        }
+
+        fn entity_id(&self) -> String {
+            SHELF.to_string()
+        }
+    }
+
+    async fn replayed(store: &MemoryAdapter) -> Vec<(i64, Moved)> {
+        store
+            .replay::<Moved>(SHELF, 0, u64::MAX, u64::MAX)
+            .await
+            .unwrap()
+            .map(|record| (record.seq_nr(), record.message().clone()))
+            .collect()
+            .await
+    }
+
+    #[actix::test]
+    async fn strict_policy_rejects_the_command_without_writing_it() {
+        let store = MemoryAdapter::new();
+        let mut shelf: TestEngine<Shelf, Move> = TestEngine::with_store(store.clone())
+            .with_apply_failure_policy(ApplyFailurePolicy::Strict);
+        shelf.enqueue(Move(vec![Moved::Stocked(3)])).await.unwrap();
+
+        let rejected = shelf
+            .enqueue(Move(vec![
+                Moved::Taken(1),
+                Moved::Taken(5),
+                Moved::Stocked(1),
+            ]))
+            .await;
+
+        assert!(rejected.is_err());
+        assert_eq!(shelf.state(SHELF), Shelf { items: 3 });
+        assert_eq!(replayed(&store).await, vec![(1, Moved::Stocked(3))]);
+    }
+
+    #[actix::test]
+    async fn lenient_policy_writes_the_events_that_apply() {
+        let store = MemoryAdapter::new();
+        let mut shelf: TestEngine<Shelf, Move> = TestEngine::with_store(store.clone())
+            .with_apply_failure_policy(ApplyFailurePolicy::Lenient);
+        shelf.enqueue(Move(vec![Moved::Stocked(3)])).await.unwrap();
+
+        let outcome = shelf
+            .enqueue(Move(vec![
+                Moved::Taken(1),
+                Moved::Taken(5),
+                Moved::Stocked(1),
+            ]))
+            .await
+            .unwrap();
+
+        // The skipped event leaves no gap in the sequence numbers
+        assert_eq!(outcome.state(), &Shelf { items: 3 });
+        assert_eq!(outcome.version(), 3);
+        assert_eq!(
+            replayed(&store).await,
+            vec![
+                (1, Moved::Stocked(3)),
+                (2, Moved::Taken(1)),
+                (3, Moved::Stocked(1)),
+            ]
+        );
+
+        // Nothing can be taken from an empty shelf, so nothing is written
+        let mut empty: TestEngine<Shelf, Move> =
+            TestEngine::new().with_apply_failure_policy(ApplyFailurePolicy::Lenient);
+        assert!(empty.enqueue(Move(vec![Moved::Taken(1)])).await.is_err());
+    }
+
+    #[actix::test]
+    async fn replay_checks_catch_events_that_do_not_replay_as_applied() {
+        let store = MemoryAdapter::new();
+        let mut shelf: TestEngine<Shelf, Move> = TestEngine::with_store(store.clone());
+        shelf.seed(SHELF, Shelf { items: 5 }, 0);
+        shelf
+            .enqueue(Move(vec![Moved::Taken(2), Moved::Stocked(1)]))
+            .await
+            .unwrap();
+        let metas = [1, 2].map(|seq_nr| EventMeta::new(SHELF, seq_nr, chrono::Utc::now()));
+        let check = |events: Vec<Moved>, items: u64| {
+            let store = store.clone();
+            let metas = metas.clone();
+            async move {
+                let events = NonEmptyVec::new(events.into_iter().map(Box::new).collect())?;
+                check_replays::<Shelf, MemoryAdapter, Moved>(
+                    &store,
+                    SHELF,
+                    &Shelf { items },
+                    &events,
+                    &metas,
+                )
+                .await
+            }
+        };
+
+        let applied = check(vec![Moved::Taken(2), Moved::Stocked(1)], 5).await;
+        let reordered = check(vec![Moved::Stocked(1), Moved::Taken(2)], 5).await;
+        // Two items cannot be taken from a shelf holding one
+        let unappliable = check(vec![Moved::Taken(2), Moved::Stocked(1)], 1).await;
+
+        assert!(applied.is_ok());
+        assert!(matches!(reordered, Err(Error::InvalidState(_))));
+        assert!(matches!(unappliable, Err(Error::InvalidState(_))));
+    }
+
+    // Every state its effects saw, as (before, after), shared by the copies of a tally
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    struct Tally {
+        entity_id: String,
+        fail: bool,
+        #[serde(skip)]
+        seen: Arc<std::sync::Mutex<Vec<(u64, u64)>>>,
+    }
+
+    impl Command<Counter> for Tally {
+        type T = Incremented;
+
+        fn validate(&self, _: &Counter) -> Result<Unit, Error> {
+            Ok(())
+        }
+
+        fn directive(&self, _: &Counter) -> Result<NonEmptyVec<Box<Incremented>>, Error> {
+            Ok(NonEmptyVec::new(vec![Box::new(Incremented)])?)
+        }
+
+        fn entity_id(&self) -> String {
+            self.entity_id.clone()
+        }
+
+        async fn effects(&self, before: &Counter, after: &Counter) -> Result<Unit, Error> {
+            self.seen.lock().unwrap().push((before.count, after.count));
+            match self.fail {
+                true => Err(Error::Error("the tally failed".into())),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[actix::test]
+    async fn effects_run_once_per_command_in_order() {
+        let store = MemoryAdapter::new();
+        let counter = start("counter:1", &store, &EngineConfig::default());
+        let tally = Tally {
+            entity_id: "counter:1".into(),
+            ..Tally::default()
+        };
+        let send = |tally: Tally| {
+            let record = Record::command("counter:1", tally, chrono::Utc::now(), "Tally".into(), 0);
+            counter.send(Process::<Counter, Tally, Incremented>::new(record))
+        };
+
+        let mut outcomes = Vec::new();
+        for fail in [false, false, true, false] {
+            let tally = Tally {
+                fail,
+                ..tally.clone()
+            };
+            outcomes.push(send(tally).await.unwrap().map(|outcome| outcome.version()));
+        }
 
-        Box::pin(async move { Ok(state.lock().await.clone()) })
+        // Effects run once the events are applied, seeing the state before and after them
+        assert_eq!(
+            *tally.seen.lock().unwrap(),
+            vec![(0, 1), (1, 2), (2, 3), (3, 4)]
+        );
+        // A failed effect fails its command, its events are written all the same and the
+        // next command follows them
+        assert!(matches!(&outcomes[2], Err(Error::Error(e)) if e == "the tally failed"));
+        assert_eq!(outcomes[3].as_ref().unwrap(), &4);
+        assert_eq!(seq_nrs(&store, "counter:1").await, vec![1, 2, 3, 4]);
     }
 }
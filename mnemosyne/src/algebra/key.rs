@@ -0,0 +1,127 @@
+use crate::domain::Error;
+use std::{fmt, str::FromStr};
+
+const SEPARATOR: char = ':';
+const ESCAPE: char = '\\';
+
+/// An entity id made of several fields, e.g. `(game_id, round)`.
+///
+/// The fields are joined with `:` into a single entity id, separators and backslashes
+/// inside a field are escaped with a backslash. Encoding is deterministic and decoding is
+/// its exact inverse, whatever the fields contain.
+///
+/// # Examples
+/// ```rust,ignore
+/// fn entity_id(&self) -> String {
+///     CompositeKey::new(&self.game_id).with(self.round).into()
+/// }
+///
+/// let key: CompositeKey = record.entity_id().parse()?;
+/// let round: u32 = key.get(1).unwrap().parse()?;
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CompositeKey {
+    fields: Vec<String>,
+}
+
+impl CompositeKey {
+    /// Create a key from its first field.
+    pub fn new(field: impl ToString) -> Self {
+        Self {
+            fields: vec![field.to_string()],
+        }
+    }
+
+    /// Append a field to the key.
+    pub fn with(mut self, field: impl ToString) -> Self {
+        self.fields.push(field.to_string());
+        self
+    }
+
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.fields.get(index).map(String::as_str)
+    }
+
+    /// Decode an entity id produced by `CompositeKey`. Fails on a trailing escape, which
+    /// encoding never produces.
+    pub fn parse(entity_id: &str) -> Result<Self, Error> {
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut chars = entity_id.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                ESCAPE => match chars.next() {
+                    Some(escaped) => field.push(escaped),
+                    None => {
+                        return Err(Error::InvalidEntityId(format!(
+                            "Composite key {} ends with an escape",
+                            entity_id
+                        )))
+                    }
+                },
+                SEPARATOR => fields.push(std::mem::take(&mut field)),
+                c => field.push(c),
+            }
+        }
+        fields.push(field);
+
+        Ok(Self { fields })
+    }
+}
+
+impl fmt::Display for CompositeKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, field) in self.fields.iter().enumerate() {
+            if index > 0 {
+                write!(f, "{}", SEPARATOR)?;
+            }
+            for c in field.chars() {
+                if c == SEPARATOR || c == ESCAPE {
+                    write!(f, "{}", ESCAPE)?;
+                }
+                write!(f, "{}", c)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for CompositeKey {
+    type Err = Error;
+
+    fn from_str(entity_id: &str) -> Result<Self, Self::Err> {
+        Self::parse(entity_id)
+    }
+}
+
+impl From<CompositeKey> for String {
+    fn from(key: CompositeKey) -> Self {
+        key.to_string()
+    }
+}
+
+impl<A, B> From<(A, B)> for CompositeKey
+where
+    A: ToString,
+    B: ToString,
+{
+    fn from((a, b): (A, B)) -> Self {
+        Self::new(a).with(b)
+    }
+}
+
+impl<A, B, C> From<(A, B, C)> for CompositeKey
+where
+    A: ToString,
+    B: ToString,
+    C: ToString,
+{
+    fn from((a, b, c): (A, B, C)) -> Self {
+        Self::new(a).with(b).with(c)
+    }
+}
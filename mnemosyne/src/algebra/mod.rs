@@ -1,17 +1,41 @@
 mod aggregate;
+mod audit;
 mod command;
+mod effects;
 mod engine;
+mod envelope;
 mod event;
+mod handle;
 mod init;
 mod inner;
+mod key;
+mod outcome;
+mod projection;
 mod record;
+mod relay;
 mod schedule;
+mod state;
+mod subscription;
+mod test_engine;
+mod topic;
 
 pub(crate) use aggregate::*;
+pub use audit::*;
 pub use command::*;
+pub(crate) use effects::*;
 pub use engine::*;
+pub use envelope::*;
 pub use event::*;
+pub use handle::*;
 pub(crate) use init::*;
 pub(crate) use inner::*;
-pub(crate) use record::*;
-pub(crate) use schedule::*;
+pub use key::*;
+pub use outcome::*;
+pub use projection::*;
+pub use record::*;
+pub(crate) use relay::*;
+pub use schedule::*;
+pub use state::*;
+pub use subscription::*;
+pub use test_engine::*;
+pub use topic::*;
@@ -0,0 +1,55 @@
+use crate::domain::NonEmptyVec;
+
+/// The outcome of a command that was applied, as resolved by `EnqueueHandle` and returned by
+/// `TestEngine::enqueue`.
+///
+/// A command that is not applied, e.g. one rejected by `Command::validate`, yields an error
+/// instead, see `Error::Rejected`.
+///
+/// # Examples
+/// ```rust,ignore
+/// let outcome = engine.enqueue(command).await?.await?;
+/// render(outcome.state());
+/// tracing::info!("Entity at version {}", outcome.version());
+/// ```
+#[derive(Debug, Clone)]
+pub struct CommandOutcome<State, Evt> {
+    events: NonEmptyVec<Box<Evt>>,
+    version: u64,
+    state: State,
+}
+
+impl<State, Evt> CommandOutcome<State, Evt> {
+    pub(crate) fn new(events: NonEmptyVec<Box<Evt>>, version: u64, state: State) -> Self {
+        Self {
+            events,
+            version,
+            state,
+        }
+    }
+
+    /// The events the command produced.
+    pub fn events(&self) -> &NonEmptyVec<Box<Evt>> {
+        &self.events
+    }
+
+    pub fn into_events(self) -> NonEmptyVec<Box<Evt>> {
+        self.events
+    }
+
+    /// The version of the entity once the command was applied, i.e. the sequence number of
+    /// the last event the command produced.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// The state of the entity once the command was applied. For a command deduplicated by
+    /// its idempotency key, this is the state of the entity when the retry was received.
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn into_state(self) -> State {
+        self.state
+    }
+}
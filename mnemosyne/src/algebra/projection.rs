@@ -0,0 +1,486 @@
+use super::Record;
+use crate::{
+    domain::{Error, Pacer, ReplayThrottle, PROJECTION_BATCH_SIZE, PROJECTION_INTERVAL},
+    storage::{Adapter, CheckpointStore},
+};
+use actix::prelude::*;
+use futures::{future::LocalBoxFuture, lock::Mutex, FutureExt, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::HashSet, fmt::Debug, sync::Arc, time::Duration};
+
+type Fold<ReadModel, Evt> = Arc<dyn Fn(&mut ReadModel, &Record<Evt>) + Send + Sync>;
+
+// An event read by a source, folded into the read model when applied
+type Apply<ReadModel> = Box<dyn FnOnce(&mut ReadModel)>;
+
+// Reads up to `max` events from a global offset on, paired with their global offset
+type Source<ReadModel, Store> = Arc<
+    dyn Fn(&Store, u64, u64) -> LocalBoxFuture<'static, Result<Vec<(u64, Apply<ReadModel>)>, Error>>
+        + Send
+        + Sync,
+>;
+
+/// Builds a projection, which folds every event in the store into a read model.
+///
+/// The projection tails the store through `Adapter::stream_all`, or
+/// `Adapter::replay_category` for a category projection, and saves its position
+/// to a `CheckpointStore` after every batch, so a restarted projection resumes where it
+/// left off. Only the position is checkpointed: the read model handed to the builder is
+/// what the projection resumes with, so it should either be restored by the caller or
+/// the fold should write to a durable read model.
+///
+/// # Examples
+/// ```rust,ignore
+/// let projection = ProjectionBuilder::new("user-count", 0u64)
+///     .fold(|count, _record: &Record<UserEvent>| *count += 1)
+///     .start(store.clone(), store)?;
+///
+/// let count = projection.query(|count| *count).await;
+/// ```
+pub struct ProjectionBuilder<ReadModel, Evt> {
+    name: String,
+    read_model: ReadModel,
+    fold: Option<Fold<ReadModel, Evt>>,
+    batch_size: u64,
+    interval: Duration,
+    throttle: ReplayThrottle,
+    category: Option<String>,
+    arbiter: Option<ArbiterHandle>,
+}
+
+impl<ReadModel, Evt> ProjectionBuilder<ReadModel, Evt>
+where
+    ReadModel: Send + 'static,
+    Evt: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+{
+    /// Create a builder for a projection with the given name, starting from the given
+    /// read model. The name identifies the checkpoint of the projection.
+    pub fn new(name: &str, read_model: ReadModel) -> Self {
+        Self {
+            name: name.to_string(),
+            read_model,
+            fold: None,
+            batch_size: PROJECTION_BATCH_SIZE,
+            interval: Duration::from_secs(PROJECTION_INTERVAL),
+            throttle: ReplayThrottle::default(),
+            category: None,
+            arbiter: None,
+        }
+    }
+
+    /// Register the function folding an event into the read model.
+    pub fn fold(
+        mut self,
+        fold: impl Fn(&mut ReadModel, &Record<Evt>) + Send + Sync + 'static,
+    ) -> Self {
+        self.fold = Some(Arc::new(fold));
+        self
+    }
+
+    /// Set the maximum number of events folded per poll.
+    pub fn batch_size(mut self, batch_size: u64) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Set how often the store is polled for new events.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Limit the rate events are folded at, so rebuilding the read model over a large
+    /// event log does not starve live traffic. Only `max_events_per_second` applies to
+    /// projections.
+    pub fn throttle(mut self, throttle: ReplayThrottle) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    /// Only fold the events of the given category, e.g. every order for `order`, read
+    /// through `Adapter::replay_category`. See `category` for how the category of an entity
+    /// is derived from its id.
+    pub fn category(mut self, category: &str) -> Self {
+        self.category = Some(category.to_string());
+        self
+    }
+
+    /// Run the projection on the given arbiter, see `EngineConfig::with_arbiter`.
+    pub fn arbiter(mut self, arbiter: ArbiterHandle) -> Self {
+        self.arbiter = Some(arbiter);
+        self
+    }
+
+    /// Start the projection, reading events from `store` and saving its position to
+    /// `checkpoints`. Must be called from within a running actix system, unless an
+    /// arbiter is set.
+    pub fn start<Store, Checkpoints>(
+        self,
+        store: Store,
+        checkpoints: Checkpoints,
+    ) -> Result<ProjectionHandle<ReadModel>, Error>
+    where
+        Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+        Checkpoints: CheckpointStore + Clone + Send + Sync + 'static + Unpin,
+    {
+        let fold = self.fold.ok_or_else(|| {
+            Error::InvalidConfiguration(format!(
+                "Projection {} has no fold function registered",
+                self.name
+            ))
+        })?;
+        let source = source::<ReadModel, Evt, Store>(self.category, fold);
+
+        Ok(Projection {
+            name: self.name,
+            store,
+            checkpoints,
+            read_model: Arc::new(Mutex::new(self.read_model)),
+            sources: vec![source],
+            position: 0,
+            batch_size: self.batch_size,
+            interval: self.interval,
+            throttle: self.throttle,
+        }
+        .spawn(self.arbiter))
+    }
+}
+
+/// Builds a projection folding the events of several categories, each with its own event
+/// type, into one read model, e.g. an order status view built from both orders and
+/// payments.
+///
+/// Every category is read through `Adapter::replay_category` as the event type of its
+/// fold, and the events of all categories are folded in the order they were written, i.e.
+/// by global offset. Events of other categories are skipped. The projection is checkpointed
+/// like one built with `ProjectionBuilder`.
+///
+/// # Examples
+/// ```rust,ignore
+/// let projection = MultiProjectionBuilder::new("order-status", OrderStatus::default())
+///     .fold("order", |status, record: &Record<OrderEvent>| status.apply_order(record))
+///     .fold("payment", |status, record: &Record<PaymentEvent>| status.apply_payment(record))
+///     .start(store.clone(), store)?;
+///
+/// let paid = projection.query(|status| status.is_paid("order:42")).await;
+/// ```
+pub struct MultiProjectionBuilder<ReadModel, Store> {
+    name: String,
+    read_model: ReadModel,
+    sources: Vec<(String, Source<ReadModel, Store>)>,
+    batch_size: u64,
+    interval: Duration,
+    throttle: ReplayThrottle,
+    arbiter: Option<ArbiterHandle>,
+}
+
+impl<ReadModel, Store> MultiProjectionBuilder<ReadModel, Store>
+where
+    ReadModel: Send + 'static,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+{
+    /// Create a builder for a projection with the given name, starting from the given
+    /// read model. The name identifies the checkpoint of the projection.
+    pub fn new(name: &str, read_model: ReadModel) -> Self {
+        Self {
+            name: name.to_string(),
+            read_model,
+            sources: Vec::new(),
+            batch_size: PROJECTION_BATCH_SIZE,
+            interval: Duration::from_secs(PROJECTION_INTERVAL),
+            throttle: ReplayThrottle::default(),
+            arbiter: None,
+        }
+    }
+
+    /// Register the function folding the events of a category into the read model. The
+    /// events of the category are read as `Evt`. See `category` for how the category of an
+    /// entity is derived from its id.
+    pub fn fold<Evt>(
+        mut self,
+        category: &str,
+        fold: impl Fn(&mut ReadModel, &Record<Evt>) + Send + Sync + 'static,
+    ) -> Self
+    where
+        Evt: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    {
+        let source = source::<ReadModel, Evt, Store>(Some(category.to_string()), Arc::new(fold));
+        self.sources.push((category.to_string(), source));
+        self
+    }
+
+    /// Set the maximum number of events folded per poll, across all categories.
+    pub fn batch_size(mut self, batch_size: u64) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Set how often the store is polled for new events.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Limit the rate events are folded at, see `ProjectionBuilder::throttle`.
+    pub fn throttle(mut self, throttle: ReplayThrottle) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    /// Run the projection on the given arbiter, see `EngineConfig::with_arbiter`.
+    pub fn arbiter(mut self, arbiter: ArbiterHandle) -> Self {
+        self.arbiter = Some(arbiter);
+        self
+    }
+
+    /// Start the projection, reading events from `store` and saving its position to
+    /// `checkpoints`. Fails if no fold is registered, or more than one for a category.
+    /// Must be called from within a running actix system, unless an arbiter is set.
+    pub fn start<Checkpoints>(
+        self,
+        store: Store,
+        checkpoints: Checkpoints,
+    ) -> Result<ProjectionHandle<ReadModel>, Error>
+    where
+        Checkpoints: CheckpointStore + Clone + Send + Sync + 'static + Unpin,
+    {
+        if self.sources.is_empty() {
+            return Err(Error::InvalidConfiguration(format!(
+                "Projection {} has no fold function registered",
+                self.name
+            )));
+        }
+
+        let mut categories = HashSet::new();
+        if let Some((category, _)) = self
+            .sources
+            .iter()
+            .find(|(category, _)| !categories.insert(category.as_str()))
+        {
+            return Err(Error::InvalidConfiguration(format!(
+                "Projection {} has more than one fold function registered for category {}",
+                self.name, category
+            )));
+        }
+
+        Ok(Projection {
+            name: self.name,
+            store,
+            checkpoints,
+            read_model: Arc::new(Mutex::new(self.read_model)),
+            sources: self.sources.into_iter().map(|(_, source)| source).collect(),
+            position: 0,
+            batch_size: self.batch_size,
+            interval: self.interval,
+            throttle: self.throttle,
+        }
+        .spawn(self.arbiter))
+    }
+}
+
+// Read the events of a category, or every event, as `Evt` and fold them with `fold`
+fn source<ReadModel, Evt, Store>(
+    category: Option<String>,
+    fold: Fold<ReadModel, Evt>,
+) -> Source<ReadModel, Store>
+where
+    ReadModel: 'static,
+    Evt: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    Store: Adapter + Clone + 'static,
+{
+    Arc::new(move |store: &Store, position, max| {
+        let store = store.clone();
+        let category = category.clone();
+        let fold = fold.clone();
+
+        async move {
+            let records = match &category {
+                Some(category) => {
+                    store
+                        .replay_category::<Evt>(category, position, max)
+                        .await?
+                }
+                None => store.stream_all::<Evt>(position, max).await?,
+            };
+
+            Ok(records
+                .map(|(offset, record)| {
+                    let fold = fold.clone();
+                    let apply: Apply<ReadModel> =
+                        Box::new(move |read_model| fold(read_model, &record));
+                    (offset, apply)
+                })
+                .collect()
+                .await)
+        }
+        .boxed_local()
+    })
+}
+
+/// Handle to a running projection, used to query its read model.
+pub struct ProjectionHandle<ReadModel> {
+    read_model: Arc<Mutex<ReadModel>>,
+}
+
+impl<ReadModel> Clone for ProjectionHandle<ReadModel> {
+    fn clone(&self) -> Self {
+        Self {
+            read_model: self.read_model.clone(),
+        }
+    }
+}
+
+impl<ReadModel> ProjectionHandle<ReadModel> {
+    /// Query the current read model. The projection does not fold new events while the
+    /// query runs.
+    pub async fn query<R>(&self, query: impl FnOnce(&ReadModel) -> R) -> R {
+        query(&*self.read_model.lock().await)
+    }
+
+    /// Return a copy of the current read model.
+    pub async fn read_model(&self) -> ReadModel
+    where
+        ReadModel: Clone,
+    {
+        self.read_model.lock().await.clone()
+    }
+}
+
+/// Polls the store for new events and folds them into the read model.
+pub(crate) struct Projection<ReadModel, Store, Checkpoints> {
+    name: String,
+    store: Store,
+    checkpoints: Checkpoints,
+    read_model: Arc<Mutex<ReadModel>>,
+    sources: Vec<Source<ReadModel, Store>>,
+    // Global offset of the next event to fold
+    position: u64,
+    batch_size: u64,
+    interval: Duration,
+    throttle: ReplayThrottle,
+}
+
+impl<ReadModel, Store, Checkpoints> Projection<ReadModel, Store, Checkpoints>
+where
+    ReadModel: Send + 'static,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Checkpoints: CheckpointStore + Clone + Send + Sync + 'static + Unpin,
+{
+    fn spawn(self, arbiter: Option<ArbiterHandle>) -> ProjectionHandle<ReadModel> {
+        let read_model = self.read_model.clone();
+        match arbiter {
+            Some(arbiter) => {
+                Supervisor::start_in_arbiter(&arbiter, |_| self);
+            }
+            None => {
+                Supervisor::start(|_| self);
+            }
+        }
+
+        ProjectionHandle { read_model }
+    }
+}
+
+impl<ReadModel, Store, Checkpoints> Actor for Projection<ReadModel, Store, Checkpoints>
+where
+    ReadModel: Send + 'static,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Checkpoints: CheckpointStore + Clone + Send + Sync + 'static + Unpin,
+{
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let checkpoints = self.checkpoints.clone();
+        let name = self.name.clone();
+
+        // Resume from the last checkpoint before polling for events. This also runs when
+        // the supervisor restarts the projection.
+        ctx.wait(
+            async move { checkpoints.load_checkpoint(&name).await }
+                .into_actor(self)
+                .map(|checkpoint, act, _| match checkpoint {
+                    Ok(Some(position)) => act.position = position,
+                    Ok(None) => {}
+                    Err(e) => tracing::error!(
+                        "Could not load the checkpoint of projection {}: {}",
+                        act.name,
+                        e
+                    ),
+                }),
+        );
+
+        ctx.run_interval(self.interval, |act, ctx| {
+            let reads = act
+                .sources
+                .iter()
+                .map(|source| source(&act.store, act.position, act.batch_size))
+                .collect::<Vec<_>>();
+            let checkpoints = act.checkpoints.clone();
+            let read_model = act.read_model.clone();
+            let name = act.name.clone();
+            let position = act.position;
+            let batch_size = act.batch_size;
+            let mut pacer = Pacer::new(&act.throttle);
+
+            let future = async move {
+                let mut events = Vec::new();
+                for read in reads {
+                    match read.await {
+                        Ok(read) => events.extend(read),
+                        Err(e) => {
+                            tracing::error!("Could not read events for projection {}: {}", name, e);
+                            return position;
+                        }
+                    }
+                }
+
+                // Every source read at most a batch past the position, so the first batch
+                // of the merged events holds every event up to its last one
+                events.sort_by_key(|(offset, _)| *offset);
+                events.truncate(batch_size as usize);
+
+                let next = match events.last() {
+                    Some((offset, _)) => offset + 1,
+                    None => return position,
+                };
+
+                for (_, apply) in events {
+                    let delay = pacer.next_delay();
+                    if !delay.is_zero() {
+                        tokio::time::sleep(delay).await;
+                    }
+
+                    // The read model is locked per event, so queries are not blocked by a
+                    // throttled batch
+                    apply(&mut *read_model.lock().await);
+                }
+
+                if let Err(e) = checkpoints.save_checkpoint(&name, next).await {
+                    tracing::error!(
+                        "Could not save the checkpoint of projection {}: {}",
+                        name,
+                        e
+                    );
+                }
+
+                next
+            };
+
+            // Wait for the batch to be folded before handling the next tick, so events are
+            // not folded twice by overlapping runs.
+            ctx.wait(
+                future
+                    .into_actor(act)
+                    .map(|position, act, _| act.position = position),
+            );
+        });
+    }
+}
+
+impl<ReadModel, Store, Checkpoints> Supervised for Projection<ReadModel, Store, Checkpoints>
+where
+    ReadModel: Send + 'static,
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+    Checkpoints: CheckpointStore + Clone + Send + Sync + 'static + Unpin,
+{
+}
@@ -1,7 +1,10 @@
+use crate::domain::CommandFormat;
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Record<T> {
     entity_id: String,
     seq_nr: i64,
@@ -9,6 +12,28 @@ pub struct Record<T> {
     message: T,
     #[serde(skip_serializing_if = "Option::is_none")]
     r#type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<Uuid>,
+    /// W3C `traceparent` of the span the command was enqueued in, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    traceparent: Option<String>,
+    /// Name of the service that produced the record, if it is known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    /// Key identifying retries of the same command, see
+    /// `Engine::enqueue_with_idempotency_key`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    idempotency_key: Option<String>,
+    /// Id correlating the command with the requests it relates to, see
+    /// `CommandEnvelope::correlation_id`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    correlation_id: Option<Uuid>,
+    /// When the command expires, it is rejected if it is not processed before.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+    /// Hash chaining the event to the event before it, see `verify_integrity`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
 }
 
 impl<T> Record<T> {
@@ -19,6 +44,13 @@ impl<T> Record<T> {
             message,
             timestamp,
             r#type: None,
+            id: None,
+            traceparent: None,
+            source: None,
+            idempotency_key: None,
+            correlation_id: None,
+            expires_at: None,
+            hash: None,
         }
     }
 
@@ -39,9 +71,60 @@ impl<T> Record<T> {
             message,
             timestamp,
             r#type: Some(command),
+            id: None,
+            traceparent: None,
+            source: None,
+            idempotency_key: None,
+            correlation_id: None,
+            expires_at: None,
+            hash: None,
         }
     }
 
+    /// Attach an id to the record, used to correlate a command with its outcome. Events carry
+    /// the id of the command that produced them.
+    pub fn with_id(mut self, id: Uuid) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    /// Attach the W3C `traceparent` of the span a command is enqueued in, so processing
+    /// it can be linked to that trace.
+    pub fn with_traceparent(mut self, traceparent: impl Into<String>) -> Self {
+        self.traceparent = Some(traceparent.into());
+        self
+    }
+
+    /// Attach the name of the service producing the record.
+    pub fn with_source(mut self, source: impl Into<String>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// Attach the idempotency key of a command.
+    pub fn with_idempotency_key(mut self, idempotency_key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(idempotency_key.into());
+        self
+    }
+
+    /// Attach the correlation id of a command.
+    pub fn with_correlation_id(mut self, correlation_id: Uuid) -> Self {
+        self.correlation_id = Some(correlation_id);
+        self
+    }
+
+    /// Attach the time a command expires at.
+    pub fn with_expires_at(mut self, expires_at: DateTime<Utc>) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    /// Attach the hash chaining an event to the event before it.
+    pub fn with_hash(mut self, hash: impl Into<String>) -> Self {
+        self.hash = Some(hash.into());
+        self
+    }
+
     pub fn message(&self) -> &T {
         &self.message
     }
@@ -54,10 +137,46 @@ impl<T> Record<T> {
         self.r#type.as_deref()
     }
 
+    pub fn id(&self) -> Option<Uuid> {
+        self.id
+    }
+
+    pub fn traceparent(&self) -> Option<&str> {
+        self.traceparent.as_deref()
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    pub fn idempotency_key(&self) -> Option<&str> {
+        self.idempotency_key.as_deref()
+    }
+
+    pub fn correlation_id(&self) -> Option<Uuid> {
+        self.correlation_id
+    }
+
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.expires_at
+    }
+
+    /// The hash of an event, computed from the event and the hash of the event before it,
+    /// so that altering an event that was written is detected, see `verify_integrity`.
+    /// None for events written before events were hashed.
+    pub fn hash(&self) -> Option<&str> {
+        self.hash.as_deref()
+    }
+
     pub fn entity_id(&self) -> &str {
         &self.entity_id
     }
 
+    /// The category of the entity, see `category`.
+    pub fn category(&self) -> &str {
+        category(&self.entity_id)
+    }
+
     pub fn seq_nr(&self) -> i64 {
         self.seq_nr
     }
@@ -65,4 +184,169 @@ impl<T> Record<T> {
     pub fn timestamp(&self) -> DateTime<Utc> {
         self.timestamp
     }
+
+    /// Transform the message of the record, keeping its metadata.
+    ///
+    /// # Examples
+    /// ```rust,ignore
+    /// let upcasted: Record<UserEventV2> = record.map(UserEventV2::from);
+    /// ```
+    pub fn map<B>(self, f: impl FnOnce(T) -> B) -> Record<B> {
+        Record {
+            entity_id: self.entity_id,
+            seq_nr: self.seq_nr,
+            timestamp: self.timestamp,
+            message: f(self.message),
+            r#type: self.r#type,
+            id: self.id,
+            traceparent: self.traceparent,
+            source: self.source,
+            idempotency_key: self.idempotency_key,
+            correlation_id: self.correlation_id,
+            expires_at: self.expires_at,
+            hash: self.hash,
+        }
+    }
+
+    /// Borrow the message of the record, e.g. to write it with `Adapter::write`.
+    pub(crate) fn borrowed(&self) -> Record<&T> {
+        Record {
+            entity_id: self.entity_id.clone(),
+            seq_nr: self.seq_nr,
+            timestamp: self.timestamp,
+            message: &self.message,
+            r#type: self.r#type.clone(),
+            id: self.id,
+            traceparent: self.traceparent.clone(),
+            source: self.source.clone(),
+            idempotency_key: self.idempotency_key.clone(),
+            correlation_id: self.correlation_id,
+            expires_at: self.expires_at,
+            hash: self.hash.clone(),
+        }
+    }
+
+    /// Transform the message of the record with a fallible function, keeping its metadata.
+    pub fn try_map<B, E>(self, f: impl FnOnce(T) -> Result<B, E>) -> Result<Record<B>, E> {
+        Ok(Record {
+            entity_id: self.entity_id,
+            seq_nr: self.seq_nr,
+            timestamp: self.timestamp,
+            message: f(self.message)?,
+            r#type: self.r#type,
+            id: self.id,
+            traceparent: self.traceparent,
+            source: self.source,
+            idempotency_key: self.idempotency_key,
+            correlation_id: self.correlation_id,
+            expires_at: self.expires_at,
+            hash: self.hash,
+        })
+    }
+}
+
+/// The category of an entity id, i.e. the part before the first `:`, or the whole id if it
+/// has none. Entities of the same aggregate type share a prefix, e.g. `order:42`, which makes
+/// the category the stream of every entity of that type.
+pub fn category(entity_id: &str) -> &str {
+    entity_id
+        .split_once(CATEGORY_SEPARATOR)
+        .map_or(entity_id, |(category, _)| category)
+}
+
+const CATEGORY_SEPARATOR: char = ':';
+
+/// Flattened representation of a command record, see `CommandFormat::Flat`.
+#[derive(Serialize, Deserialize)]
+struct FlatRecord<T> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    entity_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seq_nr: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    traceparent: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    idempotency_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    correlation_id: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+    #[serde(flatten)]
+    message: T,
+}
+
+impl<T> Record<T> {
+    /// Encode a command record in the given wire format.
+    pub(crate) fn encode(&self, format: CommandFormat) -> serde_json::Result<Vec<u8>>
+    where
+        T: Serialize,
+    {
+        match format {
+            CommandFormat::Enveloped => serde_json::to_vec(self),
+            CommandFormat::Flat => serde_json::to_vec(&FlatRecord {
+                entity_id: Some(self.entity_id.clone()),
+                seq_nr: Some(self.seq_nr),
+                timestamp: Some(self.timestamp),
+                id: self.id,
+                traceparent: self.traceparent.clone(),
+                source: self.source.clone(),
+                idempotency_key: self.idempotency_key.clone(),
+                correlation_id: self.correlation_id,
+                expires_at: self.expires_at,
+                message: &self.message,
+            }),
+        }
+    }
+
+    /// The entity id of an encoded command record, without decoding the command, or None if
+    /// the record does not carry one, e.g. a flat record from an external producer.
+    pub(crate) fn decode_entity_id(payload: &[u8]) -> Option<String> {
+        // Both wire formats carry the entity id at the top level
+        #[derive(Deserialize)]
+        struct Keyed {
+            #[serde(default)]
+            entity_id: Option<String>,
+        }
+
+        serde_json::from_slice::<Keyed>(payload).ok()?.entity_id
+    }
+
+    /// Decode a command record from the given wire format. `entity_id` is used to
+    /// derive the entity id of flat records that do not carry one.
+    pub(crate) fn decode(
+        payload: &[u8],
+        format: CommandFormat,
+        entity_id: impl FnOnce(&T) -> String,
+    ) -> serde_json::Result<Self>
+    where
+        T: DeserializeOwned,
+    {
+        match format {
+            CommandFormat::Enveloped => serde_json::from_slice(payload),
+            CommandFormat::Flat => {
+                let flat = serde_json::from_slice::<FlatRecord<T>>(payload)?;
+
+                Ok(Self {
+                    entity_id: flat.entity_id.unwrap_or_else(|| entity_id(&flat.message)),
+                    seq_nr: flat.seq_nr.unwrap_or_default(),
+                    timestamp: flat.timestamp.unwrap_or_else(Utc::now),
+                    message: flat.message,
+                    r#type: None,
+                    id: flat.id,
+                    traceparent: flat.traceparent,
+                    source: flat.source,
+                    idempotency_key: flat.idempotency_key,
+                    correlation_id: flat.correlation_id,
+                    expires_at: flat.expires_at,
+                    hash: None,
+                })
+            }
+        }
+    }
 }
@@ -0,0 +1,96 @@
+use super::send_event;
+use crate::{
+    domain::{ActorFailure, ActorKind, EngineConfig},
+    storage::Adapter,
+};
+use actix::prelude::*;
+use rdkafka::producer::FutureProducer;
+use std::sync::Arc;
+
+/// Publishes the entries of the storage outbox to the event topic, see `PublishMode::Outbox`.
+pub(crate) struct Relay<Store>
+where
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+{
+    store: Store,
+    producer: Arc<FutureProducer>,
+    config: EngineConfig,
+}
+
+impl<Store> Relay<Store>
+where
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+{
+    pub fn new(store: Store, producer: Arc<FutureProducer>, config: EngineConfig) -> Self {
+        Self {
+            store,
+            producer,
+            config,
+        }
+    }
+}
+
+impl<Store> Actor for Relay<Store>
+where
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+{
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.config.relay_interval(), |act, ctx| {
+            let store = act.store.clone();
+            let producer = act.producer.clone();
+            let batch_size = act.config.relay_batch_size();
+            let config = act.config.clone();
+            let codec = act.config.event_codec();
+
+            let future = async move {
+                let relayed = store
+                    .relay_outbox(batch_size, |entries| async move {
+                        let mut sent = Vec::with_capacity(entries.len());
+
+                        for entry in entries {
+                            match send_event(
+                                &producer,
+                                codec.as_ref(),
+                                entry.entity_id(),
+                                entry.payload(),
+                                entry.timestamp(),
+                            )
+                            .await
+                            {
+                                Ok(()) => sent.push(entry.id()),
+                                Err(e) => tracing::error!(
+                                    "Could not relay outbox entry {} of entity {}: {}",
+                                    entry.id(),
+                                    entry.entity_id(),
+                                    e
+                                ),
+                            }
+                        }
+
+                        sent
+                    })
+                    .await;
+
+                if let Err(e) = relayed {
+                    tracing::error!("Could not relay the outbox: {}", e);
+                    config.report(ActorFailure::new(ActorKind::Relay, None, e));
+                }
+            };
+
+            // Wait for the relay to finish before handling the next tick, so entries are
+            // not picked up twice by overlapping runs.
+            ctx.wait(future.into_actor(act));
+        });
+    }
+}
+
+impl<Store> Supervised for Relay<Store>
+where
+    Store: Adapter + Clone + Send + Sync + 'static + Unpin,
+{
+    fn restarting(&mut self, _: &mut Self::Context) {
+        self.config.restarted(ActorKind::Relay, None);
+    }
+}
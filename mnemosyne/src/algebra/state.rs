@@ -0,0 +1,18 @@
+/// Build the state of an entity before any event has been applied to it.
+///
+/// States that implement `Default` get this for free, the initial state of every entity
+/// then is the default state. States without a meaningful default implement it instead,
+/// e.g. to derive the initial state from the entity id.
+pub trait StateFactory: Sized {
+    /// Return the initial state of the entity with the given id.
+    fn initial(entity_id: &str) -> Self;
+}
+
+impl<State> StateFactory for State
+where
+    State: Default,
+{
+    fn initial(_entity_id: &str) -> Self {
+        State::default()
+    }
+}
@@ -0,0 +1,97 @@
+use super::Record;
+use crate::{
+    domain::{Error, EVENT_TOPIC},
+    Unit,
+};
+use futures::Future;
+use rdkafka::{
+    consumer::{CommitMode, Consumer, StreamConsumer},
+    ClientConfig, Message,
+};
+use serde::de::DeserializeOwned;
+
+/// A subscription to the event topic as a member of a Kafka consumer group.
+///
+/// Instances subscribing with the same group id share the events: Kafka assigns each
+/// partition of the event topic to a single member of the group, so every event is handled
+/// by one instance only. Events are keyed by entity id, hence all events of an entity are
+/// handled by the same instance and in order. There is no ordering across entities, nor
+/// across instances.
+///
+/// The position of the group is the committed offset. An event's offset is committed once
+/// its handler succeeds, so a restarted or rebalanced member resumes after the last handled
+/// event. An event may be handled twice if the instance stops between handling and
+/// committing, handlers should be idempotent.
+///
+/// # Examples
+/// ```rust,ignore
+/// let subscription = EventSubscription::<UserEvent>::new(&configuration, "user-read-model")?;
+///
+/// subscription
+///     .run(|record| async move { read_model.apply(record).await })
+///     .await?;
+/// ```
+pub struct EventSubscription<Evt> {
+    consumer: StreamConsumer,
+    _marker: std::marker::PhantomData<Evt>,
+}
+
+impl<Evt> EventSubscription<Evt>
+where
+    Evt: DeserializeOwned,
+{
+    /// Join the consumer group `group_id` and subscribe to `EVENT_TOPIC`. A group that has
+    /// not committed any offset yet starts from the earliest event.
+    pub fn new(configuration: &ClientConfig, group_id: &str) -> Result<Self, Error> {
+        let consumer = configuration
+            .clone()
+            .set("group.id", group_id)
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", "earliest")
+            .create::<StreamConsumer>()
+            .map_err(Error::Kafka)?;
+
+        consumer.subscribe(&[EVENT_TOPIC]).map_err(Error::Kafka)?;
+
+        Ok(Self {
+            consumer,
+            _marker: std::marker::PhantomData,
+        })
+    }
+
+    /// Handle the events assigned to this member, one after the other, committing the
+    /// offset of each event once it is handled. Returns on the first error, the offset of
+    /// the failed event is not committed so it is handled again once the subscription is
+    /// restarted.
+    pub async fn run<F, Fut>(&self, mut handler: F) -> Result<Unit, Error>
+    where
+        F: FnMut(Record<Evt>) -> Fut,
+        Fut: Future<Output = Result<Unit, Error>>,
+    {
+        loop {
+            let message = self.consumer.recv().await.map_err(Error::Kafka)?;
+
+            let payload = message.payload().ok_or_else(|| {
+                Error::InvalidEvent(format!(
+                    "Event at offset {} of partition {} has no payload",
+                    message.offset(),
+                    message.partition()
+                ))
+            })?;
+            let record = serde_json::from_slice::<Record<Evt>>(payload).map_err(|e| {
+                Error::InvalidEvent(format!(
+                    "Could not deserialize event at offset {} of partition {}: {}",
+                    message.offset(),
+                    message.partition(),
+                    e
+                ))
+            })?;
+
+            handler(record).await?;
+
+            self.consumer
+                .commit_message(&message, CommitMode::Async)
+                .map_err(Error::Kafka)?;
+        }
+    }
+}
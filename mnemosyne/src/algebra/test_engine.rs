@@ -0,0 +1,232 @@
+use super::{
+    apply_events, check_creation, tombstoned, Command, CommandOutcome, EventMeta, Record,
+    StateFactory,
+};
+use crate::{
+    domain::{
+        check_aggregate_type, run_effect, ApplyFailurePolicy, EngineConfig, Error,
+        SequenceGenerator, ValidationContext,
+    },
+    storage::{chain_hash, hash_at, Adapter, MemoryAdapter},
+};
+use std::{any::Any, collections::HashMap, fmt::Debug, sync::Arc};
+
+/// An engine without Kafka, actors or timers, meant for testing domain code.
+///
+/// Every command is processed as soon as it is enqueued, going through the same
+/// validate, directive, apply, write and effects steps as the `Engine`, with the events
+/// written to a `MemoryAdapter`. The outcome is returned from `enqueue` directly, so the
+/// exact same `Command` and `Event` implementations can be tested deterministically.
+///
+/// # Examples
+/// ```rust,ignore
+/// let mut engine: TestEngine<State, UserCommand> = TestEngine::new();
+///
+/// let outcome = engine.enqueue(UserCommand::Increment(Increment)).await?;
+///
+/// assert_eq!(outcome.state().count, 1);
+/// ```
+pub struct TestEngine<State, Cmd>
+where
+    State: Debug + Clone + Send + Sync + 'static + StateFactory,
+    Cmd: Command<State> + Debug,
+{
+    store: MemoryAdapter,
+    // State, sequence number and whether it is deleted per entity id
+    entities: HashMap<String, (State, i64, bool)>,
+    sequence_generator: Arc<dyn SequenceGenerator>,
+    apply_failure_policy: ApplyFailurePolicy,
+    creation_checks: bool,
+    aggregate_type: Option<String>,
+    read_model: Option<Arc<dyn Any + Send + Sync>>,
+    _marker: std::marker::PhantomData<Cmd>,
+}
+
+impl<State, Cmd> TestEngine<State, Cmd>
+where
+    State: Debug + Clone + Send + Sync + 'static + StateFactory,
+    Cmd: Command<State> + Debug,
+{
+    pub fn new() -> Self {
+        Self::with_store(MemoryAdapter::new())
+    }
+
+    /// Create an engine writing its events to the given store.
+    pub fn with_store(store: MemoryAdapter) -> Self {
+        Self {
+            store,
+            entities: HashMap::new(),
+            sequence_generator: EngineConfig::default().sequence_generator(),
+            apply_failure_policy: ApplyFailurePolicy::default(),
+            creation_checks: false,
+            aggregate_type: None,
+            read_model: None,
+            _marker: std::marker::PhantomData,
+        }
+    }
+
+    /// Set what happens when an event cannot be applied, see `ApplyFailurePolicy`.
+    pub fn with_apply_failure_policy(mut self, apply_failure_policy: ApplyFailurePolicy) -> Self {
+        self.apply_failure_policy = apply_failure_policy;
+        self
+    }
+
+    /// Enforce `Command::is_creation`, see `EngineConfig::with_creation_checks`.
+    pub fn with_creation_checks(mut self, creation_checks: bool) -> Self {
+        self.creation_checks = creation_checks;
+        self
+    }
+
+    /// Reject commands of entities of another aggregate type, see
+    /// `EngineConfig::with_aggregate_type`.
+    pub fn with_aggregate_type(mut self, aggregate_type: impl Into<String>) -> Self {
+        self.aggregate_type = Some(aggregate_type.into());
+        self
+    }
+
+    /// Share a read model with every command being validated, see
+    /// `EngineConfig::with_read_model`.
+    pub fn with_read_model<M>(mut self, read_model: M) -> Self
+    where
+        M: Send + Sync + 'static,
+    {
+        self.read_model = Some(Arc::new(read_model));
+        self
+    }
+
+    /// Put an entity at the given state and sequence number, bypassing the command pipeline,
+    /// to set up a scenario in one call. Nothing is written to the store, the events of the
+    /// next command are written from `seq_nr + 1` on. An entity seeded at a sequence number
+    /// of 0 has no events as far as `Command::is_creation` is concerned.
+    ///
+    /// # Examples
+    /// ```rust,ignore
+    /// let mut engine: TestEngine<Board, GameCommand> = TestEngine::new();
+    /// engine.seed(GAME_ID, Board::almost_won_by(Player::X), 8);
+    ///
+    /// let outcome = engine.enqueue(GameCommand::Move(winning_move)).await?;
+    /// ```
+    pub fn seed(&mut self, entity_id: &str, state: State, seq_nr: i64) {
+        self.entities
+            .insert(entity_id.to_string(), (state, seq_nr, false));
+    }
+
+    /// Process a command and return its outcome, as the handle returned by
+    /// `Engine::enqueue` would resolve to.
+    pub async fn enqueue(&mut self, command: Cmd) -> Result<CommandOutcome<State, Cmd::T>, Error> {
+        let id = command.entity_id();
+        check_aggregate_type(self.aggregate_type.as_deref(), &id)?;
+        let (state, seq_nr, deleted) = self
+            .entities
+            .get(&id)
+            .cloned()
+            .unwrap_or_else(|| (State::initial(&id), 0, false));
+
+        // 1. Validate command
+        if deleted && !command.resurrects() {
+            return Err(Error::EntityDeleted(id));
+        }
+        if self.creation_checks {
+            check_creation(&id, seq_nr, command.is_creation())?;
+        }
+        let validation = ValidationContext::new(&id, self.read_model.as_ref());
+        command
+            .validate_with(&state, &validation)
+            .await
+            .map_err(|e| match e {
+                Error::Rejected { .. } => e,
+                e => Error::Validation(format!(
+                    "Command {:?} is not valid for state {:?}: {}",
+                    command, state, e
+                )),
+            })?;
+
+        // 2. If valid, yield events
+        let events = command.directive(&state)?;
+
+        // 3. Apply events to state, before anything is written
+        let (new_state, events, metas) = apply_events(
+            &id,
+            &state,
+            seq_nr,
+            events,
+            self.sequence_generator.as_ref(),
+            self.apply_failure_policy,
+        )?;
+
+        let mut previous_hash = hash_at::<Cmd::T, _>(&self.store, &id, seq_nr).await?;
+        let records = events
+            .iter()
+            .zip(metas.iter())
+            .map(|(event, meta)| {
+                let record = Record::event(id.clone(), meta.seq_nr(), event, meta.timestamp());
+                let hash = chain_hash(previous_hash.as_deref(), &record)?;
+                previous_hash = Some(hash.clone());
+                Ok(record.with_hash(hash))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        // 4. Save events to storage, together with the durable effects of the command. They
+        // are not run, see `Adapter::run_effects` to run them in a test
+        let durable = command.durable_effects(&state, &new_state)?;
+        match durable.is_empty() {
+            true => self.store.write(records).await?,
+            false => {
+                self.store
+                    .write_with_effects(records, durable, false)
+                    .await?
+            }
+        }
+
+        // The events are written, so the entity moves past them even if one of the effects fails
+        let seq_nr = metas.last().map_or(seq_nr, EventMeta::seq_nr);
+        let deleted = events.iter().fold(deleted, |deleted, event| {
+            tombstoned::<State, Cmd::T>(deleted, event.as_ref())
+        });
+        self.entities
+            .insert(id.clone(), (new_state.clone(), seq_nr, deleted));
+
+        // 5. Yield effects
+        run_effect(
+            None,
+            &command.name(),
+            &id,
+            false,
+            command.effects(&state, &new_state),
+        )
+        .await?;
+
+        Ok(CommandOutcome::new(events, seq_nr as u64, new_state))
+    }
+
+    /// Return the current state of an entity, which is its initial state if no command
+    /// was processed for it yet.
+    pub fn state(&self, entity_id: &str) -> State {
+        self.entities
+            .get(entity_id)
+            .map(|(state, _, _)| state.clone())
+            .unwrap_or_else(|| State::initial(entity_id))
+    }
+
+    /// Whether an entity is deleted by a tombstone event, see `Event::deletes`.
+    pub fn is_deleted(&self, entity_id: &str) -> bool {
+        self.entities
+            .get(entity_id)
+            .is_some_and(|(_, _, deleted)| *deleted)
+    }
+
+    /// The store the events are written to.
+    pub fn store(&self) -> &MemoryAdapter {
+        &self.store
+    }
+}
+
+impl<State, Cmd> Default for TestEngine<State, Cmd>
+where
+    State: Debug + Clone + Send + Sync + 'static + StateFactory,
+    Cmd: Command<State> + Debug,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,101 @@
+use crate::{
+    domain::{Error, EventCodec, EVENT_TOPIC, STATE_TOPIC},
+    Unit,
+};
+use chrono::{DateTime, Utc};
+use rdkafka::{
+    admin::{AdminClient, AdminOptions, NewTopic, TopicReplication},
+    client::DefaultClientContext,
+    producer::{FutureProducer, FutureRecord},
+    types::RDKafkaErrorCode,
+    util::Timeout,
+    ClientConfig,
+};
+
+/// Create a topic with `cleanup.policy=compact`, so Kafka only retains the latest
+/// message per key. Messages published by the engine are keyed by entity id, which
+/// makes a compacted topic retain the latest message per entity.
+///
+/// This is meant for the `STATE_TOPIC`, the `EVENT_TOPIC` must not be compacted as
+/// every event of an entity is needed to rebuild its state.
+///
+/// If the topic already exists this is a no-op.
+///
+/// # Examples
+/// ```rust,ignore
+/// let mut configuration = ClientConfig::new();
+/// let configuration = configuration.set("bootstrap.servers", "localhost:9092");
+///
+/// create_compacted_topic(configuration, STATE_TOPIC, 3, 1).await?;
+/// ```
+pub async fn create_compacted_topic(
+    configuration: &ClientConfig,
+    topic: &str,
+    partitions: i32,
+    replication: i32,
+) -> Result<Unit, Error> {
+    let admin: AdminClient<DefaultClientContext> = configuration.create().map_err(Error::Kafka)?;
+
+    let new_topic = NewTopic::new(topic, partitions, TopicReplication::Fixed(replication))
+        .set("cleanup.policy", "compact");
+
+    let results = admin
+        .create_topics(&[new_topic], &AdminOptions::new())
+        .await
+        .map_err(Error::Kafka)?;
+
+    for result in results {
+        match result {
+            Ok(_) | Err((_, RDKafkaErrorCode::TopicAlreadyExists)) => {}
+            Err((topic, code)) => {
+                return Err(Error::InvalidConfiguration(format!(
+                    "Could not create compacted topic {}: {}",
+                    topic, code
+                )))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Publish a serialized event record to the event topic keyed by entity id, so all events
+/// of an entity land on the same partition.
+pub(crate) async fn send_event(
+    producer: &FutureProducer,
+    codec: &dyn EventCodec,
+    entity_id: &str,
+    payload: &[u8],
+    timestamp: DateTime<Utc>,
+) -> Result<Unit, Error> {
+    let payload = codec.encode(entity_id, payload)?;
+    let message = FutureRecord::to(EVENT_TOPIC)
+        .payload(payload.as_ref())
+        .key(entity_id)
+        .timestamp(timestamp.timestamp_millis());
+
+    producer
+        .send(message, Timeout::Never)
+        .await
+        .map(|_| ())
+        .map_err(|(e, _)| Error::Kafka(e))
+}
+
+/// Publish the serialized state of an entity to the state topic keyed by entity id, or a
+/// tombstone if the entity is deleted, so compaction eventually drops it.
+pub(crate) async fn send_state(
+    producer: &FutureProducer,
+    entity_id: &str,
+    payload: Option<&[u8]>,
+) -> Result<Unit, Error> {
+    let mut message = FutureRecord::<str, [u8]>::to(STATE_TOPIC).key(entity_id);
+    if let Some(payload) = payload {
+        message = message.payload(payload);
+    }
+
+    producer
+        .send(message, Timeout::Never)
+        .await
+        .map(|_| ())
+        .map_err(|(e, _)| Error::Kafka(e))
+}
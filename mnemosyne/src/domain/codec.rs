@@ -0,0 +1,229 @@
+use super::Error;
+#[cfg(feature = "avro")]
+use apache_avro::Schema;
+use std::{borrow::Cow, fmt::Debug};
+
+/// Encodes the records published to the event topic.
+///
+/// Records are handed to the codec serialized as JSON, the codec returns the payload
+/// actually produced. This is where a binary encoding plugs in, e.g. Avro framed for a
+/// Confluent schema registry: a magic byte, the 4-byte big-endian schema id and the Avro
+/// datum. Encoding is synchronous, codecs that register schemas should do it once and cache
+/// the schema id. Consumers of the event topic decode the payload accordingly.
+///
+/// With the `avro` feature, `AvroCodec` does exactly that.
+///
+/// # Examples
+/// ```rust,ignore
+/// #[derive(Debug)]
+/// struct Compressed;
+///
+/// impl EventCodec for Compressed {
+///     fn encode<'a>(&self, _entity_id: &str, json: &'a [u8]) -> Result<Cow<'a, [u8]>, Error> {
+///         Ok(Cow::Owned(zstd::encode_all(json, 0)?))
+///     }
+/// }
+/// ```
+pub trait EventCodec: Debug + Send + Sync {
+    fn encode<'a>(&self, entity_id: &str, json: &'a [u8]) -> Result<Cow<'a, [u8]>, Error>;
+}
+
+/// The default codec, publishes records as JSON.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl EventCodec for JsonCodec {
+    fn encode<'a>(&self, _entity_id: &str, json: &'a [u8]) -> Result<Cow<'a, [u8]>, Error> {
+        Ok(Cow::Borrowed(json))
+    }
+}
+
+/// Magic byte starting every payload framed for a Confluent schema registry.
+#[cfg(feature = "avro")]
+const MAGIC_BYTE: u8 = 0;
+
+/// Publishes records in Avro, framed for a Confluent schema registry: a magic byte, the
+/// 4-byte big-endian schema id and the Avro datum, so the event topic can be consumed by
+/// ksqlDB, Kafka Connect or any other consumer decoding with the registry.
+///
+/// The schema describes the record of an event as it is serialized to JSON, i.e. a record
+/// with the `entity_id`, `seq_nr`, `timestamp` and `message` fields. The optional fields of
+/// the record, e.g. `id` or `hash`, are only written when set, so the schema should declare
+/// them as unions with `null` and a `null` default. Fields the schema does not declare are
+/// dropped. Only the records published to the event topic are encoded, the events in storage
+/// and the subscriptions of the engine, see `Subscription`, remain JSON.
+///
+/// # Examples
+/// ```rust,ignore
+/// let codec = AvroCodec::parse(include_str!("order_event.avsc"), 42)?;
+/// let config = EngineConfig::default().with_event_codec(codec);
+/// ```
+#[cfg(feature = "avro")]
+#[derive(Debug, Clone)]
+pub struct AvroCodec {
+    schema: Schema,
+    schema_id: u32,
+}
+
+#[cfg(feature = "avro")]
+impl AvroCodec {
+    /// Create a codec for a schema registered under `schema_id`.
+    pub fn new(schema: Schema, schema_id: u32) -> Self {
+        Self { schema, schema_id }
+    }
+
+    /// Create a codec for a schema, given as JSON, registered under `schema_id`.
+    pub fn parse(schema: &str, schema_id: u32) -> Result<Self, Error> {
+        let schema = Schema::parse_str(schema).map_err(|e| {
+            Error::InvalidConfiguration(format!("Could not parse the Avro schema: {}", e))
+        })?;
+        Ok(Self::new(schema, schema_id))
+    }
+
+    /// Register a schema under `subject` with the schema registry and create a codec for it.
+    /// Registering a schema that is registered already returns its id, so this is meant to
+    /// run once when starting the service.
+    ///
+    /// # Examples
+    /// ```rust,ignore
+    /// let settings = SrSettings::new("http://localhost:8081".to_string());
+    /// let codec = AvroCodec::register(&settings, "events-value", schema).await?;
+    /// ```
+    #[cfg(feature = "schema_registry")]
+    pub async fn register(
+        settings: &schema_registry_converter::async_impl::schema_registry::SrSettings,
+        subject: &str,
+        schema: Schema,
+    ) -> Result<Self, Error> {
+        use schema_registry_converter::{
+            async_impl::schema_registry::post_schema,
+            schema_registry_common::{SchemaType, SuppliedSchema},
+        };
+
+        let supplied = SuppliedSchema {
+            name: schema.name().map(|name| name.fullname(None)),
+            schema_type: SchemaType::Avro,
+            schema: schema.canonical_form(),
+            references: Vec::new(),
+            properties: None,
+            tags: None,
+        };
+        let registered = post_schema(settings, subject.to_string(), supplied)
+            .await
+            .map_err(|e| {
+                Error::Error(format!(
+                    "Could not register the Avro schema under subject {}: {}",
+                    subject, e
+                ))
+            })?;
+
+        Ok(Self::new(schema, registered.id))
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    pub fn schema_id(&self) -> u32 {
+        self.schema_id
+    }
+}
+
+#[cfg(feature = "avro")]
+impl EventCodec for AvroCodec {
+    fn encode<'a>(&self, entity_id: &str, json: &'a [u8]) -> Result<Cow<'a, [u8]>, Error> {
+        let invalid = |e: &dyn std::fmt::Display| {
+            Error::InvalidEvent(format!(
+                "Could not encode an event of entity {} in Avro: {}",
+                entity_id, e
+            ))
+        };
+
+        let value = serde_json::from_slice::<serde_json::Value>(json).map_err(|e| invalid(&e))?;
+        let value = apache_avro::to_value(value)
+            .and_then(|value| value.resolve(&self.schema))
+            .map_err(|e| invalid(&e))?;
+        let datum = apache_avro::to_avro_datum(&self.schema, value).map_err(|e| invalid(&e))?;
+
+        let mut payload = Vec::with_capacity(5 + datum.len());
+        payload.push(MAGIC_BYTE);
+        payload.extend_from_slice(&self.schema_id.to_be_bytes());
+        payload.extend_from_slice(&datum);
+        Ok(Cow::Owned(payload))
+    }
+}
+
+#[cfg(all(test, feature = "avro"))]
+mod tests {
+    use super::*;
+    use crate::algebra::Record;
+    use apache_avro::types::Value;
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    struct Shipped {
+        parcels: i32,
+    }
+
+    const SCHEMA: &str = r#"{
+        "type": "record",
+        "name": "ShippedRecord",
+        "fields": [
+            {"name": "entity_id", "type": "string"},
+            {"name": "seq_nr", "type": "long"},
+            {"name": "timestamp", "type": "string"},
+            {"name": "message", "type": {
+                "type": "record",
+                "name": "Shipped",
+                "fields": [{"name": "parcels", "type": "int"}]
+            }},
+            {"name": "hash", "type": ["null", "string"], "default": null}
+        ]
+    }"#;
+
+    #[test]
+    fn records_are_framed_for_the_schema_registry() {
+        let codec = AvroCodec::parse(SCHEMA, 7).unwrap();
+        let record = Record::event(
+            "shipment:1".to_string(),
+            3,
+            Shipped { parcels: 2 },
+            chrono::Utc::now(),
+        );
+        let json = serde_json::to_vec(&record).unwrap();
+
+        let payload = codec.encode("shipment:1", &json).unwrap();
+
+        assert_eq!(payload[0], MAGIC_BYTE);
+        assert_eq!(payload[1..5], 7u32.to_be_bytes());
+        let datum = apache_avro::from_avro_datum(codec.schema(), &mut &payload[5..], None).unwrap();
+        let Value::Record(fields) = datum else {
+            panic!("expected a record, got {:?}", datum);
+        };
+        assert_eq!(
+            fields[0],
+            ("entity_id".into(), Value::String("shipment:1".into()))
+        );
+        assert_eq!(fields[1], ("seq_nr".into(), Value::Long(3)));
+        assert_eq!(
+            fields[3],
+            (
+                "message".into(),
+                Value::Record(vec![("parcels".into(), Value::Int(2))])
+            )
+        );
+        assert_eq!(
+            fields[4],
+            ("hash".into(), Value::Union(0, Box::new(Value::Null)))
+        );
+    }
+
+    #[test]
+    fn records_not_matching_the_schema_are_invalid() {
+        let codec = AvroCodec::parse(SCHEMA, 7).unwrap();
+
+        let encoded = codec.encode("shipment:1", br#"{"entity_id": "shipment:1"}"#);
+
+        assert!(matches!(encoded, Err(Error::InvalidEvent(_))));
+    }
+}
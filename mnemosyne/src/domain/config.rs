@@ -0,0 +1,1005 @@
+use super::{
+    ActorFailure, ActorKind, ActorObserver, CommandKey, DefaultFailurePolicy, EffectHandler,
+    EffectRecorder, EntityIdKey, Error, EventCodec, FailurePolicy, Incremental, JsonCodec,
+    LatencyRecorder, MailboxPressure, Middleware, RateLimit, ReplayThrottle, SequenceGenerator,
+    UnknownCommandHandler, COMMAND_TOPIC, CONSISTENCY_TIMEOUT, DEAD_LETTER_TOPIC,
+    IDEMPOTENCY_WINDOW, IDLE_BACKOFF, MAILBOX_CAPACITY, POLL_TIMEOUT, RELAY_BATCH_SIZE,
+    RELAY_INTERVAL,
+};
+use crate::{
+    storage::{DiscardOutdated, SnapshotUpcaster},
+    Unit,
+};
+use actix::{Actor, Addr, ArbiterHandle, Context, Supervised, Supervisor};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    any::{Any, TypeId},
+    fmt::Debug,
+    sync::Arc,
+    time::Duration,
+};
+
+/// Serializes a state for its snapshot, see `EngineConfig::with_snapshot_every`, or for the
+/// state topic, see `EngineConfig::with_state_publishing`.
+pub(crate) type SnapshotEncoder<State> = fn(&State) -> serde_json::Result<Value>;
+
+/// Wire format of the records on the command topic.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CommandFormat {
+    /// The command is nested under the `message` field of a `Record`, next to its
+    /// metadata. This is what the engine has always produced.
+    #[default]
+    Enveloped,
+    /// The command fields sit at the top level, next to the (optional) metadata
+    /// fields `entity_id`, `seq_nr`, `timestamp`, `id`, `traceparent`, `source`,
+    /// `idempotency_key`, `correlation_id` and `expires_at`. This allows external
+    /// producers to publish commands directly, e.g. `{"type": "Increment"}`.
+    ///
+    /// Missing metadata is derived on ingestion: the entity id from
+    /// `Command::entity_id`, the timestamp from the time of consumption. Commands
+    /// must serialize to a map, and their fields must not clash with the metadata
+    /// fields.
+    Flat,
+}
+
+/// When events are published to the event topic relative to being written to storage.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PublishMode {
+    /// Publish events before writing them to storage. Events may be published that are
+    /// never persisted, if the write fails.
+    BeforeStorage,
+    /// Publish events once they are written to storage and applied. Events may never be
+    /// published, if the process stops between the write and the publish.
+    #[default]
+    AfterStorage,
+    /// Write events together with an outbox entry in a single transaction, a relay then
+    /// publishes the outbox entries and marks them as sent. Events are never lost between
+    /// storage and Kafka, but may be published more than once. Requires an adapter that
+    /// supports an outbox, see `Adapter::write_with_outbox`.
+    Outbox,
+}
+
+/// What happens when an event yielded by a command cannot be applied to the state.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ApplyFailurePolicy {
+    /// Reject the whole command, nothing is written.
+    #[default]
+    Strict,
+    /// Write and apply the events that can be applied, skipping the others. Skipped events
+    /// are logged. The command is still rejected if none of its events can be applied.
+    Lenient,
+}
+
+/// What happens when a command holds its entity for longer than the stuck entity timeout,
+/// see `EngineConfig::with_stuck_entity_timeout`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StuckEntityPolicy {
+    /// Log a warning and keep waiting for the command.
+    #[default]
+    Warn,
+    /// Log a warning, abandon the command with `Error::EntityStuck` and restart the actor
+    /// of the entity, which loads the entity from storage again on its next command. A
+    /// command stuck on its write is only abandoned once the write finished or failed. The
+    /// command may or may not have been written, so it is not retried.
+    Restart,
+}
+
+/// Engine level configuration. Everything that is not related to the Kafka client
+/// itself lives here, the Kafka client is still configured through `ClientConfig`.
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    service_name: Option<String>,
+    aggregate_type: Option<String>,
+    max_payload_size: Option<usize>,
+    max_event_size: Option<usize>,
+    command_format: CommandFormat,
+    command_key: Arc<dyn CommandKey>,
+    rate_limit: Option<RateLimit>,
+    sequence_generator: Arc<dyn SequenceGenerator>,
+    publish_mode: PublishMode,
+    apply_failure_policy: ApplyFailurePolicy,
+    creation_checks: bool,
+    gap_check: bool,
+    replay_check: bool,
+    ensure_schema: bool,
+    max_entities: Option<usize>,
+    stuck_entity: Option<(Duration, StuckEntityPolicy)>,
+    idempotency_window: Duration,
+    consistency_timeout: Duration,
+    relay_batch_size: u64,
+    relay_interval: Duration,
+    command_topics: Vec<String>,
+    poll_timeout: Duration,
+    idle_backoff: Duration,
+    max_poll_interval: Option<Duration>,
+    session_timeout: Option<Duration>,
+    middlewares: Vec<Arc<dyn Middleware>>,
+    replay_throttle: ReplayThrottle,
+    actor_observer: Option<Arc<dyn ActorObserver>>,
+    failure_policy: Arc<dyn FailurePolicy>,
+    dead_letter_topic: String,
+    unknown_command_handler: Option<Arc<dyn UnknownCommandHandler>>,
+    snapshot_upcaster: Option<OfState>,
+    snapshot_every: Option<u64>,
+    snapshot_encoder: Option<OfState>,
+    state_encoder: Option<OfState>,
+    arbiter: Option<ArbiterHandle>,
+    event_codec: Arc<dyn EventCodec>,
+    latency_recorder: Option<Arc<dyn LatencyRecorder>>,
+    read_model: Option<Arc<dyn Any + Send + Sync>>,
+    effect_handler: Option<Arc<dyn EffectHandler>>,
+    effect_recorder: Option<Arc<dyn EffectRecorder>>,
+    mailbox_capacity: usize,
+}
+
+// Something generic over the state, e.g. a `SnapshotUpcaster`, as the configuration is not
+// generic over the state. The state it was set for is kept, so an engine of another state
+// fails to start rather than silently ignoring it, see `EngineConfig::state_problems`.
+#[derive(Clone)]
+struct OfState {
+    state: TypeId,
+    name: &'static str,
+    value: Arc<dyn Any + Send + Sync>,
+}
+
+impl OfState {
+    fn new<State>(value: impl Any + Send + Sync) -> Self
+    where
+        State: 'static,
+    {
+        Self {
+            state: TypeId::of::<State>(),
+            name: std::any::type_name::<State>(),
+            value: Arc::new(value),
+        }
+    }
+
+    fn get<T>(&self) -> Option<T>
+    where
+        T: Clone + 'static,
+    {
+        (*self.value).downcast_ref::<T>().cloned()
+    }
+}
+
+impl Debug for OfState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OfState")
+            .field("state", &self.name)
+            .finish()
+    }
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            service_name: None,
+            aggregate_type: None,
+            max_payload_size: None,
+            max_event_size: None,
+            command_format: CommandFormat::default(),
+            command_key: Arc::new(EntityIdKey),
+            rate_limit: None,
+            sequence_generator: Arc::new(Incremental),
+            publish_mode: PublishMode::default(),
+            apply_failure_policy: ApplyFailurePolicy::default(),
+            creation_checks: false,
+            gap_check: false,
+            replay_check: false,
+            ensure_schema: false,
+            max_entities: None,
+            stuck_entity: None,
+            idempotency_window: Duration::from_secs(IDEMPOTENCY_WINDOW),
+            consistency_timeout: Duration::from_secs(CONSISTENCY_TIMEOUT),
+            relay_batch_size: RELAY_BATCH_SIZE,
+            relay_interval: Duration::from_secs(RELAY_INTERVAL),
+            command_topics: vec![COMMAND_TOPIC.to_string()],
+            poll_timeout: Duration::from_secs(POLL_TIMEOUT),
+            idle_backoff: Duration::from_secs(IDLE_BACKOFF),
+            max_poll_interval: None,
+            session_timeout: None,
+            middlewares: Vec::new(),
+            replay_throttle: ReplayThrottle::default(),
+            actor_observer: None,
+            failure_policy: Arc::new(DefaultFailurePolicy),
+            dead_letter_topic: DEAD_LETTER_TOPIC.to_string(),
+            unknown_command_handler: None,
+            snapshot_upcaster: None,
+            snapshot_every: None,
+            snapshot_encoder: None,
+            state_encoder: None,
+            arbiter: None,
+            event_codec: Arc::new(JsonCodec),
+            latency_recorder: None,
+            read_model: None,
+            effect_handler: None,
+            effect_recorder: None,
+            mailbox_capacity: MAILBOX_CAPACITY,
+        }
+    }
+}
+
+impl EngineConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the name of the service running the engine. Enqueued commands and the events
+    /// yielded by processing commands carry it as their `Record::source`.
+    pub fn with_service_name(mut self, service_name: impl Into<String>) -> Self {
+        self.service_name = Some(service_name.into());
+        self
+    }
+
+    pub fn service_name(&self) -> Option<&str> {
+        self.service_name.as_deref()
+    }
+
+    /// Set the aggregate type of the engine, e.g. `order`. Commands are then rejected with
+    /// `Error::InvalidEntityId` unless the category of their entity id is the aggregate type,
+    /// see `category`, i.e. `order:42`. This keeps engines of different aggregate types
+    /// sharing a store from writing to each other's entities.
+    pub fn with_aggregate_type(mut self, aggregate_type: impl Into<String>) -> Self {
+        self.aggregate_type = Some(aggregate_type.into());
+        self
+    }
+
+    pub fn aggregate_type(&self) -> Option<&str> {
+        self.aggregate_type.as_deref()
+    }
+
+    /// Check that an entity belongs to the aggregate type of the engine, if any.
+    pub(crate) fn check_aggregate_type(&self, entity_id: &str) -> Result<Unit, Error> {
+        check_aggregate_type(self.aggregate_type.as_deref(), entity_id)
+    }
+
+    /// Set the maximum size, in bytes, of a command payload accepted by the consumer.
+    ///
+    /// Payloads above this size are rejected before they are deserialized.
+    pub fn with_max_payload_size(mut self, max_payload_size: usize) -> Self {
+        self.max_payload_size = Some(max_payload_size);
+        self
+    }
+
+    pub fn max_payload_size(&self) -> Option<usize> {
+        self.max_payload_size
+    }
+
+    /// Set the maximum size, in bytes, of an event as serialized to JSON with its record.
+    ///
+    /// A command producing a larger event fails with `Error::PayloadTooLarge` before any of
+    /// its events is written or published, rather than exceeding `message.max.bytes` of the
+    /// broker once stored. Unlimited by default.
+    pub fn with_max_event_size(mut self, max_event_size: usize) -> Self {
+        self.max_event_size = Some(max_event_size);
+        self
+    }
+
+    pub fn max_event_size(&self) -> Option<usize> {
+        self.max_event_size
+    }
+
+    /// Set the wire format used to produce and consume commands, see `CommandFormat`.
+    pub fn with_command_format(mut self, command_format: CommandFormat) -> Self {
+        self.command_format = command_format;
+        self
+    }
+
+    pub fn command_format(&self) -> CommandFormat {
+        self.command_format
+    }
+
+    /// Set how the Kafka key of a command is built from its entity id, defaults to
+    /// `EntityIdKey`, see `CommandKey`.
+    pub fn with_command_key(mut self, command_key: impl CommandKey + 'static) -> Self {
+        self.command_key = Arc::new(command_key);
+        self
+    }
+
+    pub fn command_key(&self) -> Arc<dyn CommandKey> {
+        self.command_key.clone()
+    }
+
+    /// Rate limit commands per entity, commands above the limit are rejected with
+    /// `Error::RateLimited` instead of being processed.
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limit
+    }
+
+    /// Set the generator used to assign sequence numbers, defaults to `Incremental`.
+    pub fn with_sequence_generator(
+        mut self,
+        sequence_generator: impl SequenceGenerator + 'static,
+    ) -> Self {
+        self.sequence_generator = Arc::new(sequence_generator);
+        self
+    }
+
+    pub fn sequence_generator(&self) -> Arc<dyn SequenceGenerator> {
+        self.sequence_generator.clone()
+    }
+
+    /// Set when events are published relative to being written, see `PublishMode`.
+    pub fn with_publish_mode(mut self, publish_mode: PublishMode) -> Self {
+        self.publish_mode = publish_mode;
+        self
+    }
+
+    pub fn publish_mode(&self) -> PublishMode {
+        self.publish_mode
+    }
+
+    /// Set what happens when an event cannot be applied, see `ApplyFailurePolicy`.
+    pub fn with_apply_failure_policy(mut self, apply_failure_policy: ApplyFailurePolicy) -> Self {
+        self.apply_failure_policy = apply_failure_policy;
+        self
+    }
+
+    pub fn apply_failure_policy(&self) -> ApplyFailurePolicy {
+        self.apply_failure_policy
+    }
+
+    /// Enforce `Command::is_creation`: creation commands fail on entities that already have
+    /// events and every other command fails on entities without events. Disabled by default.
+    pub fn with_creation_checks(mut self, creation_checks: bool) -> Self {
+        self.creation_checks = creation_checks;
+        self
+    }
+
+    pub fn creation_checks(&self) -> bool {
+        self.creation_checks
+    }
+
+    /// Check the sequence numbers of an entity for gaps whenever its events are replayed,
+    /// failing the replay with `Error::SequenceGap` rather than folding a state that misses
+    /// lost events, see `check_gaps`. Disabled by default, meant for debugging data loss.
+    pub fn with_gap_check(mut self, gap_check: bool) -> Self {
+        self.gap_check = gap_check;
+        self
+    }
+
+    pub fn gap_check(&self) -> bool {
+        self.gap_check
+    }
+
+    /// Read the events of every command back once they are written, and check that they are
+    /// the events that were applied, in the same order, and that they apply again onto the
+    /// state they were applied to, as they would when the entity is replayed. A mismatch is
+    /// logged as an error. Disabled by default, as it reads every write back, meant for
+    /// debugging events that do not replay the way they were applied.
+    pub fn with_replay_check(mut self, replay_check: bool) -> Self {
+        self.replay_check = replay_check;
+        self
+    }
+
+    pub fn replay_check(&self) -> bool {
+        self.replay_check
+    }
+
+    /// Migrate the schema of the storage when the engine starts, see `Adapter::migrate`.
+    /// Disabled by default, the schema can also be migrated with `Engine::migrate`.
+    pub fn with_ensure_schema(mut self, ensure_schema: bool) -> Self {
+        self.ensure_schema = ensure_schema;
+        self
+    }
+
+    pub fn ensure_schema(&self) -> bool {
+        self.ensure_schema
+    }
+
+    /// Cap the number of entities kept in memory, each by an actor of its own. Beyond the
+    /// cap, the actors of the least recently used entities are stopped, unless a command is
+    /// being processed by them, an evicted entity is loaded from storage again when it
+    /// receives its next command. Unbounded by default.
+    pub fn with_max_entities(mut self, max_entities: usize) -> Self {
+        self.max_entities = Some(max_entities);
+        self
+    }
+
+    pub fn max_entities(&self) -> Option<usize> {
+        self.max_entities
+    }
+
+    /// Detect entities stuck on a command, e.g. on a hung storage write or effect. A command
+    /// that holds its entity, and so every command of the entity queued behind it, for
+    /// longer than `timeout` is handled according to `policy`. Disabled by default.
+    pub fn with_stuck_entity_timeout(
+        mut self,
+        timeout: Duration,
+        policy: StuckEntityPolicy,
+    ) -> Self {
+        self.stuck_entity = Some((timeout, policy));
+        self
+    }
+
+    pub fn stuck_entity(&self) -> Option<(Duration, StuckEntityPolicy)> {
+        self.stuck_entity
+    }
+
+    /// Set how long the idempotency key of a command is remembered, see
+    /// `Engine::enqueue_with_idempotency_key`. A command retried with the same key within
+    /// the window is not processed again.
+    pub fn with_idempotency_window(mut self, idempotency_window: Duration) -> Self {
+        self.idempotency_window = idempotency_window;
+        self
+    }
+
+    pub fn idempotency_window(&self) -> Duration {
+        self.idempotency_window
+    }
+
+    /// Set how long a `Consistency::Strong` read waits for the last command of its entity to
+    /// be processed before failing with `Error::ConsistencyTimeout`, defaults to
+    /// `CONSISTENCY_TIMEOUT`.
+    pub fn with_consistency_timeout(mut self, consistency_timeout: Duration) -> Self {
+        self.consistency_timeout = consistency_timeout;
+        self
+    }
+
+    pub fn consistency_timeout(&self) -> Duration {
+        self.consistency_timeout
+    }
+
+    /// Set the maximum number of outbox entries published per relay run.
+    pub fn with_relay_batch_size(mut self, relay_batch_size: u64) -> Self {
+        self.relay_batch_size = relay_batch_size;
+        self
+    }
+
+    pub fn relay_batch_size(&self) -> u64 {
+        self.relay_batch_size
+    }
+
+    /// Set how often the relay polls the outbox for unsent entries.
+    pub fn with_relay_interval(mut self, relay_interval: Duration) -> Self {
+        self.relay_interval = relay_interval;
+        self
+    }
+
+    pub fn relay_interval(&self) -> Duration {
+        self.relay_interval
+    }
+
+    /// Set the topics commands are consumed from, defaults to `COMMAND_TOPIC`. Topics
+    /// starting with `^` are regular expressions matching every topic they match, e.g.
+    /// `^tenant-.*-commands`.
+    ///
+    /// Commands from every topic are routed to the same per-entity actors by entity id.
+    /// `Engine::enqueue` always produces to `COMMAND_TOPIC`, so it must be part of the
+    /// subscription for enqueued commands to be processed.
+    pub fn with_command_topics<I, T>(mut self, command_topics: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.command_topics = command_topics.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn command_topics(&self) -> &[String] {
+        &self.command_topics
+    }
+
+    /// Set how long to wait for commands before a poll of the command topics is
+    /// considered idle.
+    pub fn with_poll_timeout(mut self, poll_timeout: Duration) -> Self {
+        self.poll_timeout = poll_timeout;
+        self
+    }
+
+    pub fn poll_timeout(&self) -> Duration {
+        self.poll_timeout
+    }
+
+    /// Set how long to wait before polling the command topics again after an idle poll.
+    pub fn with_idle_backoff(mut self, idle_backoff: Duration) -> Self {
+        self.idle_backoff = idle_backoff;
+        self
+    }
+
+    pub fn idle_backoff(&self) -> Duration {
+        self.idle_backoff
+    }
+
+    /// Set `max.poll.interval.ms` of the consumer, i.e. how long it may go without polling
+    /// before it leaves its group and its partitions are reassigned. A chunk of up to
+    /// `CHUNK_SIZE` commands taking longer than a third of it to process pauses consumption,
+    /// the consumer keeps polling until the chunk is processed. Defaults to the value of the
+    /// client configuration, or `MAX_POLL_INTERVAL`.
+    pub fn with_max_poll_interval(mut self, max_poll_interval: Duration) -> Self {
+        self.max_poll_interval = Some(max_poll_interval);
+        self
+    }
+
+    pub fn max_poll_interval(&self) -> Option<Duration> {
+        self.max_poll_interval
+    }
+
+    /// Set `session.timeout.ms` of the consumer, i.e. how long it may go without a heartbeat
+    /// before it leaves its group. Heartbeats are sent in the background, regardless of how
+    /// long processing takes. Defaults to the value of the client configuration.
+    pub fn with_session_timeout(mut self, session_timeout: Duration) -> Self {
+        self.session_timeout = Some(session_timeout);
+        self
+    }
+
+    pub fn session_timeout(&self) -> Option<Duration> {
+        self.session_timeout
+    }
+
+    /// Add a middleware around the processing of every command, see `Middleware`.
+    /// Middlewares added first run outermost.
+    pub fn with_middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    pub fn middlewares(&self) -> &[Arc<dyn Middleware>] {
+        &self.middlewares
+    }
+
+    /// Throttle snapshot rebuilds, see `ReplayThrottle`. Rebuilds are not throttled by
+    /// default.
+    pub fn with_replay_throttle(mut self, replay_throttle: ReplayThrottle) -> Self {
+        self.replay_throttle = replay_throttle;
+        self
+    }
+
+    pub fn replay_throttle(&self) -> ReplayThrottle {
+        self.replay_throttle
+    }
+
+    /// Set the observer notified of actor failures, see `ActorObserver`.
+    pub fn with_actor_observer(mut self, actor_observer: impl ActorObserver + 'static) -> Self {
+        self.actor_observer = Some(Arc::new(actor_observer));
+        self
+    }
+
+    pub fn actor_observer(&self) -> Option<Arc<dyn ActorObserver>> {
+        self.actor_observer.clone()
+    }
+
+    /// Set the recorder of the time commands take from being enqueued to being applied,
+    /// see `LatencyRecorder` and `LatencyHistogram`. Latencies are not recorded by default.
+    pub fn with_latency_recorder(
+        mut self,
+        latency_recorder: impl LatencyRecorder + 'static,
+    ) -> Self {
+        self.latency_recorder = Some(Arc::new(latency_recorder));
+        self
+    }
+
+    pub fn latency_recorder(&self) -> Option<Arc<dyn LatencyRecorder>> {
+        self.latency_recorder.clone()
+    }
+
+    /// Share a read model with every command being validated, see `ValidationContext`. The
+    /// read model may lag behind the commands being processed, see `ValidationContext` for
+    /// how to enforce invariants that must hold strictly.
+    pub fn with_read_model<M>(mut self, read_model: M) -> Self
+    where
+        M: Send + Sync + 'static,
+    {
+        self.read_model = Some(Arc::new(read_model));
+        self
+    }
+
+    pub(crate) fn read_model(&self) -> Option<Arc<dyn Any + Send + Sync>> {
+        self.read_model.clone()
+    }
+
+    /// Set the handler running the durable effects of commands, see `EffectHandler` and
+    /// `Command::durable_effects`. Pending effects are polled every `relay_interval`, at most
+    /// `relay_batch_size` at a time, and failed ones are run again on the next poll. Without a
+    /// handler the effects are written but not run.
+    pub fn with_effect_handler(mut self, effect_handler: impl EffectHandler + 'static) -> Self {
+        self.effect_handler = Some(Arc::new(effect_handler));
+        self
+    }
+
+    pub fn effect_handler(&self) -> Option<Arc<dyn EffectHandler>> {
+        self.effect_handler.clone()
+    }
+
+    /// Set the recorder of every effect run, inline and durable, with its name, entity,
+    /// duration and outcome, see `EffectRecorder`. Every effect runs in a span of its own
+    /// either way, effects are not recorded by default.
+    pub fn with_effect_recorder(mut self, effect_recorder: impl EffectRecorder + 'static) -> Self {
+        self.effect_recorder = Some(Arc::new(effect_recorder));
+        self
+    }
+
+    pub fn effect_recorder(&self) -> Option<Arc<dyn EffectRecorder>> {
+        self.effect_recorder.clone()
+    }
+
+    /// Set the number of messages the mailbox of every actor holds, `MAILBOX_CAPACITY` by
+    /// default. Senders wait for room in a full mailbox, so a larger one absorbs bursts of
+    /// commands and queries at the cost of memory. A warning is logged, and the actor
+    /// observer notified, see `ActorObserver::on_mailbox_pressure`, when the messages in
+    /// flight to the engine reach three quarters of it.
+    pub fn with_mailbox_capacity(mut self, mailbox_capacity: usize) -> Self {
+        self.mailbox_capacity = mailbox_capacity;
+        self
+    }
+
+    pub fn mailbox_capacity(&self) -> usize {
+        self.mailbox_capacity
+    }
+
+    /// Every problem with the configuration, empty if it is valid.
+    pub(crate) fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.command_topics.is_empty() {
+            problems.push("no command topic is configured".to_string());
+        }
+        if self
+            .command_topics
+            .iter()
+            .any(|topic| topic.trim().is_empty())
+        {
+            problems.push("command topics must not be empty".to_string());
+        }
+        if self.max_payload_size == Some(0) {
+            problems.push("the maximum payload size must be greater than 0".to_string());
+        }
+        if self.max_event_size == Some(0) {
+            problems.push("the maximum event size must be greater than 0".to_string());
+        }
+        if self
+            .aggregate_type
+            .as_deref()
+            .is_some_and(|aggregate_type| aggregate_type.is_empty() || aggregate_type.contains(':'))
+        {
+            problems.push("the aggregate type must not be empty nor contain ':'".to_string());
+        }
+        if self.idempotency_window.is_zero() {
+            problems.push("the idempotency window must be greater than 0".to_string());
+        }
+        if self.consistency_timeout.is_zero() {
+            problems.push("the consistency timeout must be greater than 0".to_string());
+        }
+        if self
+            .stuck_entity
+            .is_some_and(|(timeout, _)| timeout.is_zero())
+        {
+            problems.push("the stuck entity timeout must be greater than 0".to_string());
+        }
+        if self.snapshot_every == Some(0) {
+            problems.push("snapshots must be taken every 1 event or more".to_string());
+        }
+        if self.max_entities == Some(0) {
+            problems.push("the maximum number of entities must be greater than 0".to_string());
+        }
+        if self.mailbox_capacity == 0 {
+            problems.push("the mailbox capacity must be greater than 0".to_string());
+        }
+        if self.relay_batch_size == 0 {
+            problems.push("the relay batch size must be greater than 0".to_string());
+        }
+        if self.relay_interval.is_zero() {
+            problems.push("the relay interval must be greater than 0".to_string());
+        }
+        if self.dead_letter_topic.trim().is_empty() {
+            problems.push("the dead-letter topic must not be empty".to_string());
+        }
+        if self
+            .max_poll_interval
+            .is_some_and(|interval| interval.is_zero())
+        {
+            problems.push("the maximum poll interval must be greater than 0".to_string());
+        }
+        if self
+            .session_timeout
+            .is_some_and(|timeout| timeout.is_zero())
+        {
+            problems.push("the session timeout must be greater than 0".to_string());
+        }
+        if let (Some(interval), Some(timeout)) = (self.max_poll_interval, self.session_timeout) {
+            if interval < timeout {
+                problems.push(
+                    "the maximum poll interval must not be shorter than the session timeout"
+                        .to_string(),
+                );
+            }
+        }
+        if self.poll_timeout.is_zero() {
+            problems.push("the poll timeout must be greater than 0".to_string());
+        }
+        if self.replay_throttle.max_concurrent_entities() == Some(0) {
+            problems.push(
+                "the maximum number of concurrent rebuilds must be greater than 0".to_string(),
+            );
+        }
+
+        problems
+    }
+
+    /// Every option set for a state other than `State`, the state of the engine being started.
+    /// Such options would otherwise be silently ignored, e.g. snapshots enabled with
+    /// `with_snapshot_every::<Other>` would never be written.
+    pub(crate) fn state_problems<State>(&self) -> Vec<String>
+    where
+        State: 'static,
+    {
+        [
+            ("the snapshot upcaster is", &self.snapshot_upcaster),
+            ("snapshots are", &self.snapshot_encoder),
+            ("state publishing is", &self.state_encoder),
+        ]
+        .into_iter()
+        .filter_map(|(option, of_state)| {
+            of_state
+                .as_ref()
+                .filter(|of_state| of_state.state != TypeId::of::<State>())
+                .map(|of_state| {
+                    format!(
+                        "{} set for state {}, but the engine's state is {}",
+                        option,
+                        of_state.name,
+                        std::any::type_name::<State>()
+                    )
+                })
+        })
+        .collect()
+    }
+
+    /// Set the policy deciding what happens to commands that fail to be processed,
+    /// defaults to `DefaultFailurePolicy`.
+    pub fn with_failure_policy(mut self, failure_policy: impl FailurePolicy + 'static) -> Self {
+        self.failure_policy = Arc::new(failure_policy);
+        self
+    }
+
+    pub fn failure_policy(&self) -> Arc<dyn FailurePolicy> {
+        self.failure_policy.clone()
+    }
+
+    /// Set the topic dead-lettered commands are produced to, defaults to
+    /// `DEAD_LETTER_TOPIC`.
+    pub fn with_dead_letter_topic(mut self, dead_letter_topic: impl Into<String>) -> Self {
+        self.dead_letter_topic = dead_letter_topic.into();
+        self
+    }
+
+    pub fn dead_letter_topic(&self) -> &str {
+        &self.dead_letter_topic
+    }
+
+    /// Set what happens to commands of an unknown type, e.g. `UnknownCommandPolicy::Requeue`
+    /// or a handler of its own, see `UnknownCommandHandler`. Without a handler, unknown
+    /// commands are handed to the failure policy like any other failed command.
+    pub fn with_unknown_command_handler(
+        mut self,
+        unknown_command_handler: impl UnknownCommandHandler + 'static,
+    ) -> Self {
+        self.unknown_command_handler = Some(Arc::new(unknown_command_handler));
+        self
+    }
+
+    pub fn unknown_command_handler(&self) -> Option<Arc<dyn UnknownCommandHandler>> {
+        self.unknown_command_handler.clone()
+    }
+
+    /// Set the upcaster versioning the snapshots of `State`, defaults to `DiscardOutdated`.
+    /// An engine of another state fails to start with `Error::InvalidConfiguration`.
+    pub fn with_snapshot_upcaster<State>(
+        mut self,
+        snapshot_upcaster: impl SnapshotUpcaster<State> + 'static,
+    ) -> Self
+    where
+        State: 'static,
+    {
+        let snapshot_upcaster: Arc<dyn SnapshotUpcaster<State>> = Arc::new(snapshot_upcaster);
+        self.snapshot_upcaster = Some(OfState::new::<State>(snapshot_upcaster));
+        self
+    }
+
+    /// The upcaster of the snapshots of `State`, `DiscardOutdated` if none was set for it.
+    pub fn snapshot_upcaster<State>(&self) -> Arc<dyn SnapshotUpcaster<State>>
+    where
+        State: 'static,
+    {
+        self.snapshot_upcaster
+            .as_ref()
+            .and_then(OfState::get::<Arc<dyn SnapshotUpcaster<State>>>)
+            .unwrap_or_else(|| Arc::new(DiscardOutdated))
+    }
+
+    /// Snapshot the state of an entity every `every` events, i.e. whenever a command moves
+    /// its sequence number past a multiple of `every`. Disabled by default.
+    ///
+    /// Once enabled, entities are loaded from their latest snapshot, replaying only the
+    /// events that follow it, and so are their states read with `Engine::state`. Snapshots
+    /// are only a cache of the events: one that cannot be read or written is logged and the
+    /// events are replayed instead. Snapshots are versioned by the `SnapshotUpcaster` of
+    /// `State`, bump its version when the state changes shape. An engine of another state
+    /// fails to start with `Error::InvalidConfiguration`.
+    pub fn with_snapshot_every<State>(mut self, every: u64) -> Self
+    where
+        State: Serialize + 'static,
+    {
+        let encoder: SnapshotEncoder<State> = |state| serde_json::to_value(state);
+        self.snapshot_every = Some(every);
+        self.snapshot_encoder = Some(OfState::new::<State>(encoder));
+        self
+    }
+
+    pub fn snapshot_every(&self) -> Option<u64> {
+        self.snapshot_every
+    }
+
+    /// Serializes the snapshots of `State`, None unless snapshots are enabled for it.
+    pub(crate) fn snapshot_encoder<State>(&self) -> Option<SnapshotEncoder<State>>
+    where
+        State: 'static,
+    {
+        self.snapshot_encoder
+            .as_ref()
+            .and_then(OfState::get::<SnapshotEncoder<State>>)
+    }
+
+    /// Publish the state of an entity to `STATE_TOPIC` after every command that writes events,
+    /// keyed by entity id, so a compacted topic holds the latest state of every entity, see
+    /// `create_compacted_topic`. The state is published as JSON, deleted entities as a
+    /// tombstone. Disabled by default.
+    ///
+    /// Storage is the source of truth, so a state that fails to be published is logged and
+    /// the next command of the entity publishes its state again. An engine of another state
+    /// fails to start with `Error::InvalidConfiguration`.
+    pub fn with_state_publishing<State>(mut self) -> Self
+    where
+        State: Serialize + 'static,
+    {
+        let encoder: SnapshotEncoder<State> = |state| serde_json::to_value(state);
+        self.state_encoder = Some(OfState::new::<State>(encoder));
+        self
+    }
+
+    /// Serializes the states of `State` published to the state topic, None unless state
+    /// publishing is enabled for it.
+    pub(crate) fn state_encoder<State>(&self) -> Option<SnapshotEncoder<State>>
+    where
+        State: 'static,
+    {
+        self.state_encoder
+            .as_ref()
+            .and_then(OfState::get::<SnapshotEncoder<State>>)
+    }
+
+    /// Run the engine's actors on the given arbiter rather than on the arbiter of the task
+    /// starting the engine. This lets the engine run on a runtime of its own, e.g. one built
+    /// with `Arbiter::with_tokio_rt`, or be started from outside of an actix `System`.
+    ///
+    /// # Examples
+    /// ```rust,ignore
+    /// let arbiter = Arbiter::with_tokio_rt(|| {
+    ///     tokio::runtime::Builder::new_current_thread()
+    ///         .enable_all()
+    ///         .build()
+    ///         .unwrap()
+    /// });
+    /// let config = EngineConfig::default().with_arbiter(arbiter.handle());
+    /// ```
+    pub fn with_arbiter(mut self, arbiter: ArbiterHandle) -> Self {
+        self.arbiter = Some(arbiter);
+        self
+    }
+
+    pub fn arbiter(&self) -> Option<&ArbiterHandle> {
+        self.arbiter.as_ref()
+    }
+
+    /// Set the codec encoding the records published to the event topic, defaults to
+    /// `JsonCodec`.
+    pub fn with_event_codec(mut self, event_codec: impl EventCodec + 'static) -> Self {
+        self.event_codec = Arc::new(event_codec);
+        self
+    }
+
+    pub fn event_codec(&self) -> Arc<dyn EventCodec> {
+        self.event_codec.clone()
+    }
+
+    /// Start a supervised actor on the configured arbiter, if any, or on the current one.
+    pub(crate) fn start<A>(&self, actor: A) -> Addr<A>
+    where
+        A: Actor<Context = Context<A>> + Supervised + Send,
+    {
+        let capacity = self.mailbox_capacity;
+        match &self.arbiter {
+            Some(arbiter) => Supervisor::start_in_arbiter(arbiter, move |ctx: &mut Context<A>| {
+                ctx.set_mailbox_capacity(capacity);
+                actor
+            }),
+            None => self.start_local(actor),
+        }
+    }
+
+    /// Start a supervised actor on the current arbiter, e.g. next to the actor starting it.
+    pub(crate) fn start_local<A>(&self, actor: A) -> Addr<A>
+    where
+        A: Actor<Context = Context<A>> + Supervised,
+    {
+        let capacity = self.mailbox_capacity;
+        Supervisor::start(move |ctx: &mut Context<A>| {
+            ctx.set_mailbox_capacity(capacity);
+            actor
+        })
+    }
+
+    /// Notify the actor observer, if any, of a failure.
+    pub(crate) fn report(&self, failure: ActorFailure) {
+        if let Some(observer) = &self.actor_observer {
+            observer.on_actor_error(&failure.with_aggregate_type(self.aggregate_type()));
+        }
+    }
+
+    /// Log and report the restart of an actor by its supervisor, with the entity of the actor,
+    /// if any, and the aggregate type of the engine.
+    pub(crate) fn restarted(&self, kind: ActorKind, entity_id: Option<&str>) {
+        let failure = ActorFailure::new(kind, entity_id, "Actor restarted by its supervisor")
+            .with_aggregate_type(self.aggregate_type());
+        tracing::warn!("{}", failure);
+        self.report(failure);
+    }
+
+    /// Notify the actor observer, if any, of the pressure on a mailbox.
+    pub(crate) fn report_pressure(&self, pressure: &MailboxPressure) {
+        if let Some(observer) = &self.actor_observer {
+            observer.on_mailbox_pressure(pressure);
+        }
+    }
+}
+
+/// Check that an entity belongs to an aggregate type, if any, see
+/// `EngineConfig::with_aggregate_type`.
+pub(crate) fn check_aggregate_type(
+    aggregate_type: Option<&str>,
+    entity_id: &str,
+) -> Result<Unit, Error> {
+    match aggregate_type {
+        Some(aggregate_type)
+            if entity_id.split_once(':').map(|(category, _)| category) != Some(aggregate_type) =>
+        {
+            Err(Error::InvalidEntityId(format!(
+                "Entity {} is not of aggregate type {}, its id must start with {}:",
+                entity_id, aggregate_type, aggregate_type
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Serialize)]
+    struct Order;
+
+    #[derive(Debug, Serialize)]
+    struct Payment;
+
+    #[test]
+    fn options_set_for_another_state_are_problems() {
+        let config = EngineConfig::default()
+            .with_snapshot_every::<Payment>(10)
+            .with_snapshot_upcaster::<Order>(DiscardOutdated)
+            .with_state_publishing::<Order>();
+
+        let problems = config.state_problems::<Order>();
+
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("snapshots are set for state"));
+        assert!(config.snapshot_encoder::<Order>().is_none());
+        assert!(config.state_encoder::<Order>().is_some());
+    }
+}
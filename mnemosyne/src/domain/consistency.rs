@@ -0,0 +1,16 @@
+/// How up to date the state returned by `Engine::state_with_consistency` is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Consistency {
+    /// Return the state as it is stored, commands still on the command topic are not
+    /// reflected in it.
+    #[default]
+    Eventual,
+    /// Wait until the last command this instance enqueued for the entity before the call is
+    /// processed, by whichever instance of the consumer group consumes it, i.e.
+    /// read-your-writes.
+    ///
+    /// Processed means the consumer group committed past the command on its partition, which
+    /// happens once it is applied, skipped or dead-lettered. The wait fails with
+    /// `Error::ConsistencyTimeout` once it exceeds `EngineConfig::with_consistency_timeout`.
+    Strong,
+}
@@ -0,0 +1,117 @@
+use crate::{domain::Error, storage::PendingEffect, Unit};
+use futures::{future::BoxFuture, Future};
+use std::{
+    fmt::Debug,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::Instrument;
+
+/// Runs the durable effects of commands, see `Command::durable_effects` and
+/// `EngineConfig::with_effect_handler`.
+///
+/// An effect is run until the handler returns `Ok`, so it runs at least once and possibly
+/// more than once, e.g. when the engine stops after running it but before marking it as
+/// done. Handlers should be idempotent, the id of the effect makes a good idempotency key
+/// for the system called.
+///
+/// # Examples
+/// ```rust,ignore
+/// #[derive(Debug)]
+/// struct Mailer(Client);
+///
+/// impl EffectHandler for Mailer {
+///     fn run<'a>(&'a self, effect: &'a PendingEffect) -> BoxFuture<'a, Result<Unit, Error>> {
+///         Box::pin(async move {
+///             match effect.name() {
+///                 "send_welcome_email" => self.0.send(effect.payload::<WelcomeEmail>()?).await,
+///                 _ => Ok(()),
+///             }
+///         })
+///     }
+/// }
+/// ```
+pub trait EffectHandler: Debug + Send + Sync {
+    fn run<'a>(&'a self, effect: &'a PendingEffect) -> BoxFuture<'a, Result<Unit, Error>>;
+}
+
+/// Records every effect run, inline ones, see `Command::effects`, and durable ones, see
+/// `Command::durable_effects`, e.g. to count failures and time effects per name, see
+/// `EngineConfig::with_effect_recorder`.
+///
+/// The recorder is called from within the actors, so it should return quickly.
+pub trait EffectRecorder: Debug + Send + Sync {
+    fn record(&self, run: &EffectRun);
+}
+
+/// A run of an effect, see `EffectRecorder`.
+#[derive(Debug, Clone)]
+pub struct EffectRun {
+    name: String,
+    entity_id: String,
+    durable: bool,
+    duration: Duration,
+    error: Option<String>,
+}
+
+impl EffectRun {
+    /// The name of the effect, the name of the command for inline effects.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn entity_id(&self) -> &str {
+        &self.entity_id
+    }
+
+    /// Whether the effect is durable, i.e. run again until it succeeds.
+    pub fn is_durable(&self) -> bool {
+        self.durable
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// The error the effect failed with, if any.
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Run an effect in a span of its own, named `effect`, and record how it went.
+pub(crate) async fn run_effect<F>(
+    recorder: Option<&Arc<dyn EffectRecorder>>,
+    name: &str,
+    entity_id: &str,
+    durable: bool,
+    effect: F,
+) -> Result<Unit, Error>
+where
+    F: Future<Output = Result<Unit, Error>>,
+{
+    let span = tracing::info_span!("effect", effect = name, entity_id, durable);
+    let start = Instant::now();
+    let result = effect.instrument(span.clone()).await;
+    let duration = start.elapsed();
+
+    span.in_scope(|| match &result {
+        Ok(()) => tracing::debug!("Effect {} ran in {:?}", name, duration),
+        Err(e) => tracing::warn!("Effect {} failed after {:?}: {}", name, duration, e),
+    });
+    if let Some(recorder) = recorder {
+        recorder.record(&EffectRun {
+            name: name.to_string(),
+            entity_id: entity_id.to_string(),
+            durable,
+            duration,
+            error: result.as_ref().err().map(ToString::to_string),
+        });
+    }
+
+    result
+}
@@ -1,16 +1,18 @@
 use crate::{
-    algebra::{Command, Event},
+    algebra::{Command, EnqueueHandle, Event, StateFactory},
     domain::Error,
-    Unit,
 };
 use actix::prelude::*;
+use chrono::{DateTime, Utc};
 use serde::{de::DeserializeOwned, Serialize};
 use std::fmt::Debug;
+use uuid::Uuid;
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub enum EnqueueType<Cmd, Evt, State>
 where
-    State: Debug + Send + Sync + Unpin + Clone + Default + 'static + DeserializeOwned,
+    State: Debug + Send + Sync + Unpin + Clone + StateFactory + 'static + DeserializeOwned,
     Cmd: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Command<State>,
     Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
 {
@@ -20,14 +22,18 @@ where
 }
 
 #[derive(Message, Debug)]
-#[rtype(result = "Result<Unit, Error>")]
+#[rtype(result = "Result<EnqueueHandle<State, Cmd::T>, Error>")]
 pub struct Enqueue<Cmd, Evt, State>
 where
     Cmd: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Command<State>,
     Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
-    State: Debug + Send + Sync + Unpin + Clone + Default + 'static + DeserializeOwned,
+    State: Debug + Send + Sync + Unpin + Clone + StateFactory + 'static + DeserializeOwned,
 {
     element: EnqueueType<Cmd, Evt, State>,
+    traceparent: Option<String>,
+    idempotency_key: Option<String>,
+    correlation_id: Option<Uuid>,
+    expires_at: Option<DateTime<Utc>>,
     _marker: std::marker::PhantomData<State>,
 }
 
@@ -35,15 +41,60 @@ impl<Cmd, Evt, State> Enqueue<Cmd, Evt, State>
 where
     Evt: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Event<State> + Serialize,
     Cmd: Send + Sync + Unpin + 'static + DeserializeOwned + Debug + Command<State>,
-    State: Debug + Send + Sync + Unpin + Clone + Default + 'static + DeserializeOwned,
+    State: Debug + Send + Sync + Unpin + Clone + StateFactory + 'static + DeserializeOwned,
 {
     pub fn from_command(command: Cmd) -> Self {
         Self {
             element: EnqueueType::Command(command),
+            traceparent: None,
+            idempotency_key: None,
+            correlation_id: None,
+            expires_at: None,
             _marker: std::marker::PhantomData,
         }
     }
 
+    /// Attach the W3C `traceparent` the produced command record carries.
+    #[cfg(feature = "otel")]
+    pub fn with_traceparent(mut self, traceparent: Option<String>) -> Self {
+        self.traceparent = traceparent;
+        self
+    }
+
+    pub fn traceparent(&self) -> Option<&str> {
+        self.traceparent.as_deref()
+    }
+
+    /// Attach the idempotency key the produced command record carries.
+    pub fn with_idempotency_key(mut self, idempotency_key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(idempotency_key.into());
+        self
+    }
+
+    pub fn idempotency_key(&self) -> Option<&str> {
+        self.idempotency_key.as_deref()
+    }
+
+    /// Attach the correlation id the produced command record carries.
+    pub fn with_correlation_id(mut self, correlation_id: Uuid) -> Self {
+        self.correlation_id = Some(correlation_id);
+        self
+    }
+
+    pub fn correlation_id(&self) -> Option<Uuid> {
+        self.correlation_id
+    }
+
+    /// Attach the time the produced command record expires at.
+    pub fn with_expires_at(mut self, expires_at: DateTime<Utc>) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.expires_at
+    }
+
     pub fn command(&self) -> Option<&Cmd> {
         match &self.element {
             EnqueueType::Command(command) => Some(command),
@@ -9,14 +9,48 @@ use tokio_postgres::Error as PostgresError;
 pub enum Error {
     #[error("Actix error: {0}")]
     Actix(#[from] actix::MailboxError),
+    /// A creation command was sent to an entity that already has events, see
+    /// `Command::is_creation`.
+    #[error("Entity {0} already exists")]
+    AlreadyExists(String),
+    #[error("Concurrency conflict for entity {entity_id}: expected highest sequence number {expected}, found {actual}")]
+    ConcurrencyConflict {
+        entity_id: String,
+        expected: u64,
+        actual: u64,
+    },
+    /// A `Consistency::Strong` read gave up waiting for the last command of its entity to be
+    /// processed, see `EngineConfig::with_consistency_timeout`.
+    #[error("Consistency timeout: {0}")]
+    ConsistencyTimeout(String),
     #[error("Unable to connect to database.")]
     ConnectionError(#[source] BuildError),
+    /// The connection to the database was lost while streaming, and could not be resumed.
+    #[error("Connection to database lost: {0}")]
+    ConnectionLost(String),
     #[error("Unable to retrieve database connection.")]
     ConnectionRetrievalError(#[source] PoolError<PostgresError>),
     #[error("Decoding error: {0}")]
     Decoding(String),
+    #[error(transparent)]
+    EmptyVec(#[from] EmptyVecError),
+    /// A command was sent to an entity deleted by a tombstone event, see `Event::deletes`.
+    #[error("Entity {0} is deleted")]
+    EntityDeleted(String),
+    /// A command held its entity for longer than the stuck entity timeout and was abandoned,
+    /// see `StuckEntityPolicy::Restart`. Its events may have been written, so it is not
+    /// retryable.
+    #[error("Entity {0} is stuck")]
+    EntityStuck(String),
+    /// A command other than a creation command was sent to an entity without events, see
+    /// `Command::is_creation`.
+    #[error("Entity {0} not found")]
+    EntityNotFound(String),
     #[error("{0}")]
     Error(String),
+    /// A command was not processed before it expired, see `CommandEnvelope::expires_in`.
+    #[error("Command expired: {0}")]
+    Expired(String),
     #[error("Invalid entity id: {0}")]
     InvalidEntityId(String),
     #[error("Invalid configuration: {0}")]
@@ -31,18 +65,152 @@ pub enum Error {
     InvalidState(String),
     #[error("Kafka error: {0}")]
     Kafka(#[from] KafkaError),
+    #[error("Partial write for entity {entity_id}: wrote {written} of {expected} records")]
+    PartialWrite {
+        expected: usize,
+        written: usize,
+        entity_id: String,
+    },
+    /// An event serialized to more bytes than allowed, see `EngineConfig::with_max_event_size`.
+    #[error("Event of entity {entity_id} is {size} bytes, above the maximum of {max} bytes")]
+    PayloadTooLarge {
+        entity_id: String,
+        size: usize,
+        max: usize,
+    },
+    #[error("Rate limited: {0}")]
+    RateLimited(String),
+    /// A command was rejected by `Command::validate`, with a machine-readable code
+    /// that callers can branch on, see `Command::reject`.
+    #[error("Command rejected ({code}): {message}")]
+    Rejected { code: String, message: String },
     #[error("System error: {0}")]
     System(#[from] Box<dyn StdError + Send + Sync>),
     #[error("Storage error: {0}")]
     StorageError(String),
+    /// A command could be read, but is not of a type the engine knows, see
+    /// `UnknownCommandPolicy`.
+    #[error("Unknown command: {0}")]
+    UnknownCommand(String),
     #[error("Command validation error: {0}")]
     Validation(String),
+    /// A state was asked for at a sequence number the entity has not reached, see
+    /// `Engine::state_at_version`.
+    #[error("Entity {entity_id} has no version {seq_nr}, its highest is {highest}")]
+    VersionNotFound {
+        entity_id: String,
+        seq_nr: u64,
+        highest: u64,
+    },
+    /// The sequence numbers of an entity have gaps, i.e. events were lost, see
+    /// `EngineConfig::with_gap_check`.
+    #[error("Entity {entity_id} is missing the events at sequence numbers {missing:?}")]
+    SequenceGap {
+        entity_id: String,
+        missing: Vec<u64>,
+    },
 }
 
+/// A `NonEmptyVec` was built from an empty vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("Empty vector")]
+pub struct EmptyVecError;
+
 impl Error {
     pub fn new(message: &str) -> Self {
         Error::Error(message.to_string())
     }
+
+    /// Whether the failure is transient, i.e. whether processing the same command again
+    /// may succeed. Retryable failures keep the offset of the command from being committed.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Error::StorageError(_)
+                | Error::ConnectionError(_)
+                | Error::ConnectionLost(_)
+                | Error::ConnectionRetrievalError(_)
+                | Error::PartialWrite { .. }
+        )
+    }
+}
+
+impl Error {
+    /// Produce an owned copy of the error. Variants wrapping errors that cannot be
+    /// cloned keep their variant where possible and carry the rendered message instead.
+    pub(crate) fn replicate(&self) -> Self {
+        match self {
+            Error::Actix(e) => Error::Actix(*e),
+            Error::AlreadyExists(e) => Error::AlreadyExists(e.clone()),
+            Error::ConcurrencyConflict {
+                entity_id,
+                expected,
+                actual,
+            } => Error::ConcurrencyConflict {
+                entity_id: entity_id.clone(),
+                expected: *expected,
+                actual: *actual,
+            },
+            Error::ConsistencyTimeout(e) => Error::ConsistencyTimeout(e.clone()),
+            Error::ConnectionError(e) => Error::StorageError(e.to_string()),
+            Error::ConnectionLost(e) => Error::ConnectionLost(e.clone()),
+            Error::ConnectionRetrievalError(e) => Error::StorageError(e.to_string()),
+            Error::Decoding(e) => Error::Decoding(e.clone()),
+            Error::EmptyVec(e) => Error::EmptyVec(*e),
+            Error::EntityDeleted(e) => Error::EntityDeleted(e.clone()),
+            Error::EntityStuck(e) => Error::EntityStuck(e.clone()),
+            Error::EntityNotFound(e) => Error::EntityNotFound(e.clone()),
+            Error::Error(e) => Error::Error(e.clone()),
+            Error::Expired(e) => Error::Expired(e.clone()),
+            Error::InvalidEntityId(e) => Error::InvalidEntityId(e.clone()),
+            Error::InvalidConfiguration(e) => Error::InvalidConfiguration(e.clone()),
+            Error::InvalidKey(e) => Error::InvalidKey(e.clone()),
+            Error::InvalidCommand(e) => Error::InvalidCommand(e.clone()),
+            Error::InvalidEvent(e) => Error::InvalidEvent(e.clone()),
+            Error::InvalidState(e) => Error::InvalidState(e.clone()),
+            Error::Kafka(e) => Error::Kafka(e.clone()),
+            Error::PartialWrite {
+                expected,
+                written,
+                entity_id,
+            } => Error::PartialWrite {
+                expected: *expected,
+                written: *written,
+                entity_id: entity_id.clone(),
+            },
+            Error::PayloadTooLarge {
+                entity_id,
+                size,
+                max,
+            } => Error::PayloadTooLarge {
+                entity_id: entity_id.clone(),
+                size: *size,
+                max: *max,
+            },
+            Error::RateLimited(e) => Error::RateLimited(e.clone()),
+            Error::Rejected { code, message } => Error::Rejected {
+                code: code.clone(),
+                message: message.clone(),
+            },
+            Error::System(e) => Error::Error(e.to_string()),
+            Error::StorageError(e) => Error::StorageError(e.clone()),
+            Error::UnknownCommand(e) => Error::UnknownCommand(e.clone()),
+            Error::Validation(e) => Error::Validation(e.clone()),
+            Error::VersionNotFound {
+                entity_id,
+                seq_nr,
+                highest,
+            } => Error::VersionNotFound {
+                entity_id: entity_id.clone(),
+                seq_nr: *seq_nr,
+                highest: *highest,
+            },
+            Error::SequenceGap { entity_id, missing } => Error::SequenceGap {
+                entity_id: entity_id.clone(),
+                missing: missing.clone(),
+            },
+        }
+    }
 }
 
 impl From<Error> for KafkaError {
@@ -0,0 +1,115 @@
+use super::{Error, RETRY_ATTEMPTS, RETRY_BACKOFF};
+use crate::algebra::Record;
+use serde_json::Value;
+use std::{fmt::Debug, time::Duration};
+
+/// What to do with a command that failed to be processed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureAction {
+    /// Process the command again, up to `max` more times, waiting `backoff` before every
+    /// attempt. The command is dead-lettered if the last attempt fails too.
+    Retry { max: u32, backoff: Duration },
+    /// Produce the command to the dead-letter topic, see
+    /// `EngineConfig::with_dead_letter_topic`, and move on.
+    DeadLetter,
+    /// Log the failure and move on.
+    Skip,
+}
+
+/// Decides what happens to a command that failed to be processed, based on its error.
+///
+/// Retries happen in place: the commands following a retried command of the same entity
+/// wait for it, commands of other entities do not. Commands are only committed once they
+/// are processed, skipped or dead-lettered, a command that cannot be dead-lettered is
+/// consumed again, followed by the commands of its entity after it. The commands of other
+/// entities dealt with meanwhile are not processed again. A command whose entity cannot be
+/// told, e.g. one without a key, fails with `Error::InvalidKey` and is dealt with like any
+/// other.
+///
+/// # Examples
+/// ```rust,ignore
+/// #[derive(Debug)]
+/// struct Policy;
+///
+/// impl FailurePolicy for Policy {
+///     fn action(&self, error: &Error) -> FailureAction {
+///         match error {
+///             Error::Validation(_) => FailureAction::DeadLetter,
+///             error if error.is_retryable() => FailureAction::Retry {
+///                 max: 5,
+///                 backoff: Duration::from_millis(500),
+///             },
+///             _ => FailureAction::Skip,
+///         }
+///     }
+/// }
+/// ```
+pub trait FailurePolicy: Debug + Send + Sync {
+    fn action(&self, error: &Error) -> FailureAction;
+}
+
+/// The default policy, retries errors that are retryable, see `Error::is_retryable`, and
+/// skips every other error.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultFailurePolicy;
+
+impl FailurePolicy for DefaultFailurePolicy {
+    fn action(&self, error: &Error) -> FailureAction {
+        if error.is_retryable() {
+            FailureAction::Retry {
+                max: RETRY_ATTEMPTS,
+                backoff: Duration::from_secs(RETRY_BACKOFF),
+            }
+        } else {
+            FailureAction::Skip
+        }
+    }
+}
+
+/// What to do with a command of a type this instance does not know, e.g. one produced by a
+/// newer version of the service during a rolling deploy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownCommandPolicy {
+    /// Log the command and move on.
+    Skip,
+    /// Produce the command to the dead-letter topic, see
+    /// `EngineConfig::with_dead_letter_topic`, and move on.
+    DeadLetter,
+    /// Produce the command to the end of the topic it was consumed from, so it can be picked
+    /// up again, e.g. by an instance that was upgraded in the meantime. Commands no instance
+    /// knows go round in circles, pair it with a handler that eventually dead-letters them.
+    Requeue,
+}
+
+/// Decides what happens to a command of an unknown type, see
+/// `EngineConfig::with_unknown_command_handler`.
+///
+/// A command is unknown when its record can be read, but its message cannot be decoded into
+/// the command type of the engine. The handler gets the record with the message as JSON, so
+/// it can also act on it, e.g. log or forward it. `UnknownCommandPolicy` is a handler that
+/// always applies itself.
+///
+/// # Examples
+/// ```rust,ignore
+/// #[derive(Debug)]
+/// struct Handler;
+///
+/// impl UnknownCommandHandler for Handler {
+///     fn handle(&self, record: &Record<Value>) -> UnknownCommandPolicy {
+///         match record.source() {
+///             // Produced by the service being rolled out, another instance knows it
+///             Some("orders") => UnknownCommandPolicy::Requeue,
+///             _ => UnknownCommandPolicy::DeadLetter,
+///         }
+///     }
+/// }
+/// ```
+pub trait UnknownCommandHandler: Debug + Send + Sync {
+    fn handle(&self, record: &Record<Value>) -> UnknownCommandPolicy;
+}
+
+impl UnknownCommandHandler for UnknownCommandPolicy {
+    fn handle(&self, _record: &Record<Value>) -> UnknownCommandPolicy {
+        *self
+    }
+}
@@ -0,0 +1,7 @@
+use crate::{domain::Error, Unit};
+use actix::prelude::*;
+
+/// Check the health of the storage backing the engine.
+#[derive(Message, Debug, Default)]
+#[rtype(result = "Result<Unit, Error>")]
+pub struct Health;
@@ -0,0 +1,34 @@
+use crate::storage::fnv1a;
+use std::fmt::Debug;
+
+/// Builds the key of the Kafka message carrying a command, which decides the partition of
+/// the command, see `EngineConfig::with_command_key`.
+///
+/// The entity id travels in the payload of the command, the consumer reads it from there,
+/// so the key only partitions commands. It must be the same for every command of an
+/// entity, as that keeps its commands on one partition and so in order.
+pub trait CommandKey: Debug + Send + Sync {
+    fn key(&self, entity_id: &str) -> Vec<u8>;
+}
+
+/// The default key, the UTF-8 bytes of the entity id.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EntityIdKey;
+
+impl CommandKey for EntityIdKey {
+    fn key(&self, entity_id: &str) -> Vec<u8> {
+        entity_id.as_bytes().to_vec()
+    }
+}
+
+/// A 64-bit FNV-1a hash of the entity id, big endian, e.g. to balance entity ids sharing a
+/// long common prefix over the partitions, or to keep them out of the key. The hash is
+/// stable across processes and releases.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HashedKey;
+
+impl CommandKey for HashedKey {
+    fn key(&self, entity_id: &str) -> Vec<u8> {
+        fnv1a(entity_id.as_bytes()).to_be_bytes().to_vec()
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Upper bounds of the buckets of `LatencyHistogram`, in milliseconds. Latencies above the
+/// last bound fall in an unbounded bucket.
+pub const LATENCY_BUCKETS: [u64; 14] = [
+    1, 2, 5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 30_000,
+];
+
+/// Records how long commands took from being enqueued to being applied, i.e. the time spent
+/// on the command topic plus the time spent processing, see
+/// `EngineConfig::with_latency_recorder`.
+///
+/// Only applied commands are recorded. The latency is measured against the timestamp the
+/// command was enqueued at, clock skew between instances shows up in the latency. The
+/// recorder is called from within the actors, so it should return quickly.
+pub trait LatencyRecorder: Debug + Send + Sync {
+    fn record(&self, entity_id: &str, latency: Duration);
+}
+
+/// A histogram of command latencies with fixed buckets, see `LATENCY_BUCKETS`, which also
+/// keeps the most recent latencies. Clones share the same histogram, so keep a clone to read
+/// it while the engine records into it.
+///
+/// # Examples
+/// ```rust,ignore
+/// let latencies = LatencyHistogram::new(1000);
+/// let config = EngineConfig::default().with_latency_recorder(latencies.clone());
+///
+/// // Later on
+/// let p99 = latencies.quantile(0.99);
+/// ```
+#[derive(Debug, Clone)]
+pub struct LatencyHistogram {
+    buckets: Arc<[AtomicU64; LATENCY_BUCKETS.len() + 1]>,
+    sum: Arc<AtomicU64>,
+    recent: Arc<Mutex<VecDeque<Duration>>>,
+    recent_capacity: usize,
+}
+
+impl LatencyHistogram {
+    /// Create a histogram keeping the last `recent_capacity` latencies.
+    pub fn new(recent_capacity: usize) -> Self {
+        Self {
+            buckets: Arc::new(std::array::from_fn(|_| AtomicU64::new(0))),
+            sum: Arc::new(AtomicU64::new(0)),
+            recent: Arc::new(Mutex::new(VecDeque::with_capacity(recent_capacity))),
+            recent_capacity,
+        }
+    }
+
+    /// The number of latencies per bucket, paired with the upper bound of the bucket. The
+    /// last bucket has no upper bound.
+    pub fn buckets(&self) -> Vec<(Option<Duration>, u64)> {
+        LATENCY_BUCKETS
+            .iter()
+            .map(|bound| Some(Duration::from_millis(*bound)))
+            .chain(std::iter::once(None))
+            .zip(self.buckets.iter())
+            .map(|(bound, count)| (bound, count.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// The number of latencies recorded.
+    pub fn count(&self) -> u64 {
+        self.buckets
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// The sum of the latencies recorded.
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum.load(Ordering::Relaxed))
+    }
+
+    /// The upper bound of the bucket holding the given quantile, e.g. 0.99 for the 99th
+    /// percentile. None if nothing was recorded, or if the quantile falls in the unbounded
+    /// bucket.
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        let buckets = self.buckets();
+        let count = buckets.iter().map(|(_, count)| count).sum::<u64>();
+        if count == 0 {
+            return None;
+        }
+
+        let rank = ((count as f64 * quantile.clamp(0.0, 1.0)).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bound, bucket) in buckets {
+            seen += bucket;
+            if seen >= rank {
+                return bound;
+            }
+        }
+
+        None
+    }
+
+    /// The most recent latencies, oldest first.
+    pub fn recent(&self) -> Vec<Duration> {
+        self.recent
+            .lock()
+            .map(|recent| recent.iter().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+impl LatencyRecorder for LatencyHistogram {
+    fn record(&self, _entity_id: &str, latency: Duration) {
+        let millis = latency.as_millis();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| millis <= *bound as u128)
+            .unwrap_or(LATENCY_BUCKETS.len());
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+
+        if self.recent_capacity > 0 {
+            if let Ok(mut recent) = self.recent.lock() {
+                if recent.len() == self.recent_capacity {
+                    recent.pop_front();
+                }
+                recent.push_back(latency);
+            }
+        }
+    }
+}
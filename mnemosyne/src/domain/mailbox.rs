@@ -0,0 +1,96 @@
+use super::{ActorKind, EngineConfig, MailboxPressure};
+use actix::{dev::ToEnvelope, Actor, Addr, Handler, MailboxError, Message};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// The address of an actor which counts the messages sent to it and not answered yet, and
+/// reports when their number nears the mailbox capacity, see
+/// `EngineConfig::with_mailbox_capacity`.
+///
+/// Messages are counted from being sent until they are answered, so the count is an upper
+/// bound of the messages waiting in the mailbox: it includes the ones being handled.
+pub(crate) struct Mailbox<A>
+where
+    A: Actor,
+{
+    addr: Addr<A>,
+    kind: ActorKind,
+    in_flight: Arc<AtomicUsize>,
+    config: EngineConfig,
+}
+
+impl<A> Clone for Mailbox<A>
+where
+    A: Actor,
+{
+    fn clone(&self) -> Self {
+        Self {
+            addr: self.addr.clone(),
+            kind: self.kind,
+            in_flight: self.in_flight.clone(),
+            config: self.config.clone(),
+        }
+    }
+}
+
+impl<A> Mailbox<A>
+where
+    A: Actor,
+{
+    pub(crate) fn new(addr: Addr<A>, kind: ActorKind, config: &EngineConfig) -> Self {
+        Self {
+            addr,
+            kind,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            config: config.clone(),
+        }
+    }
+
+    /// Send a message like `Addr::send`, reporting the pressure on the mailbox when the
+    /// messages in flight reach three quarters of its capacity.
+    pub(crate) async fn send<M>(&self, msg: M) -> Result<M::Result, MailboxError>
+    where
+        A: Handler<M>,
+        A::Context: ToEnvelope<A, M>,
+        M: Message + Send + 'static,
+        M::Result: Send,
+    {
+        let in_flight = InFlight::enter(&self.in_flight);
+
+        let capacity = self.config.mailbox_capacity();
+        // Only report when the threshold is crossed, not for every message beyond it
+        if in_flight.count == (capacity * 3).div_ceil(4) {
+            let pressure = MailboxPressure::new(self.kind, in_flight.count, capacity);
+            tracing::warn!(
+                "The mailbox of the {:?} actor nears its capacity: {} messages in flight, capacity {}",
+                self.kind,
+                in_flight.count,
+                capacity
+            );
+            self.config.report_pressure(&pressure);
+        }
+
+        self.addr.send(msg).await
+    }
+}
+
+// A message in flight, which is no longer counted once dropped, even if the send is cancelled
+struct InFlight<'a> {
+    counter: &'a AtomicUsize,
+    count: usize,
+}
+
+impl<'a> InFlight<'a> {
+    fn enter(counter: &'a AtomicUsize) -> Self {
+        let count = counter.fetch_add(1, Ordering::SeqCst) + 1;
+        Self { counter, count }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{domain::Error, Unit};
+use futures::future::LocalBoxFuture;
+use std::{any::Any, fmt::Debug, sync::Arc};
+
+/// Wraps the processing of every command with cross-cutting logic such as timing,
+/// logging, authorization or feature flags, analogous to a tower layer.
+///
+/// Middlewares are run in the order they were registered with
+/// `EngineConfig::with_middleware`, the first one being the outermost. A middleware
+/// either calls `next.run(ctx)` to continue processing, or returns an error to reject
+/// the command before it is validated.
+///
+/// # Examples
+/// ```rust,ignore
+/// #[derive(Debug)]
+/// struct Timing;
+///
+/// impl Middleware for Timing {
+///     fn around<'a>(
+///         &'a self,
+///         ctx: &'a ProcessContext<'a>,
+///         next: Next<'a>,
+///     ) -> LocalBoxFuture<'a, Result<Unit, Error>> {
+///         Box::pin(async move {
+///             let start = std::time::Instant::now();
+///             let result = next.run(ctx).await;
+///             tracing::info!("Processed {} in {:?}", ctx.name(), start.elapsed());
+///             result
+///         })
+///     }
+/// }
+/// ```
+pub trait Middleware: Debug + Send + Sync {
+    fn around<'a>(
+        &'a self,
+        ctx: &'a ProcessContext<'a>,
+        next: Next<'a>,
+    ) -> LocalBoxFuture<'a, Result<Unit, Error>>;
+}
+
+/// The command being processed, as seen by a `Middleware`.
+pub struct ProcessContext<'a> {
+    entity_id: &'a str,
+    name: String,
+    command: &'a (dyn Any + Send + Sync),
+    source: Option<&'a str>,
+}
+
+impl<'a> ProcessContext<'a> {
+    pub(crate) fn new(
+        entity_id: &'a str,
+        name: String,
+        command: &'a (dyn Any + Send + Sync),
+        source: Option<&'a str>,
+    ) -> Self {
+        Self {
+            entity_id,
+            name,
+            command,
+            source,
+        }
+    }
+
+    pub fn entity_id(&self) -> &str {
+        self.entity_id
+    }
+
+    /// The name of the command, see `Command::name`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The service that enqueued the command, see `Record::source`.
+    pub fn source(&self) -> Option<&str> {
+        self.source
+    }
+
+    /// The command, if it is of type `Cmd`.
+    pub fn command<Cmd: 'static>(&self) -> Option<&Cmd> {
+        self.command.downcast_ref::<Cmd>()
+    }
+}
+
+type Endpoint<'a> = Box<dyn FnOnce() -> LocalBoxFuture<'a, Result<Unit, Error>> + 'a>;
+
+/// The rest of the middleware chain, ending with the processing of the command.
+pub struct Next<'a> {
+    middlewares: &'a [Arc<dyn Middleware>],
+    endpoint: Endpoint<'a>,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(middlewares: &'a [Arc<dyn Middleware>], endpoint: Endpoint<'a>) -> Self {
+        Self {
+            middlewares,
+            endpoint,
+        }
+    }
+
+    /// Run the rest of the chain.
+    pub fn run(self, ctx: &'a ProcessContext<'a>) -> LocalBoxFuture<'a, Result<Unit, Error>> {
+        match self.middlewares.split_first() {
+            Some((middleware, middlewares)) => {
+                middleware.around(ctx, Next::new(middlewares, self.endpoint))
+            }
+            None => (self.endpoint)(),
+        }
+    }
+}
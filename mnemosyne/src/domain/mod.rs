@@ -1,27 +1,112 @@
+mod codec;
+mod config;
+mod consistency;
 mod dequeue;
+mod effect;
 mod enqueue;
 mod error;
+mod failure;
+mod health;
+mod key;
+mod latency;
+mod mailbox;
+mod middleware;
+mod observer;
+#[cfg(feature = "otel")]
+mod otel;
+mod pause;
 mod process;
+mod rate_limit;
+mod sequence;
 mod state;
+mod validation;
 
+pub use codec::*;
+pub use config::*;
+pub use consistency::*;
 pub(crate) use dequeue::*;
+pub use effect::*;
 pub(crate) use enqueue::*;
 pub use error::*;
+pub use failure::*;
+pub(crate) use health::*;
+pub use key::*;
+pub use latency::*;
+pub(crate) use mailbox::*;
+pub use middleware::*;
+pub use observer::*;
+#[cfg(feature = "otel")]
+pub(crate) use otel::*;
+pub(crate) use pause::*;
 pub(crate) use process::*;
+pub use rate_limit::*;
+pub use sequence::*;
 pub(crate) use state::*;
+pub use validation::*;
 
 use serde::{Deserialize, Serialize};
 use std::{slice::Iter, vec::IntoIter};
 
 // Make all this configurable
+/// Topic holding the latest state per entity, see `EngineConfig::with_state_publishing`.
+/// Messages are keyed by entity id and the topic is expected to be compacted, see
+/// `create_compacted_topic`.
 pub const STATE_TOPIC: &str = "state";
+/// Topic holding every event, keyed by entity id.
 pub const EVENT_TOPIC: &str = "events";
 pub const COMMAND_TOPIC: &str = "commands";
+/// Topic commands are produced to when they are dead-lettered, see `FailureAction::DeadLetter`.
+pub const DEAD_LETTER_TOPIC: &str = "commands-dead-letter";
 
-pub const BATCH_BACKPRESSURE: u64 = 2;
+/// Seconds to wait for more commands when a chunk holds two commands or fewer.
 pub const CHUNK_BACKPRESSURE: u64 = 2;
-
+/// Maximum number of commands consumed and processed at once. A chunk taking long to
+/// process pauses consumption, see `EngineConfig::with_max_poll_interval`.
 pub const CHUNK_SIZE: u64 = 100;
+/// Seconds to wait before checking again whether consuming commands was resumed.
+pub const PAUSE_BACKOFF: u64 = 1;
+/// Attempts made by `DefaultFailurePolicy` to process a command again after a retryable error.
+pub const RETRY_ATTEMPTS: u32 = 3;
+/// Seconds `DefaultFailurePolicy` waits before every attempt.
+pub const RETRY_BACKOFF: u64 = 1;
+/// Seconds to wait for a partition to be rewound to a command that has to be retried.
+pub const SEEK_TIMEOUT: u64 = 5;
+/// Seconds to wait for commands before a poll of the command topics is considered idle.
+pub const POLL_TIMEOUT: u64 = 1;
+/// Seconds to wait before polling the command topics again after an idle poll.
+pub const IDLE_BACKOFF: u64 = 1;
+/// Milliseconds to wait before checking again whether the consumer group committed past the
+/// commands a `Consistency::Strong` read waits for.
+pub const CONSISTENCY_BACKOFF: u64 = 50;
+/// Seconds a `Consistency::Strong` read waits for the command of its entity to be processed by
+/// default, see `EngineConfig::with_consistency_timeout`.
+pub const CONSISTENCY_TIMEOUT: u64 = 30;
+/// Seconds the consumer may go without polling before it leaves its group, unless set with
+/// `EngineConfig::with_max_poll_interval` or in the client configuration. This is the default
+/// `max.poll.interval.ms` of librdkafka.
+pub const MAX_POLL_INTERVAL: u64 = 300;
+
+/// Messages an actor mailbox holds by default, see `EngineConfig::with_mailbox_capacity`.
+/// This is the default capacity of actix.
+pub const MAILBOX_CAPACITY: usize = 16;
+
+pub const RELAY_INTERVAL: u64 = 1;
+pub const RELAY_BATCH_SIZE: u64 = 100;
+
+/// Maximum number of replayed events applied at once when folding the history of an entity.
+pub const REPLAY_CHUNK_SIZE: usize = 1024;
+
+pub const PROJECTION_INTERVAL: u64 = 1;
+pub const PROJECTION_BATCH_SIZE: u64 = 100;
+/// Seconds the idempotency key of a command is remembered by default, see
+/// `EngineConfig::with_idempotency_window`.
+pub const IDEMPOTENCY_WINDOW: u64 = 24 * 60 * 60;
+/// States buffered per entity for its watchers, see `Engine::watch`.
+pub const WATCH_CAPACITY: usize = 64;
+/// Maximum number of events handled at once by `Engine::for_each_event`.
+pub const FOR_EACH_CONCURRENCY: usize = 16;
+/// Events read at once when checking every entity of a store for gaps, see `check_all_gaps`.
+pub const GAP_CHECK_BATCH_SIZE: u64 = 1000;
 pub const GROUP_ID: &str = "mnemosyne";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,9 +114,9 @@ pub struct NonEmptyVec<T>(Vec<T>);
 
 impl<T> NonEmptyVec<T> {
     /// Create a new NonEmptyVec. If the vector is empty, an error is returned.
-    pub fn new(vec: Vec<T>) -> Result<Self, Error> {
+    pub fn new(vec: Vec<T>) -> Result<Self, EmptyVecError> {
         if vec.is_empty() {
-            Err(Error::InvalidCommand("Empty vector".to_string()))
+            Err(EmptyVecError)
         } else {
             Ok(Self(vec))
         }
@@ -64,11 +149,19 @@ impl<T> NonEmptyVec<T> {
     /// assert_eq!(iterator.next(), Some(&4));
     /// assert_eq!(iterator.next(), None);
     /// ```
-    pub fn iter(&self) -> Iter<T> {
+    pub fn iter(&self) -> Iter<'_, T> {
         self.0.iter()
     }
 }
 
+impl<T> TryFrom<Vec<T>> for NonEmptyVec<T> {
+    type Error = EmptyVecError;
+
+    fn try_from(vec: Vec<T>) -> Result<Self, Self::Error> {
+        Self::new(vec)
+    }
+}
+
 impl<T> IntoIterator for NonEmptyVec<T> {
     type Item = T;
     type IntoIter = IntoIter<T>;
@@ -0,0 +1,121 @@
+use std::fmt::{self, Debug, Display};
+
+/// The actors run by the engine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActorKind {
+    /// Produces commands and answers state queries.
+    Init,
+    /// Consumes commands and routes them to the actor of their entity.
+    Aggregate,
+    /// Processes the commands of a single entity.
+    Inner,
+    /// Publishes the outbox, see `PublishMode::Outbox`.
+    Relay,
+    /// Runs the durable effects of commands, see `EngineConfig::with_effect_handler`.
+    EffectRunner,
+}
+
+/// An error that happened in, or caused the restart of, one of the engine actors.
+#[derive(Debug, Clone)]
+pub struct ActorFailure {
+    kind: ActorKind,
+    aggregate_type: Option<String>,
+    entity_id: Option<String>,
+    error: String,
+}
+
+impl ActorFailure {
+    pub(crate) fn new(kind: ActorKind, entity_id: Option<&str>, error: impl ToString) -> Self {
+        Self {
+            kind,
+            aggregate_type: None,
+            entity_id: entity_id.map(str::to_string),
+            error: error.to_string(),
+        }
+    }
+
+    pub(crate) fn with_aggregate_type(mut self, aggregate_type: Option<&str>) -> Self {
+        self.aggregate_type = aggregate_type.map(str::to_string);
+        self
+    }
+
+    pub fn kind(&self) -> ActorKind {
+        self.kind
+    }
+
+    /// The aggregate type of the engine running the actor, if set with
+    /// `EngineConfig::with_aggregate_type`, to tell apart the actors of several engines.
+    pub fn aggregate_type(&self) -> Option<&str> {
+        self.aggregate_type.as_deref()
+    }
+
+    /// The entity the actor is processing commands for, only set for `ActorKind::Inner`.
+    pub fn entity_id(&self) -> Option<&str> {
+        self.entity_id.as_deref()
+    }
+
+    pub fn error(&self) -> &str {
+        &self.error
+    }
+}
+
+/// Renders the actor with its context, e.g. `Inner actor of order for entity order:42: ...`.
+impl Display for ActorFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} actor", self.kind)?;
+        if let Some(aggregate_type) = &self.aggregate_type {
+            write!(f, " of {}", aggregate_type)?;
+        }
+        if let Some(entity_id) = &self.entity_id {
+            write!(f, " for entity {}", entity_id)?;
+        }
+        write!(f, ": {}", self.error)
+    }
+}
+
+/// The messages in flight to an actor nearing the capacity of its mailbox, see
+/// `EngineConfig::with_mailbox_capacity`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MailboxPressure {
+    kind: ActorKind,
+    in_flight: usize,
+    capacity: usize,
+}
+
+impl MailboxPressure {
+    pub(crate) fn new(kind: ActorKind, in_flight: usize, capacity: usize) -> Self {
+        Self {
+            kind,
+            in_flight,
+            capacity,
+        }
+    }
+
+    pub fn kind(&self) -> ActorKind {
+        self.kind
+    }
+
+    /// The messages sent to the actor and not answered yet, including the ones it is
+    /// handling, i.e. an upper bound of the messages waiting in its mailbox.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+/// Observes actor failures, so that restart loops and swallowed errors can be logged,
+/// alerted on or used to trip a circuit breaker.
+///
+/// The observer is called from within the actors, so it should return quickly.
+pub trait ActorObserver: Debug + Send + Sync {
+    fn on_actor_error(&self, failure: &ActorFailure);
+
+    /// Called when the messages in flight to an actor reach three quarters of the capacity
+    /// of its mailbox, e.g. to count how often the engine is under backpressure. Does nothing
+    /// by default, the engine logs a warning either way.
+    #[allow(unused_variables)]
+    fn on_mailbox_pressure(&self, pressure: &MailboxPressure) {}
+}
@@ -0,0 +1,17 @@
+use crate::Unit;
+use actix::prelude::*;
+
+/// Stop consuming commands, commands already consumed are still processed.
+#[derive(Message, Debug, Default)]
+#[rtype(result = "Unit")]
+pub struct Pause;
+
+/// Resume consuming commands after a `Pause`.
+#[derive(Message, Debug, Default)]
+#[rtype(result = "Unit")]
+pub struct Resume;
+
+/// Whether consuming commands is paused.
+#[derive(Message, Debug, Default)]
+#[rtype(result = "bool")]
+pub struct IsPaused;
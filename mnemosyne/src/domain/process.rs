@@ -1,29 +1,95 @@
-use crate::Unit;
-use crate::{algebra::Record, domain::Error};
+use crate::{
+    algebra::{CommandOutcome, Record},
+    domain::Error,
+};
 use actix::prelude::*;
+use chrono::{DateTime, Utc};
 use serde::{de::DeserializeOwned, Serialize};
 use std::fmt::Debug;
+use uuid::Uuid;
 
+/// Process a command, yielding its outcome.
 #[derive(Message)]
-#[rtype(result = "Result<Unit, Error>")]
-pub struct Process<Cmd>
+#[rtype(result = "Result<CommandOutcome<State, Evt>, Error>")]
+pub struct Process<State, Cmd, Evt>
 where
+    State: 'static,
     Cmd: Send + Sync + Unpin + 'static + Debug + DeserializeOwned + Serialize,
+    Evt: 'static,
 {
     record: Box<Record<Cmd>>,
+    _marker: std::marker::PhantomData<(State, Evt)>,
 }
 
-impl<Cmd> Process<Cmd>
+impl<State, Cmd, Evt> Process<State, Cmd, Evt>
 where
+    State: 'static,
     Cmd: Send + Sync + Unpin + 'static + Debug + DeserializeOwned + Serialize,
+    Evt: 'static,
 {
     pub fn new(record: Record<Cmd>) -> Self {
         Self {
             record: Box::new(record),
+            _marker: std::marker::PhantomData,
         }
     }
 
     pub fn command(&self) -> &Cmd {
         self.record.message()
     }
+
+    /// When the command was enqueued.
+    pub fn enqueued_at(&self) -> DateTime<Utc> {
+        self.record.timestamp()
+    }
+
+    /// The id the events the command produces carry, see `AuditEntry::correlation_id`: the
+    /// correlation id of the command, or else its id.
+    pub fn correlation_id(&self) -> Option<Uuid> {
+        self.record.correlation_id().or(self.record.id())
+    }
+
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.record.expires_at()
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        self.record.source()
+    }
+
+    pub fn idempotency_key(&self) -> Option<&str> {
+        self.record.idempotency_key()
+    }
+
+    #[cfg(feature = "otel")]
+    pub fn traceparent(&self) -> Option<&str> {
+        self.record.traceparent()
+    }
+}
+
+/// Process a command right away, without going through the command topic, see
+/// `Engine::dispatch`.
+#[derive(Message)]
+#[rtype(result = "Result<CommandOutcome<State, Evt>, Error>")]
+pub struct Dispatch<State, Cmd, Evt>
+where
+    State: 'static,
+    Cmd: 'static,
+    Evt: 'static,
+{
+    command: Cmd,
+    _marker: std::marker::PhantomData<(State, Evt)>,
+}
+
+impl<State, Cmd, Evt> Dispatch<State, Cmd, Evt> {
+    pub fn new(command: Cmd) -> Self {
+        Self {
+            command,
+            _marker: std::marker::PhantomData,
+        }
+    }
+
+    pub fn into_command(self) -> Cmd {
+        self.command
+    }
 }
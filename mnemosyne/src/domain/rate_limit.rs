@@ -0,0 +1,130 @@
+use std::time::{Duration, Instant};
+
+/// Token bucket parameters used to rate limit commands per entity.
+///
+/// Every entity starts with a full bucket of `capacity` tokens, each command takes one
+/// token and tokens are refilled continuously at `refill_per_second`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    capacity: u32,
+    refill_per_second: f64,
+}
+
+impl RateLimit {
+    pub fn new(capacity: u32, refill_per_second: f64) -> Self {
+        Self {
+            capacity,
+            refill_per_second,
+        }
+    }
+
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    pub fn refill_per_second(&self) -> f64 {
+        self.refill_per_second
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.capacity as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take a token from the bucket, returns `false` if the bucket is empty.
+    pub(crate) fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+
+        self.tokens =
+            (self.tokens + elapsed * self.limit.refill_per_second).min(self.limit.capacity as f64);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Limits how fast events are replayed by background rebuilds, such as
+/// `Engine::rebuild_snapshot` and projections, so they do not starve live traffic.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReplayThrottle {
+    max_events_per_second: Option<u32>,
+    max_concurrent_entities: Option<usize>,
+}
+
+impl ReplayThrottle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replay at most this many events per second.
+    pub fn with_max_events_per_second(mut self, max_events_per_second: u32) -> Self {
+        self.max_events_per_second = Some(max_events_per_second);
+        self
+    }
+
+    /// Rebuild at most this many entities at the same time, additional rebuilds wait
+    /// for a running one to finish.
+    pub fn with_max_concurrent_entities(mut self, max_concurrent_entities: usize) -> Self {
+        self.max_concurrent_entities = Some(max_concurrent_entities);
+        self
+    }
+
+    pub fn max_events_per_second(&self) -> Option<u32> {
+        self.max_events_per_second
+    }
+
+    pub fn max_concurrent_entities(&self) -> Option<usize> {
+        self.max_concurrent_entities
+    }
+}
+
+/// Spaces out events evenly to replay them at the rate of a `ReplayThrottle`.
+#[derive(Debug, Clone)]
+pub(crate) struct Pacer {
+    start: Instant,
+    interval: Option<Duration>,
+    count: u32,
+}
+
+impl Pacer {
+    pub(crate) fn new(throttle: &ReplayThrottle) -> Self {
+        Self {
+            start: Instant::now(),
+            interval: throttle
+                .max_events_per_second
+                .filter(|rate| *rate > 0)
+                .map(|rate| Duration::from_secs(1) / rate),
+            count: 0,
+        }
+    }
+
+    /// How long to wait before replaying the next event.
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        match self.interval {
+            Some(interval) => {
+                let slot = self.start + interval * self.count;
+                self.count = self.count.saturating_add(1);
+                slot.saturating_duration_since(Instant::now())
+            }
+            None => Duration::ZERO,
+        }
+    }
+}
@@ -0,0 +1,21 @@
+use std::fmt::Debug;
+
+/// Assigns sequence numbers to records.
+///
+/// Recovery still relies on `Adapter::read_highest_sequence_number`, so generated
+/// numbers must be strictly increasing per entity.
+pub trait SequenceGenerator: Debug + Send + Sync {
+    /// Return the sequence number following `current`, the last sequence number assigned
+    /// for the given entity.
+    fn next(&self, entity_id: &str, current: i64) -> i64;
+}
+
+/// The default generator, increments the sequence number of each entity by one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Incremental;
+
+impl SequenceGenerator for Incremental {
+    fn next(&self, _entity_id: &str, current: i64) -> i64 {
+        current + 1
+    }
+}
@@ -1,6 +1,8 @@
-use crate::domain::Error;
+use crate::domain::{Consistency, Error};
 use actix::prelude::*;
+use std::collections::HashMap;
 use std::fmt::Debug;
+use tokio::sync::broadcast;
 
 #[derive(Message)]
 #[rtype(result = "Result<State, Error>")]
@@ -9,10 +11,149 @@ where
     State: Debug + Clone + Send + Sync + Unpin + 'static,
 {
     entity_id: String,
+    consistency: Consistency,
     _phantom: std::marker::PhantomData<State>,
 }
 
 impl<State> GetState<State>
+where
+    State: Debug + Clone + Send + Sync + Unpin + 'static,
+{
+    pub fn new(entity_id: &str) -> Self {
+        Self {
+            _phantom: std::marker::PhantomData,
+            entity_id: entity_id.into(),
+            consistency: Consistency::default(),
+        }
+    }
+
+    pub fn with_consistency(mut self, consistency: Consistency) -> Self {
+        self.consistency = consistency;
+        self
+    }
+
+    pub fn entity_id(&self) -> &str {
+        &self.entity_id
+    }
+
+    pub fn consistency(&self) -> Consistency {
+        self.consistency
+    }
+}
+
+/// Get the state the actor of an entity holds in memory, with its sequence number. Resolves
+/// to None if the entity has no actor, or its actor did not load the entity yet. No actor is
+/// started to answer it.
+#[derive(Message)]
+#[rtype(result = "Result<Option<(i64, State)>, Error>")]
+pub(crate) struct Live<State>
+where
+    State: Debug + Clone + Send + Sync + Unpin + 'static,
+{
+    entity_id: String,
+    _phantom: std::marker::PhantomData<State>,
+}
+
+impl<State> Live<State>
+where
+    State: Debug + Clone + Send + Sync + Unpin + 'static,
+{
+    pub fn new(entity_id: &str) -> Self {
+        Self {
+            _phantom: std::marker::PhantomData,
+            entity_id: entity_id.into(),
+        }
+    }
+
+    pub fn entity_id(&self) -> &str {
+        &self.entity_id
+    }
+}
+
+/// Rebuild the snapshot of an entity from its full event history, ignoring any existing
+/// snapshot, or from its latest snapshot. Resolves to the sequence number the snapshot was
+/// written at and the state.
+#[derive(Message)]
+#[rtype(result = "Result<(u64, State), Error>")]
+pub struct RebuildSnapshot<State>
+where
+    State: Debug + Clone + Send + Sync + Unpin + 'static,
+{
+    entity_id: String,
+    from_latest: bool,
+    _phantom: std::marker::PhantomData<State>,
+}
+
+impl<State> RebuildSnapshot<State>
+where
+    State: Debug + Clone + Send + Sync + Unpin + 'static,
+{
+    pub fn new(entity_id: &str) -> Self {
+        Self {
+            _phantom: std::marker::PhantomData,
+            entity_id: entity_id.into(),
+            from_latest: false,
+        }
+    }
+
+    /// Build the snapshot from the latest snapshot and the events following it.
+    pub fn from_latest(entity_id: &str) -> Self {
+        Self {
+            from_latest: true,
+            ..Self::new(entity_id)
+        }
+    }
+
+    pub fn entity_id(&self) -> &str {
+        &self.entity_id
+    }
+
+    pub fn is_from_latest(&self) -> bool {
+        self.from_latest
+    }
+}
+
+/// Discard the state an entity's actor holds in memory and rehydrate it from its full event
+/// history. Resolves to the highest sequence number of the entity and the reloaded state.
+#[derive(Message)]
+#[rtype(result = "Result<(u64, State), Error>")]
+pub struct Reload<State>
+where
+    State: Debug + Clone + Send + Sync + Unpin + 'static,
+{
+    entity_id: String,
+    _phantom: std::marker::PhantomData<State>,
+}
+
+impl<State> Reload<State>
+where
+    State: Debug + Clone + Send + Sync + Unpin + 'static,
+{
+    pub fn new(entity_id: &str) -> Self {
+        Self {
+            _phantom: std::marker::PhantomData,
+            entity_id: entity_id.into(),
+        }
+    }
+
+    pub fn entity_id(&self) -> &str {
+        &self.entity_id
+    }
+}
+
+/// Subscribe to the state of an entity. Resolves to its current state and a receiver of the
+/// states it moves to as commands are applied.
+#[derive(Message)]
+#[rtype(result = "Result<(State, broadcast::Receiver<State>), Error>")]
+pub struct Watch<State>
+where
+    State: Debug + Clone + Send + Sync + Unpin + 'static,
+{
+    entity_id: String,
+    _phantom: std::marker::PhantomData<State>,
+}
+
+impl<State> Watch<State>
 where
     State: Debug + Clone + Send + Sync + Unpin + 'static,
 {
@@ -27,3 +168,31 @@ where
         &self.entity_id
     }
 }
+
+/// Get the current state of several entities at once. Entities without events are absent
+/// from the result.
+#[derive(Message)]
+#[rtype(result = "Result<HashMap<String, State>, Error>")]
+pub struct GetStates<State>
+where
+    State: Debug + Clone + Send + Sync + Unpin + 'static,
+{
+    entity_ids: Vec<String>,
+    _phantom: std::marker::PhantomData<State>,
+}
+
+impl<State> GetStates<State>
+where
+    State: Debug + Clone + Send + Sync + Unpin + 'static,
+{
+    pub fn new(entity_ids: &[&str]) -> Self {
+        Self {
+            _phantom: std::marker::PhantomData,
+            entity_ids: entity_ids.iter().map(|id| id.to_string()).collect(),
+        }
+    }
+
+    pub fn entity_ids(&self) -> &[String] {
+        &self.entity_ids
+    }
+}
@@ -0,0 +1,55 @@
+use std::{any::Any, sync::Arc};
+
+/// What a command can consult besides the state of its entity when it is validated, see
+/// `Command::validate_with`.
+///
+/// The read model is whatever was registered with `EngineConfig::with_read_model`, e.g. a
+/// projection of every entity or a handle to query one. It is shared by every entity, so it
+/// lets a command check invariants that span entities, such as a unique email address.
+///
+/// The read model is eventually consistent: it is built from events that were written
+/// already, so it lags behind commands that are being processed, possibly on other
+/// instances. Two commands can both see an email address as free and both be accepted.
+/// Checks that must hold strictly are best backed by a reservation, i.e. an entity of its
+/// own keyed by the unique value, e.g. `email:jane@example.com`, which is claimed before the
+/// command relying on it is enqueued. Commands of one entity are processed one at a time,
+/// so only one claim succeeds.
+///
+/// # Examples
+/// ```rust,ignore
+/// async fn validate_with(&self, state: &User, ctx: &ValidationContext<'_>) -> Result<Unit, Error> {
+///     self.validate(state)?;
+///
+///     let directory = ctx.read_model::<Directory>().expect("a directory is registered");
+///     if directory.is_taken(&self.email).await? {
+///         return Err(Self::reject("email_taken", "The email address is taken"));
+///     }
+///
+///     Ok(())
+/// }
+/// ```
+pub struct ValidationContext<'a> {
+    entity_id: &'a str,
+    read_model: Option<&'a (dyn Any + Send + Sync)>,
+}
+
+impl<'a> ValidationContext<'a> {
+    pub(crate) fn new(
+        entity_id: &'a str,
+        read_model: Option<&'a Arc<dyn Any + Send + Sync>>,
+    ) -> Self {
+        Self {
+            entity_id,
+            read_model: read_model.map(|read_model| read_model.as_ref()),
+        }
+    }
+
+    pub fn entity_id(&self) -> &str {
+        self.entity_id
+    }
+
+    /// The read model, if one of type `M` was registered.
+    pub fn read_model<M: 'static>(&self) -> Option<&M> {
+        self.read_model?.downcast_ref::<M>()
+    }
+}
@@ -1,8 +1,16 @@
 pub mod algebra;
 pub mod domain;
 pub mod storage;
+#[cfg(feature = "avro")]
+pub use apache_avro;
+#[cfg(feature = "postgres")]
+pub use deadpool_postgres;
 pub use futures;
 pub use rdkafka;
+#[cfg(feature = "schema_registry")]
+pub use schema_registry_converter;
+#[cfg(feature = "schema")]
+pub use schemars;
 
 pub type Unit = ();
 
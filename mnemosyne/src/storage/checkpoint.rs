@@ -0,0 +1,24 @@
+use crate::{domain::Error, Unit};
+use futures::Future;
+
+/// Stores the position a projection has processed the event log up to, so it can resume
+/// from there instead of reprocessing the entire log.
+pub trait CheckpointStore {
+    /// Save the position of a projection, the position is the global offset of the next
+    /// message the projection is going to process, see `Adapter::stream_all`.
+    ///
+    /// # Arguments
+    /// * `projection` - The name of the projection
+    /// * `position` - The position to resume the projection from
+    fn save_checkpoint(
+        &self,
+        projection: &str,
+        position: u64,
+    ) -> impl Future<Output = Result<Unit, Error>>;
+    /// Load the position of a projection.
+    ///
+    /// # Returns
+    /// The position to resume the projection from or None if the projection never saved one.
+    fn load_checkpoint(&self, projection: &str)
+        -> impl Future<Output = Result<Option<u64>, Error>>;
+}
@@ -0,0 +1,191 @@
+use super::Adapter;
+use crate::{algebra::Record, domain::Error};
+use futures::TryStreamExt;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::fmt::Debug;
+
+/// One of the two stores compared by `verify_consistency`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    First,
+    Second,
+}
+
+impl Side {
+    fn other(self) -> Self {
+        match self {
+            Side::First => Side::Second,
+            Side::Second => Side::First,
+        }
+    }
+}
+
+/// The first difference found between the event histories of an entity in two stores.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Divergence {
+    /// The event at `seq_nr` is in one store but not in the store at `missing_from`.
+    Missing { seq_nr: i64, missing_from: Side },
+    /// Both stores hold an event at `seq_nr`, with different payloads.
+    PayloadMismatch {
+        seq_nr: i64,
+        first: Value,
+        second: Value,
+    },
+    /// The store at `side` replayed the event at `seq_nr` after the one at `previous`.
+    OutOfOrder {
+        seq_nr: i64,
+        previous: i64,
+        side: Side,
+    },
+}
+
+/// The outcome of `verify_consistency`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsistencyReport {
+    entity_id: String,
+    compared: u64,
+    divergence: Option<Divergence>,
+}
+
+impl ConsistencyReport {
+    pub fn entity_id(&self) -> &str {
+        &self.entity_id
+    }
+
+    /// The number of events found identical in both stores before the first divergence,
+    /// if any.
+    pub fn compared(&self) -> u64 {
+        self.compared
+    }
+
+    pub fn divergence(&self) -> Option<&Divergence> {
+        self.divergence.as_ref()
+    }
+
+    pub fn is_consistent(&self) -> bool {
+        self.divergence.is_none()
+    }
+}
+
+/// Replay the event history of an entity from two stores side by side and report the first
+/// divergence, e.g. to validate a storage migration before switching over.
+///
+/// Events are matched by sequence number and compared by payload, as JSON. Timestamps are
+/// not compared, stores keep them at different precisions. An event either store cannot
+/// read fails the verification.
+///
+/// # Examples
+/// ```rust,ignore
+/// let report = verify_consistency::<UserEvent, _, _>(&postgres, &memory, "user:1").await?;
+/// if let Some(divergence) = report.divergence() {
+///     tracing::error!("Stores diverge after {} events: {:?}", report.compared(), divergence);
+/// }
+/// ```
+pub async fn verify_consistency<Evt, A, B>(
+    first: &A,
+    second: &B,
+    entity_id: &str,
+) -> Result<ConsistencyReport, Error>
+where
+    Evt: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    A: Adapter,
+    B: Adapter,
+{
+    let mut firsts = first
+        .try_replay::<Evt>(entity_id, 0, u64::MAX, u64::MAX)
+        .await?;
+    let mut seconds = second
+        .try_replay::<Evt>(entity_id, 0, u64::MAX, u64::MAX)
+        .await?;
+
+    let mut compared = 0;
+    let mut previous: (Option<i64>, Option<i64>) = (None, None);
+    let mut next_first = firsts.try_next().await?;
+    let mut next_second = seconds.try_next().await?;
+
+    let divergence = loop {
+        if let Some(divergence) = out_of_order(next_first.as_ref(), previous.0, Side::First)
+            .or_else(|| out_of_order(next_second.as_ref(), previous.1, Side::Second))
+        {
+            break Some(divergence);
+        }
+
+        match (&next_first, &next_second) {
+            (None, None) => break None,
+            (Some(record), None) => {
+                break Some(Divergence::Missing {
+                    seq_nr: record.seq_nr(),
+                    missing_from: Side::Second,
+                })
+            }
+            (None, Some(record)) => {
+                break Some(Divergence::Missing {
+                    seq_nr: record.seq_nr(),
+                    missing_from: Side::First,
+                })
+            }
+            (Some(a), Some(b)) if a.seq_nr() != b.seq_nr() => {
+                // The lowest sequence number is the one the other store skipped
+                let (seq_nr, side) = if a.seq_nr() < b.seq_nr() {
+                    (a.seq_nr(), Side::First)
+                } else {
+                    (b.seq_nr(), Side::Second)
+                };
+                break Some(Divergence::Missing {
+                    seq_nr,
+                    missing_from: side.other(),
+                });
+            }
+            (Some(a), Some(b)) => {
+                let (first, second) = (payload(a)?, payload(b)?);
+                if first != second {
+                    break Some(Divergence::PayloadMismatch {
+                        seq_nr: a.seq_nr(),
+                        first,
+                        second,
+                    });
+                }
+            }
+        }
+
+        compared += 1;
+        previous = (
+            next_first.as_ref().map(Record::seq_nr),
+            next_second.as_ref().map(Record::seq_nr),
+        );
+        next_first = firsts.try_next().await?;
+        next_second = seconds.try_next().await?;
+    };
+
+    Ok(ConsistencyReport {
+        entity_id: entity_id.to_string(),
+        compared,
+        divergence,
+    })
+}
+
+fn out_of_order<Evt>(
+    record: Option<&Record<Evt>>,
+    previous: Option<i64>,
+    side: Side,
+) -> Option<Divergence> {
+    match (record, previous) {
+        (Some(record), Some(previous)) if record.seq_nr() <= previous => {
+            Some(Divergence::OutOfOrder {
+                seq_nr: record.seq_nr(),
+                previous,
+                side,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn payload<Evt>(record: &Record<Evt>) -> Result<Value, Error>
+where
+    Evt: Serialize,
+{
+    serde_json::to_value(record.message())
+        .map_err(|e| Error::InvalidEvent(format!("Failed to serialize event: {}", e)))
+}
@@ -0,0 +1,96 @@
+use crate::domain::Error;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+/// A side effect of a command, written together with the events of the command and run
+/// until it succeeds, see `Command::durable_effects`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Effect {
+    name: String,
+    payload: Value,
+}
+
+impl Effect {
+    /// Create an effect, e.g. `Effect::new("send_welcome_email", &WelcomeEmail { .. })`. The
+    /// name tells the `EffectHandler` what to run, the payload what to run it with.
+    pub fn new(name: impl Into<String>, payload: &impl Serialize) -> Result<Self, Error> {
+        let payload = serde_json::to_value(payload)
+            .map_err(|e| Error::InvalidEvent(format!("Failed to serialize effect: {}", e)))?;
+
+        Ok(Self {
+            name: name.into(),
+            payload,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn payload(&self) -> &Value {
+        &self.payload
+    }
+}
+
+/// An effect waiting to be run, see `Adapter::run_effects`.
+#[derive(Debug, Clone)]
+pub struct PendingEffect {
+    id: u64,
+    entity_id: String,
+    seq_nr: i64,
+    effect: Effect,
+    attempts: u32,
+}
+
+impl PendingEffect {
+    pub fn new(id: u64, entity_id: String, seq_nr: i64, effect: Effect, attempts: u32) -> Self {
+        Self {
+            id,
+            entity_id,
+            seq_nr,
+            effect,
+            attempts,
+        }
+    }
+
+    /// The id of the effect in the store, used to mark it as done.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn entity_id(&self) -> &str {
+        &self.entity_id
+    }
+
+    /// The sequence number of the last event of the command the effect belongs to.
+    pub fn seq_nr(&self) -> i64 {
+        self.seq_nr
+    }
+
+    pub fn effect(&self) -> &Effect {
+        &self.effect
+    }
+
+    pub fn name(&self) -> &str {
+        self.effect.name()
+    }
+
+    /// The payload of the effect, as the type it was created from.
+    pub fn payload<T>(&self) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        serde_json::from_value(self.effect.payload().clone())
+            .map_err(|e| Error::InvalidEvent(format!("Failed to deserialize effect: {}", e)))
+    }
+
+    /// The number of times running the effect failed so far.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    // Count a failed attempt at running the effect
+    pub(crate) fn attempted(&mut self) {
+        self.attempts += 1;
+    }
+}
@@ -0,0 +1,125 @@
+use super::Adapter;
+use crate::domain::{Error, SequenceGenerator, GAP_CHECK_BATCH_SIZE};
+use futures::{StreamExt, TryStreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::BTreeMap, fmt::Debug};
+
+/// Walk the sequence numbers of an entity and return the ones missing, in order, i.e. the
+/// events lost between the first and the highest sequence number of the entity. An entity
+/// without events has no gaps.
+///
+/// The sequence numbers expected are the ones `generator` assigns, see
+/// `EngineConfig::with_sequence_generator`, so a gap is a number it would have assigned
+/// that no event has.
+///
+/// # Examples
+/// ```rust,ignore
+/// let missing = check_gaps::<UserEvent, _>(&postgres, "user:1", &Incremental).await?;
+/// if !missing.is_empty() {
+///     tracing::error!("Events {:?} of user:1 were lost", missing);
+/// }
+/// ```
+pub async fn check_gaps<Evt, A>(
+    store: &A,
+    entity_id: &str,
+    generator: &dyn SequenceGenerator,
+) -> Result<Vec<u64>, Error>
+where
+    Evt: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    A: Adapter,
+{
+    let mut records = store
+        .try_replay::<Evt>(entity_id, 0, u64::MAX, u64::MAX)
+        .await?;
+
+    let mut gaps = Gaps::new(entity_id, 0, generator);
+    while let Some(record) = records.try_next().await? {
+        gaps.observe(record.seq_nr());
+    }
+
+    Ok(gaps.into_missing())
+}
+
+/// Walk the sequence numbers of every entity in the store, see `check_gaps`, and return the
+/// missing ones of each entity that has gaps.
+///
+/// This reads every event of the store through `Adapter::stream_all`, a batch of
+/// `GAP_CHECK_BATCH_SIZE` events at a time, so it fails for a `ShardedAdapter`. Check every
+/// shard on its own instead.
+pub async fn check_all_gaps<Evt, A>(
+    store: &A,
+    generator: &dyn SequenceGenerator,
+) -> Result<BTreeMap<String, Vec<u64>>, Error>
+where
+    Evt: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    A: Adapter,
+{
+    let mut entities = BTreeMap::<String, Gaps>::new();
+    let mut position = 0;
+
+    loop {
+        let records = store
+            .stream_all::<Evt>(position, GAP_CHECK_BATCH_SIZE)
+            .await?
+            .collect::<Vec<_>>()
+            .await;
+        let Some((last, _)) = records.last() else {
+            break;
+        };
+        position = last + 1;
+
+        for (_, record) in records {
+            entities
+                .entry(record.entity_id().to_string())
+                .or_insert_with(|| Gaps::new(record.entity_id(), 0, generator))
+                .observe(record.seq_nr());
+        }
+    }
+
+    Ok(entities
+        .into_iter()
+        .map(|(entity_id, gaps)| (entity_id, gaps.into_missing()))
+        .filter(|(_, missing)| !missing.is_empty())
+        .collect())
+}
+
+/// Tracks the sequence numbers of an entity as its events are read in order, collecting the
+/// ones missing.
+pub(crate) struct Gaps<'a> {
+    entity_id: String,
+    generator: &'a dyn SequenceGenerator,
+    // The sequence number the next event is expected at
+    expected: i64,
+    missing: Vec<u64>,
+}
+
+impl<'a> Gaps<'a> {
+    /// Expect the events following the one at `seq_nr`, 0 expecting the first event.
+    pub(crate) fn new(entity_id: &str, seq_nr: i64, generator: &'a dyn SequenceGenerator) -> Self {
+        Self {
+            entity_id: entity_id.to_string(),
+            generator,
+            expected: generator.next(entity_id, seq_nr),
+            missing: Vec::new(),
+        }
+    }
+
+    pub(crate) fn observe(&mut self, seq_nr: i64) {
+        while self.expected < seq_nr {
+            self.missing.push(self.expected as u64);
+
+            let next = self.generator.next(&self.entity_id, self.expected);
+            // A generator that does not increase would never reach the event
+            if next <= self.expected {
+                break;
+            }
+            self.expected = next;
+        }
+
+        self.expected = self.generator.next(&self.entity_id, seq_nr);
+    }
+
+    pub(crate) fn into_missing(self) -> Vec<u64> {
+        self.missing
+    }
+}
@@ -0,0 +1,28 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// The idempotency key of a command, remembered until it expires, see
+/// `Adapter::write_with_idempotency_key`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdempotencyKey {
+    key: String,
+    expires_at: DateTime<Utc>,
+}
+
+impl IdempotencyKey {
+    pub fn new(key: impl Into<String>, expires_at: DateTime<Utc>) -> Self {
+        Self {
+            key: key.into(),
+            expires_at,
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// When the key is forgotten, a command carrying it is processed again from then on.
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        self.expires_at
+    }
+}
@@ -0,0 +1,167 @@
+use super::Adapter;
+use crate::{algebra::Record, domain::Error};
+use futures::TryStreamExt;
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::Debug;
+
+/// The first link of the hash chain of an entity that does not hold, see `Record::hash`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BrokenLink {
+    /// The event at `seq_nr`, or one before it, was altered: its hash is not the one
+    /// computed from the event and the hash of the event before it.
+    Mismatch {
+        seq_nr: i64,
+        expected: String,
+        actual: String,
+    },
+    /// The event at `seq_nr` has no hash, although an event before it has one.
+    Unhashed { seq_nr: i64 },
+}
+
+/// The outcome of `verify_integrity`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IntegrityReport {
+    entity_id: String,
+    verified: u64,
+    broken: Option<BrokenLink>,
+}
+
+impl IntegrityReport {
+    pub fn entity_id(&self) -> &str {
+        &self.entity_id
+    }
+
+    /// The number of hashed events found intact before the first broken link, if any.
+    /// Events written before events were hashed are not counted.
+    pub fn verified(&self) -> u64 {
+        self.verified
+    }
+
+    pub fn broken(&self) -> Option<&BrokenLink> {
+        self.broken.as_ref()
+    }
+
+    pub fn is_intact(&self) -> bool {
+        self.broken.is_none()
+    }
+}
+
+/// Walk the hash chain of an entity and report the first broken link, i.e. detect events
+/// that were altered, removed or reordered in storage since they were written.
+///
+/// Every event is hashed together with the hash of the event before it, see `Record::hash`,
+/// so altering an event breaks the link at that event. The chain starts at the first hashed
+/// event, the events written before events were hashed are not verified. An event the
+/// store cannot read fails the verification.
+///
+/// # Examples
+/// ```rust,ignore
+/// let report = verify_integrity::<UserEvent, _>(&postgres, "user:1").await?;
+/// if let Some(broken) = report.broken() {
+///     tracing::error!("History of user:1 was tampered with: {:?}", broken);
+/// }
+/// ```
+pub async fn verify_integrity<Evt, A>(store: &A, entity_id: &str) -> Result<IntegrityReport, Error>
+where
+    Evt: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    A: Adapter,
+{
+    let mut records = store
+        .try_replay::<Evt>(entity_id, 0, u64::MAX, u64::MAX)
+        .await?;
+
+    let mut verified = 0;
+    let mut previous: Option<String> = None;
+
+    let broken = loop {
+        let Some(record) = records.try_next().await? else {
+            break None;
+        };
+
+        let actual = match (record.hash(), &previous) {
+            (Some(actual), _) => actual,
+            // Written before events were hashed
+            (None, None) => continue,
+            (None, Some(_)) => {
+                break Some(BrokenLink::Unhashed {
+                    seq_nr: record.seq_nr(),
+                })
+            }
+        };
+
+        let expected = chain_hash(previous.as_deref(), &record)?;
+        if actual != expected {
+            break Some(BrokenLink::Mismatch {
+                seq_nr: record.seq_nr(),
+                expected,
+                actual: actual.to_string(),
+            });
+        }
+
+        verified += 1;
+        previous = Some(expected);
+    };
+
+    Ok(IntegrityReport {
+        entity_id: entity_id.to_string(),
+        verified,
+        broken,
+    })
+}
+
+/// The hash of an event chained to the hash of the event before it, if any, as hex encoded
+/// SHA-256 of the previous hash, the entity id, the sequence number and the payload.
+///
+/// Every part is length prefixed, so no two events hash the same input. The payload is
+/// hashed as JSON with sorted keys, which survives stores normalizing it, e.g. `jsonb`.
+pub(crate) fn chain_hash<T>(previous: Option<&str>, record: &Record<T>) -> Result<String, Error>
+where
+    T: Serialize,
+{
+    let payload = serde_json::to_value(record.message())
+        .and_then(|payload| serde_json::to_vec(&payload))
+        .map_err(|e| Error::InvalidEvent(format!("Failed to serialize event: {}", e)))?;
+    let previous = previous.unwrap_or_default();
+
+    let mut hasher = Sha256::new();
+    for part in [
+        previous.as_bytes(),
+        record.entity_id().as_bytes(),
+        &record.seq_nr().to_be_bytes(),
+        &payload,
+    ] {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// The hash of the event of an entity at `seq_nr`, None if it has none or there is no
+/// such event, e.g. at a sequence number of 0.
+pub(crate) async fn hash_at<Evt, A>(
+    store: &A,
+    entity_id: &str,
+    seq_nr: i64,
+) -> Result<Option<String>, Error>
+where
+    Evt: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    A: Adapter,
+{
+    if seq_nr <= 0 {
+        return Ok(None);
+    }
+
+    let record = store
+        .try_replay::<Evt>(entity_id, seq_nr as u64, seq_nr as u64, 1)
+        .await?
+        .try_next()
+        .await?;
+
+    Ok(record.and_then(|record| record.hash().map(str::to_owned)))
+}
@@ -1,26 +1,380 @@
-use super::{Adapter, Record};
+use super::{
+    best_effort, Adapter, CheckpointStore, Effect, IdempotencyKey, OutboxEntry, PendingEffect,
+    Record, Snapshot,
+};
 use crate::{domain::Error, Unit};
-use futures::stream::BoxStream;
-use serde::{de::DeserializeOwned, Serialize};
-use std::fmt::Debug;
+use bincode::Options;
+use chrono::{DateTime, Utc};
+use futures::{stream::BoxStream, Future};
+use serde::{
+    de::{self, DeserializeOwned, DeserializeSeed, SeqAccess, Visitor},
+    Deserializer, Serialize,
+};
+use std::fmt::{self, Debug};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     sync::{Arc, Mutex},
 };
+use uuid::Uuid;
 
+/// An adapter keeping everything in memory, e.g. for tests and examples.
+///
+/// Events are keyed by their entity id followed by their sequence number in big endian, in
+/// an ordered map, so the events of an entity are next to each other and in order. Replaying
+/// an entity and reading its highest sequence number are range scans over its own events,
+/// regardless of how many other entities the store holds.
+///
+/// Nothing survives a restart, wrap the adapter in a `WalAdapter` to keep batches across
+/// restarts.
 #[derive(Clone, Debug)]
 pub struct MemoryAdapter {
-    storage: Arc<Mutex<HashMap<Vec<u8>, Vec<u8>>>>,
+    storage: Arc<Mutex<BTreeMap<Vec<u8>, Vec<u8>>>>,
+    // Keys in the order they were written, the index of a key is its global offset
+    log: Arc<Mutex<Vec<Vec<u8>>>>,
+    // Unsent outbox entries keyed by the global offset of their message
+    outbox: Arc<Mutex<BTreeMap<u64, OutboxEntry>>>,
+    // Latest snapshot per entity id
+    snapshots: Arc<Mutex<HashMap<String, Snapshot>>>,
+    // Position per projection name
+    checkpoints: Arc<Mutex<HashMap<String, u64>>>,
+    // Sequence numbers and expiry per entity id and idempotency key
+    idempotency_keys: Arc<Mutex<HashMap<(String, String), IdempotencyEntry>>>,
+    effects: Arc<Mutex<PendingEffects>>,
+}
+
+type IdempotencyEntry = ((i64, i64), DateTime<Utc>);
+
+// Effects that are not done yet, keyed by id
+#[derive(Debug, Default)]
+struct PendingEffects {
+    next_id: u64,
+    pending: BTreeMap<u64, PendingEffect>,
 }
 
 impl MemoryAdapter {
     pub fn new() -> Self {
         Self {
-            storage: Arc::new(Mutex::new(HashMap::new())),
+            storage: Arc::new(Mutex::new(BTreeMap::new())),
+            log: Arc::new(Mutex::new(Vec::new())),
+            outbox: Arc::new(Mutex::new(BTreeMap::new())),
+            snapshots: Arc::new(Mutex::new(HashMap::new())),
+            checkpoints: Arc::new(Mutex::new(HashMap::new())),
+            idempotency_keys: Arc::new(Mutex::new(HashMap::new())),
+            effects: Arc::new(Mutex::new(PendingEffects::default())),
+        }
+    }
+
+    /// Write a batch, with an outbox entry per record when `with_outbox` is set, with the
+    /// given effects and idempotency key, and only if the highest sequence number of the
+    /// entity matches `expected_highest`, if given.
+    fn insert<T>(
+        &self,
+        batch: Vec<Record<&T>>,
+        with_outbox: bool,
+        effects: Vec<Effect>,
+        idempotency_key: Option<IdempotencyKey>,
+        expected_highest: Option<u64>,
+    ) -> Result<Unit, Error>
+    where
+        T: Serialize,
+    {
+        let mut locked = self
+            .storage
+            .lock()
+            .map_err(|e| Error::InvalidConfiguration(format!("Failed to write storage: {}", e)))?;
+
+        let mut log = self
+            .log
+            .lock()
+            .map_err(|e| Error::InvalidConfiguration(format!("Failed to write storage: {}", e)))?;
+
+        let mut outbox = self
+            .outbox
+            .lock()
+            .map_err(|e| Error::InvalidConfiguration(format!("Failed to write storage: {}", e)))?;
+
+        let mut pending_effects = self
+            .effects
+            .lock()
+            .map_err(|e| Error::InvalidConfiguration(format!("Failed to write storage: {}", e)))?;
+
+        let mut idempotency_keys = self
+            .idempotency_keys
+            .lock()
+            .map_err(|e| Error::InvalidConfiguration(format!("Failed to write storage: {}", e)))?;
+
+        // The storage is locked, so nothing can be written between the check and the write
+        if let (Some(expected), Some(record)) = (expected_highest, batch.first()) {
+            let entity_id = record.entity_id();
+            let actual = highest_seq_nr(&locked, entity_id).unwrap_or_default();
+
+            if actual != expected {
+                return Err(Error::ConcurrencyConflict {
+                    entity_id: entity_id.to_string(),
+                    expected,
+                    actual,
+                });
+            }
+        }
+
+        // Serialize the whole batch before touching the storage, so that a failure leaves
+        // the storage untouched rather than partially written.
+        let entries = batch
+            .into_iter()
+            .map(|value| {
+                let key = key(value.entity_id(), value.seq_nr() as u64);
+                // The entity id and sequence number are part of the key, so only the rest of
+                // the record is stored
+                let serialized = bincode::serialize(&(
+                    value.timestamp(),
+                    value.message(),
+                    value.id(),
+                    value.source(),
+                    value.hash(),
+                ))
+                .map_err(|e| {
+                    Error::InvalidConfiguration(format!("Failed to serialize value: {}", e))
+                })?;
+                let published = match with_outbox {
+                    true => Some(serde_json::to_vec(&value).map_err(|e| {
+                        Error::InvalidConfiguration(format!("Failed to serialize value: {}", e))
+                    })?),
+                    false => None,
+                };
+
+                Ok((key, serialized, value, published))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        // The effects belong to the last record of the batch
+        if let Some(last) = entries.last().map(|(_, _, value, _)| value) {
+            for effect in effects {
+                let id = pending_effects.next_id;
+                pending_effects.next_id += 1;
+                pending_effects.pending.insert(
+                    id,
+                    PendingEffect::new(id, last.entity_id().to_string(), last.seq_nr(), effect, 0),
+                );
+            }
         }
+
+        // The idempotency key covers the whole batch
+        let first_and_last = entries.first().zip(entries.last());
+        if let (Some(idempotency_key), Some(((_, _, first, _), (_, _, last, _)))) =
+            (idempotency_key, first_and_last)
+        {
+            let now = Utc::now();
+            idempotency_keys.retain(|_, (_, expires_at)| *expires_at > now);
+            idempotency_keys.insert(
+                (
+                    first.entity_id().to_string(),
+                    idempotency_key.key().to_string(),
+                ),
+                (
+                    (first.seq_nr(), last.seq_nr()),
+                    idempotency_key.expires_at(),
+                ),
+            );
+        }
+
+        for (key, serialized, value, published) in entries {
+            if let Some(payload) = published {
+                let offset = log.len() as u64;
+                outbox.insert(
+                    offset,
+                    OutboxEntry::new(
+                        offset,
+                        value.entity_id().to_string(),
+                        value.timestamp(),
+                        payload,
+                    ),
+                );
+            }
+
+            locked.insert(key.clone(), serialized);
+            log.push(key);
+        }
+
+        Ok(())
+    }
+
+    /// Stream the messages from a global offset on, only those of `category` if given.
+    fn stream_from<T>(
+        &self,
+        from_global_offset: u64,
+        max: u64,
+        category: Option<&str>,
+    ) -> Result<BoxStream<'static, (u64, Record<T>)>, Error>
+    where
+        T: Send + DeserializeOwned + Debug + 'static + Serialize + Sync,
+    {
+        let locked = self
+            .storage
+            .lock()
+            .map_err(|e| Error::InvalidConfiguration(format!("Failed to read storage: {}", e)))?;
+
+        let log = self
+            .log
+            .lock()
+            .map_err(|e| Error::InvalidConfiguration(format!("Failed to read storage: {}", e)))?;
+
+        let events: Vec<(u64, Record<T>)> = log
+            .iter()
+            .enumerate()
+            .skip(from_global_offset as usize)
+            .filter_map(|(offset, k)| {
+                let entity_id = std::str::from_utf8(&k[..k.len() - 8]).ok()?;
+                if category.is_some_and(|category| crate::algebra::category(entity_id) != category)
+                {
+                    return None;
+                }
+                let seq_nr = seq_nr_from_key(k)?;
+                let record = stored_record(entity_id, seq_nr, locked.get(k)?).ok()?;
+
+                Some((offset as u64, record))
+            })
+            .take(max as usize)
+            .collect();
+
+        Ok(Box::pin(futures::stream::iter(events)))
+    }
+}
+
+// The record as stored, see `MemoryAdapter::insert`
+type Stored<T> = (
+    DateTime<Utc>,
+    T,
+    Option<Uuid>,
+    Option<String>,
+    Option<String>,
+);
+
+/// Read a stored record of the given entity and sequence number.
+fn stored_record<T>(entity_id: &str, seq_nr: i64, bytes: &[u8]) -> bincode::Result<Record<T>>
+where
+    T: DeserializeOwned,
+{
+    Ok(into_record(
+        entity_id,
+        seq_nr,
+        bincode::deserialize::<Stored<T>>(bytes)?,
+    ))
+}
+
+/// Read a stored record of the given entity and sequence number, deserializing its message
+/// with a seed, see `Adapter::replay_seeded`.
+fn seeded_record<S, T>(
+    entity_id: &str,
+    seq_nr: i64,
+    bytes: &[u8],
+    seed: S,
+) -> bincode::Result<Record<T>>
+where
+    S: for<'de> DeserializeSeed<'de, Value = T>,
+{
+    // The options of `bincode::deserialize`
+    let stored = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .deserialize_seed(StoredSeed(seed), bytes)?;
+
+    Ok(into_record(entity_id, seq_nr, stored))
+}
+
+fn into_record<T>(entity_id: &str, seq_nr: i64, stored: Stored<T>) -> Record<T> {
+    let (timestamp, message, id, source, hash) = stored;
+
+    let mut record = Record::event(entity_id.to_string(), seq_nr, message, timestamp);
+    if let Some(id) = id {
+        record = record.with_id(id);
+    }
+    if let Some(source) = source {
+        record = record.with_source(source);
+    }
+    if let Some(hash) = hash {
+        record = record.with_hash(hash);
+    }
+    record
+}
+
+// Deserializes a stored record like `Stored<T>` does, with a seed for its message
+struct StoredSeed<S>(S);
+
+impl<'de, S> DeserializeSeed<'de> for StoredSeed<S>
+where
+    S: DeserializeSeed<'de>,
+{
+    type Value = Stored<S::Value>;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_tuple(5, self)
+    }
+}
+
+impl<'de, S> Visitor<'de> for StoredSeed<S>
+where
+    S: DeserializeSeed<'de>,
+{
+    type Value = Stored<S::Value>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a stored record")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let missing = |index| de::Error::invalid_length(index, &"a stored record");
+
+        let timestamp = seq.next_element()?.ok_or_else(|| missing(0))?;
+        let message = seq.next_element_seed(self.0)?.ok_or_else(|| missing(1))?;
+        let id = seq.next_element()?.ok_or_else(|| missing(2))?;
+        let source = seq.next_element()?.ok_or_else(|| missing(3))?;
+        let hash = seq.next_element()?.ok_or_else(|| missing(4))?;
+
+        Ok((timestamp, message, id, source, hash))
     }
 }
 
+/// The key of the event of an entity at a sequence number.
+fn key(entity_id: &str, seq_nr: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(entity_id.len() + 8);
+    key.extend_from_slice(entity_id.as_bytes());
+    key.extend_from_slice(&seq_nr.to_be_bytes());
+    key
+}
+
+/// The events of an entity between two sequence numbers, inclusive, in order.
+fn entity_range<'a>(
+    storage: &'a BTreeMap<Vec<u8>, Vec<u8>>,
+    entity_id: &'a str,
+    from_seq_nr: u64,
+    to_seq_nr: u64,
+) -> impl DoubleEndedIterator<Item = (i64, &'a Vec<u8>)> + 'a {
+    // Keys of entities whose id starts with this one sort in between, e.g. those of `a:1`
+    // between the keys of `a`, and are told apart by their length
+    storage
+        .range(key(entity_id, from_seq_nr)..=key(entity_id, to_seq_nr))
+        .filter(move |(k, _)| k.len() == entity_id.len() + 8)
+        .filter_map(|(k, v)| seq_nr_from_key(k).map(|seq_nr| (seq_nr, v)))
+}
+
+fn highest_seq_nr(storage: &BTreeMap<Vec<u8>, Vec<u8>>, entity_id: &str) -> Option<u64> {
+    entity_range(storage, entity_id, 0, u64::MAX)
+        .next_back()
+        .map(|(seq_nr, _)| seq_nr as u64)
+}
+
+fn seq_nr_from_key(key: &[u8]) -> Option<i64> {
+    let length = key.len();
+    let seq_nr_part: [u8; 8] = key[length - 8..].try_into().ok()?;
+    Some(i64::from_be_bytes(seq_nr_part))
+}
+
 impl Default for MemoryAdapter {
     fn default() -> Self {
         Self::new()
@@ -29,62 +383,30 @@ impl Default for MemoryAdapter {
 
 impl Adapter for MemoryAdapter {
     async fn read_highest_sequence_number(&self, entity_id: &str) -> Result<Option<u64>, Error> {
-        let entity_id_in_bytes = entity_id.as_bytes();
-        // A max key is created by appending 8 bytes of u8::MAX to the entity id in bytes
-        let mut max_key = Vec::with_capacity(entity_id_in_bytes.len() + 8);
-
-        max_key.extend_from_slice(entity_id_in_bytes);
-        max_key.extend_from_slice(&[u8::MAX; 8]);
-
         let locked = self
             .storage
             .lock()
             .map_err(|e| Error::InvalidConfiguration(format!("Failed to read storage: {}", e)))?;
 
-        Ok(locked
-            .iter()
-            .filter_map(|(k, _)| {
-                if k.len() == entity_id_in_bytes.len() + 8 || k.starts_with(entity_id_in_bytes) {
-                    // For the keys that matched the entity id, we extract the sequence number
-                    // assuming that the sequence number is stored in the last 8 bytes of the key
-                    // in a big endian format
-                    let sequence_nr_bytes = &k[k.len() - 8..];
-                    sequence_nr_bytes.try_into().ok().map(u64::from_be_bytes)
-                } else {
-                    None
-                }
-            })
-            .max())
+        Ok(highest_seq_nr(&locked, entity_id))
     }
 
     async fn write<T>(&self, batch: Vec<Record<&T>>) -> Result<Unit, Error>
     where
         T: Serialize + Send + DeserializeOwned + Sync,
     {
-        fn mk_key(entity_id: &str, sequence_nr: i64) -> Vec<u8> {
-            let mut key = Vec::with_capacity(entity_id.len() + 8);
-            key.extend_from_slice(entity_id.as_bytes());
-            key.extend_from_slice(&sequence_nr.to_be_bytes());
-            key
-        }
-
-        let mut locked = self
-            .storage
-            .lock()
-            .map_err(|e| Error::InvalidConfiguration(format!("Failed to write storage: {}", e)))?;
+        self.insert(batch, false, Vec::new(), None, None)
+    }
 
-        batch.into_iter().try_for_each(|value| {
-            let entity_id = value.entity_id();
-            let sequence_nr = value.seq_nr();
-            let key = mk_key(entity_id, sequence_nr);
-            // TODO: Retry on failure and if the error persists, then save the batch somewhere else
-            // such that the data is not lost
-            let serialized = bincode::serialize(&value).map_err(|e| {
-                Error::InvalidConfiguration(format!("Failed to serialize value: {}", e))
-            })?;
-            locked.insert(key, serialized);
-            Ok(())
-        })
+    async fn write_if_version<T>(
+        &self,
+        batch: Vec<Record<&T>>,
+        expected_highest: u64,
+    ) -> Result<Unit, Error>
+    where
+        T: Serialize + Send + DeserializeOwned + Sync,
+    {
+        self.insert(batch, false, Vec::new(), None, Some(expected_highest))
     }
 
     async fn replay<T>(
@@ -97,53 +419,274 @@ impl Adapter for MemoryAdapter {
     where
         T: Send + DeserializeOwned + Debug + 'static + Serialize + Sync,
     {
-        fn seq_nr_from_key(key: &[u8]) -> Option<i64> {
-            let length = key.len();
-            let seq_nr_part: [u8; 8] = key[length - 8..].try_into().ok()?;
-            Some(i64::from_be_bytes(seq_nr_part))
+        self.try_replay(entity_id, from_sequence_number, to_sequence_number, max)
+            .await
+            .map(best_effort)
+    }
+
+    async fn replay_seeded<S, T>(
+        &self,
+        entity_id: &str,
+        from_sequence_number: u64,
+        to_sequence_number: u64,
+        max: u64,
+        seed: S,
+    ) -> Result<BoxStream<'static, Result<Record<T>, Error>>, Error>
+    where
+        S: for<'de> DeserializeSeed<'de, Value = T> + Clone + Send + 'static,
+        T: Send + 'static,
+    {
+        let locked = self
+            .storage
+            .lock()
+            .map_err(|e| Error::InvalidConfiguration(format!("Failed to read storage: {}", e)))?;
+
+        if from_sequence_number > to_sequence_number {
+            return Ok(Box::pin(futures::stream::empty()));
         }
 
+        let events: Vec<Result<Record<T>, Error>> =
+            entity_range(&locked, entity_id, from_sequence_number, to_sequence_number)
+                .take(max as usize)
+                .map(|(seq_nr, v)| {
+                    seeded_record(entity_id, seq_nr, v, seed.clone())
+                        .map_err(|e| Error::StorageError(format!("Failed to deserialize: {}", e)))
+                })
+                .collect();
+
+        Ok(Box::pin(futures::stream::iter(events)))
+    }
+
+    async fn try_replay<T>(
+        &self,
+        entity_id: &str,
+        from_sequence_number: u64,
+        to_sequence_number: u64,
+        max: u64,
+    ) -> Result<BoxStream<'static, Result<Record<T>, Error>>, Error>
+    where
+        T: Send + DeserializeOwned + Debug + 'static + Serialize + Sync,
+    {
         let locked = self
             .storage
             .lock()
             .map_err(|e| Error::InvalidConfiguration(format!("Failed to read storage: {}", e)))?;
 
-        let entity_id_in_bytes = entity_id.as_bytes();
+        if from_sequence_number > to_sequence_number {
+            return Ok(Box::pin(futures::stream::empty()));
+        }
 
-        let from_key = {
-            let mut key = Vec::with_capacity(entity_id_in_bytes.len() + 8);
-            key.extend_from_slice(entity_id_in_bytes);
-            key.extend_from_slice(&from_sequence_number.to_be_bytes());
-            key
-        };
+        let events: Vec<Result<Record<T>, Error>> =
+            entity_range(&locked, entity_id, from_sequence_number, to_sequence_number)
+                .take(max as usize)
+                .map(|(seq_nr, v)| {
+                    stored_record(entity_id, seq_nr, v)
+                        .map_err(|e| Error::StorageError(format!("Failed to deserialize: {}", e)))
+                })
+                .collect();
 
-        let to_key = {
-            let mut key = Vec::with_capacity(entity_id_in_bytes.len() + 8);
-            key.extend_from_slice(entity_id_in_bytes);
-            key.extend_from_slice(&to_sequence_number.to_be_bytes());
-            key
-        };
+        Ok(Box::pin(futures::stream::iter(events)))
+    }
 
-        let events: Vec<Record<T>> = locked
-            .iter()
-            .filter_map(|(k, v)| {
-                if k.starts_with(entity_id_in_bytes)
-                    && k.as_slice() >= from_key.as_slice()
-                    && k.as_slice() <= to_key.as_slice()
-                {
-                    let timestamp = chrono::Utc::now();
-                    seq_nr_from_key(k).and_then(|seq_nr| {
-                        bincode::deserialize::<T>(v)
-                            .ok()
-                            .map(|msg| Record::event(entity_id.to_string(), seq_nr, msg, timestamp))
-                    })
-                } else {
-                    None
-                }
-            })
+    async fn stream_all<T>(
+        &self,
+        from_global_offset: u64,
+        max: u64,
+    ) -> Result<BoxStream<'static, (u64, Record<T>)>, Error>
+    where
+        T: Send + DeserializeOwned + Debug + 'static + Serialize + Sync,
+    {
+        self.stream_from(from_global_offset, max, None)
+    }
+
+    async fn replay_category<T>(
+        &self,
+        category: &str,
+        from_global_offset: u64,
+        max: u64,
+    ) -> Result<BoxStream<'static, (u64, Record<T>)>, Error>
+    where
+        T: Send + DeserializeOwned + Debug + 'static + Serialize + Sync,
+    {
+        self.stream_from(from_global_offset, max, Some(category))
+    }
+
+    async fn write_with_outbox<T>(&self, batch: Vec<Record<&T>>) -> Result<Unit, Error>
+    where
+        T: Serialize + Send + DeserializeOwned + Sync,
+    {
+        self.insert(batch, true, Vec::new(), None, None)
+    }
+
+    async fn write_with_effects<T>(
+        &self,
+        batch: Vec<Record<&T>>,
+        effects: Vec<Effect>,
+        with_outbox: bool,
+    ) -> Result<Unit, Error>
+    where
+        T: Serialize + Send + DeserializeOwned + Sync,
+    {
+        self.insert(batch, with_outbox, effects, None, None)
+    }
+
+    async fn run_effects<F, Fut>(&self, max: u64, run: F) -> Result<usize, Error>
+    where
+        F: FnOnce(Vec<PendingEffect>) -> Fut + Send,
+        Fut: Future<Output = Vec<u64>> + Send,
+    {
+        let pending = self
+            .effects
+            .lock()
+            .map_err(|e| Error::InvalidConfiguration(format!("Failed to read storage: {}", e)))?
+            .pending
+            .values()
             .take(max as usize)
-            .collect();
+            .cloned()
+            .collect::<Vec<_>>();
 
-        Ok(Box::pin(futures::stream::iter(events)))
+        if pending.is_empty() {
+            return Ok(0);
+        }
+
+        let ids = pending.iter().map(PendingEffect::id).collect::<Vec<_>>();
+        let done = run(pending).await;
+
+        let mut effects = self
+            .effects
+            .lock()
+            .map_err(|e| Error::InvalidConfiguration(format!("Failed to write storage: {}", e)))?;
+
+        let mut count = 0;
+        for id in ids {
+            if done.contains(&id) {
+                count += effects.pending.remove(&id).is_some() as usize;
+            } else if let Some(effect) = effects.pending.get_mut(&id) {
+                effect.attempted();
+            }
+        }
+
+        Ok(count)
+    }
+
+    async fn relay_outbox<F, Fut>(&self, max: u64, publish: F) -> Result<usize, Error>
+    where
+        F: FnOnce(Vec<OutboxEntry>) -> Fut + Send,
+        Fut: Future<Output = Vec<u64>> + Send,
+    {
+        let unsent = self
+            .outbox
+            .lock()
+            .map_err(|e| Error::InvalidConfiguration(format!("Failed to read storage: {}", e)))?
+            .values()
+            .take(max as usize)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if unsent.is_empty() {
+            return Ok(0);
+        }
+
+        let sent = publish(unsent).await;
+
+        let mut outbox = self
+            .outbox
+            .lock()
+            .map_err(|e| Error::InvalidConfiguration(format!("Failed to write storage: {}", e)))?;
+
+        Ok(sent.iter().filter(|id| outbox.remove(id).is_some()).count())
+    }
+
+    async fn write_snapshot<S>(
+        &self,
+        entity_id: &str,
+        seq_nr: i64,
+        state_version: u32,
+        state: &S,
+        deleted: bool,
+    ) -> Result<Unit, Error>
+    where
+        S: Serialize + Send + Sync,
+    {
+        // Snapshots are kept as JSON rather than bincode, so that outdated ones can be upcast
+        let payload = serde_json::to_value(state).map_err(|e| {
+            Error::InvalidConfiguration(format!("Failed to serialize value: {}", e))
+        })?;
+
+        let mut snapshots = self
+            .snapshots
+            .lock()
+            .map_err(|e| Error::InvalidConfiguration(format!("Failed to write storage: {}", e)))?;
+
+        // Only the latest snapshot is kept, an older one never replaces a newer one
+        match snapshots.get(entity_id) {
+            Some(latest) if latest.seq_nr() > seq_nr => {}
+            _ => {
+                snapshots.insert(
+                    entity_id.to_string(),
+                    Snapshot::new(seq_nr, state_version, payload).with_deleted(deleted),
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn read_latest_snapshot(&self, entity_id: &str) -> Result<Option<Snapshot>, Error> {
+        let snapshots = self
+            .snapshots
+            .lock()
+            .map_err(|e| Error::InvalidConfiguration(format!("Failed to read storage: {}", e)))?;
+
+        Ok(snapshots.get(entity_id).cloned())
+    }
+
+    async fn write_with_idempotency_key<T>(
+        &self,
+        batch: Vec<Record<&T>>,
+        idempotency_key: IdempotencyKey,
+        effects: Vec<Effect>,
+        with_outbox: bool,
+    ) -> Result<Unit, Error>
+    where
+        T: Serialize + Send + DeserializeOwned + Sync,
+    {
+        self.insert(batch, with_outbox, effects, Some(idempotency_key), None)
+    }
+
+    async fn read_idempotency_key(
+        &self,
+        entity_id: &str,
+        key: &str,
+    ) -> Result<Option<(i64, i64)>, Error> {
+        let keys = self
+            .idempotency_keys
+            .lock()
+            .map_err(|e| Error::InvalidConfiguration(format!("Failed to read storage: {}", e)))?;
+
+        Ok(keys
+            .get(&(entity_id.to_string(), key.to_string()))
+            .filter(|(_, expires_at)| *expires_at > Utc::now())
+            .map(|(seq_nrs, _)| *seq_nrs))
+    }
+}
+
+impl CheckpointStore for MemoryAdapter {
+    async fn save_checkpoint(&self, projection: &str, position: u64) -> Result<Unit, Error> {
+        self.checkpoints
+            .lock()
+            .map_err(|e| Error::InvalidConfiguration(format!("Failed to write storage: {}", e)))?
+            .insert(projection.to_string(), position);
+
+        Ok(())
+    }
+
+    async fn load_checkpoint(&self, projection: &str) -> Result<Option<u64>, Error> {
+        Ok(self
+            .checkpoints
+            .lock()
+            .map_err(|e| Error::InvalidConfiguration(format!("Failed to read storage: {}", e)))?
+            .get(projection)
+            .copied())
     }
 }
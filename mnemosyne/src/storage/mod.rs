@@ -1,19 +1,63 @@
+mod checkpoint;
+mod consistency;
+mod effect;
+mod gaps;
+mod idempotency;
+mod integrity;
 mod memory;
+mod outbox;
 mod postgres;
+mod sharded;
+mod snapshot;
+mod wal;
 
+pub use checkpoint::*;
+pub use consistency::*;
+pub use effect::*;
 use futures::Future;
+pub use gaps::*;
+pub use idempotency::*;
+pub use integrity::*;
 pub use memory::*;
+pub use outbox::*;
 #[cfg(feature = "postgres")]
 pub use postgres::*;
 use serde::Deserialize;
+pub use sharded::*;
+pub use snapshot::*;
+pub use wal::*;
 
 use crate::Unit;
 use crate::{algebra::Record, domain::Error};
 use futures::stream::BoxStream;
-use serde::{de::DeserializeOwned, Serialize};
+use futures::StreamExt;
+use serde::{
+    de::{DeserializeOwned, DeserializeSeed},
+    Serialize,
+};
+use serde_json::Value;
 use std::fmt::Debug;
 
 pub trait Adapter {
+    /// Check that the database is reachable, e.g. by running a trivial query.
+    ///
+    /// Adapters without a remote database are always healthy.
+    ///
+    /// # Returns
+    /// Ok(()) if the database is reachable or the `Error` explaining why it is not.
+    fn health(&self) -> impl Future<Output = Result<Unit, Error>> {
+        async move { Ok(()) }
+    }
+    /// Bring the schema of the database up to date, by applying the versioned migrations
+    /// that were not applied yet. Migrations are idempotent, running them again is a no-op.
+    ///
+    /// Adapters without a schema have nothing to migrate.
+    ///
+    /// # Returns
+    /// Ok(()) once the schema is up to date or the `Error` of the migration that failed.
+    fn migrate(&self) -> impl Future<Output = Result<Unit, Error>> {
+        async move { Ok(()) }
+    }
     /// Read the highest sequence number for a given entity id from the database
     ///
     /// # Arguments
@@ -42,9 +86,44 @@ pub trait Adapter {
     where
         T: Serialize + Send + Sync,
         T: for<'de> Deserialize<'de>;
+    /// Write a batch of messages atomically to the database, only if the highest sequence
+    /// number of the entity is still `expected_highest`. An entity without messages has
+    /// a highest sequence number of 0.
+    ///
+    /// The check and the write are atomic, so of two writers expecting the same highest
+    /// sequence number only one succeeds.
+    ///
+    /// Adapters that do not support conditional writes return an error.
+    ///
+    /// # Arguments
+    /// * `batch` - The atomic batch to write to the database
+    /// * `expected_highest` - The highest sequence number the entity is expected to have
+    ///
+    /// # Returns
+    /// Ok(()) if the batch was written, `Error::ConcurrencyConflict` if the highest sequence
+    /// number did not match.
+    #[allow(unused_variables)]
+    fn write_if_version<T>(
+        &self,
+        batch: Vec<Record<&T>>,
+        expected_highest: u64,
+    ) -> impl Future<Output = Result<Unit, Error>>
+    where
+        T: Serialize + Send + Sync,
+        T: for<'de> Deserialize<'de>,
+    {
+        async move {
+            Err(Error::StorageError(
+                "This adapter does not support conditional writes".to_string(),
+            ))
+        }
+    }
     /// Replay messages from the database for a given entity id and sequence number
     /// range.
     ///
+    /// This is the best effort variant: messages that cannot be read or deserialized are
+    /// logged and skipped. Use `try_replay` to get them as errors instead.
+    ///
     /// # Arguments
     /// * `entity_id` - The entity id to replay messages for
     /// * `from_sequence_number` - The sequence number to start replaying messages from
@@ -62,4 +141,410 @@ pub trait Adapter {
     ) -> impl Future<Output = Result<BoxStream<'static, Record<T>>, Error>>
     where
         T: DeserializeOwned + Send + Debug + 'static + Serialize + Sync;
+    /// Replay messages like `replay`, yielding every message that cannot be read or
+    /// deserialized as an error instead of skipping it, so a corrupt event can be detected,
+    /// e.g. to alert or upcast it, rather than silently leaving the state wrong.
+    ///
+    /// The default implementation never yields an error, it is only correct for adapters
+    /// whose `replay` does not skip messages.
+    fn try_replay<T>(
+        &self,
+        entity_id: &str,
+        from_sequence_number: u64,
+        to_sequence_number: u64,
+        max: u64,
+    ) -> impl Future<Output = Result<BoxStream<'static, Result<Record<T>, Error>>, Error>>
+    where
+        T: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    {
+        async move {
+            Ok(self
+                .replay::<T>(entity_id, from_sequence_number, to_sequence_number, max)
+                .await?
+                .map(Ok)
+                .boxed())
+        }
+    }
+    /// Replay messages like `try_replay`, deserializing each with a clone of `seed` rather
+    /// than with `Deserialize`, for event types that need context to be deserialized, e.g. a
+    /// registry of subtypes or a shared interner.
+    ///
+    /// The default implementation replays the messages as JSON and deserializes them from
+    /// it, which suits every adapter storing JSON.
+    ///
+    /// # Examples
+    /// ```rust,ignore
+    /// let events = store
+    ///     .replay_seeded("shape:1", 0, u64::MAX, u64::MAX, ShapeSeed::new(&registry))
+    ///     .await?;
+    /// ```
+    fn replay_seeded<S, T>(
+        &self,
+        entity_id: &str,
+        from_sequence_number: u64,
+        to_sequence_number: u64,
+        max: u64,
+        seed: S,
+    ) -> impl Future<Output = Result<BoxStream<'static, Result<Record<T>, Error>>, Error>>
+    where
+        S: for<'de> DeserializeSeed<'de, Value = T> + Clone + Send + 'static,
+        T: Send + 'static,
+    {
+        async move {
+            Ok(self
+                .try_replay::<Value>(entity_id, from_sequence_number, to_sequence_number, max)
+                .await?
+                .map(move |record| {
+                    record?.try_map(|message| {
+                        seed.clone().deserialize(message).map_err(|e| {
+                            Error::StorageError(format!("Failed to deserialize: {}", e))
+                        })
+                    })
+                })
+                .boxed())
+        }
+    }
+    /// Replay every message of several entities at once.
+    ///
+    /// The messages of each entity are in sequence number order, the messages of different
+    /// entities may be interleaved. Entities without messages are simply absent from the
+    /// stream. The default implementation replays the entities one after the other, adapters
+    /// should override it with a single round trip where possible.
+    ///
+    /// # Arguments
+    /// * `entity_ids` - The entity ids to replay messages for
+    ///
+    /// # Returns
+    /// A stream of the messages of all the given entities.
+    fn replay_many<T>(
+        &self,
+        entity_ids: &[String],
+    ) -> impl Future<Output = Result<BoxStream<'static, Record<T>>, Error>>
+    where
+        T: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    {
+        async move {
+            let mut streams = Vec::with_capacity(entity_ids.len());
+            for entity_id in entity_ids {
+                streams.push(self.replay::<T>(entity_id, 0, u64::MAX, u64::MAX).await?);
+            }
+            Ok(futures::stream::iter(streams).flatten().boxed())
+        }
+    }
+    /// Stream every message in the database, across all entities, in the order they were
+    /// written.
+    ///
+    /// Each message is paired with its global offset, a monotonically increasing position
+    /// in the store. Resuming from the offset following the last one seen yields every
+    /// message written since, which makes this suitable for change data capture.
+    ///
+    /// # Arguments
+    /// * `from_global_offset` - The global offset to start streaming from, inclusive
+    /// * `max` - The maximum number of messages to stream
+    ///
+    /// # Returns
+    /// A stream of messages paired with their global offset.
+    fn stream_all<T>(
+        &self,
+        from_global_offset: u64,
+        max: u64,
+    ) -> impl Future<Output = Result<BoxStream<'static, (u64, Record<T>)>, Error>>
+    where
+        T: DeserializeOwned + Send + Debug + 'static + Serialize + Sync;
+    /// Stream every message of a category, across all the entities of the category, in the
+    /// order they were written. The category of an entity is the prefix of its id, see
+    /// `category`.
+    ///
+    /// Adapters that do not support categories return an error.
+    ///
+    /// # Arguments
+    /// * `category` - The category to stream messages for
+    /// * `from_global_offset` - The global offset to start streaming from, inclusive
+    /// * `max` - The maximum number of messages to stream
+    ///
+    /// # Returns
+    /// A stream of the messages of the category paired with their global offset.
+    #[allow(unused_variables)]
+    fn replay_category<T>(
+        &self,
+        category: &str,
+        from_global_offset: u64,
+        max: u64,
+    ) -> impl Future<Output = Result<BoxStream<'static, (u64, Record<T>)>, Error>>
+    where
+        T: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    {
+        async move {
+            Err(Error::StorageError(
+                "This adapter does not support category streams".to_string(),
+            ))
+        }
+    }
+    /// Write a batch of messages atomically to the database, together with an outbox entry
+    /// per message. The outbox entries are published by the relay once the write is committed,
+    /// see `PublishMode::Outbox`.
+    ///
+    /// Adapters that do not support an outbox return an error.
+    ///
+    /// # Arguments
+    /// * `batch` - The atomic batch to write to the database
+    #[allow(unused_variables)]
+    fn write_with_outbox<T>(
+        &self,
+        batch: Vec<Record<&T>>,
+    ) -> impl Future<Output = Result<Unit, Error>>
+    where
+        T: Serialize + Send + Sync,
+        T: for<'de> Deserialize<'de>,
+    {
+        async move {
+            Err(Error::StorageError(
+                "This adapter does not support an outbox".to_string(),
+            ))
+        }
+    }
+    /// Relay unsent outbox entries, oldest first.
+    ///
+    /// `publish` is handed at most `max` unsent entries and returns the ids of the ones it
+    /// published, which are then marked as sent. Entries that were not published are retried
+    /// on the next call.
+    ///
+    /// # Returns
+    /// The number of entries marked as sent.
+    #[allow(unused_variables)]
+    fn relay_outbox<F, Fut>(
+        &self,
+        max: u64,
+        publish: F,
+    ) -> impl Future<Output = Result<usize, Error>>
+    where
+        F: FnOnce(Vec<OutboxEntry>) -> Fut + Send,
+        Fut: Future<Output = Vec<u64>> + Send,
+    {
+        async move {
+            Err(Error::StorageError(
+                "This adapter does not support an outbox".to_string(),
+            ))
+        }
+    }
+    /// Write a batch of messages atomically to the database, together with the effects of
+    /// the command that produced them, see `Command::durable_effects`, and with an outbox
+    /// entry per message when `with_outbox` is set, see `write_with_outbox`. The effects
+    /// belong to the entity and the sequence number of the last message of the batch.
+    ///
+    /// Adapters that do not support durable effects return an error.
+    ///
+    /// # Arguments
+    /// * `batch` - The atomic batch to write to the database
+    /// * `effects` - The effects to run once the batch is written
+    /// * `with_outbox` - Whether to write an outbox entry per message
+    #[allow(unused_variables)]
+    fn write_with_effects<T>(
+        &self,
+        batch: Vec<Record<&T>>,
+        effects: Vec<Effect>,
+        with_outbox: bool,
+    ) -> impl Future<Output = Result<Unit, Error>>
+    where
+        T: Serialize + Send + Sync,
+        T: for<'de> Deserialize<'de>,
+    {
+        async move {
+            Err(Error::StorageError(
+                "This adapter does not support durable effects".to_string(),
+            ))
+        }
+    }
+    /// Run pending effects, oldest first.
+    ///
+    /// `run` is handed at most `max` pending effects and returns the ids of the ones that
+    /// succeeded, which are then marked as done. Effects that did not succeed have their
+    /// attempts counted and are retried on the next call.
+    ///
+    /// # Returns
+    /// The number of effects marked as done.
+    #[allow(unused_variables)]
+    fn run_effects<F, Fut>(&self, max: u64, run: F) -> impl Future<Output = Result<usize, Error>>
+    where
+        F: FnOnce(Vec<PendingEffect>) -> Fut + Send,
+        Fut: Future<Output = Vec<u64>> + Send,
+    {
+        async move {
+            Err(Error::StorageError(
+                "This adapter does not support durable effects".to_string(),
+            ))
+        }
+    }
+    /// Write a snapshot of the state of an entity at the given sequence number.
+    ///
+    /// Snapshots are only a cache of the state folded from the events, so a snapshot
+    /// written at a sequence number replaces any previous snapshot at the same one.
+    ///
+    /// Adapters that do not support snapshots return an error.
+    ///
+    /// # Arguments
+    /// * `entity_id` - The entity id the state belongs to
+    /// * `seq_nr` - The sequence number of the last event folded into the state
+    /// * `state_version` - The version of the state, see `SnapshotUpcaster`
+    /// * `state` - The state to snapshot
+    /// * `deleted` - Whether the entity is deleted as of the snapshot, see `Event::deletes`
+    #[allow(unused_variables)]
+    fn write_snapshot<S>(
+        &self,
+        entity_id: &str,
+        seq_nr: i64,
+        state_version: u32,
+        state: &S,
+        deleted: bool,
+    ) -> impl Future<Output = Result<Unit, Error>>
+    where
+        S: Serialize + Send + Sync,
+    {
+        async move {
+            Err(Error::StorageError(
+                "This adapter does not support snapshots".to_string(),
+            ))
+        }
+    }
+    /// Read the latest snapshot of an entity, as it is stored.
+    ///
+    /// # Returns
+    /// The latest snapshot, or None if there is no snapshot for the given entity id.
+    /// Adapters that do not support snapshots always return None.
+    #[allow(unused_variables)]
+    fn read_latest_snapshot(
+        &self,
+        entity_id: &str,
+    ) -> impl Future<Output = Result<Option<Snapshot>, Error>> {
+        async move { Ok(None) }
+    }
+    /// Write a batch of messages atomically to the database, together with the idempotency
+    /// key of the command that produced them, see `Engine::enqueue_with_idempotency_key`.
+    /// The key is remembered, until it expires, as having produced the messages from the
+    /// first to the last sequence number of the batch, see `read_idempotency_key`. Writing a
+    /// key again replaces it, adapters may drop expired keys at any time.
+    ///
+    /// The effects and the outbox entries are written as with `write_with_effects`.
+    ///
+    /// Adapters that do not support idempotency keys return an error.
+    ///
+    /// # Arguments
+    /// * `batch` - The atomic batch to write to the database
+    /// * `idempotency_key` - The idempotency key of the command that produced the batch
+    /// * `effects` - The effects to run once the batch is written
+    /// * `with_outbox` - Whether to write an outbox entry per message
+    #[allow(unused_variables)]
+    fn write_with_idempotency_key<T>(
+        &self,
+        batch: Vec<Record<&T>>,
+        idempotency_key: IdempotencyKey,
+        effects: Vec<Effect>,
+        with_outbox: bool,
+    ) -> impl Future<Output = Result<Unit, Error>>
+    where
+        T: Serialize + Send + Sync,
+        T: for<'de> Deserialize<'de>,
+    {
+        async move {
+            Err(Error::StorageError(
+                "This adapter does not support idempotency keys".to_string(),
+            ))
+        }
+    }
+    /// Read the sequence numbers of the events produced by the command carrying an
+    /// idempotency key, see `write_with_idempotency_key`.
+    ///
+    /// # Returns
+    /// The first and last sequence numbers, or None if the key is unknown or expired.
+    #[allow(unused_variables)]
+    fn read_idempotency_key(
+        &self,
+        entity_id: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<(i64, i64)>, Error>> {
+        async move {
+            Err(Error::StorageError(
+                "This adapter does not support idempotency keys".to_string(),
+            ))
+        }
+    }
+    /// Read the state of the latest snapshot of an entity at the current version of
+    /// `upcaster`.
+    ///
+    /// A snapshot written at another version is upcast. A migrated snapshot is written back
+    /// at the current version, a discarded one is ignored, as is a snapshot at the current
+    /// version that fails to deserialize.
+    ///
+    /// # Returns
+    /// The sequence number the snapshot was taken at together with the state, or None if
+    /// there is no usable snapshot for the given entity id.
+    fn read_latest_state<S>(
+        &self,
+        entity_id: &str,
+        upcaster: &dyn SnapshotUpcaster<S>,
+    ) -> impl Future<Output = Result<Option<(i64, S)>, Error>>
+    where
+        S: DeserializeOwned + Serialize + Send + Sync,
+    {
+        async move {
+            let Some(snapshot) = self.read_latest_snapshot(entity_id).await? else {
+                return Ok(None);
+            };
+
+            if snapshot.state_version() == upcaster.version() {
+                return match snapshot.state::<S>() {
+                    Ok(state) => Ok(Some((snapshot.seq_nr(), state))),
+                    Err(e) => {
+                        tracing::warn!("Ignoring snapshot of entity {}: {}", entity_id, e);
+                        Ok(None)
+                    }
+                };
+            }
+
+            match upcaster.upcast(snapshot.state_version(), snapshot.payload().clone()) {
+                Some(state) => {
+                    self.write_snapshot(
+                        entity_id,
+                        snapshot.seq_nr(),
+                        upcaster.version(),
+                        &state,
+                        snapshot.is_deleted(),
+                    )
+                    .await?;
+                    Ok(Some((snapshot.seq_nr(), state)))
+                }
+                None => {
+                    tracing::info!(
+                        "Discarding snapshot of entity {} at version {}, the current version is {}",
+                        entity_id,
+                        snapshot.state_version(),
+                        upcaster.version()
+                    );
+                    Ok(None)
+                }
+            }
+        }
+    }
+}
+
+/// Turn a stream of `try_replay` into one of `replay`, logging and skipping the messages
+/// that could not be read. A lost connection ends the stream, which is logged as an error.
+pub(crate) fn best_effort<T>(
+    stream: BoxStream<'static, Result<Record<T>, Error>>,
+) -> BoxStream<'static, Record<T>>
+where
+    T: Send + 'static,
+{
+    stream
+        .filter_map(|record| async move {
+            record
+                .map_err(|e| match e {
+                    Error::ConnectionLost(_) => {
+                        tracing::error!("Replay stopped before its end: {}", e)
+                    }
+                    e => tracing::warn!("Skipping a message that could not be read: {}", e),
+                })
+                .ok()
+        })
+        .boxed()
 }
@@ -0,0 +1,39 @@
+use chrono::{DateTime, Utc};
+
+/// A message waiting in the outbox to be published to the event topic.
+#[derive(Debug, Clone)]
+pub struct OutboxEntry {
+    id: u64,
+    entity_id: String,
+    timestamp: DateTime<Utc>,
+    payload: Vec<u8>,
+}
+
+impl OutboxEntry {
+    pub fn new(id: u64, entity_id: String, timestamp: DateTime<Utc>, payload: Vec<u8>) -> Self {
+        Self {
+            id,
+            entity_id,
+            timestamp,
+            payload,
+        }
+    }
+
+    /// The id of the entry in the outbox, used to mark it as sent.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn entity_id(&self) -> &str {
+        &self.entity_id
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    /// The serialized record, as it is published to the event topic.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
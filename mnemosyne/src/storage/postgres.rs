@@ -1,22 +1,102 @@
-use super::Adapter;
+use super::{
+    best_effort, Adapter, CheckpointStore, Effect, IdempotencyKey, OutboxEntry, PendingEffect,
+    Snapshot,
+};
 use crate::{algebra::Record, domain::Error, Unit};
 use chrono::{DateTime, Utc};
 use deadpool_postgres::GenericClient;
-use deadpool_postgres::{Manager, Pool};
-use futures::{stream::BoxStream, StreamExt};
+use deadpool_postgres::{Manager, Object, Pool};
+use futures::{stream::BoxStream, Future, StreamExt};
 use serde::{de::DeserializeOwned, Serialize};
 use serde_json::Value;
+use std::collections::HashMap;
 use std::fmt::Debug;
-use tokio_postgres::Config;
+use std::{error::Error as _, pin::Pin, time::Duration};
+use tokio_postgres::{error::SqlState, types::ToSql, Config, Row, RowStream, Statement};
+
+/// Default maximum number of events inserted per statement.
+pub const WRITE_BATCH_SIZE: usize = 100;
+/// Attempts made to resume a replay on a fresh connection after its connection was lost.
+pub const REPLAY_RESUME_ATTEMPTS: u32 = 3;
+/// Milliseconds to wait before every attempt to resume a replay.
+pub const REPLAY_RESUME_BACKOFF: u64 = 100;
+// Postgres allows at most 65535 parameters per statement, an event takes eight
+const MAX_WRITE_BATCH_SIZE: usize = u16::MAX as usize / 8;
+
+/// The type of the `payload` column of the `events` table, see
+/// `PostgresAdapterBuilder::with_payload_type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PayloadType {
+    /// Decomposed binary JSON, payloads can be queried and indexed.
+    #[default]
+    Jsonb,
+    /// JSON stored as text, cheaper to write than `jsonb` as it is not decomposed. Payloads
+    /// can still be queried, but not indexed.
+    Json,
+    /// The serialized JSON as raw bytes, the cheapest to write. Payloads cannot be queried.
+    Bytea,
+}
+
+impl PayloadType {
+    fn sql(&self) -> &'static str {
+        match self {
+            PayloadType::Jsonb => "JSONB",
+            PayloadType::Json => "JSON",
+            PayloadType::Bytea => "BYTEA",
+        }
+    }
+
+    // The value bound to the payload column for an event
+    fn encode<T>(&self, message: &T) -> Result<Box<dyn ToSql + Send + Sync>, Error>
+    where
+        T: Serialize,
+    {
+        let serialize =
+            |e: serde_json::Error| Error::StorageError(format!("Failed to serialize: {}", e));
+
+        Ok(match self {
+            PayloadType::Jsonb | PayloadType::Json => {
+                Box::new(serde_json::to_value(message).map_err(serialize)?)
+            }
+            PayloadType::Bytea => Box::new(serde_json::to_vec(message).map_err(serialize)?),
+        })
+    }
+
+    // Read the event from the payload column of a row
+    fn decode<T>(&self, row: &Row) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let get =
+            |e: tokio_postgres::Error| Error::StorageError(format!("Failed to get payload: {}", e));
+        let deserialize =
+            |e: serde_json::Error| Error::StorageError(format!("Failed to deserialize: {}", e));
+
+        match self {
+            PayloadType::Jsonb | PayloadType::Json => {
+                let payload = row.try_get::<_, Value>("payload").map_err(get)?;
+                serde_json::from_value::<T>(payload).map_err(deserialize)
+            }
+            PayloadType::Bytea => {
+                let payload = row.try_get::<_, Vec<u8>>("payload").map_err(get)?;
+                serde_json::from_slice::<T>(&payload).map_err(deserialize)
+            }
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct PostgresAdapter {
     pool: Pool,
+    write_batch_size: usize,
+    payload_type: PayloadType,
+    payload_index: bool,
 }
 
 impl PostgresAdapter {
+    /// Connect to the database, failing if no connection can be established.
     #[allow(dead_code)]
-    pub async fn connect(connect: PostgresAdapterBuilder) -> Self {
+    pub async fn connect(connect: PostgresAdapterBuilder) -> Result<Self, Error> {
         let mut config = Config::new();
         config.host(&connect.host);
         config.user(&connect.user);
@@ -29,24 +109,596 @@ impl PostgresAdapter {
         let manager = Manager::new(config, tokio_postgres::NoTls);
         let pool = Pool::builder(manager) // This is already an Arc, so no need to wrap it
             .build()
-            .map_err(Error::ConnectionError);
+            .map_err(Error::ConnectionError)?;
 
-        if let Err(e) = pool {
-            panic!("Failed to connect to database: {}", e);
-        }
+        let adapter = Self {
+            pool,
+            write_batch_size: connect.write_batch_size,
+            payload_type: connect.payload_type,
+            payload_index: connect.payload_index,
+        };
 
         // test connection
-        let pool = pool.unwrap();
-        let connection = pool.get().await.map_err(Error::ConnectionRetrievalError);
+        adapter.health().await?;
+
+        Ok(adapter)
+    }
+
+    /// The connection pool of the adapter, to run custom queries against the same database
+    /// without opening a second pool.
+    pub fn pool(&self) -> &Pool {
+        &self.pool
+    }
+
+    /// The migrations of the schema, in the order they are applied. Migrations are never
+    /// changed once released, a change to the schema is a new migration.
+    fn migrations(&self) -> Vec<Migration> {
+        vec![
+            Migration {
+                version: 1,
+                name: "create events",
+                statements: vec![
+                    format!(
+                        "CREATE TABLE IF NOT EXISTS events (
+                            id UUID PRIMARY KEY,
+                            entity_id TEXT NOT NULL,
+                            seq_nr BIGINT NOT NULL,
+                            timestamp TIMESTAMPTZ NOT NULL,
+                            payload {} NOT NULL,
+                            position BIGSERIAL NOT NULL,
+                            category TEXT GENERATED ALWAYS AS (split_part(entity_id, ':', 1)) STORED
+                        )",
+                        self.payload_type.sql()
+                    ),
+                    "CREATE UNIQUE INDEX IF NOT EXISTS events_entity_id_seq_nr_idx ON events (entity_id, seq_nr)".to_string(),
+                    "CREATE UNIQUE INDEX IF NOT EXISTS events_position_idx ON events (position)".to_string(),
+                    "CREATE INDEX IF NOT EXISTS events_category_position_idx ON events (category, position)".to_string(),
+                ],
+            },
+            Migration {
+                version: 2,
+                name: "create outbox",
+                statements: vec![
+                    "CREATE TABLE IF NOT EXISTS outbox (
+                        id BIGSERIAL PRIMARY KEY,
+                        entity_id TEXT NOT NULL,
+                        timestamp TIMESTAMPTZ NOT NULL,
+                        payload BYTEA NOT NULL,
+                        sent BOOLEAN NOT NULL DEFAULT FALSE
+                    )"
+                    .to_string(),
+                    "CREATE INDEX IF NOT EXISTS outbox_unsent_idx ON outbox (id) WHERE sent = FALSE".to_string(),
+                ],
+            },
+            Migration {
+                version: 3,
+                name: "create snapshots",
+                statements: vec![
+                    "CREATE TABLE IF NOT EXISTS snapshots (
+                        entity_id TEXT NOT NULL,
+                        seq_nr BIGINT NOT NULL,
+                        timestamp TIMESTAMPTZ NOT NULL,
+                        payload JSONB NOT NULL,
+                        PRIMARY KEY (entity_id, seq_nr)
+                    )"
+                    .to_string(),
+                ],
+            },
+            Migration {
+                version: 4,
+                name: "add snapshot state version",
+                statements: vec![
+                    "ALTER TABLE snapshots ADD COLUMN IF NOT EXISTS state_version BIGINT NOT NULL DEFAULT 0".to_string(),
+                ],
+            },
+            Migration {
+                version: 5,
+                name: "create checkpoints",
+                statements: vec![
+                    "CREATE TABLE IF NOT EXISTS checkpoints (
+                        projection TEXT PRIMARY KEY,
+                        position BIGINT NOT NULL
+                    )"
+                    .to_string(),
+                ],
+            },
+            Migration {
+                version: 6,
+                name: "create idempotency keys",
+                statements: vec![
+                    "CREATE TABLE IF NOT EXISTS idempotency_keys (
+                        entity_id TEXT NOT NULL,
+                        key TEXT NOT NULL,
+                        first_seq_nr BIGINT NOT NULL,
+                        last_seq_nr BIGINT NOT NULL,
+                        expires_at TIMESTAMPTZ NOT NULL,
+                        PRIMARY KEY (entity_id, key)
+                    )"
+                    .to_string(),
+                ],
+            },
+            Migration {
+                version: 7,
+                name: "add event metadata",
+                statements: vec![
+                    "ALTER TABLE events ADD COLUMN IF NOT EXISTS correlation_id UUID".to_string(),
+                    "ALTER TABLE events ADD COLUMN IF NOT EXISTS source TEXT".to_string(),
+                ],
+            },
+            Migration {
+                version: 8,
+                name: "add event hashes",
+                statements: vec![
+                    "ALTER TABLE events ADD COLUMN IF NOT EXISTS hash TEXT".to_string(),
+                ],
+            },
+            Migration {
+                version: 9,
+                name: "create effects",
+                statements: vec![
+                    "CREATE TABLE IF NOT EXISTS effects (
+                        id BIGSERIAL PRIMARY KEY,
+                        entity_id TEXT NOT NULL,
+                        seq_nr BIGINT NOT NULL,
+                        name TEXT NOT NULL,
+                        payload JSONB NOT NULL,
+                        attempts INTEGER NOT NULL DEFAULT 0,
+                        done BOOLEAN NOT NULL DEFAULT FALSE
+                    )"
+                    .to_string(),
+                    "CREATE INDEX IF NOT EXISTS effects_pending_idx ON effects (id) WHERE done = FALSE".to_string(),
+                ],
+            },
+            Migration {
+                version: 10,
+                name: "add snapshot deletion",
+                statements: vec![
+                    "ALTER TABLE snapshots ADD COLUMN IF NOT EXISTS deleted BOOLEAN NOT NULL DEFAULT FALSE".to_string(),
+                ],
+            },
+        ]
+    }
+
+    /// Write a batch of events in a single transaction, together with a row per event in
+    /// the `outbox` table when `with_outbox` is set, a row per effect in the `effects` table
+    /// and only if the highest sequence number of the entity matches `expected_highest`, if
+    /// given.
+    ///
+    /// The `outbox` table is expected to have the following shape:
+    ///
+    /// ```sql
+    /// CREATE TABLE outbox (
+    ///     id BIGSERIAL PRIMARY KEY,
+    ///     entity_id TEXT NOT NULL,
+    ///     timestamp TIMESTAMPTZ NOT NULL,
+    ///     payload BYTEA NOT NULL,
+    ///     sent BOOLEAN NOT NULL DEFAULT FALSE
+    /// );
+    /// ```
+    async fn insert<T>(
+        &self,
+        batch: Vec<Record<&T>>,
+        with_outbox: bool,
+        effects: Vec<Effect>,
+        idempotency_key: Option<IdempotencyKey>,
+        expected_highest: Option<u64>,
+    ) -> Result<Unit, Error>
+    where
+        T: Serialize,
+    {
+        let mut connection = self
+            .pool
+            .get()
+            .await
+            .map_err(Error::ConnectionRetrievalError)?;
+
+        let transaction = connection
+            .transaction()
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?;
+
+        if let (Some(expected), Some(record)) = (expected_highest, batch.first()) {
+            let entity_id = record.entity_id();
+
+            // Serialize conditional writes per entity until the transaction ends, so nothing
+            // can be written between the check and the write
+            transaction
+                .execute("SELECT pg_advisory_xact_lock(hashtext($1))", &[&entity_id])
+                .await
+                .map_err(|e| Error::StorageError(e.to_string()))?;
+
+            let actual = transaction
+                .query_one(
+                    "SELECT COALESCE(MAX(seq_nr), 0) AS seq_nr FROM events WHERE entity_id = $1",
+                    &[&entity_id],
+                )
+                .await
+                .map_err(|e| Error::StorageError(e.to_string()))?
+                .try_get::<_, i64>("seq_nr")
+                .map_err(|e| Error::StorageError(format!("Failed to get seq_nr: {}", e)))?
+                as u64;
+
+            if actual != expected {
+                transaction
+                    .rollback()
+                    .await
+                    .map_err(|e| Error::StorageError(e.to_string()))?;
+
+                return Err(Error::ConcurrencyConflict {
+                    entity_id: entity_id.to_string(),
+                    expected,
+                    actual,
+                });
+            }
+        }
+
+        let expected = batch.len();
+        let mut written = 0;
+
+        let outbox = match with_outbox {
+            true => Some(
+                transaction
+                    .prepare(
+                        "INSERT INTO outbox (entity_id, timestamp, payload) VALUES ($1, $2, $3)",
+                    )
+                    .await
+                    .map_err(|e| Error::StorageError(e.to_string()))?,
+            ),
+            false => None,
+        };
+
+        // Events are inserted `write_batch_size` rows per statement. A batch needs at most
+        // two statements, one for full chunks and one for the remainder, each prepared once.
+        let mut statements: HashMap<usize, Statement> = HashMap::new();
+
+        for chunk in batch.chunks(self.write_batch_size) {
+            let rows = chunk
+                .iter()
+                .map(|record| {
+                    let payload = self.payload_type.encode(record.message())?;
+
+                    Ok((
+                        uuid::Uuid::new_v4(),
+                        record.entity_id(),
+                        record.seq_nr(),
+                        record.timestamp(),
+                        payload,
+                        record.id(),
+                        record.source(),
+                        record.hash(),
+                    ))
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
 
-        if let Err(e) = connection {
-            panic!("Failed to connect to database: {}", e);
+            let statement = match statements.get(&chunk.len()) {
+                Some(statement) => statement.clone(),
+                None => {
+                    let statement = transaction
+                        .prepare(&insert_events_query(chunk.len()))
+                        .await
+                        .map_err(|e| Error::StorageError(e.to_string()))?;
+                    statements.insert(chunk.len(), statement.clone());
+                    statement
+                }
+            };
+
+            let params = rows
+                .iter()
+                .flat_map(
+                    |(
+                        uuid,
+                        entity_id,
+                        seq_nr,
+                        timestamp,
+                        payload,
+                        correlation_id,
+                        source,
+                        hash,
+                    )| {
+                        [
+                            uuid as &(dyn ToSql + Sync),
+                            entity_id,
+                            seq_nr,
+                            timestamp,
+                            payload.as_ref(),
+                            correlation_id,
+                            source,
+                            hash,
+                        ]
+                    },
+                )
+                .collect::<Vec<_>>();
+
+            written += transaction
+                .execute(&statement, &params)
+                .await
+                .map_err(|e| Error::StorageError(e.to_string()))? as usize;
+
+            if let Some(outbox) = &outbox {
+                for record in chunk {
+                    let published = serde_json::to_vec(record)
+                        .map_err(|e| Error::StorageError(format!("Failed to serialize: {}", e)))?;
+
+                    transaction
+                        .execute(
+                            outbox,
+                            &[&record.entity_id(), &record.timestamp(), &published],
+                        )
+                        .await
+                        .map_err(|e| Error::StorageError(e.to_string()))?;
+                }
+            }
+        }
+
+        // The effects belong to the last event of the batch
+        if let Some(last) = batch.last().filter(|_| !effects.is_empty()) {
+            let statement = transaction
+                .prepare(
+                    "INSERT INTO effects (entity_id, seq_nr, name, payload) VALUES ($1, $2, $3, $4)",
+                )
+                .await
+                .map_err(|e| Error::StorageError(e.to_string()))?;
+
+            for effect in &effects {
+                transaction
+                    .execute(
+                        &statement,
+                        &[
+                            &last.entity_id(),
+                            &last.seq_nr(),
+                            &effect.name(),
+                            effect.payload(),
+                        ],
+                    )
+                    .await
+                    .map_err(|e| Error::StorageError(e.to_string()))?;
+            }
+        }
+
+        // The idempotency key covers the whole batch, expired keys of the entity are deleted
+        // on every write of a key
+        if let (Some(idempotency_key), Some((first, last))) =
+            (idempotency_key, batch.first().zip(batch.last()))
+        {
+            transaction
+                .execute(
+                    "DELETE FROM idempotency_keys WHERE entity_id = $1 AND expires_at <= now()",
+                    &[&first.entity_id()],
+                )
+                .await
+                .map_err(|e| Error::StorageError(e.to_string()))?;
+
+            transaction
+                .execute(
+                    "INSERT INTO idempotency_keys (entity_id, key, first_seq_nr, last_seq_nr, expires_at) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (entity_id, key) DO UPDATE SET first_seq_nr = EXCLUDED.first_seq_nr, last_seq_nr = EXCLUDED.last_seq_nr, expires_at = EXCLUDED.expires_at",
+                    &[
+                        &first.entity_id(),
+                        &idempotency_key.key(),
+                        &first.seq_nr(),
+                        &last.seq_nr(),
+                        &idempotency_key.expires_at(),
+                    ],
+                )
+                .await
+                .map_err(|e| Error::StorageError(e.to_string()))?;
+        }
+
+        // Every record must have been inserted, otherwise the whole batch is rolled back
+        // so that retrying it is safe.
+        if written != expected {
+            transaction
+                .rollback()
+                .await
+                .map_err(|e| Error::StorageError(e.to_string()))?;
+
+            return Err(Error::PartialWrite {
+                expected,
+                written,
+                entity_id: batch
+                    .first()
+                    .map(|record| record.entity_id().to_string())
+                    .unwrap_or_default(),
+            });
         }
 
-        Self { pool }
+        transaction
+            .commit()
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?;
+
+        Ok(())
+    }
+}
+
+/// Read an event together with its global offset from a row of the `events` table.
+fn positioned_record<T>(row: &Row, payload_type: PayloadType) -> Result<(u64, Record<T>), Error>
+where
+    T: DeserializeOwned,
+{
+    let position = row
+        .try_get::<_, i64>("position")
+        .map_err(|e| Error::StorageError(format!("Failed to get position: {}", e)))?;
+
+    Ok((position as u64, record(row, payload_type)?))
+}
+
+fn record<T>(row: &Row, payload_type: PayloadType) -> Result<Record<T>, Error>
+where
+    T: DeserializeOwned,
+{
+    let entity_id = row
+        .try_get::<_, String>("entity_id")
+        .map_err(|e| Error::StorageError(e.to_string()))?;
+    let payload = payload_type.decode::<T>(row)?;
+    let timestamp = row
+        .try_get::<_, DateTime<Utc>>("timestamp")
+        .map_err(|e| Error::StorageError(format!("Failed to get timestamp: {}", e)))?;
+    let seq_nr = row
+        .try_get::<_, i64>("seq_nr")
+        .map_err(|e| Error::StorageError(format!("Failed to get seq_nr: {}", e)))?;
+    let correlation_id = row
+        .try_get::<_, Option<uuid::Uuid>>("correlation_id")
+        .map_err(|e| Error::StorageError(format!("Failed to get correlation_id: {}", e)))?;
+    let source = row
+        .try_get::<_, Option<String>>("source")
+        .map_err(|e| Error::StorageError(format!("Failed to get source: {}", e)))?;
+    let hash = row
+        .try_get::<_, Option<String>>("hash")
+        .map_err(|e| Error::StorageError(format!("Failed to get hash: {}", e)))?;
+
+    let mut record = Record::event(entity_id, seq_nr, payload, timestamp);
+    if let Some(correlation_id) = correlation_id {
+        record = record.with_id(correlation_id);
+    }
+    if let Some(source) = source {
+        record = record.with_source(source);
+    }
+    if let Some(hash) = hash {
+        record = record.with_hash(hash);
+    }
+    Ok(record)
+}
+
+/// Why a replay could not query the events of its entity.
+enum ReplayFailure {
+    Pool(deadpool_postgres::PoolError),
+    Query(tokio_postgres::Error),
+}
+
+/// Where a replay is at, so it can be resumed on a fresh connection from the event after
+/// the last one it yielded, see `Adapter::try_replay` of `PostgresAdapter`.
+struct ReplayCursor {
+    pool: Pool,
+    payload_type: PayloadType,
+    entity_id: String,
+    next_seq_nr: i64,
+    to_seq_nr: i64,
+    remaining: i64,
+    // The connection is held for as long as its rows are streamed
+    rows: Option<(Object, Pin<Box<RowStream>>)>,
+    attempts: u32,
+    done: bool,
+}
+
+impl ReplayCursor {
+    async fn open(&mut self) -> Result<Unit, ReplayFailure> {
+        let connection = self.pool.get().await.map_err(ReplayFailure::Pool)?;
+        let rows = connection
+            .query_raw(
+                "SELECT entity_id, seq_nr, payload, timestamp, correlation_id, source, hash FROM events WHERE entity_id = $1 AND seq_nr >= $2 AND seq_nr <= $3 ORDER BY seq_nr ASC LIMIT $4",
+                [&self.entity_id as &(dyn ToSql + Sync), &self.next_seq_nr, &self.to_seq_nr, &self.remaining],
+            )
+            .await
+            .map_err(ReplayFailure::Query)?;
+
+        self.rows = Some((connection, Box::pin(rows)));
+        Ok(())
+    }
+
+    /// The next record of the replay, resuming it on a fresh connection if its connection
+    /// is lost, or None once it is over. A replay that cannot be resumed yields
+    /// `Error::ConnectionLost` and ends.
+    async fn next<T>(&mut self) -> Option<Result<Record<T>, Error>>
+    where
+        T: DeserializeOwned,
+    {
+        loop {
+            if self.done || self.remaining <= 0 {
+                return None;
+            }
+
+            let failure = match self.rows.as_mut() {
+                None => match self.open().await {
+                    Ok(()) => continue,
+                    Err(failure) => failure,
+                },
+                Some((_, rows)) => match rows.next().await? {
+                    Ok(row) => {
+                        self.attempts = 0;
+                        // Unreadable events are yielded as errors, the replay goes on after them
+                        if let Ok(seq_nr) = row.try_get::<_, i64>("seq_nr") {
+                            self.next_seq_nr = seq_nr.saturating_add(1);
+                            self.remaining -= 1;
+                        }
+                        return Some(record(&row, self.payload_type));
+                    }
+                    Err(e) if !is_lost(&e) => {
+                        self.done = true;
+                        return Some(Err(Error::StorageError(e.to_string())));
+                    }
+                    Err(e) => ReplayFailure::Query(e),
+                },
+            };
+
+            self.rows = None;
+            let reason = match failure {
+                ReplayFailure::Pool(e) => e.to_string(),
+                ReplayFailure::Query(e) => e.to_string(),
+            };
+            if self.attempts >= REPLAY_RESUME_ATTEMPTS {
+                self.done = true;
+                return Some(Err(Error::ConnectionLost(format!(
+                    "Replay of entity {} stopped before event {}: {}",
+                    self.entity_id, self.next_seq_nr, reason
+                ))));
+            }
+
+            self.attempts += 1;
+            tracing::warn!(
+                "Resuming replay of entity {} from event {} on a fresh connection: {}",
+                self.entity_id,
+                self.next_seq_nr,
+                reason
+            );
+            tokio::time::sleep(Duration::from_millis(REPLAY_RESUME_BACKOFF)).await;
+        }
     }
 }
 
+/// Whether an error means the connection is gone, rather than the query failing. A
+/// connection terminated by the server, e.g. with `pg_terminate_backend` or on a shutdown,
+/// is gone too.
+fn is_lost(error: &tokio_postgres::Error) -> bool {
+    error.is_closed()
+        || error.source().is_some_and(|e| e.is::<std::io::Error>())
+        || error.code().is_some_and(|code| {
+            *code == SqlState::ADMIN_SHUTDOWN || *code == SqlState::CRASH_SHUTDOWN
+        })
+}
+
+/// A versioned change to the schema, see `PostgresAdapter::migrate`.
+struct Migration {
+    version: i64,
+    name: &'static str,
+    statements: Vec<String>,
+}
+
+/// Build an insert of `rows` events in a single statement.
+fn insert_events_query(rows: usize) -> String {
+    let values = (0..rows)
+        .map(|row| {
+            let first = row * 8;
+            format!(
+                "(${}, ${}, ${}, ${}, ${}, ${}, ${}, ${})",
+                first + 1,
+                first + 2,
+                first + 3,
+                first + 4,
+                first + 5,
+                first + 6,
+                first + 7,
+                first + 8
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(
+        "INSERT INTO events (id, entity_id, seq_nr, timestamp, payload, correlation_id, source, hash) VALUES {}",
+        values
+    )
+}
+
 pub struct PostgresAdapterBuilder {
     host: String,
     user: String,
@@ -55,6 +707,9 @@ pub struct PostgresAdapterBuilder {
     database: String,
     timeout: u64,
     ssl: SslMode,
+    write_batch_size: usize,
+    payload_type: PayloadType,
+    payload_index: bool,
 }
 
 impl PostgresAdapterBuilder {
@@ -75,8 +730,35 @@ impl PostgresAdapterBuilder {
             database: database.into(),
             timeout,
             ssl,
+            write_batch_size: WRITE_BATCH_SIZE,
+            payload_type: PayloadType::default(),
+            payload_index: false,
         }
     }
+
+    /// Set the maximum number of events inserted per statement, defaults to
+    /// `WRITE_BATCH_SIZE`. Larger batches mean fewer round trips for commands yielding
+    /// many events. Postgres caps a statement at 65535 parameters, i.e. 8191 events.
+    pub fn with_write_batch_size(mut self, write_batch_size: usize) -> Self {
+        self.write_batch_size = write_batch_size.clamp(1, MAX_WRITE_BATCH_SIZE);
+        self
+    }
+
+    /// Set the type of the payload column of the `events` table, defaults to `jsonb`. Pick
+    /// `json` or `bytea` for write heavy workloads that never query payloads. The type must
+    /// match the column of an existing table, see `Adapter::migrate`.
+    pub fn with_payload_type(mut self, payload_type: PayloadType) -> Self {
+        self.payload_type = payload_type;
+        self
+    }
+
+    /// Whether `Adapter::migrate` creates a GIN index on the payloads,
+    /// disabled by default. The index speeds up queries on payloads at the cost of slower
+    /// writes, it only applies to `jsonb` payloads.
+    pub fn with_payload_index(mut self, payload_index: bool) -> Self {
+        self.payload_index = payload_index;
+        self
+    }
 }
 
 pub struct SslMode(bool);
@@ -96,29 +778,30 @@ impl SslMode {
 }
 
 impl Adapter for PostgresAdapter {
-    async fn read_highest_sequence_number(&self, entity_id: &str) -> Result<Option<u64>, Error> {
+    async fn health(&self) -> Result<Unit, Error> {
         let connection = self
             .pool
             .get()
             .await
             .map_err(Error::ConnectionRetrievalError)?;
 
-        let number = connection
-            .query_opt(
-                "SELECT MAX(seq_nr) FROM events WHERE entity_id = $1",
-                &[&entity_id],
-            )
+        connection
+            .execute("SELECT 1", &[])
             .await
-            .map_err(|e| Error::StorageError(e.to_string()))
-            .map(|row| row.map(|row| row.try_get::<_, i64>("seq_nr").unwrap() as u64))?;
+            .map_err(|e| Error::StorageError(e.to_string()))?;
 
-        Ok(number)
+        Ok(())
     }
 
-    async fn write<T>(&self, batch: Vec<Record<&T>>) -> Result<Unit, Error>
-    where
-        T: Serialize + Send + DeserializeOwned + Sync,
-    {
+    /// Applies the migrations missing from the `schema_migrations` table in a single
+    /// transaction, under an advisory lock so instances starting together do not race.
+    /// Every migration is idempotent, so a database set up with
+    /// `example/resource/MIGRATION.sql` is migrated as is.
+    ///
+    /// The `events` table is created with the payload column of the configured type, and
+    /// the GIN index on the payloads is created whenever it is enabled, see
+    /// `PostgresAdapterBuilder`.
+    async fn migrate(&self) -> Result<Unit, Error> {
         let mut connection = self
             .pool
             .get()
@@ -130,22 +813,73 @@ impl Adapter for PostgresAdapter {
             .await
             .map_err(|e| Error::StorageError(e.to_string()))?;
 
-        for record in batch {
-            let payload = serde_json::to_value(record.message()).unwrap();
-            let timestamp = record.timestamp();
-            let entity_id = record.entity_id();
-            let seq_nr = record.seq_nr();
-            let uuid = uuid::Uuid::new_v4();
+        transaction
+            .execute(
+                "SELECT pg_advisory_xact_lock(hashtext('schema_migrations'))",
+                &[],
+            )
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?;
 
-            let stmt = transaction
-                .prepare(
-                    "INSERT INTO events (id, entity_id, seq_nr, timestamp, payload) VALUES ($1, $2, $3, $4, $5)",
+        transaction
+            .execute(
+                "CREATE TABLE IF NOT EXISTS schema_migrations (
+                    version BIGINT PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+                )",
+                &[],
+            )
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?;
+
+        let applied = transaction
+            .query("SELECT version FROM schema_migrations", &[])
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?
+            .iter()
+            .map(|row| row.try_get::<_, i64>("version"))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Error::StorageError(format!("Failed to get version: {}", e)))?;
+
+        for migration in self.migrations() {
+            if applied.contains(&migration.version) {
+                continue;
+            }
+
+            for statement in &migration.statements {
+                transaction
+                    .execute(statement.as_str(), &[])
+                    .await
+                    .map_err(|e| {
+                        Error::StorageError(format!(
+                            "Migration {} ({}) failed: {}",
+                            migration.version, migration.name, e
+                        ))
+                    })?;
+            }
+
+            transaction
+                .execute(
+                    "INSERT INTO schema_migrations (version, name) VALUES ($1, $2)",
+                    &[&migration.version, &migration.name],
                 )
                 .await
                 .map_err(|e| Error::StorageError(e.to_string()))?;
 
+            tracing::info!(
+                "Applied migration {} ({})",
+                migration.version,
+                migration.name
+            );
+        }
+
+        if self.payload_index && self.payload_type == PayloadType::Jsonb {
             transaction
-                .execute(&stmt, &[&uuid, &entity_id, &seq_nr, &timestamp, &payload])
+                .execute(
+                    "CREATE INDEX IF NOT EXISTS events_payload_idx ON events USING GIN (payload)",
+                    &[],
+                )
                 .await
                 .map_err(|e| Error::StorageError(e.to_string()))?;
         }
@@ -158,6 +892,50 @@ impl Adapter for PostgresAdapter {
         Ok(())
     }
 
+    async fn read_highest_sequence_number(&self, entity_id: &str) -> Result<Option<u64>, Error> {
+        let connection = self
+            .pool
+            .get()
+            .await
+            .map_err(Error::ConnectionRetrievalError)?;
+
+        let row = connection
+            .query_one(
+                "SELECT MAX(seq_nr) AS seq_nr FROM events WHERE entity_id = $1",
+                &[&entity_id],
+            )
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?;
+
+        // MAX yields NULL when the entity has no events
+        let number = row
+            .try_get::<_, Option<i64>>("seq_nr")
+            .map_err(|e| Error::StorageError(format!("Failed to get seq_nr: {}", e)))?;
+
+        Ok(number.map(|number| number as u64))
+    }
+
+    async fn write<T>(&self, batch: Vec<Record<&T>>) -> Result<Unit, Error>
+    where
+        T: Serialize + Send + DeserializeOwned + Sync,
+    {
+        self.insert(batch, false, Vec::new(), None, None).await
+    }
+
+    /// Conditional writes take a transaction scoped advisory lock on the entity id, so
+    /// the check of the highest sequence number and the write are atomic.
+    async fn write_if_version<T>(
+        &self,
+        batch: Vec<Record<&T>>,
+        expected_highest: u64,
+    ) -> Result<Unit, Error>
+    where
+        T: Serialize + Send + DeserializeOwned + Sync,
+    {
+        self.insert(batch, false, Vec::new(), None, Some(expected_highest))
+            .await
+    }
+
     async fn replay<T>(
         &self,
         entity_id: &str,
@@ -165,6 +943,55 @@ impl Adapter for PostgresAdapter {
         to_sequence_number: u64,
         max: u64,
     ) -> Result<BoxStream<'static, Record<T>>, Error>
+    where
+        T: Send + DeserializeOwned + Debug + 'static + Serialize + Sync,
+    {
+        self.try_replay(entity_id, from_sequence_number, to_sequence_number, max)
+            .await
+            .map(best_effort)
+    }
+
+    async fn try_replay<T>(
+        &self,
+        entity_id: &str,
+        from_sequence_number: u64,
+        to_sequence_number: u64,
+        max: u64,
+    ) -> Result<BoxStream<'static, Result<Record<T>, Error>>, Error>
+    where
+        T: Send + DeserializeOwned + Debug + 'static + Serialize + Sync,
+    {
+        // Sequence numbers and limits beyond the range of BIGINT mean no bound
+        let bound = |value: u64| i64::try_from(value).unwrap_or(i64::MAX);
+        let mut cursor = ReplayCursor {
+            pool: self.pool.clone(),
+            payload_type: self.payload_type,
+            entity_id: entity_id.to_string(),
+            next_seq_nr: bound(from_sequence_number),
+            to_seq_nr: bound(to_sequence_number),
+            remaining: bound(max),
+            rows: None,
+            attempts: 0,
+            done: false,
+        };
+
+        // The first query fails the call, rather than the stream
+        cursor.open().await.map_err(|e| match e {
+            ReplayFailure::Pool(e) => Error::ConnectionRetrievalError(e),
+            ReplayFailure::Query(e) => Error::StorageError(e.to_string()),
+        })?;
+
+        Ok(futures::stream::unfold(cursor, |mut cursor| async move {
+            let record = cursor.next().await?;
+            Some((record, cursor))
+        })
+        .boxed())
+    }
+
+    async fn replay_many<T>(
+        &self,
+        entity_ids: &[String],
+    ) -> Result<BoxStream<'static, Record<T>>, Error>
     where
         T: Send + DeserializeOwned + Debug + 'static + Serialize + Sync,
     {
@@ -174,53 +1001,546 @@ impl Adapter for PostgresAdapter {
             .await
             .map_err(Error::ConnectionRetrievalError)?;
 
-        let from_sequence_number = from_sequence_number.to_string();
-        let to_sequence_number = to_sequence_number.to_string();
-        let max = max.to_string();
+        let rows = connection
+            .query(
+                "SELECT entity_id, seq_nr, timestamp, payload, correlation_id, source, hash FROM events WHERE entity_id = ANY($1) ORDER BY entity_id ASC, seq_nr ASC",
+                &[&entity_ids],
+            )
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?;
+
+        let records = rows
+            .iter()
+            .map(|row| record(row, self.payload_type))
+            .collect::<Result<Vec<_>, Error>>()?;
 
-        let row_stream = connection
-            .query_raw(
-                "SELECT payload FROM events WHERE entity_id = $1 AND seq_nr >= $2 AND seq_nr <= $3 ORDER BY seq_nr ASC LIMIT $4",
-                &[&entity_id, &from_sequence_number.as_str(), &to_sequence_number.as_str(), &max.as_str()],
+        Ok(futures::stream::iter(records).boxed())
+    }
+
+    /// Streams events in the order of the `position` column, which is expected to be a
+    /// `BIGSERIAL` on the `events` table and serves as the global offset.
+    async fn stream_all<T>(
+        &self,
+        from_global_offset: u64,
+        max: u64,
+    ) -> Result<BoxStream<'static, (u64, Record<T>)>, Error>
+    where
+        T: Send + DeserializeOwned + Debug + 'static + Serialize + Sync,
+    {
+        let connection = self
+            .pool
+            .get()
+            .await
+            .map_err(Error::ConnectionRetrievalError)?;
+
+        let from_global_offset = from_global_offset as i64;
+        let max = max as i64;
+
+        let rows = connection
+            .query(
+                "SELECT position, entity_id, seq_nr, timestamp, payload, correlation_id, source, hash FROM events WHERE position >= $1 ORDER BY position ASC LIMIT $2",
+                &[&from_global_offset, &max],
             )
             .await
             .map_err(|e| Error::StorageError(e.to_string()))?;
 
-        let stream = row_stream
-            .map(|row| match row {
-                Ok(row) => {
-                    let entity_id = row
-                        .try_get::<_, String>("entity_id")
-                        .map_err(|e| Error::StorageError(e.to_string()))?;
-                    let payload = row.try_get::<_, Value>("payload").map_err(|e| {
-                        Error::StorageError(format!("Failed to get payload: {}", e))
-                    })?;
-                    let payload = serde_json::from_value::<T>(payload).map_err(|e| {
-                        Error::StorageError(format!("Failed to deserialize: {}", e))
-                    })?;
-                    let timestamp = row.try_get::<_, DateTime<Utc>>("timestamp").map_err(|e| {
-                        Error::StorageError(format!("Failed to get timestamp: {}", e))
-                    })?;
-                    let seq_nr = row
-                        .try_get::<_, i64>("seq_nr")
-                        .map_err(|e| Error::StorageError(format!("Failed to get seq_nr: {}", e)))?
-                        as u64;
-
-                    Ok(Record::event(
-                        entity_id.to_string(),
-                        seq_nr as i64,
-                        payload,
-                        timestamp,
-                    ))
-                }
-                Err(e) => {
-                    println!("Error: {}", e);
-                    Err(Error::StorageError(e.to_string()))
-                }
+        let records = rows
+            .iter()
+            .map(|row| positioned_record(row, self.payload_type))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(futures::stream::iter(records).boxed())
+    }
+
+    /// Streams the events of a category in the order of the `position` column. The
+    /// `category` column is expected to be generated from the entity id, see
+    /// `example/resource/MIGRATION.sql`.
+    async fn replay_category<T>(
+        &self,
+        category: &str,
+        from_global_offset: u64,
+        max: u64,
+    ) -> Result<BoxStream<'static, (u64, Record<T>)>, Error>
+    where
+        T: Send + DeserializeOwned + Debug + 'static + Serialize + Sync,
+    {
+        let connection = self
+            .pool
+            .get()
+            .await
+            .map_err(Error::ConnectionRetrievalError)?;
+
+        let from_global_offset = from_global_offset as i64;
+        let max = max as i64;
+
+        let rows = connection
+            .query(
+                "SELECT position, entity_id, seq_nr, timestamp, payload, correlation_id, source, hash FROM events WHERE category = $1 AND position >= $2 ORDER BY position ASC LIMIT $3",
+                &[&category, &from_global_offset, &max],
+            )
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?;
+
+        let records = rows
+            .iter()
+            .map(|row| positioned_record(row, self.payload_type))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(futures::stream::iter(records).boxed())
+    }
+
+    async fn write_with_outbox<T>(&self, batch: Vec<Record<&T>>) -> Result<Unit, Error>
+    where
+        T: Serialize + Send + DeserializeOwned + Sync,
+    {
+        self.insert(batch, true, Vec::new(), None, None).await
+    }
+
+    async fn write_with_effects<T>(
+        &self,
+        batch: Vec<Record<&T>>,
+        effects: Vec<Effect>,
+        with_outbox: bool,
+    ) -> Result<Unit, Error>
+    where
+        T: Serialize + Send + DeserializeOwned + Sync,
+    {
+        self.insert(batch, with_outbox, effects, None, None).await
+    }
+
+    /// Relays the unsent rows of the `outbox` table. Rows are locked with
+    /// `FOR UPDATE SKIP LOCKED` for the duration of the relay, so several relays can run
+    /// concurrently without publishing the same rows.
+    async fn relay_outbox<F, Fut>(&self, max: u64, publish: F) -> Result<usize, Error>
+    where
+        F: FnOnce(Vec<OutboxEntry>) -> Fut + Send,
+        Fut: Future<Output = Vec<u64>> + Send,
+    {
+        let mut connection = self
+            .pool
+            .get()
+            .await
+            .map_err(Error::ConnectionRetrievalError)?;
+
+        let transaction = connection
+            .transaction()
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?;
+
+        let max = max as i64;
+
+        let rows = transaction
+            .query(
+                "SELECT id, entity_id, timestamp, payload FROM outbox WHERE sent = FALSE ORDER BY id ASC LIMIT $1 FOR UPDATE SKIP LOCKED",
+                &[&max],
+            )
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?;
+
+        let entries = rows
+            .into_iter()
+            .map(|row| {
+                let id = row
+                    .try_get::<_, i64>("id")
+                    .map_err(|e| Error::StorageError(format!("Failed to get id: {}", e)))?;
+                let entity_id = row
+                    .try_get::<_, String>("entity_id")
+                    .map_err(|e| Error::StorageError(e.to_string()))?;
+                let timestamp = row
+                    .try_get::<_, DateTime<Utc>>("timestamp")
+                    .map_err(|e| Error::StorageError(format!("Failed to get timestamp: {}", e)))?;
+                let payload = row
+                    .try_get::<_, Vec<u8>>("payload")
+                    .map_err(|e| Error::StorageError(format!("Failed to get payload: {}", e)))?;
+
+                Ok(OutboxEntry::new(id as u64, entity_id, timestamp, payload))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        if entries.is_empty() {
+            return Ok(0);
+        }
+
+        let sent = publish(entries)
+            .await
+            .into_iter()
+            .map(|id| id as i64)
+            .collect::<Vec<_>>();
+
+        let updated = transaction
+            .execute("UPDATE outbox SET sent = TRUE WHERE id = ANY($1)", &[&sent])
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?;
+
+        transaction
+            .commit()
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?;
+
+        Ok(updated as usize)
+    }
+
+    /// Runs the pending rows of the `effects` table. Rows are locked with
+    /// `FOR UPDATE SKIP LOCKED` while they run, so several runners can run concurrently
+    /// without running the same effects.
+    async fn run_effects<F, Fut>(&self, max: u64, run: F) -> Result<usize, Error>
+    where
+        F: FnOnce(Vec<PendingEffect>) -> Fut + Send,
+        Fut: Future<Output = Vec<u64>> + Send,
+    {
+        let mut connection = self
+            .pool
+            .get()
+            .await
+            .map_err(Error::ConnectionRetrievalError)?;
+
+        let transaction = connection
+            .transaction()
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?;
+
+        let max = max as i64;
+
+        let rows = transaction
+            .query(
+                "SELECT id, entity_id, seq_nr, name, payload, attempts FROM effects WHERE done = FALSE ORDER BY id ASC LIMIT $1 FOR UPDATE SKIP LOCKED",
+                &[&max],
+            )
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?;
+
+        let effects = rows
+            .into_iter()
+            .map(|row| {
+                let id = row
+                    .try_get::<_, i64>("id")
+                    .map_err(|e| Error::StorageError(format!("Failed to get id: {}", e)))?;
+                let entity_id = row
+                    .try_get::<_, String>("entity_id")
+                    .map_err(|e| Error::StorageError(e.to_string()))?;
+                let seq_nr = row
+                    .try_get::<_, i64>("seq_nr")
+                    .map_err(|e| Error::StorageError(format!("Failed to get seq_nr: {}", e)))?;
+                let name = row
+                    .try_get::<_, String>("name")
+                    .map_err(|e| Error::StorageError(format!("Failed to get name: {}", e)))?;
+                let payload = row
+                    .try_get::<_, Value>("payload")
+                    .map_err(|e| Error::StorageError(format!("Failed to get payload: {}", e)))?;
+                let attempts = row
+                    .try_get::<_, i32>("attempts")
+                    .map_err(|e| Error::StorageError(format!("Failed to get attempts: {}", e)))?;
+
+                Ok(PendingEffect::new(
+                    id as u64,
+                    entity_id,
+                    seq_nr,
+                    Effect::new(name, &payload)?,
+                    attempts as u32,
+                ))
             })
-            .filter_map(|row| async move { row.ok() })
-            .boxed();
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        if effects.is_empty() {
+            return Ok(0);
+        }
+
+        let ids = effects
+            .iter()
+            .map(|effect| effect.id() as i64)
+            .collect::<Vec<_>>();
+        let done = run(effects)
+            .await
+            .into_iter()
+            .map(|id| id as i64)
+            .collect::<Vec<_>>();
+        let failed = ids
+            .into_iter()
+            .filter(|id| !done.contains(id))
+            .collect::<Vec<_>>();
+
+        let updated = transaction
+            .execute(
+                "UPDATE effects SET done = TRUE WHERE id = ANY($1)",
+                &[&done],
+            )
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?;
+        transaction
+            .execute(
+                "UPDATE effects SET attempts = attempts + 1 WHERE id = ANY($1)",
+                &[&failed],
+            )
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?;
+
+        transaction
+            .commit()
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?;
+
+        Ok(updated as usize)
+    }
+
+    /// Snapshots are stored in the `snapshots` table, keyed by entity id and sequence
+    /// number, with the state serialized as JSON in the `payload` column.
+    async fn write_snapshot<S>(
+        &self,
+        entity_id: &str,
+        seq_nr: i64,
+        state_version: u32,
+        state: &S,
+        deleted: bool,
+    ) -> Result<Unit, Error>
+    where
+        S: Serialize + Send + Sync,
+    {
+        let connection = self
+            .pool
+            .get()
+            .await
+            .map_err(Error::ConnectionRetrievalError)?;
+
+        let payload = serde_json::to_value(state)
+            .map_err(|e| Error::StorageError(format!("Failed to serialize: {}", e)))?;
+        let timestamp = Utc::now();
+        let state_version = state_version as i64;
+
+        connection
+            .execute(
+                "INSERT INTO snapshots (entity_id, seq_nr, timestamp, state_version, payload, deleted) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (entity_id, seq_nr) DO UPDATE SET timestamp = EXCLUDED.timestamp, state_version = EXCLUDED.state_version, payload = EXCLUDED.payload, deleted = EXCLUDED.deleted",
+                &[&entity_id, &seq_nr, &timestamp, &state_version, &payload, &deleted],
+            )
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn read_latest_snapshot(&self, entity_id: &str) -> Result<Option<Snapshot>, Error> {
+        let connection = self
+            .pool
+            .get()
+            .await
+            .map_err(Error::ConnectionRetrievalError)?;
+
+        let row = connection
+            .query_opt(
+                "SELECT seq_nr, state_version, payload, deleted FROM snapshots WHERE entity_id = $1 ORDER BY seq_nr DESC LIMIT 1",
+                &[&entity_id],
+            )
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?;
+
+        row.map(|row| {
+            let seq_nr = row
+                .try_get::<_, i64>("seq_nr")
+                .map_err(|e| Error::StorageError(format!("Failed to get seq_nr: {}", e)))?;
+            let state_version = row
+                .try_get::<_, i64>("state_version")
+                .map_err(|e| Error::StorageError(format!("Failed to get state_version: {}", e)))?;
+            let payload = row
+                .try_get::<_, Value>("payload")
+                .map_err(|e| Error::StorageError(format!("Failed to get payload: {}", e)))?;
+            let deleted = row
+                .try_get::<_, bool>("deleted")
+                .map_err(|e| Error::StorageError(format!("Failed to get deleted: {}", e)))?;
+
+            Ok(Snapshot::new(seq_nr, state_version as u32, payload).with_deleted(deleted))
+        })
+        .transpose()
+    }
+
+    /// The key is written in the same transaction as the events, expired keys of the entity
+    /// are deleted on every write of a key.
+    async fn write_with_idempotency_key<T>(
+        &self,
+        batch: Vec<Record<&T>>,
+        idempotency_key: IdempotencyKey,
+        effects: Vec<Effect>,
+        with_outbox: bool,
+    ) -> Result<Unit, Error>
+    where
+        T: Serialize + Send + DeserializeOwned + Sync,
+    {
+        self.insert(batch, with_outbox, effects, Some(idempotency_key), None)
+            .await
+    }
+
+    async fn read_idempotency_key(
+        &self,
+        entity_id: &str,
+        key: &str,
+    ) -> Result<Option<(i64, i64)>, Error> {
+        let connection = self
+            .pool
+            .get()
+            .await
+            .map_err(Error::ConnectionRetrievalError)?;
+
+        let row = connection
+            .query_opt(
+                "SELECT first_seq_nr, last_seq_nr FROM idempotency_keys WHERE entity_id = $1 AND key = $2 AND expires_at > now()",
+                &[&entity_id, &key],
+            )
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?;
+
+        row.map(|row| {
+            let first = row
+                .try_get::<_, i64>("first_seq_nr")
+                .map_err(|e| Error::StorageError(format!("Failed to get first_seq_nr: {}", e)))?;
+            let last = row
+                .try_get::<_, i64>("last_seq_nr")
+                .map_err(|e| Error::StorageError(format!("Failed to get last_seq_nr: {}", e)))?;
+
+            Ok((first, last))
+        })
+        .transpose()
+    }
+}
+
+/// Checkpoints are stored in the `checkpoints` table, one row per projection name.
+impl CheckpointStore for PostgresAdapter {
+    async fn save_checkpoint(&self, projection: &str, position: u64) -> Result<Unit, Error> {
+        let connection = self
+            .pool
+            .get()
+            .await
+            .map_err(Error::ConnectionRetrievalError)?;
+
+        let position = position as i64;
+
+        connection
+            .execute(
+                "INSERT INTO checkpoints (projection, position) VALUES ($1, $2) ON CONFLICT (projection) DO UPDATE SET position = EXCLUDED.position",
+                &[&projection, &position],
+            )
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn load_checkpoint(&self, projection: &str) -> Result<Option<u64>, Error> {
+        let connection = self
+            .pool
+            .get()
+            .await
+            .map_err(Error::ConnectionRetrievalError)?;
+
+        let row = connection
+            .query_opt(
+                "SELECT position FROM checkpoints WHERE projection = $1",
+                &[&projection],
+            )
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))?;
+
+        row.map(|row| {
+            row.try_get::<_, i64>("position")
+                .map(|position| position as u64)
+                .map_err(|e| Error::StorageError(format!("Failed to get position: {}", e)))
+        })
+        .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    const EVENTS: i64 = 20_000;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Counted(i64);
+
+    // The tests need a database, so they only run when `TEST_POSTGRES_HOST` is set, e.g.
+    // TEST_POSTGRES_HOST=localhost cargo test -p mnemosyne --features postgres
+    async fn connect() -> Option<PostgresAdapter> {
+        let host = std::env::var("TEST_POSTGRES_HOST").ok()?;
+        let port = std::env::var("TEST_POSTGRES_PORT")
+            .ok()
+            .and_then(|port| port.parse().ok())
+            .unwrap_or(5432);
+
+        let store = PostgresAdapter::connect(PostgresAdapterBuilder::new(
+            &host,
+            "postgres",
+            port,
+            "postgres",
+            "mnemosyne",
+            10,
+            SslMode::new(false),
+        ))
+        .await
+        .expect("a database");
+        store.migrate().await.expect("an up to date schema");
+        Some(store)
+    }
+
+    #[tokio::test]
+    async fn replays_resume_once_their_connection_is_killed() {
+        let Some(store) = connect().await else {
+            return;
+        };
+        let entity_id = format!("counter:{}", uuid::Uuid::new_v4());
+        let events = (1..=EVENTS).map(Counted).collect::<Vec<_>>();
+        let records = events
+            .iter()
+            .map(|event| Record::event(entity_id.clone(), event.0, event, Utc::now()))
+            .collect();
+        store.write(records).await.unwrap();
+
+        let mut replay = store
+            .try_replay::<Counted>(&entity_id, 0, u64::MAX, u64::MAX)
+            .await
+            .unwrap();
+        let mut seq_nrs = Vec::new();
+        while seq_nrs.len() < 100 {
+            seq_nrs.push(replay.next().await.unwrap().unwrap().seq_nr());
+        }
+
+        // Kill the connection streaming the replay partway through it
+        let killed = store
+            .pool()
+            .get()
+            .await
+            .unwrap()
+            .query_one(
+                "SELECT count(pg_terminate_backend(pid)) FROM pg_stat_activity WHERE query LIKE 'SELECT entity_id, seq_nr, payload%' AND pid <> pg_backend_pid()",
+                &[],
+            )
+            .await
+            .unwrap()
+            .get::<_, i64>(0);
+        while let Some(record) = replay.next().await {
+            seq_nrs.push(record.unwrap().seq_nr());
+        }
+
+        assert_eq!(killed, 1);
+        assert_eq!(seq_nrs, (1..=EVENTS).collect::<Vec<_>>());
+    }
+    #[tokio::test]
+    async fn snapshots_keep_the_deletion_of_their_entity() {
+        let Some(store) = connect().await else {
+            return;
+        };
+        let entity_id = format!("counter:{}", uuid::Uuid::new_v4());
+
+        store
+            .write_snapshot(&entity_id, 3, 0, &Counted(3), true)
+            .await
+            .unwrap();
+        let deleted = store.read_latest_snapshot(&entity_id).await.unwrap();
+        store
+            .write_snapshot(&entity_id, 3, 0, &Counted(3), false)
+            .await
+            .unwrap();
+        let restored = store.read_latest_snapshot(&entity_id).await.unwrap();
 
-        Ok(stream)
+        assert!(deleted.is_some_and(|snapshot| snapshot.is_deleted()));
+        assert!(restored.is_some_and(|snapshot| !snapshot.is_deleted()));
     }
 }
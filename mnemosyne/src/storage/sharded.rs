@@ -0,0 +1,487 @@
+use super::{
+    Adapter, CheckpointStore, Effect, IdempotencyKey, OutboxEntry, PendingEffect, Record, Snapshot,
+};
+use crate::{domain::Error, Unit};
+use futures::{stream::BoxStream, Future, StreamExt};
+use serde::{
+    de::{DeserializeOwned, DeserializeSeed},
+    Deserialize, Serialize,
+};
+use std::fmt::Debug;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// An adapter spreading entities over several inner adapters, the shards, e.g. one per
+/// Postgres database.
+///
+/// Every entity lives on the shard at `hash(entity_id) % N`, the hash is stable across
+/// processes and releases. Operations on a single entity are routed to its shard, so its
+/// events stay in order. Operations across entities fan out to every shard:
+///
+/// - There is no global offset across shards, as shards grow independently, so
+///   `stream_all` and `replay_category` fail. `stream_all_shards` and
+///   `replay_category_shards` stream every shard from a position of its own instead, and a
+///   projection runs on every shard on its own, see `shards`.
+/// - `relay_outbox` relays the outbox of one shard per call, in turn, and so does
+///   `run_effects` run the effects of one shard per call.
+///
+/// There are no transactions across shards: a batch spanning entities of several shards
+/// is rejected. The number of shards must not change once events are written, as that
+/// moves entities to other shards.
+#[derive(Debug, Clone)]
+pub struct ShardedAdapter<A> {
+    shards: Vec<A>,
+    // The shard whose outbox is relayed next
+    relay_cursor: Arc<AtomicUsize>,
+    // The shard whose effects are run next
+    effect_cursor: Arc<AtomicUsize>,
+}
+
+impl<A> ShardedAdapter<A> {
+    /// Create an adapter over the given shards, failing if there are none.
+    pub fn new(shards: Vec<A>) -> Result<Self, Error> {
+        if shards.is_empty() {
+            return Err(Error::InvalidConfiguration(
+                "A sharded adapter needs at least one shard".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            shards,
+            relay_cursor: Arc::new(AtomicUsize::new(0)),
+            effect_cursor: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    pub fn shards(&self) -> &[A] {
+        &self.shards
+    }
+
+    /// The index of the shard the entity lives on.
+    pub fn shard_of(&self, entity_id: &str) -> usize {
+        (fnv1a(entity_id.as_bytes()) % self.shards.len() as u64) as usize
+    }
+
+    /// The shard the entity lives on.
+    pub fn shard(&self, entity_id: &str) -> &A {
+        &self.shards[self.shard_of(entity_id)]
+    }
+
+    // The shard every record of the batch lives on
+    fn shard_of_batch<T>(&self, batch: &[Record<&T>]) -> Result<&A, Error> {
+        let mut shards = batch.iter().map(|record| self.shard_of(record.entity_id()));
+        let shard = shards.next().unwrap_or_default();
+
+        if shards.any(|other| other != shard) {
+            return Err(Error::StorageError(
+                "A batch cannot span entities of several shards".to_string(),
+            ));
+        }
+
+        Ok(&self.shards[shard])
+    }
+
+    // Check there is a position for every shard
+    fn check_positions(&self, from: &[u64]) -> Result<Unit, Error> {
+        if from.len() != self.shards.len() {
+            return Err(Error::StorageError(format!(
+                "Expected a position for each of the {} shards, got {}",
+                self.shards.len(),
+                from.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl<A> ShardedAdapter<A>
+where
+    A: Adapter + Sync,
+{
+    /// Stream every message of every shard, see `Adapter::stream_all`. `from` holds the
+    /// offset to start from in every shard, in the order of `shards`.
+    ///
+    /// Each message is paired with the index of its shard and its offset in the shard, so the
+    /// position of every shard can be saved and resumed from on its own. The shards are
+    /// interleaved as they yield, each yields up to `max` messages.
+    pub async fn stream_all_shards<T>(
+        &self,
+        from: &[u64],
+        max: u64,
+    ) -> Result<BoxStream<'static, (usize, u64, Record<T>)>, Error>
+    where
+        T: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    {
+        self.check_positions(from)?;
+
+        let mut streams = Vec::with_capacity(self.shards.len());
+        for (shard, from) in self.shards.iter().zip(from) {
+            streams.push(shard.stream_all::<T>(*from, max).await?);
+        }
+        Ok(by_shard(streams))
+    }
+
+    /// Stream every message of a category from every shard, like `stream_all_shards`, see
+    /// `Adapter::replay_category`.
+    pub async fn replay_category_shards<T>(
+        &self,
+        category: &str,
+        from: &[u64],
+        max: u64,
+    ) -> Result<BoxStream<'static, (usize, u64, Record<T>)>, Error>
+    where
+        T: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    {
+        self.check_positions(from)?;
+
+        let mut streams = Vec::with_capacity(self.shards.len());
+        for (shard, from) in self.shards.iter().zip(from) {
+            streams.push(shard.replay_category::<T>(category, *from, max).await?);
+        }
+        Ok(by_shard(streams))
+    }
+}
+
+impl<A> Adapter for ShardedAdapter<A>
+where
+    A: Adapter + Sync,
+{
+    async fn health(&self) -> Result<Unit, Error> {
+        for shard in &self.shards {
+            shard.health().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn migrate(&self) -> Result<Unit, Error> {
+        for shard in &self.shards {
+            shard.migrate().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn read_highest_sequence_number(&self, entity_id: &str) -> Result<Option<u64>, Error> {
+        self.shard(entity_id)
+            .read_highest_sequence_number(entity_id)
+            .await
+    }
+
+    async fn write<T>(&self, batch: Vec<Record<&T>>) -> Result<Unit, Error>
+    where
+        T: Serialize + Send + Sync,
+        T: for<'de> Deserialize<'de>,
+    {
+        self.shard_of_batch(&batch)?.write(batch).await
+    }
+
+    async fn write_if_version<T>(
+        &self,
+        batch: Vec<Record<&T>>,
+        expected_highest: u64,
+    ) -> Result<Unit, Error>
+    where
+        T: Serialize + Send + Sync,
+        T: for<'de> Deserialize<'de>,
+    {
+        self.shard_of_batch(&batch)?
+            .write_if_version(batch, expected_highest)
+            .await
+    }
+
+    async fn replay<T>(
+        &self,
+        entity_id: &str,
+        from_sequence_number: u64,
+        to_sequence_number: u64,
+        max: u64,
+    ) -> Result<BoxStream<'static, Record<T>>, Error>
+    where
+        T: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    {
+        self.shard(entity_id)
+            .replay(entity_id, from_sequence_number, to_sequence_number, max)
+            .await
+    }
+
+    async fn try_replay<T>(
+        &self,
+        entity_id: &str,
+        from_sequence_number: u64,
+        to_sequence_number: u64,
+        max: u64,
+    ) -> Result<BoxStream<'static, Result<Record<T>, Error>>, Error>
+    where
+        T: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    {
+        self.shard(entity_id)
+            .try_replay(entity_id, from_sequence_number, to_sequence_number, max)
+            .await
+    }
+
+    async fn replay_seeded<S, T>(
+        &self,
+        entity_id: &str,
+        from_sequence_number: u64,
+        to_sequence_number: u64,
+        max: u64,
+        seed: S,
+    ) -> Result<BoxStream<'static, Result<Record<T>, Error>>, Error>
+    where
+        S: for<'de> DeserializeSeed<'de, Value = T> + Clone + Send + 'static,
+        T: Send + 'static,
+    {
+        self.shard(entity_id)
+            .replay_seeded(
+                entity_id,
+                from_sequence_number,
+                to_sequence_number,
+                max,
+                seed,
+            )
+            .await
+    }
+
+    async fn replay_many<T>(
+        &self,
+        entity_ids: &[String],
+    ) -> Result<BoxStream<'static, Record<T>>, Error>
+    where
+        T: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    {
+        let mut per_shard = vec![Vec::new(); self.shards.len()];
+        for entity_id in entity_ids {
+            per_shard[self.shard_of(entity_id)].push(entity_id.clone());
+        }
+
+        let mut streams = Vec::with_capacity(self.shards.len());
+        for (shard, entity_ids) in self.shards.iter().zip(per_shard) {
+            if !entity_ids.is_empty() {
+                streams.push(shard.replay_many::<T>(&entity_ids).await?);
+            }
+        }
+
+        Ok(futures::stream::iter(streams).flatten().boxed())
+    }
+
+    async fn stream_all<T>(
+        &self,
+        _from_global_offset: u64,
+        _max: u64,
+    ) -> Result<BoxStream<'static, (u64, Record<T>)>, Error>
+    where
+        T: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    {
+        Err(no_global_offset())
+    }
+
+    async fn replay_category<T>(
+        &self,
+        _category: &str,
+        _from_global_offset: u64,
+        _max: u64,
+    ) -> Result<BoxStream<'static, (u64, Record<T>)>, Error>
+    where
+        T: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    {
+        Err(no_global_offset())
+    }
+
+    async fn write_with_outbox<T>(&self, batch: Vec<Record<&T>>) -> Result<Unit, Error>
+    where
+        T: Serialize + Send + Sync,
+        T: for<'de> Deserialize<'de>,
+    {
+        self.shard_of_batch(&batch)?.write_with_outbox(batch).await
+    }
+
+    async fn relay_outbox<F, Fut>(&self, max: u64, publish: F) -> Result<usize, Error>
+    where
+        F: FnOnce(Vec<OutboxEntry>) -> Fut + Send,
+        Fut: Future<Output = Vec<u64>> + Send,
+    {
+        let shard = self.relay_cursor.fetch_add(1, Ordering::Relaxed) % self.shards.len();
+        self.shards[shard].relay_outbox(max, publish).await
+    }
+
+    async fn write_with_effects<T>(
+        &self,
+        batch: Vec<Record<&T>>,
+        effects: Vec<Effect>,
+        with_outbox: bool,
+    ) -> Result<Unit, Error>
+    where
+        T: Serialize + Send + Sync,
+        T: for<'de> Deserialize<'de>,
+    {
+        self.shard_of_batch(&batch)?
+            .write_with_effects(batch, effects, with_outbox)
+            .await
+    }
+
+    async fn run_effects<F, Fut>(&self, max: u64, run: F) -> Result<usize, Error>
+    where
+        F: FnOnce(Vec<PendingEffect>) -> Fut + Send,
+        Fut: Future<Output = Vec<u64>> + Send,
+    {
+        let shard = self.effect_cursor.fetch_add(1, Ordering::Relaxed) % self.shards.len();
+        self.shards[shard].run_effects(max, run).await
+    }
+
+    async fn write_snapshot<S>(
+        &self,
+        entity_id: &str,
+        seq_nr: i64,
+        state_version: u32,
+        state: &S,
+        deleted: bool,
+    ) -> Result<Unit, Error>
+    where
+        S: Serialize + Send + Sync,
+    {
+        self.shard(entity_id)
+            .write_snapshot(entity_id, seq_nr, state_version, state, deleted)
+            .await
+    }
+
+    async fn read_latest_snapshot(&self, entity_id: &str) -> Result<Option<Snapshot>, Error> {
+        self.shard(entity_id).read_latest_snapshot(entity_id).await
+    }
+
+    async fn write_with_idempotency_key<T>(
+        &self,
+        batch: Vec<Record<&T>>,
+        idempotency_key: IdempotencyKey,
+        effects: Vec<Effect>,
+        with_outbox: bool,
+    ) -> Result<Unit, Error>
+    where
+        T: Serialize + Send + Sync,
+        T: for<'de> Deserialize<'de>,
+    {
+        self.shard_of_batch(&batch)?
+            .write_with_idempotency_key(batch, idempotency_key, effects, with_outbox)
+            .await
+    }
+
+    async fn read_idempotency_key(
+        &self,
+        entity_id: &str,
+        key: &str,
+    ) -> Result<Option<(i64, i64)>, Error> {
+        self.shard(entity_id)
+            .read_idempotency_key(entity_id, key)
+            .await
+    }
+}
+
+/// Checkpoints are stored on the shard the name of the projection hashes to.
+impl<A> CheckpointStore for ShardedAdapter<A>
+where
+    A: CheckpointStore,
+{
+    async fn save_checkpoint(&self, projection: &str, position: u64) -> Result<Unit, Error> {
+        self.shard(projection)
+            .save_checkpoint(projection, position)
+            .await
+    }
+
+    async fn load_checkpoint(&self, projection: &str) -> Result<Option<u64>, Error> {
+        self.shard(projection).load_checkpoint(projection).await
+    }
+}
+
+fn no_global_offset() -> Error {
+    Error::StorageError(
+        "A sharded adapter has no global offset, stream every shard from a position of its own with `stream_all_shards` or `replay_category_shards`".to_string(),
+    )
+}
+
+/// Interleave the streams of the shards, pairing every message with the index of its shard.
+fn by_shard<T>(
+    streams: Vec<BoxStream<'static, (u64, Record<T>)>>,
+) -> BoxStream<'static, (usize, u64, Record<T>)>
+where
+    T: Send + 'static,
+{
+    futures::stream::select_all(streams.into_iter().enumerate().map(|(shard, stream)| {
+        stream
+            .map(move |(offset, record)| (shard, offset, record))
+            .boxed()
+    }))
+    .boxed()
+}
+
+// 64-bit FNV-1a, stable unlike the hasher of the standard library
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryAdapter;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Placed;
+
+    async fn place(store: &ShardedAdapter<MemoryAdapter>, entity_id: &str, seq_nr: i64) {
+        let record = Record::event(entity_id.to_string(), seq_nr, &Placed, chrono::Utc::now());
+        store.write(vec![record]).await.unwrap();
+    }
+
+    async fn stream(
+        store: &ShardedAdapter<MemoryAdapter>,
+        from: &[u64],
+    ) -> Vec<(usize, u64, String)> {
+        let mut streamed = store
+            .stream_all_shards::<Placed>(from, 100)
+            .await
+            .unwrap()
+            .map(|(shard, offset, record)| (shard, offset, record.entity_id().to_string()))
+            .collect::<Vec<_>>()
+            .await;
+        streamed.sort();
+        streamed
+    }
+
+    #[tokio::test]
+    async fn shards_are_streamed_from_positions_of_their_own() {
+        let store = ShardedAdapter::new(vec![MemoryAdapter::new(), MemoryAdapter::new()]).unwrap();
+        let order_on = |shard| {
+            (0..)
+                .map(|index| format!("order:{}", index))
+                .find(|entity_id| store.shard_of(entity_id) == shard)
+                .unwrap()
+        };
+        let (first, second) = (order_on(0), order_on(1));
+
+        place(&store, &first, 1).await;
+        place(&store, &second, 1).await;
+        place(&store, &second, 2).await;
+
+        assert!(store.stream_all::<Placed>(0, 100).await.is_err());
+        assert!(store.stream_all_shards::<Placed>(&[0], 100).await.is_err());
+
+        let streamed = stream(&store, &[0, 0]).await;
+        assert_eq!(streamed.iter().filter(|(shard, ..)| *shard == 0).count(), 1);
+        assert_eq!(streamed.iter().filter(|(shard, ..)| *shard == 1).count(), 2);
+
+        // Resuming every shard after its last offset yields only what was written since, even
+        // though the second shard is ahead of the first
+        let mut positions = vec![0, 0];
+        for (shard, offset, _) in &streamed {
+            positions[*shard] = positions[*shard].max(offset + 1);
+        }
+        place(&store, &first, 2).await;
+
+        let resumed = stream(&store, &positions).await;
+        assert_eq!(resumed.len(), 1);
+        assert_eq!((resumed[0].0, &resumed[0].2), (0, &first));
+    }
+}
@@ -0,0 +1,115 @@
+use crate::domain::Error;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::fmt::Debug;
+
+/// A snapshot as it is stored, before its state is deserialized.
+///
+/// The state is kept as JSON together with the version of the state it was written at, so
+/// a snapshot written by an older version of the state can still be read and upcast, see
+/// `SnapshotUpcaster`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    seq_nr: i64,
+    state_version: u32,
+    payload: Value,
+    deleted: bool,
+}
+
+impl Snapshot {
+    pub fn new(seq_nr: i64, state_version: u32, payload: Value) -> Self {
+        Self {
+            seq_nr,
+            state_version,
+            payload,
+            deleted: false,
+        }
+    }
+
+    pub fn with_deleted(mut self, deleted: bool) -> Self {
+        self.deleted = deleted;
+        self
+    }
+
+    /// The sequence number of the last event folded into the state.
+    pub fn seq_nr(&self) -> i64 {
+        self.seq_nr
+    }
+
+    /// The version of the state the snapshot was written at.
+    pub fn state_version(&self) -> u32 {
+        self.state_version
+    }
+
+    pub fn payload(&self) -> &Value {
+        &self.payload
+    }
+
+    /// Whether the entity is deleted as of the snapshot, see `Event::deletes`.
+    pub fn is_deleted(&self) -> bool {
+        self.deleted
+    }
+
+    /// Deserialize the state, regardless of the version it was written at.
+    pub fn state<S>(&self) -> Result<S, Error>
+    where
+        S: DeserializeOwned,
+    {
+        serde_json::from_value(self.payload.clone())
+            .map_err(|e| Error::StorageError(format!("Failed to deserialize snapshot: {}", e)))
+    }
+}
+
+/// Versions the snapshots of a state and migrates the ones written at another version.
+///
+/// Snapshots are written at `version`. A snapshot read at another version is handed to
+/// `upcast` as JSON, which either migrates it to the current state, in which case the
+/// migrated snapshot replaces the old one, or discards it, in which case the state is
+/// folded from the events again. Bump the version whenever the serialized form of the
+/// state changes.
+///
+/// # Examples
+/// ```rust,ignore
+/// #[derive(Debug)]
+/// struct AccountUpcaster;
+///
+/// impl SnapshotUpcaster<Account> for AccountUpcaster {
+///     fn version(&self) -> u32 {
+///         2
+///     }
+///
+///     fn upcast(&self, state_version: u32, mut payload: Value) -> Option<Account> {
+///         match state_version {
+///             // Version 1 had no currency, every account was in euros
+///             1 => {
+///                 payload["currency"] = Value::from("EUR");
+///                 serde_json::from_value(payload).ok()
+///             }
+///             _ => None,
+///         }
+///     }
+/// }
+/// ```
+pub trait SnapshotUpcaster<State>: Debug + Send + Sync {
+    /// The version of the state snapshots are written at.
+    fn version(&self) -> u32;
+
+    /// Migrate the state of a snapshot written at `state_version`, or return None to
+    /// discard the snapshot.
+    fn upcast(&self, state_version: u32, payload: Value) -> Option<State>;
+}
+
+/// The default upcaster, writes snapshots at version 0 and discards snapshots written at
+/// any other version.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiscardOutdated;
+
+impl<State> SnapshotUpcaster<State> for DiscardOutdated {
+    fn version(&self) -> u32 {
+        0
+    }
+
+    fn upcast(&self, _state_version: u32, _payload: Value) -> Option<State> {
+        None
+    }
+}
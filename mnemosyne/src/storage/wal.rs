@@ -0,0 +1,565 @@
+use super::{
+    Adapter, CheckpointStore, Effect, IdempotencyKey, OutboxEntry, PendingEffect, Record, Snapshot,
+};
+use crate::{domain::Error, Unit};
+use futures::{lock::Mutex, stream::BoxStream, Future};
+use serde::{
+    de::{DeserializeOwned, DeserializeSeed},
+    Deserialize, Serialize,
+};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+};
+
+/// An adapter appending every batch to a local write-ahead log before writing it to the
+/// inner adapter, so a batch that was not written, e.g. because the process crashed or the
+/// remote store was down, is not lost.
+///
+/// The log is a file of JSON lines. A batch is settled once the inner adapter wrote it or
+/// rejected it for good, a batch failing with a retryable error, see `Error::is_retryable`,
+/// stays in the log. Which batches the log keeps depends on its `WalRetention`.
+///
+/// Opening the adapter writes the batches the log keeps to the inner adapter, in the order
+/// they were logged. Records the inner adapter already holds, i.e. at or below the highest
+/// sequence number of their entity, are left out, so a batch is never written twice.
+///
+/// # Examples
+/// ```rust,ignore
+/// let store = WalAdapter::open::<UserEvent>(
+///     MemoryAdapter::default(),
+///     "events.wal",
+///     WalRetention::All,
+/// )
+/// .await?;
+/// let engine = Engine::start(configuration, store).await?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct WalAdapter<A> {
+    inner: A,
+    log: Arc<Mutex<Log>>,
+}
+
+/// Which batches a `WalAdapter` keeps in its log, and so writes to the inner adapter when it
+/// is opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WalRetention {
+    /// Keep the batches that were not settled, the log is emptied whenever no batch is
+    /// outstanding. For stores that are durable themselves, e.g. a remote database.
+    #[default]
+    Unsettled,
+    /// Keep every batch that was not rejected, the log grows with the events. For stores
+    /// that are not durable, e.g. the `MemoryAdapter`, which is rebuilt from the log.
+    All,
+}
+
+#[derive(Debug)]
+struct Log {
+    file: File,
+    path: PathBuf,
+    retention: WalRetention,
+    next_id: u64,
+    // Batches appended but not settled yet
+    outstanding: usize,
+}
+
+// Whether a batch is written with the outbox, its effects, its idempotency key and its records
+type Batch<T> = (bool, Vec<Effect>, Option<IdempotencyKey>, Vec<Record<T>>);
+
+/// A line of the log.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Entry<R> {
+    Batch {
+        id: u64,
+        outbox: bool,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        effects: Vec<Effect>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        idempotency_key: Option<IdempotencyKey>,
+        records: R,
+    },
+    Settled {
+        id: u64,
+        written: bool,
+    },
+}
+
+impl<A> WalAdapter<A>
+where
+    A: Adapter,
+{
+    /// Open the log at the given path, creating it if needed, and write the batches it keeps
+    /// to the inner adapter. `T` is the event type the batches hold.
+    pub async fn open<T>(
+        inner: A,
+        path: impl AsRef<Path>,
+        retention: WalRetention,
+    ) -> Result<Self, Error>
+    where
+        T: Serialize + DeserializeOwned + Send + Sync,
+    {
+        let path = path.as_ref().to_path_buf();
+        let contents = match tokio::fs::read(&path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(io_error(&path, e)),
+        };
+
+        let (batches, next_id) = kept::<T>(&path, &contents, retention);
+        for (outbox, effects, idempotency_key, records) in batches {
+            recover(&inner, outbox, effects, idempotency_key, records).await?;
+        }
+
+        // Everything the log held is in the inner adapter now, start over with an empty log
+        // unless the log is the only durable copy of the batches
+        if retention == WalRetention::Unsettled {
+            File::create(&path).await.map_err(|e| io_error(&path, e))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .map_err(|e| io_error(&path, e))?;
+
+        Ok(Self {
+            inner,
+            log: Arc::new(Mutex::new(Log {
+                file,
+                path,
+                retention,
+                next_id,
+                outstanding: 0,
+            })),
+        })
+    }
+}
+
+impl<A> WalAdapter<A> {
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    // Append a batch to the log and flush it to disk, returning its id
+    async fn append<T>(
+        &self,
+        outbox: bool,
+        effects: &[Effect],
+        idempotency_key: Option<&IdempotencyKey>,
+        batch: &[Record<&T>],
+    ) -> Result<u64, Error>
+    where
+        T: Serialize,
+    {
+        let mut log = self.log.lock().await;
+        let id = log.next_id;
+        let mut line = serde_json::to_vec(&Entry::Batch {
+            id,
+            outbox,
+            effects: effects.to_vec(),
+            idempotency_key: idempotency_key.cloned(),
+            records: batch,
+        })
+        .map_err(|e| Error::StorageError(format!("Could not serialize the batch: {}", e)))?;
+        line.push(b'\n');
+
+        let path = log.path.clone();
+        log.file
+            .write_all(&line)
+            .await
+            .map_err(|e| io_error(&path, e))?;
+        log.file.sync_data().await.map_err(|e| io_error(&path, e))?;
+        log.next_id += 1;
+        log.outstanding += 1;
+
+        Ok(id)
+    }
+
+    // Settle a batch once the inner adapter is done with it, unless it failed with an error
+    // that may go away, in which case it stays in the log to be recovered
+    async fn settle(&self, id: u64, result: &Result<Unit, Error>) {
+        if result.as_ref().is_err_and(Error::is_retryable) {
+            return;
+        }
+
+        let mut log = self.log.lock().await;
+        log.outstanding = log.outstanding.saturating_sub(1);
+
+        // A marker lost in a crash only makes the batch be recovered, which writes nothing
+        // the inner adapter already holds, so it is not flushed to disk
+        let settled = match (log.retention, log.outstanding) {
+            (WalRetention::Unsettled, 0) => log.file.set_len(0).await,
+            _ => {
+                let written = result.is_ok();
+                let mut line = serde_json::to_vec(&Entry::<Unit>::Settled { id, written })
+                    .expect("A settled marker always serializes");
+                line.push(b'\n');
+                log.file.write_all(&line).await
+            }
+        };
+
+        if let Err(e) = settled {
+            tracing::warn!(
+                "Could not settle batch {} in the write-ahead log {}: {}",
+                id,
+                log.path.display(),
+                e
+            );
+        }
+    }
+}
+
+impl<A> Adapter for WalAdapter<A>
+where
+    A: Adapter + Sync,
+{
+    async fn health(&self) -> Result<Unit, Error> {
+        self.inner.health().await
+    }
+
+    async fn migrate(&self) -> Result<Unit, Error> {
+        self.inner.migrate().await
+    }
+
+    async fn read_highest_sequence_number(&self, entity_id: &str) -> Result<Option<u64>, Error> {
+        self.inner.read_highest_sequence_number(entity_id).await
+    }
+
+    async fn write<T>(&self, batch: Vec<Record<&T>>) -> Result<Unit, Error>
+    where
+        T: Serialize + Send + Sync,
+        T: for<'de> Deserialize<'de>,
+    {
+        let id = self.append(false, &[], None, &batch).await?;
+        let result = self.inner.write(batch).await;
+        self.settle(id, &result).await;
+
+        result
+    }
+
+    async fn write_if_version<T>(
+        &self,
+        batch: Vec<Record<&T>>,
+        expected_highest: u64,
+    ) -> Result<Unit, Error>
+    where
+        T: Serialize + Send + Sync,
+        T: for<'de> Deserialize<'de>,
+    {
+        let id = self.append(false, &[], None, &batch).await?;
+        let result = self.inner.write_if_version(batch, expected_highest).await;
+        self.settle(id, &result).await;
+
+        result
+    }
+
+    async fn replay<T>(
+        &self,
+        entity_id: &str,
+        from_sequence_number: u64,
+        to_sequence_number: u64,
+        max: u64,
+    ) -> Result<BoxStream<'static, Record<T>>, Error>
+    where
+        T: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    {
+        self.inner
+            .replay(entity_id, from_sequence_number, to_sequence_number, max)
+            .await
+    }
+
+    async fn try_replay<T>(
+        &self,
+        entity_id: &str,
+        from_sequence_number: u64,
+        to_sequence_number: u64,
+        max: u64,
+    ) -> Result<BoxStream<'static, Result<Record<T>, Error>>, Error>
+    where
+        T: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    {
+        self.inner
+            .try_replay(entity_id, from_sequence_number, to_sequence_number, max)
+            .await
+    }
+
+    async fn replay_seeded<S, T>(
+        &self,
+        entity_id: &str,
+        from_sequence_number: u64,
+        to_sequence_number: u64,
+        max: u64,
+        seed: S,
+    ) -> Result<BoxStream<'static, Result<Record<T>, Error>>, Error>
+    where
+        S: for<'de> DeserializeSeed<'de, Value = T> + Clone + Send + 'static,
+        T: Send + 'static,
+    {
+        self.inner
+            .replay_seeded(
+                entity_id,
+                from_sequence_number,
+                to_sequence_number,
+                max,
+                seed,
+            )
+            .await
+    }
+
+    async fn replay_many<T>(
+        &self,
+        entity_ids: &[String],
+    ) -> Result<BoxStream<'static, Record<T>>, Error>
+    where
+        T: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    {
+        self.inner.replay_many(entity_ids).await
+    }
+
+    async fn stream_all<T>(
+        &self,
+        from_global_offset: u64,
+        max: u64,
+    ) -> Result<BoxStream<'static, (u64, Record<T>)>, Error>
+    where
+        T: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    {
+        self.inner.stream_all(from_global_offset, max).await
+    }
+
+    async fn replay_category<T>(
+        &self,
+        category: &str,
+        from_global_offset: u64,
+        max: u64,
+    ) -> Result<BoxStream<'static, (u64, Record<T>)>, Error>
+    where
+        T: DeserializeOwned + Send + Debug + 'static + Serialize + Sync,
+    {
+        self.inner
+            .replay_category(category, from_global_offset, max)
+            .await
+    }
+
+    async fn write_with_outbox<T>(&self, batch: Vec<Record<&T>>) -> Result<Unit, Error>
+    where
+        T: Serialize + Send + Sync,
+        T: for<'de> Deserialize<'de>,
+    {
+        let id = self.append(true, &[], None, &batch).await?;
+        let result = self.inner.write_with_outbox(batch).await;
+        self.settle(id, &result).await;
+
+        result
+    }
+
+    async fn relay_outbox<F, Fut>(&self, max: u64, publish: F) -> Result<usize, Error>
+    where
+        F: FnOnce(Vec<OutboxEntry>) -> Fut + Send,
+        Fut: Future<Output = Vec<u64>> + Send,
+    {
+        self.inner.relay_outbox(max, publish).await
+    }
+
+    async fn write_with_effects<T>(
+        &self,
+        batch: Vec<Record<&T>>,
+        effects: Vec<Effect>,
+        with_outbox: bool,
+    ) -> Result<Unit, Error>
+    where
+        T: Serialize + Send + Sync,
+        T: for<'de> Deserialize<'de>,
+    {
+        let id = self.append(with_outbox, &effects, None, &batch).await?;
+        let result = self
+            .inner
+            .write_with_effects(batch, effects, with_outbox)
+            .await;
+        self.settle(id, &result).await;
+
+        result
+    }
+
+    async fn run_effects<F, Fut>(&self, max: u64, run: F) -> Result<usize, Error>
+    where
+        F: FnOnce(Vec<PendingEffect>) -> Fut + Send,
+        Fut: Future<Output = Vec<u64>> + Send,
+    {
+        self.inner.run_effects(max, run).await
+    }
+
+    async fn write_snapshot<S>(
+        &self,
+        entity_id: &str,
+        seq_nr: i64,
+        state_version: u32,
+        state: &S,
+        deleted: bool,
+    ) -> Result<Unit, Error>
+    where
+        S: Serialize + Send + Sync,
+    {
+        self.inner
+            .write_snapshot(entity_id, seq_nr, state_version, state, deleted)
+            .await
+    }
+
+    async fn read_latest_snapshot(&self, entity_id: &str) -> Result<Option<Snapshot>, Error> {
+        self.inner.read_latest_snapshot(entity_id).await
+    }
+
+    async fn write_with_idempotency_key<T>(
+        &self,
+        batch: Vec<Record<&T>>,
+        idempotency_key: IdempotencyKey,
+        effects: Vec<Effect>,
+        with_outbox: bool,
+    ) -> Result<Unit, Error>
+    where
+        T: Serialize + Send + Sync,
+        T: for<'de> Deserialize<'de>,
+    {
+        let id = self
+            .append(with_outbox, &effects, Some(&idempotency_key), &batch)
+            .await?;
+        let result = self
+            .inner
+            .write_with_idempotency_key(batch, idempotency_key, effects, with_outbox)
+            .await;
+        self.settle(id, &result).await;
+
+        result
+    }
+
+    async fn read_idempotency_key(
+        &self,
+        entity_id: &str,
+        key: &str,
+    ) -> Result<Option<(i64, i64)>, Error> {
+        self.inner.read_idempotency_key(entity_id, key).await
+    }
+}
+
+impl<A> CheckpointStore for WalAdapter<A>
+where
+    A: CheckpointStore,
+{
+    async fn save_checkpoint(&self, projection: &str, position: u64) -> Result<Unit, Error> {
+        self.inner.save_checkpoint(projection, position).await
+    }
+
+    async fn load_checkpoint(&self, projection: &str) -> Result<Option<u64>, Error> {
+        self.inner.load_checkpoint(projection).await
+    }
+}
+
+/// The batches the log keeps, in the order they were logged, and the id of the next batch.
+/// A line that cannot be read, e.g. the last one if the process crashed while appending it,
+/// is skipped.
+fn kept<T>(path: &Path, contents: &[u8], retention: WalRetention) -> (Vec<Batch<T>>, u64)
+where
+    T: DeserializeOwned,
+{
+    let mut batches = Vec::new();
+    let mut positions = HashMap::new();
+    let mut next_id = 0;
+
+    for line in contents.split(|byte| *byte == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+
+        match serde_json::from_slice::<Entry<Vec<Record<T>>>>(line) {
+            Ok(Entry::Batch {
+                id,
+                outbox,
+                effects,
+                idempotency_key,
+                records,
+            }) => {
+                next_id = next_id.max(id + 1);
+                positions.insert(id, batches.len());
+                batches.push(Some((outbox, effects, idempotency_key, records)));
+            }
+            Ok(Entry::Settled { id, written }) => {
+                let kept = written && retention == WalRetention::All;
+                if let (false, Some(position)) = (kept, positions.get(&id)) {
+                    batches[*position] = None;
+                }
+            }
+            Err(e) => tracing::warn!(
+                "Skipping an unreadable line of the write-ahead log {}: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+
+    (batches.into_iter().flatten().collect(), next_id)
+}
+
+/// Write the records of a batch the inner adapter does not hold yet, with the effects and the
+/// idempotency key of the batch unless the inner adapter holds its last record already.
+async fn recover<A, T>(
+    inner: &A,
+    outbox: bool,
+    effects: Vec<Effect>,
+    idempotency_key: Option<IdempotencyKey>,
+    records: Vec<Record<T>>,
+) -> Result<Unit, Error>
+where
+    A: Adapter,
+    T: Serialize + DeserializeOwned + Send + Sync,
+{
+    let mut highest = HashMap::new();
+    for record in &records {
+        if !highest.contains_key(record.entity_id()) {
+            let seq_nr = inner
+                .read_highest_sequence_number(record.entity_id())
+                .await?
+                .unwrap_or_default();
+            highest.insert(record.entity_id().to_string(), seq_nr);
+        }
+    }
+
+    let missing = records
+        .iter()
+        .filter(|record| record.seq_nr() as u64 > highest[record.entity_id()])
+        .map(Record::borrowed)
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    tracing::warn!(
+        "Recovering {} records from the write-ahead log",
+        missing.len()
+    );
+    match (idempotency_key, outbox, effects.is_empty()) {
+        (Some(idempotency_key), _, _) => {
+            inner
+                .write_with_idempotency_key(missing, idempotency_key, effects, outbox)
+                .await
+        }
+        (None, _, false) => inner.write_with_effects(missing, effects, outbox).await,
+        (None, true, true) => inner.write_with_outbox(missing).await,
+        (None, false, true) => inner.write(missing).await,
+    }
+}
+
+fn io_error(path: &Path, error: std::io::Error) -> Error {
+    Error::StorageError(format!(
+        "Write-ahead log {} failed: {}",
+        path.display(),
+        error
+    ))
+}